@@ -0,0 +1,20 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 position;
+
+// Holds the full view-projection matrix when rendering depth only
+layout(set = 1, binding = 0) uniform ViewMatrixBlock {
+	mat4 vp;
+} ViewMatrix;
+
+layout(set = 2, binding = 0) uniform ObjectBlock {
+	mat4 m;
+	mat4 mvp;
+	vec4 specular;
+} Matrices;
+
+void main()
+{
+	gl_Position = ViewMatrix.vp * Matrices.m * vec4(position, 1.0);
+}
@@ -0,0 +1,20 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 texCoord;
+layout(binding = 0) uniform sampler2D depthTex;
+// Must match PushConstants in presentpass.rs
+layout(push_constant) uniform PresentParams {
+    float exposure;
+    float near;
+    float far;
+} params;
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    // Undo the perspective divide to get the linear view depth, then map [near, far] to [0, 1]
+    float depth = texture(depthTex, texCoord).r;
+    float linearDepth = params.near * params.far / (params.far - depth * (params.far - params.near));
+    float gray = (linearDepth - params.near) / (params.far - params.near);
+    outColor = vec4(vec3(gray), 1.0);
+}
@@ -3,8 +3,13 @@
 
 layout(location = 0) in vec2 texCoord;
 layout(binding = 0) uniform sampler2D tex;
+// Must match PushConstants in presentpass.rs
+layout(push_constant) uniform PresentParams {
+    float exposure;
+} params;
 layout(location = 0) out vec4 outColor;
 
 void main() {
-    outColor = texture(tex, texCoord);
+    vec4 color = texture(tex, texCoord);
+    outColor = vec4(color.rgb * params.exposure, color.a);
 }
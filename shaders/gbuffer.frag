@@ -0,0 +1,53 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+precision highp float;
+
+layout(location = 3) in vec2 tex_uv;
+layout(location = 4) in vec4 vertex_color;
+layout(location = 5) in vec3 worldspace_normal;
+layout(location = 6) in vec3 worldspace_tangent;
+layout(location = 7) in vec3 worldspace_bitangent;
+layout(location = 8) in vec3 worldspace_position;
+
+// Same constants as phong.frag, the pipelines set them for both
+layout(constant_id = 0) const bool USE_VERTEX_COLOR = false;
+layout(constant_id = 3) const bool OVERDRAW = false;
+
+// Must match OVERDRAW_INCREMENT in phong.frag
+const float OVERDRAW_INCREMENT = 1.0 / 16.0;
+
+layout(set = 0, binding = 0) uniform sampler2D color_tex;
+layout(set = 0, binding = 1) uniform sampler2D normal_tex;
+
+// Must match the attachment order of the G-buffer renderpass in mainpass.rs
+layout(location = 0) out vec4 albedo;
+layout(location = 1) out vec4 normal;
+layout(location = 2) out vec4 position;
+
+void main()
+{
+	if (OVERDRAW)
+	{
+		albedo = vec4(OVERDRAW_INCREMENT);
+		normal = vec4(0.0);
+		position = vec4(0.0);
+		return;
+	}
+
+	albedo = USE_VERTEX_COLOR ? vertex_color : texture(color_tex, tex_uv);
+
+	vec3 N = normalize(worldspace_normal);
+	if (!USE_VERTEX_COLOR)
+	{
+		// Same decoding as phong.frag, then from tangent to world space
+		vec3 tangentspace_normal = texture(normal_tex, tex_uv).rgb;
+		tangentspace_normal.g = 1.0 - tangentspace_normal.g;
+		tangentspace_normal = 2.0 * tangentspace_normal - 1.0;
+		mat3 TBN = mat3(normalize(worldspace_tangent), normalize(worldspace_bitangent), N);
+		N = normalize(TBN * tangentspace_normal);
+	}
+
+	// The alpha matches the albedo, so the blended pipelines blend all targets alike
+	normal = vec4(N, albedo.a);
+	position = vec4(worldspace_position, albedo.a);
+}
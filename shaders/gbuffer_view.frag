@@ -0,0 +1,18 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 texCoord;
+layout(binding = 0) uniform sampler2D gbufferTex;
+// Must match PushConstants in presentpass.rs
+layout(push_constant) uniform PresentParams {
+    float exposure;
+    float scale;
+    float bias;
+} params;
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    // Maps the stored vectors into the visible range, e.g. normals from [-1, 1] to [0, 1]
+    vec3 value = texture(gbufferTex, texCoord).rgb;
+    outColor = vec4(clamp(value * params.scale + params.bias, 0.0, 1.0), 1.0);
+}
@@ -0,0 +1,20 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 texCoord;
+layout(binding = 0) uniform sampler2D overdrawTex;
+layout(location = 0) out vec4 outColor;
+
+// Must match OVERDRAW_INCREMENT in phong.frag
+const float OVERDRAW_INCREMENT = 1.0 / 16.0;
+
+void main() {
+    // Every fragment added the increment, so this is the fragment count as a fraction of the saturating one
+    float amount = texture(overdrawTex, texCoord).r;
+    // Black where nothing was drawn, then blue for a single layer through green to red when saturated
+    vec3 color = amount < 0.5 * OVERDRAW_INCREMENT
+        ? vec3(0.0)
+        : mix(mix(vec3(0.0, 0.0, 1.0), vec3(0.0, 1.0, 0.0), clamp(2.0 * amount, 0.0, 1.0)),
+              vec3(1.0, 0.0, 0.0), clamp(2.0 * amount - 1.0, 0.0, 1.0));
+    outColor = vec4(color, 1.0);
+}
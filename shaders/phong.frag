@@ -3,44 +3,103 @@
 precision highp float;
 
 layout(location = 0) in vec3 tangentspace_eyedir;
-layout(location = 1) in vec3 worldspace_lightdir;
+layout(location = 1) in float viewspace_depth;
 layout(location = 2) in vec3 tangentspace_lightdir;
 layout(location = 3) in vec2 tex_uv;
+layout(location = 4) in vec4 vertex_color;
+
+// Use the vertex color instead of the textures
+layout(constant_id = 0) const bool USE_VERTEX_COLOR = false;
+// The color texture has been premultiplied by its alpha
+layout(constant_id = 1) const bool PREMULTIPLIED_ALPHA = false;
+// Only count the fragment for the overdraw heatmap, with additive blending
+layout(constant_id = 3) const bool OVERDRAW = false;
+
+// Added per fragment in overdraw mode, 16 stacked fragments saturate the render image.
+// Must match OVERDRAW_INCREMENT in overdraw_heatmap.frag
+const float OVERDRAW_INCREMENT = 1.0 / 16.0;
 
 layout(set = 0, binding = 0) uniform sampler2D color_tex;
 layout(set = 0, binding = 1) uniform sampler2D normal_tex;
+// Scales the specular color in rgb and the shininess in a, white without a specular map
+layout(set = 0, binding = 2) uniform sampler2D specular_tex;
+
+layout(set = 1, binding = 1) uniform LightBlock {
+	// Direction towards the light
+	vec4 direction;
+	// Color in rgb, intensity in a
+	vec4 color;
+	vec4 ambient;
+	// Fog color in rgb
+	vec4 fog_color;
+	// Mode (0 off, 1 linear, 2 exponential), start and end distance, density
+	vec4 fog_params;
+} Light;
 
-layout(location = 0) out vec3 fragColor;
+layout(set = 2, binding = 0) uniform ObjectBlock {
+	mat4 m;
+	mat4 mvp;
+	// Specular color in rgb, shininess in a
+	vec4 specular;
+} Object;
 
-struct PointLight {
-	float radius;
-	vec3 color;
-};
+layout(location = 0) out vec4 fragColor;
 
-//hardcoded for now
-PointLight light = PointLight(45.0, vec3(1.0, 1.0, 1.0));
+// Returns how much of the fog color to blend in at the given view space depth
+float fog_amount(float depth)
+{
+	if (Light.fog_params.x == 1.0)
+	{
+		return clamp((depth - Light.fog_params.y) / (Light.fog_params.z - Light.fog_params.y), 0.0, 1.0);
+	}
+	if (Light.fog_params.x == 2.0)
+	{
+		return 1.0 - exp(-Light.fog_params.w * max(depth, 0.0));
+	}
+	return 0.0;
+}
 
 void main()
 {
-	vec3 color = vec3(0.0);
-	vec3 texcolor = texture(color_tex, tex_uv).rgb;
+	if (OVERDRAW)
+	{
+		fragColor = vec4(OVERDRAW_INCREMENT);
+		return;
+	}
+
+	vec4 texsample = USE_VERTEX_COLOR ? vertex_color : texture(color_tex, tex_uv);
+	vec3 texcolor = texsample.rgb;
+	// Lighting is linear in the color, but the specular highlight has to be weighted by hand
+	float specular_weight = PREMULTIPLIED_ALPHA ? texsample.a : 1.0;
+	vec3 color = texcolor * Light.ambient.rgb;
+	vec3 light_color = Light.color.rgb * Light.color.a;
+	vec4 specular_params = Object.specular;
+	if (!USE_VERTEX_COLOR)
+	{
+		vec4 specular_scale = texture(specular_tex, tex_uv);
+		specular_params.rgb *= specular_scale.rgb;
+		// A map scaling the shininess keeps it at least 1, as the highlight would cover everything at
+		// shininess 0. Without a map the scale is 1 and the material shininess is used as is.
+		if (specular_scale.a < 1.0)
+		{
+			specular_params.a = max(specular_params.a * specular_scale.a, 1.0);
+		}
+	}
 	// for each light
 	for (uint i = 0; i < 1u; i++)
 	{
-		// Check distance and calculate attenuation
-		if (length(worldspace_lightdir) > light.radius)
-			continue;
-		vec3 L_div_r = worldspace_lightdir / light.radius;
-		float attenuation = max(1.0 - dot(L_div_r, L_div_r), 0.0);
-
 		// Set up phong variables
 		vec3 L = normalize(tangentspace_lightdir);
-		// Look up the normal
-		vec3 normal = texture(normal_tex, tex_uv).rgb;
-		// Flip y-value from top left to bottom left
-		normal.g = 1.0 - normal.g;
-		// Move normal it from [0,1] to [-1, 1]
-		vec3 N = normalize(2.0 * normal - 1.0);
+		vec3 N = vec3(0.0, 0.0, 1.0);
+		if (!USE_VERTEX_COLOR)
+		{
+			// Look up the normal
+			vec3 normal = texture(normal_tex, tex_uv).rgb;
+			// Flip y-value from top left to bottom left
+			normal.g = 1.0 - normal.g;
+			// Move normal it from [0,1] to [-1, 1]
+			N = normalize(2.0 * normal - 1.0);
+		}
 
 		float lambertian = max(dot(L, N), 0.0);
 		float specular = 0.0;
@@ -49,14 +108,18 @@ void main()
 		{
 			vec3 V = normalize(tangentspace_eyedir);
 			vec3 R = normalize(reflect(-L, N));
-			specular = pow(max(dot(R, V), 0.0), 50.0);
+			specular = pow(max(dot(R, V), 0.0), specular_params.a);
 		}
 
 		// Diffuse
-		color += texcolor * lambertian * light.color * attenuation;
+		color += texcolor * lambertian * light_color;
 
 		// Specular
-		color += specular * light.color * attenuation;
+		color += specular * specular_weight * specular_params.rgb * light_color;
 	}
-	fragColor = color;
+	// Premultiplied colors need premultiplied fog too
+	color = mix(color, Light.fog_color.rgb * specular_weight, fog_amount(viewspace_depth));
+
+	// Alpha is only used by the blended pipelines
+	fragColor = vec4(color, texsample.a);
 }
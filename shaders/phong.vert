@@ -6,22 +6,47 @@ layout(location = 1) in vec3 normal;
 layout(location = 2) in vec3 tangent;
 layout(location = 3) in vec3 bitangent;
 layout(location = 4) in vec2 tex_uv;
+layout(location = 5) in vec4 color;
 
-layout(push_constant) uniform MatrixBlock {
-	mat4 m;
-	mat4 mvp;
-} Matrices;
+// Expand the line quads of Mesh::line_quads() to the line width
+layout(constant_id = 2) const bool WIDE_LINES = false;
+
+// Must match LinePushConstants in mainpass.rs
+layout(push_constant) uniform LineParams {
+	// Half the line width in normalized device coordinates
+	vec2 half_width;
+} Line;
 
 layout(set = 1, binding = 0) uniform ViewMatrixBlock {
        mat4 v;
 } ViewMatrix;
 
+layout(set = 1, binding = 1) uniform LightBlock {
+	vec4 direction;
+	vec4 color;
+	vec4 ambient;
+	// Fog color in rgb
+	vec4 fog_color;
+	// Mode (0 off, 1 linear, 2 exponential), start and end distance, density
+	vec4 fog_params;
+} Light;
+
+layout(set = 2, binding = 0) uniform ObjectBlock {
+	mat4 m;
+	mat4 mvp;
+	vec4 specular;
+} Matrices;
+
 layout(location = 0) out vec3 tangentspace_eyedir;
-layout(location = 1) out vec3 worldspace_lightdir;
+layout(location = 1) out float viewspace_depth;
 layout(location = 2) out vec3 tangentspace_lightdir;
 layout(location = 3) out vec2 interpolated_tex_uv;
-
-vec3 worldspace_lightpos = vec3(0.0, 0.0, 5.0);
+layout(location = 4) out vec4 interpolated_color;
+// World space basis and position, for the G-buffer
+layout(location = 5) out vec3 worldspace_normal;
+layout(location = 6) out vec3 worldspace_tangent;
+layout(location = 7) out vec3 worldspace_bitangent;
+layout(location = 8) out vec3 worldspace_position;
 
 void main()
 {
@@ -34,20 +59,37 @@ void main()
 	// calculate the tangent space matrix
 	mat3 TBN = transpose(mat3(viewspace_tangent, viewspace_bitangent, viewspace_normal));
 
-	// position is a point, set w to 1.0 and divide it out afterwards
-	vec4 worldspace_pos4 = Matrices.m * vec4(position, 1.0);
-	vec3 worldspace_pos = vec3(worldspace_pos4) / worldspace_pos4.w;
-
 	// calculate eyedir and lightdir in tangent space
 	vec4 viewspace_pos4 = mv_matrix * vec4(position, 1.0);
 	vec3 viewspace_pos = vec3(viewspace_pos4) / viewspace_pos4.w;
 	tangentspace_eyedir = TBN * (-viewspace_pos);
-	worldspace_lightdir = (worldspace_lightpos - worldspace_pos);
-	vec3 viewspace_lightdir = vec3(ViewMatrix.v * vec4(worldspace_lightdir, 0.0));
+	viewspace_depth = -viewspace_pos.z;
+	vec3 viewspace_lightdir = vec3(ViewMatrix.v * vec4(Light.direction.xyz, 0.0));
 	tangentspace_lightdir = TBN * viewspace_lightdir;
 
 	// interpolate texture coordinates
 	interpolated_tex_uv = tex_uv;
+	interpolated_color = color;
+
+	mat3 model_rotation = mat3(Matrices.m);
+	worldspace_normal = model_rotation * normal;
+	worldspace_tangent = model_rotation * tangent;
+	worldspace_bitangent = model_rotation * bitangent;
+	vec4 worldspace_pos4 = Matrices.m * vec4(position, 1.0);
+	worldspace_position = vec3(worldspace_pos4) / worldspace_pos4.w;
 
 	gl_Position = Matrices.mvp * vec4(position, 1.0);
+
+	if (WIDE_LINES)
+	{
+		// The normal is the other end of the segment and the texture U the side to move to
+		vec4 other = Matrices.mvp * vec4(normal, 1.0);
+		// Scaled to pixels, so the offset is perpendicular on screen for any aspect ratio
+		vec2 direction = (other.xy / other.w - gl_Position.xy / gl_Position.w) / Line.half_width;
+		if (dot(direction, direction) > 0.0)
+		{
+			direction = normalize(direction);
+			gl_Position.xy += vec2(-direction.y, direction.x) * Line.half_width * tex_uv.x * gl_Position.w;
+		}
+	}
 }
@@ -0,0 +1,24 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Must match SkyPushConstants in sky.rs
+layout(push_constant) uniform SkyParams {
+	mat4 inverse_view_projection;
+	vec4 horizon_color;
+	vec4 zenith_color;
+} Sky;
+
+layout(location = 0) in vec2 ndc;
+
+layout(location = 0) out vec4 fragColor;
+
+void main()
+{
+	// The point on the far plane seen through this pixel, in world space relative to the camera
+	vec4 far_point = Sky.inverse_view_projection * vec4(ndc, 1.0, 1.0);
+	vec3 ray = normalize(far_point.xyz / far_point.w);
+
+	// Everything below the horizon gets the horizon color
+	float height = max(ray.y, 0.0);
+	fragColor = vec4(mix(Sky.horizon_color.rgb, Sky.zenith_color.rgb, height), 1.0);
+}
@@ -0,0 +1,17 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+out gl_PerVertex {
+	vec4 gl_Position;
+};
+
+// Position in normalized device coordinates, to find the view ray in the fragment shader
+layout(location = 0) out vec2 ndc;
+
+void main()
+{
+	// A single triangle covering the whole screen, like the present pass draws
+	vec2 uv = vec2(gl_VertexIndex & 2, (gl_VertexIndex << 1) & 2);
+	ndc = uv * 2.0 - 1.0;
+	gl_Position = vec4(ndc, 1.0, 1.0);
+}
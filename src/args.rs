@@ -0,0 +1,136 @@
+use log::Verbosity;
+use std::str::FromStr;
+
+const DEFAULT_CONFIG: &'static str = "options.json";
+
+/// Where and how long to record the camera spline, see --record.
+pub struct Recording
+{
+	pub directory: String,
+	pub fps: u32,
+	pub seconds: f32,
+}
+
+/// Options given on the command line, overriding the config file where given.
+pub struct Args
+{
+	/// Config file to read, the local overrides are read from next to it.
+	pub config: String,
+	/// Scene saved with the console's save command, loaded instead of the default scene.
+	pub scene: Option<String>,
+	/// Window and render size in logical pixels.
+	pub width: Option<u32>,
+	pub height: Option<u32>,
+	/// Renders without a window, only for the modes that don't present.
+	pub headless: bool,
+	/// Number of frames to render before printing the frame times and exiting.
+	pub benchmark: Option<u32>,
+	pub record: Option<Recording>,
+	/// Reference image to compare the first frame against.
+	pub regression: Option<String>,
+	pub stress: Option<usize>,
+	pub material_preview: bool,
+	/// Set by --quiet or --verbose, overriding the verbosity in the config.
+	pub verbosity: Option<Verbosity>,
+	pub help: bool,
+}
+
+impl Args
+{
+	/// Parses the arguments, without the program name.
+	pub fn parse(args: &[String]) -> Result<Args, String>
+	{
+		let mut parsed = Args {
+			config: String::from(DEFAULT_CONFIG),
+			scene: None,
+			width: None,
+			height: None,
+			headless: false,
+			benchmark: None,
+			record: None,
+			regression: None,
+			stress: None,
+			material_preview: false,
+			verbosity: None,
+			help: false,
+		};
+
+		let mut i = 0;
+		while i < args.len()
+		{
+			let name = args[i].as_str();
+			i += 1;
+			match name
+			{
+				"--config" => parsed.config = Args::value(args, &mut i, name)?,
+				"--scene" => parsed.scene = Some(Args::value(args, &mut i, name)?),
+				"--width" => parsed.width = Some(Args::value(args, &mut i, name)?),
+				"--height" => parsed.height = Some(Args::value(args, &mut i, name)?),
+				"--headless" => parsed.headless = true,
+				"--benchmark" => parsed.benchmark = Some(Args::value(args, &mut i, name)?),
+				"--record" =>
+				{
+					parsed.record = Some(Recording {
+						directory: Args::value(args, &mut i, name)?,
+						fps: Args::value(args, &mut i, name)?,
+						seconds: Args::value(args, &mut i, name)?,
+					})
+				}
+				"--regression" => parsed.regression = Some(Args::value(args, &mut i, name)?),
+				"--stress" => parsed.stress = Some(Args::value(args, &mut i, name)?),
+				"--material-preview" => parsed.material_preview = true,
+				"--quiet" | "-q" => parsed.verbosity = Some(Verbosity::Quiet),
+				"--verbose" | "-v" => parsed.verbosity = Some(Verbosity::Verbose),
+				"--help" | "-h" => parsed.help = true,
+				_ => return Err(format!("Unknown argument: {}", name)),
+			}
+		}
+
+		if parsed.width == Some(0) || parsed.height == Some(0) || parsed.benchmark == Some(0)
+		{
+			return Err(String::from("--width, --height and --benchmark must be greater than 0"));
+		}
+		if parsed.record.as_ref().map_or(false, |record| record.fps == 0)
+		{
+			return Err(String::from("--record needs at least 1 frame per second"));
+		}
+		if parsed.headless && parsed.benchmark.is_none() && !parsed.renders_offscreen()
+		{
+			return Err(String::from("--headless needs --benchmark, --record or --regression"));
+		}
+
+		Ok(parsed)
+	}
+
+	/// Parses the value following an argument and moves past it.
+	fn value<T: FromStr>(args: &[String], i: &mut usize, name: &str) -> Result<T, String>
+	{
+		let value = args.get(*i).ok_or_else(|| format!("{} is missing a value", name))?;
+		*i += 1;
+		value.parse().map_err(|_| format!("Invalid value for {}: {}", name, value))
+	}
+
+	/// Returns true if the program renders offscreen and exits instead of opening the main loop.
+	pub fn renders_offscreen(&self) -> bool
+	{
+		self.record.is_some() || self.regression.is_some()
+	}
+
+	pub fn print_usage()
+	{
+		println!("Usage: project_peril [options]");
+		println!("  --config <path>              config file to read, {} by default", DEFAULT_CONFIG);
+		println!("  --scene <path>               load a saved scene instead of the default scene");
+		println!("  --width <pixels>             window and render width");
+		println!("  --height <pixels>            window and render height");
+		println!("  --headless                   render without a window, with --benchmark");
+		println!("  --benchmark <frames>         print the frame times after the frames and exit");
+		println!("  --record <dir> <fps> <secs>  render the camera spline to PNGs, without a window");
+		println!("  --regression <path>          compare the first frame to a reference image");
+		println!("  --stress <count>             add a grid of stress test objects");
+		println!("  --material-preview           start with the material preview shown");
+		println!("  --quiet, -q                  only print errors");
+		println!("  --verbose, -v                also print the details of the device setup");
+		println!("  --help                       show this message");
+	}
+}
@@ -1,4 +1,13 @@
+use cgmath::{Deg, Rad};
+use log::Verbosity;
+use object::{SplineEndMode, TurntableMode};
+use object::mesh::Winding;
+use renderer::{Background, ColorLoadOp, DepthBias, FogMode, LineRendering, ScalingMode, StencilConfig,
+               UniformMemory};
+use scatter::Scatter;
 use serde_json;
+use serde_json::Value;
+use std::env;
 use std::fs::File;
 use std::io::{Error, ErrorKind};
 
@@ -6,20 +15,345 @@ const APP_NAME: &'static str = "ProjectPeril";
 const APP_VERSION_MAJOR: &'static str = env!("CARGO_PKG_VERSION_MAJOR");
 const APP_VERSION_MINOR: &'static str = env!("CARGO_PKG_VERSION_MINOR");
 const APP_VERSION_PATCH: &'static str = env!("CARGO_PKG_VERSION_PATCH");
+const DEFAULT_HORIZONTAL_FOV: u32 = 90;
+/// Prefix of the environment variables overriding config fields, e.g. PROJECT_PERIL_RENDER_WIDTH.
+const ENV_PREFIX: &'static str = "PROJECT_PERIL_";
+
+/// How the scene is projected onto the screen.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Projection
+{
+	Perspective,
+	Orthographic,
+}
+
+impl Projection
+{
+	/// Returns the other projection mode.
+	pub fn toggled(self) -> Projection
+	{
+		match self
+		{
+			Projection::Perspective => Projection::Orthographic,
+			Projection::Orthographic => Projection::Perspective,
+		}
+	}
+}
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config
 {
 	pub app_name: String,
 	pub app_version: u32,
-	pub horizontal_fov: u32,
+	/// Horizontal field of view in degrees. Mutually exclusive with fov_vertical.
+	#[serde(alias = "horizontal_fov")]
+	pub fov_horizontal: Option<u32>,
+	/// Vertical field of view in degrees. Mutually exclusive with fov_horizontal.
+	pub fov_vertical: Option<u32>,
+	pub projection: Projection,
+	/// Height of the view in world units when using the orthographic projection.
+	pub ortho_height: f32,
 	pub mouse_invert_x: bool,
 	pub mouse_invert_y: bool,
 	pub mouse_sensitivity: f64,
+	/// Size rendered at when headless. With a window the render size follows the window size.
 	pub render_width: u32,
 	pub render_height: u32,
+	/// Fraction of the window size (or headless render size) to actually render at, clamped to
+	/// [0.25, 2.0].
+	pub render_scale: f32,
 	pub window_width: u32,
 	pub window_height: u32,
+	/// Title of the window, the app name and version if None.
+	pub window_title: Option<String>,
+	/// Image file to use as the window icon, ignored where the platform has no window icons.
+	pub window_icon: Option<String>,
+	pub frame_interpolation: bool,
+	pub camera_position: [f32; 3],
+	pub camera_yaw: f32,
+	pub camera_pitch: f32,
+	/// Overrides yaw and pitch if set.
+	pub camera_look_at: Option<[f32; 3]>,
+	/// Use a depth format with a stencil component, if the device has one.
+	pub depth_stencil: bool,
+	/// Stencil test of the main pass pipelines, used if depth_stencil is set.
+	pub stencil: StencilConfig,
+	/// Also list devices that only implement the Vulkan portability subset, e.g. MoltenVK on macOS.
+	pub portability_enumeration: bool,
+	/// Vulkan API version to target, one of "1.0", "1.1" or "1.2".
+	pub vulkan_api_version: String,
+	/// Additional instance extensions to enable, startup fails if any of them is missing.
+	pub instance_extensions: Vec<String>,
+	/// Additional device extensions to enable, startup fails if any of them is missing.
+	pub device_extensions: Vec<String>,
+	pub camera_acceleration: f32,
+	pub camera_damping: f32,
+	/// Whether the turntable, toggled with U, orbits the camera or turns the selected object.
+	pub turntable_mode: TurntableMode,
+	/// Axis the turntable turns around.
+	pub turntable_axis: [f32; 3],
+	/// Turntable speed in degrees per second of simulated time.
+	pub turntable_speed: f32,
+	pub present_scaling: ScalingMode,
+	/// Color of the window area the presented image doesn't cover, e.g. the borders of integer scaling.
+	pub letterbox_color: [f32; 4],
+	/// Number of swapchain images to request. More images smooth out frame time spikes at the cost of
+	/// latency. Clamped to what the surface supports.
+	pub swapchain_images: u32,
+	/// Brightness multiplier applied when presenting.
+	pub exposure: f32,
+	/// Number of threads recording the scene draws, 1 records them on the main thread. The debug
+	/// overlays like the axis gizmo and the minimap are not drawn with more threads.
+	pub record_threads: usize,
+	/// Speed of the simulation relative to real time, 0 pauses it. Clamped to [0, 16].
+	pub time_scale: f32,
+	/// Maximum number of simulation steps to run per frame at time scale 1, further steps are dropped.
+	/// Faster time scales allow proportionally more.
+	pub max_sim_steps_per_frame: u32,
+	/// Frames taking longer than this (in milliseconds) are logged.
+	pub frame_spike_ms: u64,
+	/// How much is printed to the console, --quiet and --verbose override it. Messages while reading the
+	/// config itself follow the command line only.
+	pub verbosity: Verbosity,
+	/// Time the CPU side of the frame phases and print them every 100 frames.
+	pub cpu_profiling: bool,
+	/// Print how long the scenes and each part of the renderer take to clean up on exit, to find what
+	/// makes shutting down slow. Usually it is waiting for the device to be idle.
+	pub teardown_timing: bool,
+	/// Print the draw calls and triangles of the last frame every 100 frames.
+	pub draw_stats: bool,
+	/// Textures larger than this in either dimension are downscaled on load. The device limit always
+	/// applies.
+	pub max_texture_size: Option<u32>,
+	/// Vertices of a mesh that are equal within this in all attributes are merged before it is uploaded,
+	/// not set keeps them.
+	pub mesh_weld_epsilon: Option<f32>,
+	/// Remove the zero area triangles of meshes before they are uploaded.
+	pub remove_degenerate_triangles: bool,
+	/// Winding of the front faces of meshes, Auto flips the meshes whose triangles mostly face inwards.
+	pub mesh_winding: Winding,
+	/// Number of threads decoding texture images in the background, 0 decodes them on the main thread.
+	pub decode_threads: usize,
+	/// Initial size in bytes of the buffer texture and mesh uploads are staged in. It grows to fit
+	/// larger uploads.
+	pub staging_buffer_size: u64,
+	/// Memory for the uniform buffers, host coherent if not set. Device local memory is meant for
+	/// discrete GPUs, but isn't the default until it is measured to be faster.
+	pub uniform_memory: Option<UniformMemory>,
+	/// Direction towards the directional light.
+	pub light_direction: [f32; 3],
+	pub light_color: [f32; 3],
+	pub light_intensity: f32,
+	pub ambient_color: [f32; 3],
+	pub clear_color: [f32; 4],
+	/// Clear shows the clear color behind the scene, Gradient a sky from the horizon to the zenith color.
+	/// Cycled with Y.
+	pub background: Background,
+	pub sky_horizon_color: [f32; 3],
+	pub sky_zenith_color: [f32; 3],
+	/// Clear, Load or DontCare the render image at the start of a frame. DontCare is only used if
+	/// something covers the whole frame, and clears otherwise.
+	pub color_load_op: ColorLoadOp,
+	/// Render the albedo, world space normals and positions to a G-buffer instead of shading, as a base
+	/// for deferred shading. The presented target is cycled with J.
+	pub gbuffer: bool,
+	/// Positions within this distance of the origin along each axis are told apart in the G-buffer
+	/// position view.
+	pub gbuffer_position_range: f32,
+	/// Fog fading distant geometry towards fog_color, Off, Linear or Exponential.
+	pub fog_mode: FogMode,
+	pub fog_color: [f32; 3],
+	/// View space depth in world units where linear fog starts and reaches full strength.
+	pub fog_start: f32,
+	pub fog_end: f32,
+	/// Exponential fog density per world unit.
+	pub fog_density: f32,
+	/// Size in pixels of each face of the cube depth texture, 0 disables cube depth rendering.
+	pub cube_depth_size: u32,
+	/// Position the cube depth texture is rendered from each frame, where a point light would be.
+	pub cube_depth_position: [f32; 3],
+	/// Clamp the depth of shadow casters in front of the light's near plane instead of clipping them,
+	/// which would cut holes into their shadows. Applies to the cube depth pass, so it needs a
+	/// cube_depth_size, and requires the depth clamp device feature.
+	pub shadow_depth_clamp: bool,
+	/// Image shown in place of textures that fail to load, a magenta checkerboard if not set.
+	pub missing_texture: Option<String>,
+	/// Skip drawing objects that were hidden behind others in the previous frame, using occlusion
+	/// queries. Not supported with parallel recording.
+	pub occlusion_culling: bool,
+	/// Build generated grids (e.g. the walls) from triangle strips instead of triangle lists.
+	pub strip_primitives: bool,
+	/// Number of extra objects to spawn in a grid for stress testing, 0 disables stress mode.
+	pub stress_objects: usize,
+	/// Mesh of the stress test objects, one of "cube", "quad", "triangle" or "hexagon".
+	pub stress_mesh: String,
+	/// Extra objects with the stress test mesh, scattered with a seeded random layout.
+	pub scatter: Option<Scatter>,
+	/// Show the axis gizmo in the bottom left corner, toggled with G.
+	pub show_axis_gizmo: bool,
+	/// Size of the axis gizmo in window pixels.
+	pub axis_gizmo_size: u32,
+	/// Show a top-down map of the objects and the camera frustum in the bottom right corner, toggled
+	/// with K.
+	pub show_minimap: bool,
+	/// Size of the minimap in window pixels.
+	pub minimap_size: u32,
+	/// Half the width of the area shown by the minimap in world units, centered on the camera.
+	pub minimap_range: f32,
+	/// Width in pixels of debug lines like the bounds and the axis gizmo.
+	pub line_width: f32,
+	/// Whether lines are rasterized or drawn as quads, which also gets wide lines without device support.
+	pub line_rendering: LineRendering,
+	/// Depth bias of decals, negative to pull them in front of the surface they lie on.
+	pub decal_depth_bias: DepthBias,
+	/// Control points of the camera spline.
+	pub spline_points: Vec<[f64; 3]>,
+	/// JSON file with the control points of the camera spline, replacing spline_points if set.
+	pub spline_path: Option<String>,
+	/// Order of the camera spline, from 2 for linear to 5 for quartic.
+	pub spline_order: usize,
+	/// Number of points the camera spline is sampled at for drawing and exporting.
+	pub spline_samples: usize,
+	/// If set, the drawn camera spline is sampled adaptively instead, with the curve deviating at
+	/// most this far from the line between the samples.
+	pub spline_max_error: Option<f32>,
+	/// CSV file to export the sampled camera spline to on startup.
+	pub spline_export_path: Option<String>,
+	/// Speed of the cinematic camera in spline parameter units per second.
+	pub spline_speed: f64,
+	/// Ease the cinematic camera in and out at the ends of the spline.
+	pub spline_ease: bool,
+	/// What the cinematic camera does at the end of the spline.
+	pub spline_end_mode: SplineEndMode,
+	/// Scenes saved with the console's save command, loaded on startup next to the default scene and
+	/// switched to with the number keys 2 and up. All of them stay resident on the GPU.
+	pub scenes: Vec<String>,
+	/// Leave the camera where it is when switching scenes, instead of moving it to the saved pose.
+	pub keep_camera_on_scene_switch: bool,
+	/// Fields overridden by the local config file, which save() leaves untouched.
+	#[serde(skip)]
+	local_overrides: Vec<String>,
+}
+
+impl Default for Config
+{
+	/// Generates a Config with default values.
+	///
+	/// Options missing from the config file fall back to these values.
+	fn default() -> Config
+	{
+		Config {
+			app_name: String::from(APP_NAME),
+			app_version: 0,
+			fov_horizontal: None,
+			fov_vertical: None,
+			projection: Projection::Perspective,
+			ortho_height: 10.0,
+			mouse_invert_x: false,
+			mouse_invert_y: false,
+			mouse_sensitivity: 0.3,
+			render_width: 480,
+			render_height: 320,
+			render_scale: 1.0,
+			window_width: 480,
+			window_height: 320,
+			window_title: None,
+			window_icon: None,
+			frame_interpolation: true,
+			camera_position: [0.0, 0.0, 0.0],
+			camera_yaw: -90.0,
+			camera_pitch: 0.0,
+			camera_look_at: None,
+			depth_stencil: false,
+			stencil: StencilConfig::default(),
+			portability_enumeration: true,
+			vulkan_api_version: String::from("1.0"),
+			instance_extensions: Vec::new(),
+			device_extensions: Vec::new(),
+			camera_acceleration: 10.0,
+			camera_damping: 8.0,
+			turntable_mode: TurntableMode::Camera,
+			turntable_axis: [0.0, 1.0, 0.0],
+			turntable_speed: 20.0,
+			present_scaling: ScalingMode::Linear,
+			letterbox_color: [0.0, 0.0, 0.0, 1.0],
+			swapchain_images: 2,
+			exposure: 1.0,
+			record_threads: 1,
+			time_scale: 1.0,
+			max_sim_steps_per_frame: 5,
+			frame_spike_ms: 100,
+			verbosity: Verbosity::Normal,
+			cpu_profiling: false,
+			teardown_timing: false,
+			draw_stats: false,
+			max_texture_size: None,
+			mesh_weld_epsilon: Some(1e-5),
+			remove_degenerate_triangles: true,
+			mesh_winding: Winding::CounterClockwise,
+			decode_threads: 2,
+			staging_buffer_size: 16 * 1024 * 1024,
+			uniform_memory: None,
+			light_direction: [0.3, 0.5, 1.0],
+			light_color: [1.0, 1.0, 1.0],
+			light_intensity: 1.0,
+			ambient_color: [0.1, 0.1, 0.1],
+			clear_color: [0.0, 1.0, 0.0, 1.0],
+			background: Background::Clear,
+			sky_horizon_color: [0.8, 0.85, 0.9],
+			sky_zenith_color: [0.15, 0.35, 0.75],
+			color_load_op: ColorLoadOp::Clear,
+			gbuffer: false,
+			gbuffer_position_range: 50.0,
+			fog_mode: FogMode::Off,
+			fog_color: [0.5, 0.6, 0.7],
+			fog_start: 10.0,
+			fog_end: 50.0,
+			fog_density: 0.05,
+			cube_depth_size: 0,
+			cube_depth_position: [0.0, 5.0, 0.0],
+			shadow_depth_clamp: false,
+			missing_texture: None,
+			occlusion_culling: false,
+			strip_primitives: false,
+			stress_objects: 0,
+			stress_mesh: String::from("cube"),
+			scatter: None,
+			show_axis_gizmo: true,
+			axis_gizmo_size: 80,
+			show_minimap: false,
+			minimap_size: 200,
+			minimap_range: 20.0,
+			line_width: 1.0,
+			line_rendering: LineRendering::Auto,
+			decal_depth_bias: DepthBias {
+				constant: -2.0,
+				slope: -2.0,
+			},
+			spline_points: vec![
+				[1.0, 0.0, 0.0],
+				[0.0, 1.0, 0.0],
+				[-1.0, 0.0, 0.0],
+				[0.0, -1.0, 0.0],
+				[0.0, 0.0, 1.0],
+				[0.0, 0.0, -1.0],
+				[0.0, 1.0, -1.0],
+				[1.0, 0.0, -1.0],
+			],
+			spline_path: None,
+			spline_order: 4,
+			spline_samples: 100,
+			spline_max_error: None,
+			spline_export_path: None,
+			spline_speed: 0.5,
+			spline_ease: true,
+			spline_end_mode: SplineEndMode::PingPong,
+			scenes: Vec::new(),
+			keep_camera_on_scene_switch: false,
+			local_overrides: Vec::new(),
+		}
+	}
 }
 
 impl Config
@@ -50,20 +384,295 @@ impl Config
 		format!("v{}.{}.{}", major, minor, patch)
 	}
 
+	/// Returns the window title, the app name and version unless window_title is set.
+	pub fn title(&self) -> String
+	{
+		match self.window_title
+		{
+			Some(ref title) => title.clone(),
+			None => format!("{} {}", self.app_name, self.version_to_string()),
+		}
+	}
+
+	/// Returns the vertical field of view for the given aspect ratio (width / height).
+	///
+	/// The projection always uses a vertical FOV, so a horizontal FOV is converted using the aspect
+	/// ratio. Defaults to a horizontal FOV of 90 degrees if neither is set.
+	pub fn vertical_fov(&self, aspect_ratio: f32) -> Rad<f32>
+	{
+		match self.fov_vertical
+		{
+			Some(fov) => Rad::from(Deg(fov as f32)),
+			None =>
+			{
+				let horizontal = Rad::from(Deg(self.fov_horizontal.unwrap_or(DEFAULT_HORIZONTAL_FOV) as f32));
+				Rad(2.0 * ((horizontal.0 / 2.0).tan() / aspect_ratio).atan())
+			}
+		}
+	}
+
+	/// Returns the major and minor version of the targeted Vulkan API, if it is a supported one.
+	pub fn vulkan_api_version(&self) -> Option<(u32, u32)>
+	{
+		match self.vulkan_api_version.trim()
+		{
+			"1.0" => Some((1, 0)),
+			"1.1" => Some((1, 1)),
+			"1.2" => Some((1, 2)),
+			_ => None,
+		}
+	}
+
+	/// Returns the name of the environment variable overriding the given field.
+	fn env_var_name(field: &str) -> String
+	{
+		format!("{}{}", ENV_PREFIX, field.to_uppercase())
+	}
+
 	/// Saves the Config to the supplied filename.
-	fn save(&self, filename: &str) -> Result<(), Error>
+	///
+	/// Fields overridden by environment variables or the local config file keep the value they have
+	/// in the existing file.
+	pub fn save(&self, filename: &str) -> Result<(), Error>
 	{
+		let mut json = serde_json::to_value(self)?;
+		{
+			let fields = json.as_object_mut().expect("Config is not a JSON object");
+			let overridden: Vec<String> = fields
+				.keys()
+				.filter(|name| {
+					self.local_overrides.contains(name) || env::var_os(Config::env_var_name(name)).is_some()
+				})
+				.cloned()
+				.collect();
+			if !overridden.is_empty()
+			{
+				let previous: Option<Value> =
+					File::open(filename).ok().and_then(|file| serde_json::from_reader(file).ok());
+				for name in overridden
+				{
+					match previous.as_ref().and_then(|previous| previous.get(&name))
+					{
+						Some(value) => fields.insert(name, value.clone()),
+						None => fields.remove(&name),
+					};
+				}
+			}
+		}
+
 		let file = File::create(filename)?;
-		match serde_json::to_writer_pretty(file, self)
+		match serde_json::to_writer_pretty(file, &json)
 		{
 			Ok(_) => Ok(()),
 			Err(e) => Err(e.into()),
 		}
 	}
 
+	/// Saves a single field to the supplied filename, leaving the rest of the existing file as it is.
+	///
+	/// For remembering a runtime adjustment, without also saving the overrides and other runtime
+	/// changes. Does nothing if the field is overridden, like save() keeping its value.
+	pub fn save_field(&self, filename: &str, field: &str) -> Result<(), Error>
+	{
+		if self.local_overrides.iter().any(|name| name == field) || env::var_os(Config::env_var_name(field)).is_some()
+		{
+			return Ok(());
+		}
+		let value = match serde_json::to_value(self)?.get(field)
+		{
+			Some(value) => value.clone(),
+			None => return Err(Error::new(ErrorKind::InvalidInput, format!("Unknown config option {}", field))),
+		};
+
+		let mut json: Value = serde_json::from_reader(File::open(filename)?)?;
+		match json.as_object_mut()
+		{
+			Some(fields) => fields.insert(field.to_string(), value),
+			None => return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a JSON object", filename))),
+		};
+		let file = File::create(filename)?;
+		match serde_json::to_writer_pretty(file, &json)
+		{
+			Ok(_) => Ok(()),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Makes save() keep the value the field has in the existing file, for values overridden on the
+	/// command line.
+	pub fn keep_saved_value(&mut self, field: &str)
+	{
+		if !self.local_overrides.iter().any(|name| name == field)
+		{
+			self.local_overrides.push(field.to_string());
+		}
+	}
+
+	/// Returns the name of the local config file overriding the given one, e.g. options.local.json for
+	/// options.json.
+	pub fn local_config_name(filename: &str) -> String
+	{
+		match filename.rfind('.')
+		{
+			Some(dot) if !filename[dot..].contains('/') => format!("{}.local{}", &filename[..dot], &filename[dot..]),
+			_ => format!("{}.local", filename),
+		}
+	}
+
+	/// Overrides fields with the ones set in the local config file, if it exists.
+	///
+	/// The local file only needs to contain the fields it changes, the rest keep their values.
+	fn apply_local_overrides(&mut self, filename: &str) -> Result<(), Error>
+	{
+		let file = match File::open(filename)
+		{
+			Ok(file) => file,
+			Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e),
+		};
+		let local: Value = serde_json::from_reader(file)?;
+		let local = match local
+		{
+			Value::Object(local) => local,
+			_ => return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a JSON object", filename))),
+		};
+
+		let mut json = serde_json::to_value(&*self)?;
+		let mut overridden = Vec::new();
+		{
+			let fields = json.as_object_mut().expect("Config is not a JSON object");
+			for (name, value) in local
+			{
+				if fields.contains_key(&name)
+				{
+					fields.insert(name.clone(), value);
+					overridden.push(name);
+				}
+				else
+				{
+					warning!("Unknown config option {} in {}, ignoring it.", name, filename);
+				}
+			}
+		}
+		if overridden.is_empty()
+		{
+			return Ok(());
+		}
+
+		*self = serde_json::from_value(json)
+			.map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid value in {}: {}", filename, e)))?;
+		info!("Config options {} overridden by {}", overridden.join(", "), filename);
+		self.local_overrides = overridden;
+
+		Ok(())
+	}
+
+	/// Overrides fields with the values of PROJECT_PERIL_<FIELD> environment variables.
+	///
+	/// Values are parsed as JSON, falling back to a plain string, e.g. PROJECT_PERIL_RENDER_WIDTH=1280 or
+	/// PROJECT_PERIL_PRESENT_SCALING=Nearest.
+	fn apply_env_overrides(&mut self) -> Result<(), Error>
+	{
+		let mut json = serde_json::to_value(&*self)?;
+		let mut overridden = Vec::new();
+		{
+			let fields = json.as_object_mut().expect("Config is not a JSON object");
+			let names: Vec<String> = fields.keys().cloned().collect();
+			for name in names
+			{
+				let var = Config::env_var_name(&name);
+				if let Ok(value) = env::var(&var)
+				{
+					let parsed = serde_json::from_str(&value).unwrap_or_else(|_| Value::String(value.clone()));
+					fields.insert(name.clone(), parsed);
+					overridden.push((name, var, value));
+				}
+			}
+		}
+		if overridden.is_empty()
+		{
+			return Ok(());
+		}
+
+		let local_overrides = self.local_overrides.clone();
+		*self = serde_json::from_value(json).map_err(|e| {
+			Error::new(ErrorKind::InvalidData, format!("Invalid value in environment override: {}", e))
+		})?;
+		self.local_overrides = local_overrides;
+		for (name, var, value) in overridden
+		{
+			info!("Config option {} overridden by {}={}", name, var, value);
+		}
+
+		Ok(())
+	}
+
+	/// Applies the local config file and then the environment variables, and checks the result.
+	fn apply_overrides(&mut self, local_filename: Option<&str>) -> Result<(), Error>
+	{
+		if let Some(local_filename) = local_filename
+		{
+			self.apply_local_overrides(local_filename)?;
+		}
+		self.apply_env_overrides()?;
+		self.validate()
+	}
+
+	/// Checks for conflicting options.
+	fn validate(&self) -> Result<(), Error>
+	{
+		if self.fov_horizontal.is_some() && self.fov_vertical.is_some()
+		{
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				"fov_horizontal and fov_vertical are mutually exclusive, set only one of them",
+			));
+		}
+		if self.vulkan_api_version().is_none()
+		{
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				format!("Unsupported vulkan_api_version {}, use 1.0, 1.1 or 1.2", self.vulkan_api_version),
+			));
+		}
+		let unsupported = self.unsupported_with_parallel_recording();
+		if !unsupported.is_empty()
+		{
+			warning!("{} not drawn with record_threads > 1", unsupported.join(", "));
+		}
+
+		Ok(())
+	}
+
+	/// Returns the enabled options that the parallel recording skips.
+	fn unsupported_with_parallel_recording(&self) -> Vec<&'static str>
+	{
+		let mut unsupported = Vec::new();
+		if self.record_threads <= 1
+		{
+			return unsupported;
+		}
+		if self.show_axis_gizmo
+		{
+			unsupported.push("show_axis_gizmo");
+		}
+		if self.show_minimap
+		{
+			unsupported.push("show_minimap");
+		}
+		if self.background == Background::Gradient
+		{
+			unsupported.push("background Gradient");
+		}
+		unsupported
+	}
+
 	/// Either reads the config given by the filename and generates a Config struct,
 	/// or creates a default config and saves it to disk if the config file is not found.
-	pub fn read_config(filename: &str) -> Result<Config, Error>
+	///
+	/// The fields in the local config file, if given and found, replace the ones read. Environment
+	/// variable overrides are applied last, see apply_env_overrides().
+	pub fn read_config(filename: &str, local_filename: Option<&str>) -> Result<Config, Error>
 	{
 		let correct_name = String::from(APP_NAME);
 		let correct_version = Config::make_version(
@@ -76,6 +685,7 @@ impl Config
 		{
 			Ok(file) =>
 			{
+				// Validated once the overrides are applied, which may resolve or introduce conflicts
 				let mut cfg: Config = serde_json::from_reader(file)?;
 
 				let mut needs_save = false;
@@ -94,26 +704,21 @@ impl Config
 					cfg.save(filename)?;
 				}
 
+				cfg.apply_overrides(local_filename)?;
 				Ok(cfg)
 			}
 			Err(e) => match e.kind()
 			{
 				ErrorKind::NotFound =>
 				{
-					println!("WARNING: Options file ({}) not found, creating new with default values.", filename);
-					let cfg = Config {
+					warning!("Options file ({}) not found, creating new with default values.", filename);
+					let mut cfg = Config {
 						app_name: correct_name,
 						app_version: correct_version,
-						horizontal_fov: 90,
-						mouse_invert_x: false,
-						mouse_invert_y: false,
-						mouse_sensitivity: 0.3,
-						render_width: 480,
-						render_height: 320,
-						window_width: 480,
-						window_height: 320,
+						..Default::default()
 					};
 					cfg.save(filename)?;
+					cfg.apply_overrides(local_filename)?;
 					Ok(cfg)
 				}
 				_ => Err(e),
@@ -121,3 +726,67 @@ impl Config
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::fs;
+	use std::io::Write;
+
+	#[test]
+	fn options_skipped_by_parallel_recording_are_reported()
+	{
+		let mut cfg = Config {
+			show_minimap: true,
+			..Default::default()
+		};
+		assert!(cfg.unsupported_with_parallel_recording().is_empty());
+		cfg.record_threads = 4;
+		assert!(cfg.unsupported_with_parallel_recording().contains(&"show_minimap"));
+		assert!(cfg.unsupported_with_parallel_recording().contains(&"show_axis_gizmo"));
+		assert!(!cfg.unsupported_with_parallel_recording().contains(&"background Gradient"));
+		cfg.background = Background::Gradient;
+		assert!(cfg.unsupported_with_parallel_recording().contains(&"background Gradient"));
+	}
+
+	#[test]
+	fn local_override_replaces_only_its_key()
+	{
+		let path = env::temp_dir().join("project_peril_config_test.local.json");
+		let path = path.to_str().unwrap();
+		File::create(path).unwrap().write_all(b"{ \"render_width\": 1280 }").unwrap();
+		let base = Config::default();
+		let mut cfg = Config::default();
+		let result = cfg.apply_local_overrides(path);
+		fs::remove_file(path).unwrap();
+		result.unwrap();
+
+		assert_eq!(cfg.render_width, 1280);
+		assert_eq!(cfg.local_overrides, vec![String::from("render_width")]);
+		let mut expected = serde_json::to_value(&base).unwrap();
+		expected["render_width"] = Value::from(1280);
+		assert_eq!(serde_json::to_value(&cfg).unwrap(), expected);
+	}
+
+	#[test]
+	fn saving_a_field_leaves_the_others_as_saved()
+	{
+		let path = env::temp_dir().join("project_peril_config_test.json");
+		let path = path.to_str().unwrap();
+		let saved = Config::default();
+		saved.save(path).unwrap();
+		let mut cfg = Config::default();
+		cfg.exposure = 2.0;
+		cfg.light_intensity = 3.0;
+		let result = cfg.save_field(path, "exposure");
+		let file = File::open(path).unwrap();
+		let json: Value = serde_json::from_reader(file).unwrap();
+		fs::remove_file(path).unwrap();
+		result.unwrap();
+
+		let mut expected = serde_json::to_value(&saved).unwrap();
+		expected["exposure"] = Value::from(2.0);
+		assert_eq!(json, expected);
+	}
+}
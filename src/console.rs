@@ -0,0 +1,417 @@
+use cgmath::{Deg, Rad};
+use config::Config;
+use input::{key_pressed, InputHandler, BACKSPACE_SCAN_CODE, DOWN_SCAN_CODE, ENTER_SCAN_CODE, ESC_SCAN_CODE,
+            GRAVE_SCAN_CODE, UP_SCAN_CODE};
+use serde_json;
+use serde_json::Value;
+use winit;
+
+/// Maximum number of remembered commands.
+const MAX_HISTORY: usize = 32;
+
+/// Returns the direction for the given azimuth (around the y-axis, starting at +z towards +x) and
+/// elevation (above the xz-plane) in degrees.
+fn direction_from_angles(azimuth: f32, elevation: f32) -> [f32; 3]
+{
+	let azimuth = Rad::from(Deg(azimuth)).0;
+	let elevation = Rad::from(Deg(elevation)).0;
+	[elevation.cos() * azimuth.sin(), elevation.sin(), elevation.cos() * azimuth.cos()]
+}
+
+/// Parses "on" or "off" for the switch commands.
+fn parse_switch(arg: &str) -> Result<bool, String>
+{
+	match arg
+	{
+		"on" => Ok(true),
+		"off" => Ok(false),
+		_ => Err(format!("Expected on or off: {}", arg)),
+	}
+}
+
+/// Parses the index of a scene object.
+fn parse_object(arg: &str) -> Result<usize, String>
+{
+	arg.parse().map_err(|_| format!("Invalid object index: {}", arg))
+}
+
+/// A parsed console command.
+#[derive(Debug, PartialEq)]
+pub enum Command
+{
+	/// Sets a config field, the Config has already been updated.
+	Set(String),
+	/// Spawns an object of the given kind at a position.
+	Spawn(String, [f32; 3]),
+	/// Saves the next frame as a PNG, to the given path or a numbered file.
+	Screenshot(Option<String>),
+	/// Saves the scene to the given path.
+	Save(String),
+	/// Replaces the active scene with the one saved at the given path.
+	Load(String),
+	/// Loads the scene saved at the given path next to the others, for switching to it.
+	Preload(String),
+	/// Unloads the scene in the given slot, numbered like the keys.
+	Unload(usize),
+	/// Lists the loaded scenes.
+	Scenes,
+	/// Sets whether the object with the given index casts a shadow.
+	CastsShadow(usize, bool),
+	/// Shows or hides the object with the given index.
+	SetVisible(usize, bool),
+	/// Moves the object with the given index to a render layer.
+	SetLayer(usize, i32),
+	Help,
+}
+
+/// A simple developer console for changing settings at runtime.
+///
+/// There is no text overlay yet, so the input line is shown in the window title and output goes to
+/// stdout.
+pub struct Console
+{
+	open: bool,
+	input: String,
+	history: Vec<String>,
+	/// Position when browsing the history, None when editing a new line.
+	history_index: Option<usize>,
+	/// Enter was pressed, the input line is waiting for submit().
+	submitted: bool,
+	/// The prompt changed since the last call to take_dirty().
+	dirty: bool,
+}
+
+impl Console
+{
+	pub fn new() -> Console
+	{
+		Console {
+			open: false,
+			input: String::new(),
+			history: Vec::new(),
+			history_index: None,
+			submitted: false,
+			dirty: false,
+		}
+	}
+
+	pub fn is_open(&self) -> bool
+	{
+		self.open
+	}
+
+	/// Opens or closes the console. The current input is discarded.
+	pub fn toggle(&mut self)
+	{
+		self.open = !self.open;
+		self.input.clear();
+		self.history_index = None;
+		self.dirty = true;
+	}
+
+	/// Returns true once after Enter was pressed, then the input should be passed to submit().
+	pub fn take_submitted(&mut self) -> bool
+	{
+		let submitted = self.submitted;
+		self.submitted = false;
+		submitted
+	}
+
+	/// Returns true once after the prompt or the open state changed.
+	pub fn take_dirty(&mut self) -> bool
+	{
+		let dirty = self.dirty;
+		self.dirty = false;
+		dirty
+	}
+
+	/// Returns the line to show while the console is open.
+	pub fn prompt(&self) -> String
+	{
+		format!("> {}_", self.input)
+	}
+
+	/// Adds a typed character to the input line.
+	pub fn push_char(&mut self, c: char)
+	{
+		// The toggle key also produces a character
+		if c.is_control() || c == '`' || c == '~'
+		{
+			return;
+		}
+		self.input.push(c);
+	}
+
+	pub fn backspace(&mut self)
+	{
+		self.input.pop();
+	}
+
+	/// Replaces the input with an older command from the history.
+	pub fn history_previous(&mut self)
+	{
+		if self.history.is_empty()
+		{
+			return;
+		}
+		let index = match self.history_index
+		{
+			Some(index) => index.saturating_sub(1),
+			None => self.history.len() - 1,
+		};
+		self.history_index = Some(index);
+		self.input = self.history[index].clone();
+	}
+
+	/// Replaces the input with a newer command from the history, or clears it past the newest.
+	pub fn history_next(&mut self)
+	{
+		match self.history_index
+		{
+			Some(index) if index + 1 < self.history.len() =>
+			{
+				self.history_index = Some(index + 1);
+				self.input = self.history[index + 1].clone();
+			}
+			_ =>
+			{
+				self.history_index = None;
+				self.input.clear();
+			}
+		}
+	}
+
+	/// Parses the input line and adds it to the history.
+	///
+	/// Set commands are applied to the Config right away, everything else is left for the caller.
+	/// Returns an error message if the command can't be parsed.
+	pub fn submit(&mut self, cfg: &mut Config) -> Result<Option<Command>, String>
+	{
+		let line = self.input.trim().to_string();
+		self.input.clear();
+		self.history_index = None;
+		self.dirty = true;
+		if line.is_empty()
+		{
+			return Ok(None);
+		}
+
+		if self.history.last() != Some(&line)
+		{
+			self.history.push(line.clone());
+			if self.history.len() > MAX_HISTORY
+			{
+				self.history.remove(0);
+			}
+		}
+
+		let args: Vec<&str> = line.split_whitespace().collect();
+		match args[0]
+		{
+			"set" if args.len() == 3 => Console::set(cfg, args[1], args[2]).map(|name| Some(Command::Set(name))),
+			"spawn" if args.len() == 5 =>
+			{
+				let mut position = [0.0; 3];
+				for (axis, arg) in args[2..].iter().enumerate()
+				{
+					position[axis] = arg.parse().map_err(|_| format!("Invalid coordinate: {}", arg))?;
+				}
+				Ok(Some(Command::Spawn(args[1].to_string(), position)))
+			}
+			"light" if args.len() == 3 =>
+			{
+				let azimuth: f32 = args[1].parse().map_err(|_| format!("Invalid azimuth: {}", args[1]))?;
+				let elevation: f32 = args[2].parse().map_err(|_| format!("Invalid elevation: {}", args[2]))?;
+				cfg.light_direction = direction_from_angles(azimuth, elevation);
+				Ok(Some(Command::Set(String::from("light_direction"))))
+			}
+			"screenshot" if args.len() <= 2 => Ok(Some(Command::Screenshot(args.get(1).map(|path| path.to_string())))),
+			"save" if args.len() == 2 => Ok(Some(Command::Save(args[1].to_string()))),
+			"load" if args.len() == 2 => Ok(Some(Command::Load(args[1].to_string()))),
+			"preload" if args.len() == 2 => Ok(Some(Command::Preload(args[1].to_string()))),
+			"unload" if args.len() == 2 => match args[1].parse::<usize>()
+			{
+				Ok(slot) if slot > 0 => Ok(Some(Command::Unload(slot - 1))),
+				_ => Err(format!("Invalid scene number: {}", args[1])),
+			},
+			"scenes" => Ok(Some(Command::Scenes)),
+			"shadow" if args.len() == 3 =>
+			{
+				Ok(Some(Command::CastsShadow(parse_object(args[1])?, parse_switch(args[2])?)))
+			}
+			"show" if args.len() == 2 => Ok(Some(Command::SetVisible(parse_object(args[1])?, true))),
+			"hide" if args.len() == 2 => Ok(Some(Command::SetVisible(parse_object(args[1])?, false))),
+			"layer" if args.len() == 3 =>
+			{
+				let layer = args[2].parse().map_err(|_| format!("Invalid layer: {}", args[2]))?;
+				Ok(Some(Command::SetLayer(parse_object(args[1])?, layer)))
+			}
+			"help" => Ok(Some(Command::Help)),
+			_ => Err(format!("Unknown command: {}", line)),
+		}
+	}
+
+	/// Sets a Config field by name, parsing the value as JSON. Returns the name of the changed field.
+	///
+	/// "fov" is a shorthand for fov_horizontal.
+	fn set(cfg: &mut Config, name: &str, value: &str) -> Result<String, String>
+	{
+		let name = if name == "fov"
+		{
+			"fov_horizontal"
+		}
+		else
+		{
+			name
+		};
+
+		// Go through JSON so every field can be set without listing them here
+		let mut json = serde_json::to_value(&*cfg).map_err(|e| e.to_string())?;
+		{
+			let fields = json.as_object_mut().expect("Config is not a JSON object");
+			if !fields.contains_key(name)
+			{
+				return Err(format!("Unknown config field: {}", name));
+			}
+			// Allow vectors without brackets, e.g. "1,0,0"
+			let value = if value.contains(',') && !value.starts_with('[')
+			{
+				format!("[{}]", value)
+			}
+			else
+			{
+				value.to_string()
+			};
+			let value = value.as_str();
+			// Allow unquoted strings, e.g. for enums
+			let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+			fields.insert(name.to_string(), value);
+			if name == "fov_horizontal"
+			{
+				fields.insert(String::from("fov_vertical"), Value::Null);
+			}
+			else if name == "fov_vertical"
+			{
+				fields.insert(String::from("fov_horizontal"), Value::Null);
+			}
+		}
+		*cfg = serde_json::from_value(json).map_err(|e| format!("Invalid value for {}: {}", name, e))?;
+
+		Ok(name.to_string())
+	}
+
+	/// Prints the available commands.
+	pub fn print_help()
+	{
+		println!("Console commands:");
+		println!("  set <config field> <value>   e.g. set render_scale 0.75, set fov 75");
+		println!("  set light_direction <x>,<y>,<z>");
+		println!("  light <azimuth> <elevation>        light direction in degrees");
+		println!("  spawn cube <x> <y> <z>");
+		println!("  screenshot [path]                  save the next frame as PNG, screenshot_<n>.png by default");
+		println!("  save <path>                        save the scene, camera and lighting as JSON");
+		println!("  load <path>                        replace the scene with a saved one");
+		println!("  preload <path>                     load a saved scene next to the others, see scenes");
+		println!("  unload <number>                    unload a scene to free its resources");
+		println!("  scenes                             list the scenes, switch with the number keys");
+		println!("  shadow <object> on|off             whether the object with the index casts a shadow");
+		println!("  show <object>, hide <object>       show or hide the object with the index, saved with it");
+		println!("  layer <object> <n>                 render layer of the object with the index, lower first");
+		println!("  help");
+	}
+}
+
+impl InputHandler for Console
+{
+	/// Opens the console on the toggle key. While open, all keyboard input goes to the console.
+	fn on_event(&mut self, event: &winit::Event) -> bool
+	{
+		if !self.open
+		{
+			if key_pressed(event) == Some(GRAVE_SCAN_CODE)
+			{
+				self.toggle();
+				return true;
+			}
+			return false;
+		}
+
+		match *event
+		{
+			winit::Event::WindowEvent {
+				event: winit::WindowEvent::ReceivedCharacter(c),
+				..
+			} =>
+			{
+				self.push_char(c);
+				self.dirty = true;
+				true
+			}
+			winit::Event::WindowEvent {
+				event: winit::WindowEvent::KeyboardInput {
+					..
+				},
+				..
+			} =>
+			{
+				match key_pressed(event)
+				{
+					Some(GRAVE_SCAN_CODE) | Some(ESC_SCAN_CODE) => self.toggle(),
+					Some(ENTER_SCAN_CODE) => self.submitted = true,
+					Some(BACKSPACE_SCAN_CODE) => self.backspace(),
+					Some(UP_SCAN_CODE) => self.history_previous(),
+					Some(DOWN_SCAN_CODE) => self.history_next(),
+					_ => (),
+				}
+				self.dirty = true;
+				true
+			}
+			_ => false,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	/// Submits the line to a new console.
+	fn submit(line: &str) -> Result<Option<Command>, String>
+	{
+		let mut console = Console::new();
+		console.input = String::from(line);
+		console.submit(&mut Config::default())
+	}
+
+	#[test]
+	fn shadow_takes_an_object_and_a_switch()
+	{
+		assert_eq!(submit("shadow 3 off"), Ok(Some(Command::CastsShadow(3, false))));
+		assert_eq!(submit("shadow 0 on"), Ok(Some(Command::CastsShadow(0, true))));
+		assert!(submit("shadow 3 maybe").is_err());
+		assert!(submit("shadow x on").is_err());
+	}
+
+	#[test]
+	fn screenshot_takes_an_optional_path()
+	{
+		assert_eq!(submit("screenshot"), Ok(Some(Command::Screenshot(None))));
+		assert_eq!(submit("screenshot shot.png"), Ok(Some(Command::Screenshot(Some(String::from("shot.png"))))));
+	}
+
+	#[test]
+	fn show_and_hide_take_an_object()
+	{
+		assert_eq!(submit("show 2"), Ok(Some(Command::SetVisible(2, true))));
+		assert_eq!(submit("hide 2"), Ok(Some(Command::SetVisible(2, false))));
+		assert!(submit("hide").is_err());
+	}
+
+	#[test]
+	fn layer_takes_an_object_and_a_number()
+	{
+		assert_eq!(submit("layer 1 -2"), Ok(Some(Command::SetLayer(1, -2))));
+		assert!(submit("layer 1 top").is_err());
+	}
+}
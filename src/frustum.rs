@@ -0,0 +1,83 @@
+use cgmath::{Matrix4, Point3, Vector4};
+use cgmath::prelude::*;
+
+/// A view frustum, for culling objects outside of the view.
+#[derive(Clone, Copy)]
+pub struct Frustum
+{
+	/// Left, right, bottom, top, near and far planes as (normal, distance), with the normals pointing
+	/// inwards.
+	planes: [Vector4<f32>; 6],
+	/// Near corners followed by the far corners, in world space.
+	corners: [Point3<f32>; 8],
+}
+
+impl Frustum
+{
+	/// Extracts the frustum from a (Vulkan) view-projection matrix, which maps depth to [0, 1].
+	pub fn from_matrix(view_projection: &Matrix4<f32>) -> Frustum
+	{
+		let row = |i: usize| view_projection.row(i);
+		let mut planes = [
+			row(3) + row(0),
+			row(3) - row(0),
+			row(3) + row(1),
+			row(3) - row(1),
+			row(2),
+			row(3) - row(2),
+		];
+		for plane in planes.iter_mut()
+		{
+			*plane /= plane.truncate().magnitude();
+		}
+
+		// Unproject the corners of the clip volume
+		let inverse = view_projection.invert().unwrap_or(Matrix4::identity());
+		let mut corners = [Point3::new(0.0, 0.0, 0.0); 8];
+		for (i, corner) in corners.iter_mut().enumerate()
+		{
+			let x = if i & 1 == 0 { -1.0 } else { 1.0 };
+			let y = if i & 2 == 0 { -1.0 } else { 1.0 };
+			let z = if i & 4 == 0 { 0.0 } else { 1.0 };
+			let world = inverse * Vector4::new(x, y, z, 1.0);
+			*corner = Point3::from_homogeneous(world);
+		}
+
+		Frustum {
+			planes: planes,
+			corners: corners,
+		}
+	}
+
+	/// Returns true if any part of the sphere is inside the frustum.
+	pub fn intersects_sphere(&self, center: Point3<f32>, radius: f32) -> bool
+	{
+		self.planes.iter().all(|plane| plane.truncate().dot(center.to_vec()) + plane.w >= -radius)
+	}
+
+	/// Returns the eight corners, near ones first. Bit 0 of the index selects the right side, bit 1
+	/// the top (in clip space) and bit 2 the far plane.
+	pub fn corners(&self) -> &[Point3<f32>; 8]
+	{
+		&self.corners
+	}
+
+	/// Returns the corner index pairs of the twelve edges, e.g. for drawing a wireframe.
+	pub fn edges() -> [(u16, u16); 12]
+	{
+		[
+			(0, 1),
+			(2, 3),
+			(0, 2),
+			(1, 3),
+			(4, 5),
+			(6, 7),
+			(4, 6),
+			(5, 7),
+			(0, 4),
+			(1, 5),
+			(2, 6),
+			(3, 7),
+		]
+	}
+}
@@ -0,0 +1,474 @@
+use cgmath::{Vector2, Vector3};
+use object::Camera;
+use winit;
+
+pub const Q_SCAN_CODE: u32 = 16;
+pub const W_SCAN_CODE: u32 = 17;
+pub const E_SCAN_CODE: u32 = 18;
+pub const R_SCAN_CODE: u32 = 19;
+pub const A_SCAN_CODE: u32 = 30;
+pub const S_SCAN_CODE: u32 = 31;
+pub const D_SCAN_CODE: u32 = 32;
+pub const F_SCAN_CODE: u32 = 33;
+pub const G_SCAN_CODE: u32 = 34;
+pub const H_SCAN_CODE: u32 = 35;
+pub const J_SCAN_CODE: u32 = 36;
+pub const K_SCAN_CODE: u32 = 37;
+pub const I_SCAN_CODE: u32 = 23;
+pub const U_SCAN_CODE: u32 = 22;
+pub const Y_SCAN_CODE: u32 = 21;
+pub const N_SCAN_CODE: u32 = 49;
+pub const B_SCAN_CODE: u32 = 48;
+pub const M_SCAN_CODE: u32 = 50;
+pub const T_SCAN_CODE: u32 = 20;
+pub const L_SCAN_CODE: u32 = 38;
+pub const C_SCAN_CODE: u32 = 46;
+pub const Z_SCAN_CODE: u32 = 44;
+pub const P_SCAN_CODE: u32 = 25;
+pub const O_SCAN_CODE: u32 = 24;
+pub const V_SCAN_CODE: u32 = 47;
+pub const X_SCAN_CODE: u32 = 45;
+pub const LBRACKET_SCAN_CODE: u32 = 26;
+pub const RBRACKET_SCAN_CODE: u32 = 27;
+pub const MINUS_SCAN_CODE: u32 = 12;
+pub const EQUAL_SCAN_CODE: u32 = 13;
+pub const COMMA_SCAN_CODE: u32 = 51;
+pub const PERIOD_SCAN_CODE: u32 = 52;
+pub const SEMICOLON_SCAN_CODE: u32 = 39;
+pub const APOSTROPHE_SCAN_CODE: u32 = 40;
+pub const GRAVE_SCAN_CODE: u32 = 41;
+// The number keys 1 to 9 have consecutive scan codes
+pub const ONE_SCAN_CODE: u32 = 2;
+pub const NINE_SCAN_CODE: u32 = 10;
+pub const ENTER_SCAN_CODE: u32 = 28;
+pub const BACKSPACE_SCAN_CODE: u32 = 14;
+
+pub const UP_SCAN_CODE: u32 = 103;
+pub const LEFT_SCAN_CODE: u32 = 105;
+pub const DOWN_SCAN_CODE: u32 = 108;
+pub const RIGHT_SCAN_CODE: u32 = 106;
+
+pub const ESC_SCAN_CODE: u32 = 1;
+pub const SPACE_SCAN_CODE: u32 = 57;
+pub const LSHIFT_SCAN_CODE: u32 = 42;
+pub const LCTRL_SCAN_CODE: u32 = 29;
+
+/// Something that reacts to window and device events.
+pub trait InputHandler
+{
+	/// Handles the event. Returns true if the event was consumed, so handlers after this one don't
+	/// see it.
+	fn on_event(&mut self, event: &winit::Event) -> bool;
+}
+
+/// Passes the event to the handlers in order until one of them consumes it.
+///
+/// Returns true if the event was consumed.
+pub fn dispatch_event(handlers: &mut [&mut InputHandler], event: &winit::Event) -> bool
+{
+	handlers.iter_mut().any(|handler| handler.on_event(event))
+}
+
+/// Returns the scancode and state if the event is a keyboard event.
+pub fn key_event(event: &winit::Event) -> Option<(u32, winit::ElementState)>
+{
+	match *event
+	{
+		winit::Event::WindowEvent {
+			event: winit::WindowEvent::KeyboardInput {
+				input,
+				..
+			},
+			..
+		} => Some((input.scancode, input.state)),
+		_ => None,
+	}
+}
+
+/// Returns the scancode if the event is a key press.
+pub fn key_pressed(event: &winit::Event) -> Option<u32>
+{
+	match key_event(event)
+	{
+		Some((scancode, winit::ElementState::Pressed)) => Some(scancode),
+		_ => None,
+	}
+}
+
+/// Tracks the movement keys, mouse look and cursor capture for controlling the camera.
+pub struct CameraInput
+{
+	key_forward: bool,
+	key_left: bool,
+	key_back: bool,
+	key_right: bool,
+	key_up: bool,
+	key_down: bool,
+	key_sprint: bool,
+	key_roll_left: bool,
+	key_roll_right: bool,
+	cursor_captured: bool,
+	cursor_dirty: bool,
+	last_mouse_delta: Vector2<f64>,
+	// Yaw and pitch in degrees since the last call to take_look()
+	look: Vector2<f32>,
+	mouse_sensitivity: f64,
+	mouse_invert_x: bool,
+	mouse_invert_y: bool,
+}
+
+impl CameraInput
+{
+	pub fn new(mouse_sensitivity: f64, mouse_invert_x: bool, mouse_invert_y: bool) -> CameraInput
+	{
+		CameraInput {
+			key_forward: false,
+			key_left: false,
+			key_back: false,
+			key_right: false,
+			key_up: false,
+			key_down: false,
+			key_sprint: false,
+			key_roll_left: false,
+			key_roll_right: false,
+			cursor_captured: false,
+			cursor_dirty: false,
+			last_mouse_delta: Vector2::new(0.0, 0.0),
+			look: Vector2::new(0.0, 0.0),
+			mouse_sensitivity: mouse_sensitivity,
+			mouse_invert_x: mouse_invert_x,
+			mouse_invert_y: mouse_invert_y,
+		}
+	}
+
+	/// Releases all movement keys, e.g. when another handler takes over the keyboard.
+	pub fn release_keys(&mut self)
+	{
+		self.key_forward = false;
+		self.key_left = false;
+		self.key_back = false;
+		self.key_right = false;
+		self.key_up = false;
+		self.key_down = false;
+		self.key_sprint = false;
+		self.key_roll_left = false;
+		self.key_roll_right = false;
+	}
+
+	pub fn is_sprinting(&self) -> bool
+	{
+		self.key_sprint
+	}
+
+	/// Returns the (unnormalized) direction the held movement keys move the camera in.
+	pub fn move_direction(&self, camera: &Camera) -> Vector3<f32>
+	{
+		let mut move_direction = Vector3::new(0.0, 0.0, 0.0);
+		if self.key_forward
+		{
+			move_direction += camera.get_cam_front();
+		}
+		if self.key_left
+		{
+			move_direction -= camera.get_cam_right();
+		}
+		if self.key_back
+		{
+			move_direction -= camera.get_cam_front();
+		}
+		if self.key_right
+		{
+			move_direction += camera.get_cam_right();
+		}
+		if self.key_up
+		{
+			move_direction += camera.get_world_up_vector();
+		}
+		if self.key_down
+		{
+			move_direction -= camera.get_world_up_vector();
+		}
+		move_direction
+	}
+
+	/// Returns the direction the held roll keys roll the camera in, -1 (left), 0 or 1 (right).
+	pub fn roll_direction(&self) -> f32
+	{
+		let mut direction = 0.0;
+		if self.key_roll_left
+		{
+			direction -= 1.0;
+		}
+		if self.key_roll_right
+		{
+			direction += 1.0;
+		}
+		direction
+	}
+
+	/// Returns the yaw and pitch in degrees to turn the camera by, and resets them.
+	pub fn take_look(&mut self) -> Vector2<f32>
+	{
+		let look = self.look;
+		self.look = Vector2::new(0.0, 0.0);
+		look
+	}
+
+	pub fn is_cursor_captured(&self) -> bool
+	{
+		self.cursor_captured
+	}
+
+	/// Returns true once after the cursor capture state changed.
+	pub fn take_cursor_dirty(&mut self) -> bool
+	{
+		let dirty = self.cursor_dirty;
+		self.cursor_dirty = false;
+		dirty
+	}
+
+	/// Sets the held state of a movement key. Returns false if the key isn't a movement key.
+	fn set_key(&mut self, scancode: u32, held: bool) -> bool
+	{
+		match scancode
+		{
+			W_SCAN_CODE => self.key_forward = held,
+			A_SCAN_CODE => self.key_left = held,
+			S_SCAN_CODE => self.key_back = held,
+			D_SCAN_CODE => self.key_right = held,
+			SPACE_SCAN_CODE => self.key_up = held,
+			LCTRL_SCAN_CODE => self.key_down = held,
+			LSHIFT_SCAN_CODE => self.key_sprint = held,
+			Q_SCAN_CODE => self.key_roll_left = held,
+			E_SCAN_CODE => self.key_roll_right = held,
+			_ => return false,
+		}
+		true
+	}
+}
+
+impl InputHandler for CameraInput
+{
+	fn on_event(&mut self, event: &winit::Event) -> bool
+	{
+		match *event
+		{
+			winit::Event::WindowEvent {
+				ref event,
+				..
+			} => match *event
+			{
+				winit::WindowEvent::Focused(has_focus) =>
+				{
+					self.cursor_captured = has_focus;
+					self.cursor_dirty = true;
+					false
+				}
+				winit::WindowEvent::KeyboardInput {
+					input,
+					..
+				} => match input.state
+				{
+					winit::ElementState::Pressed => match input.scancode
+					{
+						F_SCAN_CODE =>
+						{
+							self.cursor_captured = !self.cursor_captured;
+							self.cursor_dirty = true;
+							true
+						}
+						UP_SCAN_CODE =>
+						{
+							self.look.y += 5.0;
+							true
+						}
+						LEFT_SCAN_CODE =>
+						{
+							self.look.x -= 5.0;
+							true
+						}
+						DOWN_SCAN_CODE =>
+						{
+							self.look.y -= 5.0;
+							true
+						}
+						RIGHT_SCAN_CODE =>
+						{
+							self.look.x += 5.0;
+							true
+						}
+						scancode => self.set_key(scancode, true),
+					},
+					winit::ElementState::Released => self.set_key(input.scancode, false),
+				},
+				// Mouse presses
+				winit::WindowEvent::MouseInput {
+					button,
+					..
+				} if self.cursor_captured =>
+				{
+					match button
+					{
+						winit::MouseButton::Left =>
+						{
+							verbose!("Left mouse!");
+						}
+						winit::MouseButton::Right =>
+						{
+							verbose!("Right mouse!");
+						}
+						_ => (),
+					}
+					true
+				}
+				_ => false,
+			},
+			// Mouse Movement
+			// Use DeviceEvent as it gives raw unfiltered physical motion
+			winit::Event::DeviceEvent {
+				event: winit::DeviceEvent::MouseMotion {
+					delta,
+					..
+				},
+				..
+			} if self.cursor_captured =>
+			{
+				let mut dir_change = Vector2 {
+					x: (self.last_mouse_delta.x + delta.0),
+					y: (self.last_mouse_delta.y + delta.1),
+				};
+				self.last_mouse_delta.x = delta.0;
+				self.last_mouse_delta.y = delta.1;
+
+				dir_change *= self.mouse_sensitivity;
+				self.look.x += match self.mouse_invert_x
+				{
+					true => -dir_change.x,
+					false => dir_change.x,
+				} as f32;
+				self.look.y += match self.mouse_invert_y
+				{
+					true => dir_change.y,
+					false => -dir_change.y,
+				} as f32;
+				true
+			}
+			_ => false,
+		}
+	}
+}
+
+/// Runtime toggles and adjustments bound to keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DebugAction
+{
+	Quit,
+	ToggleCinematic,
+	/// Starts, resumes or stops turning the camera or the selected object.
+	ToggleTurntable,
+	ToggleDepthView,
+	/// Presents the next G-buffer target, after the last one the albedo again.
+	CycleGBufferView,
+	/// Shows how many fragments were drawn into each pixel as a heatmap, or the shaded scene again.
+	ToggleOverdraw,
+	CycleScaling,
+	/// Switches the present filtering between nearest and linear, for comparing them.
+	TogglePresentFilter,
+	/// Switches between the clear color and the gradient sky behind the scene.
+	CycleBackground,
+	ToggleProjection,
+	/// Freezes the culling frustum at the current camera, or unfreezes it.
+	FreezeCulling,
+	ToggleAxisGizmo,
+	/// Shows a top-down map of the scene in a corner, or hides it.
+	ToggleMinimap,
+	/// Shows the bounding spheres tested for culling.
+	ToggleBounds,
+	/// Switches between the normal maps and flat ones, to see what normal mapping adds.
+	ToggleNormalMaps,
+	/// Shows only a sphere with the material of the selected object, or the scene again.
+	ToggleMaterialPreview,
+	ResetCameraRoll,
+	/// Selects the next scene object, for the solo and hide actions.
+	SelectNext,
+	/// Draws only the selected object, or everything again.
+	ToggleSolo,
+	ToggleHideSelected,
+	/// Change of the render scale.
+	RenderScale(f32),
+	/// Change of the exposure.
+	Exposure(f32),
+	/// Factor to multiply the simulation time scale by.
+	TimeScale(f32),
+	/// Factor to scale the tessellation of the generated spheres and planes by.
+	Tessellation(f32),
+	/// Index of the loaded scene to draw, for the number keys starting at 1.
+	SwitchScene(usize),
+}
+
+/// Turns key presses into DebugActions, which are collected until the main loop applies them.
+pub struct DebugInput
+{
+	actions: Vec<DebugAction>,
+}
+
+impl DebugInput
+{
+	pub fn new() -> DebugInput
+	{
+		DebugInput {
+			actions: Vec::new(),
+		}
+	}
+
+	/// Returns the actions triggered since the last call.
+	pub fn take_actions(&mut self) -> Vec<DebugAction>
+	{
+		self.actions.drain(..).collect()
+	}
+}
+
+impl InputHandler for DebugInput
+{
+	fn on_event(&mut self, event: &winit::Event) -> bool
+	{
+		let action = match key_pressed(event)
+		{
+			Some(ESC_SCAN_CODE) => DebugAction::Quit,
+			Some(C_SCAN_CODE) => DebugAction::ToggleCinematic,
+			Some(U_SCAN_CODE) => DebugAction::ToggleTurntable,
+			Some(Z_SCAN_CODE) => DebugAction::ToggleDepthView,
+			Some(J_SCAN_CODE) => DebugAction::CycleGBufferView,
+			Some(X_SCAN_CODE) => DebugAction::ToggleOverdraw,
+			Some(P_SCAN_CODE) => DebugAction::CycleScaling,
+			Some(L_SCAN_CODE) => DebugAction::TogglePresentFilter,
+			Some(Y_SCAN_CODE) => DebugAction::CycleBackground,
+			Some(O_SCAN_CODE) => DebugAction::ToggleProjection,
+			Some(V_SCAN_CODE) => DebugAction::FreezeCulling,
+			Some(G_SCAN_CODE) => DebugAction::ToggleAxisGizmo,
+			Some(K_SCAN_CODE) => DebugAction::ToggleMinimap,
+			Some(B_SCAN_CODE) => DebugAction::ToggleBounds,
+			Some(M_SCAN_CODE) => DebugAction::ToggleNormalMaps,
+			Some(T_SCAN_CODE) => DebugAction::ToggleMaterialPreview,
+			Some(R_SCAN_CODE) => DebugAction::ResetCameraRoll,
+			Some(N_SCAN_CODE) => DebugAction::SelectNext,
+			Some(I_SCAN_CODE) => DebugAction::ToggleSolo,
+			Some(H_SCAN_CODE) => DebugAction::ToggleHideSelected,
+			Some(LBRACKET_SCAN_CODE) => DebugAction::RenderScale(-0.25),
+			Some(RBRACKET_SCAN_CODE) => DebugAction::RenderScale(0.25),
+			Some(MINUS_SCAN_CODE) => DebugAction::Exposure(-0.1),
+			Some(EQUAL_SCAN_CODE) => DebugAction::Exposure(0.1),
+			Some(COMMA_SCAN_CODE) => DebugAction::TimeScale(0.5),
+			Some(PERIOD_SCAN_CODE) => DebugAction::TimeScale(2.0),
+			Some(SEMICOLON_SCAN_CODE) => DebugAction::Tessellation(0.5),
+			Some(APOSTROPHE_SCAN_CODE) => DebugAction::Tessellation(2.0),
+			Some(code) if code >= ONE_SCAN_CODE && code <= NINE_SCAN_CODE =>
+			{
+				DebugAction::SwitchScene((code - ONE_SCAN_CODE) as usize)
+			}
+			_ => return false,
+		};
+		self.actions.push(action);
+		true
+	}
+}
@@ -0,0 +1,120 @@
+use std::cell::Cell;
+use std::fmt;
+
+/// Prints an error. Errors are printed at every verbosity.
+///
+/// e.g. `error!("Failed to read {}: {}", path, e);`
+macro_rules! error {
+	($($arg:tt)*) => {
+		println!("ERROR! {}", format_args!($($arg)*))
+	};
+}
+
+/// Prints a warning, unless the verbosity is Quiet.
+macro_rules! warning {
+	($($arg:tt)*) => {
+		if ::log::enabled(::log::Verbosity::Normal)
+		{
+			println!("WARNING: {}", format_args!($($arg)*));
+		}
+	};
+}
+
+/// Prints a status message, unless the verbosity is Quiet.
+macro_rules! info {
+	($($arg:tt)*) => {
+		if ::log::enabled(::log::Verbosity::Normal)
+		{
+			println!($($arg)*);
+		}
+	};
+}
+
+/// Prints details that are only interesting while debugging, if the verbosity is Verbose.
+macro_rules! verbose {
+	($($arg:tt)*) => {
+		if ::log::enabled(::log::Verbosity::Verbose)
+		{
+			println!($($arg)*);
+		}
+	};
+}
+
+/// How much is printed to the console.
+///
+/// Output that was explicitly asked for, like the help texts, the benchmark results and the enabled
+/// statistics, is printed at every verbosity.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum Verbosity
+{
+	/// Only errors.
+	Quiet,
+	/// Warnings, status messages and the startup report.
+	Normal,
+	/// Also the details of the device and swapchain setup.
+	Verbose,
+}
+
+thread_local! {
+	static VERBOSITY: Cell<Verbosity> = Cell::new(Verbosity::Normal);
+}
+
+/// Sets the verbosity for the current thread, which is the only one printing.
+pub fn set_verbosity(verbosity: Verbosity)
+{
+	VERBOSITY.with(|current| current.set(verbosity));
+}
+
+/// Returns true if messages of the given verbosity are printed on the current thread.
+pub fn enabled(verbosity: Verbosity) -> bool
+{
+	VERBOSITY.with(|current| verbosity <= current.get())
+}
+
+/// The device and swapchain setup as name and value lines, printed once at startup so it can be pasted
+/// into bug reports.
+pub struct StartupReport
+{
+	entries: Vec<(&'static str, String)>,
+}
+
+impl StartupReport
+{
+	pub fn new() -> StartupReport
+	{
+		StartupReport { entries: Vec::new() }
+	}
+
+	pub fn add<T: fmt::Display>(&mut self, name: &'static str, value: T)
+	{
+		self.entries.push((name, value.to_string()));
+	}
+
+	/// Adds a list of values, or "none" if it is empty.
+	pub fn add_list<T: AsRef<str>>(&mut self, name: &'static str, values: &[T])
+	{
+		let value = if values.is_empty()
+		{
+			String::from("none")
+		}
+		else
+		{
+			values.iter().map(|value| value.as_ref()).collect::<Vec<&str>>().join(", ")
+		};
+		self.entries.push((name, value));
+	}
+}
+
+impl fmt::Display for StartupReport
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		let width = self.entries.iter().map(|&(name, _)| name.len()).max().unwrap_or(0);
+		write!(f, "Startup report:")?;
+		for &(name, ref value) in self.entries.iter()
+		{
+			write!(f, "\n  {:<width$}  {}", format!("{}:", name), value, width = width + 1)?;
+		}
+		Ok(())
+	}
+}
@@ -8,388 +8,1294 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate winit;
 
+// Declared first, so their macros are available in the other modules
+#[macro_use]
+mod log;
+#[macro_use]
+mod profiler;
+mod args;
 mod config;
+mod console;
+mod frustum;
+mod input;
 mod nurbs;
 mod object;
+mod regression;
 mod renderer;
+mod scatter;
 mod scene;
+mod scenemanager;
 
-use ash::util::Align;
 use ash::version::DeviceV1_0;
 use ash::vk;
-use cgmath::{Deg, Matrix4, Point3, Rad, Vector2};
-use config::Config;
+use args::Args;
+use cgmath::{Deg, Matrix4, Point3, Rad, Vector3};
+use config::{Config, Projection};
+use console::{Command, Console};
+use frustum::Frustum;
+use input::{dispatch_event, key_pressed, CameraInput, DebugAction, DebugInput, InputHandler};
 use nurbs::{NURBSpline, Order};
-use object::{Camera, Position};
-use renderer::{MainPass, PresentPass, RenderState};
-use scene::Scene;
-use std::mem::{align_of, size_of};
-use std::time::{Duration, SystemTime};
-
-const W_SCAN_CODE: u32 = 17;
-const A_SCAN_CODE: u32 = 30;
-const S_SCAN_CODE: u32 = 31;
-const D_SCAN_CODE: u32 = 32;
-const F_SCAN_CODE: u32 = 33;
-
-const UP_SCAN_CODE: u32 = 103;
-const LEFT_SCAN_CODE: u32 = 105;
-const DOWN_SCAN_CODE: u32 = 108;
-const RIGHT_SCAN_CODE: u32 = 106;
-
-const ESC_SCAN_CODE: u32 = 1;
-const SPACE_SCAN_CODE: u32 = 57;
-const LSHIFT_SCAN_CODE: u32 = 42;
-const LCTRL_SCAN_CODE: u32 = 29;
+use profiler::FrameTimes;
+use object::{Camera, CameraController, Turntable, TurntableMode};
+use regression::Tolerance;
+use renderer::{cube_face_projection, cube_face_views, Fog, FrameGraph, GBufferTarget, MainPass, PresentPass,
+               PresentedImage, RenderState, CUBE_FACES, MAX_OBJECTS};
+use scene::{DrawStats, Lighting, Scene, HELPER_OBJECTS};
+use scenemanager::SceneManager;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+const NEAR_PLANE: f32 = 1.0;
+const FAR_PLANE: f32 = 1000.0;
+/// Near and far planes of the cube depth faces.
+const CUBE_NEAR_PLANE: f32 = 0.1;
+const CUBE_FAR_PLANE: f32 = 100.0;
+/// Camera roll speed while a roll key is held, in degrees per second.
+const ROLL_SPEED: f32 = 90.0;
+
+/// Returns the duration in seconds.
+fn duration_as_secs(duration: Duration) -> f32
+{
+	duration.as_secs() as f32 + duration.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+/// Upper bound for the simulation time scale.
+const MAX_TIME_SCALE: f32 = 16.0;
+
+/// Returns the duration multiplied by a non-negative factor.
+fn scale_duration(duration: Duration, scale: f32) -> Duration
+{
+	let nanos = (duration.as_secs() as f64 * 1_000_000_000.0 + duration.subsec_nanos() as f64) * scale as f64;
+	Duration::new((nanos / 1_000_000_000.0) as u64, (nanos % 1_000_000_000.0) as u32)
+}
+
+/// Returns the image to present, the depth view goes before the G-buffer view.
+fn presented_image(
+	show_depth: bool, cube_depth_face: Option<usize>, gbuffer_view: Option<GBufferTarget>
+) -> PresentedImage
+{
+	match gbuffer_view
+	{
+		_ if show_depth && cube_depth_face.is_some() => PresentedImage::CubeDepth,
+		_ if show_depth => PresentedImage::Depth,
+		Some(target) => PresentedImage::GBuffer(target),
+		None => PresentedImage::Color,
+	}
+}
+
+/// Returns the window title showing the runtime state. There is no HUD yet, so this is where it goes.
+fn status_title(
+	base_title: &str, culling_frozen: bool, present_filter: vk::Filter, normal_maps: bool, triangles: usize
+) -> String
+{
+	let mut title = format!("{} [{:?} filtering] [{} triangles]", base_title, present_filter, triangles);
+	if culling_frozen
+	{
+		title.push_str(" [culling frozen]");
+	}
+	if !normal_maps
+	{
+		title.push_str(" [flat normals]");
+	}
+	title
+}
+
+/// Generates the projection matrix for the configured projection mode and the given render size (in
+/// physical pixels).
+fn generate_projection_matrix(cfg: &Config, render_size: vk::Extent2D) -> Matrix4<f32>
+{
+	let aspect_ratio = render_size.width as f32 / render_size.height as f32;
+	let glu_projection_matrix = match cfg.projection
+	{
+		Projection::Perspective =>
+		{
+			let vertical_fov = cfg.vertical_fov(aspect_ratio);
+			cgmath::perspective(vertical_fov, aspect_ratio, NEAR_PLANE, FAR_PLANE)
+		}
+		Projection::Orthographic =>
+		{
+			let half_height = cfg.ortho_height / 2.0;
+			let half_width = half_height * aspect_ratio;
+			cgmath::ortho(-half_width, half_width, -half_height, half_height, NEAR_PLANE, FAR_PLANE)
+		}
+	};
+	// Need to flip projection matrix due to the Vulkan NDC coordinates, this also maps the GL depth
+	// range of [-1, 1] to [0, 1] for both projection modes.
+	// See https://matthewwellings.com/blog/the-new-vulkan-coordinate-system/ for details.
+	let vulkan_ndc = Matrix4::new(1.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.5, 0.0, 0.0, 0.0, 1.0);
+	vulkan_ndc * glu_projection_matrix
+}
+
+/// Generates the fog settings from the Config.
+fn fog(cfg: &Config) -> Fog
+{
+	Fog {
+		mode: cfg.fog_mode,
+		color: cfg.fog_color,
+		start: cfg.fog_start,
+		end: cfg.fog_end,
+		density: cfg.fog_density,
+	}
+}
+
+/// Renders one frame of the scene without presenting it, and reads it back as RGBA8 pixels.
+///
+/// Returns the width, height and pixels.
+fn render_offscreen(
+	rs: &RenderState, mp: &mut MainPass, scene: &Scene, camera: &Camera, projection_matrix: &Matrix4<f32>
+) -> Result<(u32, u32, Vec<u8>), String>
+{
+	let view_matrix = camera.generate_view_matrix();
+	mp.update_view_matrix(&view_matrix, projection_matrix);
+	let frustum = Frustum::from_matrix(&(projection_matrix * view_matrix));
+	let cmd_buf = mp.begin_frame(rs, &FrameGraph::new(PresentedImage::Color));
+	scene.draw(rs, mp, cmd_buf, &view_matrix, projection_matrix, &frustum, 1.0);
+	mp.end_frame(rs);
+
+	mp.read_render_image(rs)
+}
+
+/// Returns the first screenshot_<n>.png in the working directory that doesn't exist yet.
+fn next_screenshot_path() -> String
+{
+	(1..)
+		.map(|n| format!("screenshot_{:03}.png", n))
+		.find(|path| !Path::new(path).exists())
+		.unwrap()
+}
+
+/// Renders the given number of frames without presenting them, and returns their frame times.
+///
+/// Each frame is waited for, so the times include the GPU work. The scene is updated at 60 FPS.
+fn benchmark_offscreen(
+	rs: &RenderState, mp: &mut MainPass, scene: &mut Scene, camera: &Camera, projection_matrix: &Matrix4<f32>,
+	frames: u32,
+) -> FrameTimes
+{
+	let view_matrix = camera.generate_view_matrix();
+	mp.update_view_matrix(&view_matrix, projection_matrix);
+	let frustum = Frustum::from_matrix(&(projection_matrix * view_matrix));
+	let frame_graph = FrameGraph::new(PresentedImage::Color);
+
+	let mut frame_times = FrameTimes::new();
+	for frame in 0..frames
+	{
+		let start = Instant::now();
+		scene.update(frame as f32 / 60.0, 1.0 / 60.0);
+		let cmd_buf = mp.begin_frame(rs, &frame_graph);
+		scene.draw(rs, mp, cmd_buf, &view_matrix, projection_matrix, &frustum, 1.0);
+		mp.end_frame(rs);
+		rs.device.device_wait_idle().unwrap();
+		frame_times.add(duration_as_secs(start.elapsed()));
+	}
+
+	frame_times
+}
+
+/// Renders the camera following the spline for the given number of seconds, saving every frame as
+/// a numbered PNG in the directory.
+///
+/// The simulation advances by exactly one frame time per frame, regardless of how long rendering
+/// takes, so the sequence plays back smoothly at the given frame rate.
+fn record_frames(
+	rs: &RenderState, mp: &mut MainPass, scene: &mut Scene, camera: &mut Camera, projection_matrix: &Matrix4<f32>,
+	directory: &str, fps: u32, seconds: f32,
+) -> Result<(), String>
+{
+	fs::create_dir_all(directory).map_err(|e| format!("Failed to create {}: {}", directory, e))?;
+
+	let frame_secs = 1.0 / fps as f32;
+	let frame_count = (seconds * fps as f32).round() as u32;
+	for frame in 1..frame_count + 1
+	{
+		let elapsed_secs = frame as f32 * frame_secs;
+		camera.advance_spline(frame_secs as f64);
+		scene.update(elapsed_secs, frame_secs);
+
+		let (width, height, pixels) = render_offscreen(rs, mp, scene, camera, projection_matrix)?;
+		let path = Path::new(directory).join(format!("frame_{:04}.png", frame));
+		image::save_buffer(&path, &pixels, width, height, image::ColorType::RGBA(8))
+			.map_err(|e| format!("Failed to save {}: {}", path.display(), e))?;
+	}
+	info!("Recorded {} frames to {}", frame_count, directory);
+
+	Ok(())
+}
 
 fn main()
 {
+	let args = match Args::parse(&env::args().skip(1).collect::<Vec<String>>())
+	{
+		Ok(args) => args,
+		Err(e) =>
+		{
+			error!("{}", e);
+			Args::print_usage();
+			process::exit(2);
+		}
+	};
+	if args.help
+	{
+		Args::print_usage();
+		return;
+	}
+
+	// The config isn't read yet, so its messages only follow --quiet and --verbose
+	if let Some(verbosity) = args.verbosity
+	{
+		log::set_verbosity(verbosity);
+	}
+
 	// init stuff
-	let options_file = "options.json";
-	let cfg = match Config::read_config(options_file)
+	let options_file = args.config.as_str();
+	// Local tweaks go in options.local.json, so they don't end up in the shared options
+	let local_options_file = Config::local_config_name(options_file);
+	let mut cfg = match Config::read_config(options_file, Some(&local_options_file))
 	{
 		Ok(cfg) => cfg,
 		Err(e) =>
 		{
-			println!("ERROR! reading config file ({}): {}", options_file, e);
+			error!("reading config file ({}): {}", options_file, e);
 			return;
 		}
 	};
 
-	let mut renderstate = RenderState::init(&cfg);
-	let mut presentpass = PresentPass::init(&renderstate);
+	log::set_verbosity(args.verbosity.unwrap_or(cfg.verbosity));
+	// Set before anything is created, so the cleanup of early returns is timed as well
+	profiler::set_teardown_timing(cfg.teardown_timing);
+
+	// The command line overrides the config, without changing the config file
+	if let Some(count) = args.stress
+	{
+		cfg.stress_objects = count;
+		cfg.keep_saved_value("stress_objects");
+	}
+	if let Some(width) = args.width
+	{
+		cfg.window_width = width;
+		cfg.render_width = width;
+		cfg.keep_saved_value("window_width");
+		cfg.keep_saved_value("render_width");
+	}
+	if let Some(height) = args.height
+	{
+		cfg.window_height = height;
+		cfg.render_height = height;
+		cfg.keep_saved_value("window_height");
+		cfg.keep_saved_value("render_height");
+	}
+
+	// The offscreen modes don't need a window
+	let headless = args.headless || args.renders_offscreen();
+	let renderstate = if headless
+	{
+		RenderState::init_headless(&cfg)
+	}
+	else
+	{
+		RenderState::init(&cfg)
+	};
+	let mut renderstate = match renderstate
+	{
+		Ok(renderstate) => renderstate,
+		Err(e) =>
+		{
+			error!("initializing Vulkan: {}", e);
+			return;
+		}
+	};
+	// The windowed report is completed by the PresentPass
+	let mut startup_report = renderstate.startup_report();
+	if headless
+	{
+		startup_report.add("Swapchain", "none, headless");
+		info!("{}", startup_report);
+	}
 	let mut mainpass = MainPass::init(&renderstate, &cfg);
-	let mut scene = Scene::new(&renderstate, &mainpass);
-	let mut camera = Camera::new(Point3::new(0.0, 0.0, 0.0));
-	let aspect_ratio = cfg.render_width as f32 / cfg.render_height as f32;
-	let vertical_fov = Rad::from(Deg(cfg.horizontal_fov as f32 / aspect_ratio));
-	let near = 1.0;
-	let far = 1000.0;
-	// Need to flip projection matrix due to the Vulkan NDC coordinates.
-	// See https://matthewwellings.com/blog/the-new-vulkan-coordinate-system/ for details.
-	let glu_projection_matrix = cgmath::perspective(vertical_fov, aspect_ratio, near, far);
-	let vulkan_ndc = Matrix4::new(1.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.5, 0.0, 0.0, 0.0, 1.0);
-	let projection_matrix = vulkan_ndc * glu_projection_matrix;
-
-	let points = vec![
-		Point3::new(1.0, 0.0, 0.0),
-		Point3::new(0.0, 1.0, 0.0),
-		Point3::new(-1.0, 0.0, 0.0),
-		Point3::new(0.0, -1.0, 0.0),
-		Point3::new(0.0, 0.0, 1.0),
-		Point3::new(0.0, 0.0, -1.0),
-		Point3::new(0.0, 1.0, -1.0),
-		Point3::new(1.0, 0.0, -1.0),
-	];
-
-	let mut u = 0.0;
-	let step = 0.1;
-	let spline = NURBSpline::new(Order::CUBIC, points);
-
-	while u < spline.eval_limit()
-	{
-		let _point = spline.evaluate_at(u);
-		u += step;
+	mainpass.set_fog(&renderstate, &fog(&cfg));
+	mainpass.set_clear_color(cfg.clear_color);
+	let mut camera = Camera::new(Point3::from(cfg.camera_position));
+	match cfg.camera_look_at
+	{
+		Some(target) => camera.look_at(Point3::from(target)),
+		None => camera.set_orientation(Deg(cfg.camera_yaw), Deg(cfg.camera_pitch)),
+	}
+	// --scene PATH starts with a saved scene instead, along with its camera pose and lighting. Its helper
+	// objects need descriptor sets as well.
+	let max_objects = MAX_OBJECTS - HELPER_OBJECTS;
+	let mut lighting = Lighting::from_config(&cfg);
+	let scene = match args.scene
+	{
+		Some(ref path) =>
+		{
+			Scene::load_from_json(&renderstate, &mainpass, &cfg, &mut camera, &mut lighting, path, max_objects)
+		}
+		None => Scene::new(&renderstate, &mainpass, &cfg),
+	};
+	let mut scene = match scene
+	{
+		Ok(scene) => scene,
+		Err(e) =>
+		{
+			error!("{}", e);
+			return;
+		}
+	};
+	mainpass.set_lighting(&renderstate, &lighting.uniforms());
+	let mut projection_matrix = generate_projection_matrix(&cfg, mainpass.render_size());
+
+	let spline_order = match Order::from_value(cfg.spline_order)
+	{
+		Some(order) => order,
+		None =>
+		{
+			warning!("Invalid spline order {}, using a cubic spline instead", cfg.spline_order);
+			Order::CUBIC
+		}
+	};
+	let spline = match cfg.spline_path
+	{
+		Some(ref path) => NURBSpline::from_file(path, spline_order),
+		None => NURBSpline::from_points(spline_order, cfg.spline_points.iter().map(|&p| Point3::from(p)).collect()),
+	};
+	let spline = match spline
+	{
+		Ok(spline) => spline,
+		Err(e) =>
+		{
+			error!("Failed to read the camera spline: {}", e);
+			return;
+		}
+	};
+	let room = scenemanager::object_slots_left(scene.resident_object_count());
+	if let Err(e) = scene.add_spline(&renderstate, &mainpass, &spline, cfg.spline_samples, cfg.spline_max_error, room)
+	{
+		warning!("Failed to add the camera spline to the scene: {}", e);
+	}
+	if let Some(ref path) = cfg.spline_export_path
+	{
+		match spline.export_samples(path, cfg.spline_samples.max(2))
+		{
+			Ok(_) => info!("Exported camera spline to {}", path),
+			Err(e) => warning!("Failed to export camera spline to {}: {}", path, e),
+		}
+	}
+
+	if cfg.stress_objects > 0
+	{
+		let room = scenemanager::object_slots_left(scene.resident_object_count());
+		match scene.add_stress_grid(&renderstate, &mainpass, cfg.stress_objects, &cfg.stress_mesh, room)
+		{
+			Ok(added) if added < cfg.stress_objects => warning!(
+				"Only room for {} of {} stress test objects",
+				added, cfg.stress_objects
+			),
+			Ok(_) => (),
+			Err(e) => warning!("{}", e),
+		}
+	}
+
+	if let Some(ref scatter) = cfg.scatter
+	{
+		let room = scenemanager::object_slots_left(scene.resident_object_count());
+		match scene.add_scatter(&renderstate, &mainpass, scatter, &cfg.stress_mesh, room)
+		{
+			Ok(added) if added < scatter.count => warning!(
+				"Only room for {} of {} scattered objects",
+				added, scatter.count
+			),
+			Ok(_) => (),
+			Err(e) => warning!("{}", e),
+		}
+	}
+
+	// --material-preview starts with only the material preview sphere shown
+	if args.material_preview
+	{
+		if let Err(e) = scene.toggle_material_preview(&renderstate, &mainpass, &camera)
+		{
+			warning!("{}", e);
+		}
+	}
+
+	// --regression PATH renders a single frame of the initial scene and compares it to the reference image
+	if let Some(ref reference_path) = args.regression
+	{
+		// Nothing has been simulated yet, so the frame only depends on the config
+		let frame = render_offscreen(&renderstate, &mut mainpass, &scene, &camera, &projection_matrix);
+		let checked = frame.and_then(|(width, height, pixels)| {
+			regression::check_frame(reference_path, width, height, &pixels, &Tolerance::default())
+		});
+		let passed = match checked
+		{
+			Ok(result) =>
+			{
+				println!("{}", result);
+				true
+			}
+			Err(e) =>
+			{
+				error!("{}", e);
+				false
+			}
+		};
+
+		// Clean up before exiting, as process::exit() skips the destructors
+		drop(scene);
+		drop(mainpass);
+		drop(renderstate);
+		process::exit(if passed { 0 } else { 1 });
+	}
+
+	// --record PATH FPS SECONDS renders the camera spline to an image sequence
+	if let Some(ref record) = args.record
+	{
+		camera.follow_spline(spline.clone(), cfg.spline_speed, cfg.spline_ease, cfg.spline_end_mode);
+		let result = record_frames(
+			&renderstate,
+			&mut mainpass,
+			&mut scene,
+			&mut camera,
+			&projection_matrix,
+			&record.directory,
+			record.fps,
+			record.seconds,
+		);
+		if let Err(ref e) = result
+		{
+			error!("{}", e);
+		}
+
+		// Clean up before exiting, as process::exit() skips the destructors
+		drop(scene);
+		drop(mainpass);
+		drop(renderstate);
+		process::exit(if result.is_ok() { 0 } else { 1 });
+	}
+
+	// --headless --benchmark FRAMES times the frames without presenting them
+	if let (true, Some(frames)) = (headless, args.benchmark)
+	{
+		let frame_times =
+			benchmark_offscreen(&renderstate, &mut mainpass, &mut scene, &camera, &projection_matrix, frames);
+		println!("Benchmark, {} frames offscreen: {}", frames, frame_times);
+
+		// Clean up before exiting, as process::exit() skips the destructors
+		drop(scene);
+		drop(mainpass);
+		drop(renderstate);
+		process::exit(0);
+	}
+
+	// Load the other scenes up front, so switching to them is instant
+	let scene_name = args.scene.as_ref().map_or("default", |path| path.as_str());
+	let mut scenes = SceneManager::new(scene, scene_name, &camera, &lighting, &cfg);
+	for path in cfg.scenes.clone()
+	{
+		match scenes.preload(&renderstate, &mainpass, &cfg, &path)
+		{
+			Ok(index) => info!("Loaded {} as scene {}", path, index + 1),
+			Err(e) => warning!("Not loading {}: {}", path, e),
+		}
 	}
 
+	let mut presentpass = PresentPass::init(&renderstate, &cfg);
+	presentpass.add_to_report(&mut startup_report);
+	info!("{}", startup_report);
+
+	// Frame time statistics for the stress test, and for the whole run with --benchmark
+	let mut stress_frame_times = FrameTimes::new();
+	let mut benchmark_frame_times = FrameTimes::new();
+	// Work recorded for the last frame
+	let mut draw_stats = DrawStats::default();
+
 	// main loop
 	let mut running = true;
 	let mut framecount: u64 = 0;
+	profiler::set_enabled(cfg.cpu_profiling);
 	// aim for 60fps = 16.66666... ms
 	let delta_time = Duration::from_millis(17);
 	let mut elapsed_time = Duration::new(0, 0);
 	let mut accumulator = Duration::new(0, 0);
-	let mut current_time = SystemTime::now();
+	// Scales how much simulated time passes per real time, the steps keep their fixed length
+	let mut time_scale = cfg.time_scale.max(0.0).min(MAX_TIME_SCALE);
+	// Instant is monotonic, so the frame time can't go negative if the system clock is adjusted
+	let mut current_time = Instant::now();
 
-	let mut last_mouse_position = Vector2 {
-		x: 0.0 as f64,
-		y: 0.0 as f64,
-	};
-	let mouse_sensitivity = cfg.mouse_sensitivity;
 	let move_sensitivity = 0.3;
+	let mut camera_controller = CameraController::new(cfg.camera_acceleration, cfg.camera_damping);
+	let mut turntable = Turntable::new(cfg.turntable_mode, cfg.turntable_axis, cfg.turntable_speed);
 
-	let mut key_forward = false;
-	let mut key_left = false;
-	let mut key_back = false;
-	let mut key_right = false;
-	let mut key_up = false;
-	let mut key_down = false;
-	let mut key_sprint = false;
-	let mut cursor_captured = false;
-	let mut cursor_dirty = false;
+	// Input handlers, in the order they get to consume events
+	let mut console = Console::new();
+	let mut debug_input = DebugInput::new();
+	let mut camera_input = CameraInput::new(cfg.mouse_sensitivity, cfg.mouse_invert_x, cfg.mouse_invert_y);
+
+	let mut new_hidpi_factor = None;
+	// Set when the physical size of the window changed, by resizing it or moving it to another monitor
+	let mut window_resized = false;
+	let mut show_depth = false;
+	// Face of the cube depth texture presented by the depth view instead of the main depth, if any
+	let mut cube_depth_face: Option<usize> = None;
+	let mut show_overdraw = false;
+	// G-buffer target presented instead of the albedo, if any
+	let mut gbuffer_view: Option<GBufferTarget> = None;
+	let mut frame_graph = FrameGraph::new(presented_image(show_depth, cube_depth_face, gbuffer_view));
+	// Culling keeps using this frustum while the camera moves on
+	let mut frozen_frustum: Option<Frustum> = None;
+	let mut base_title = match args.scene
+	{
+		Some(ref path) => format!("{} - {}", cfg.title(), path),
+		None => cfg.title(),
+	};
+	let mut window_title = base_title.clone();
+	let mut title_dirty = true;
+	let mut normal_maps = true;
+	// Where to save the next rendered frame, if a screenshot was requested
+	let mut screenshot_path: Option<String> = None;
 
 	while running
 	{
-		let new_time = SystemTime::now();
-		let frame_time = new_time.duration_since(current_time).expect("duration_since failed :(");
+		let new_time = Instant::now();
+		let frame_time = new_time.duration_since(current_time);
 		current_time = new_time;
-		accumulator += frame_time;
+		if frame_time > Duration::from_millis(cfg.frame_spike_ms)
+		{
+			warning!("Frame spike, frame took {:.1}ms", duration_as_secs(frame_time) * 1000.0);
+		}
+		// A huge frame time (e.g. after resuming from sleep) would only be dropped again below. It is clamped
+		// in real time and the step limit scaled with the time, so the time scale isn't capped by them.
+		let max_sim_steps = (cfg.max_sim_steps_per_frame.max(1) as f32 * time_scale.max(1.0)).ceil() as u32;
+		accumulator += scale_duration(frame_time.min(delta_time * cfg.max_sim_steps_per_frame.max(1)), time_scale);
 
+		let mut sim_steps = 0;
 		while accumulator >= delta_time
 		{
+			// Don't try to catch up after a stall, that would only make the next frame slower
+			if sim_steps >= max_sim_steps
+			{
+				let mut dropped_steps = 0;
+				while accumulator >= delta_time
+				{
+					accumulator -= delta_time;
+					dropped_steps += 1;
+				}
+				warning!("Simulation can't keep up, dropped {} steps", dropped_steps);
+				break;
+			}
+			sim_steps += 1;
+
 			// Update Input.
 			let mut move_speed = move_sensitivity;
-			if key_sprint
+			if camera_input.is_sprinting()
 			{
 				move_speed *= 10.0;
 			}
-			if key_forward
+			let move_direction = camera_input.move_direction(&camera);
+			// move_speed is per update, the controller wants it per second
+			let update_secs = duration_as_secs(delta_time);
+			camera_controller.update(&mut camera, move_direction * (move_speed / update_secs), update_secs);
+			let roll_direction = camera_input.roll_direction();
+			if roll_direction != 0.0
 			{
-				let translation = camera.get_cam_front();
-				camera.translate(translation * move_speed);
+				camera.roll(Rad::from(Deg(roll_direction * ROLL_SPEED * update_secs)));
 			}
-			if key_left
+
+			camera.advance_spline(duration_as_secs(delta_time) as f64);
+
+			// animation, physics engine, scene progression etc. goes here
+			{
+				scope!("scene.update");
+				scenes.active_mut().update(duration_as_secs(elapsed_time), duration_as_secs(delta_time));
+			}
+
+			accumulator -= delta_time;
+			elapsed_time += delta_time;
+		}
+
+		// Interpolate between the last two simulation steps using the leftover time
+		let alpha = if cfg.frame_interpolation
+		{
+			duration_as_secs(accumulator) / duration_as_secs(delta_time)
+		}
+		else
+		{
+			1.0
+		};
+
+		// The turntable turns per frame instead of per simulation step, so it turns smoothly
+		if let Some(turn) = turntable.rotation(duration_as_secs(scale_duration(frame_time, time_scale)))
+		{
+			match turntable.mode()
 			{
-				let translation = camera.get_cam_right() * -1.0;
-				camera.translate(translation * move_speed);
+				TurntableMode::Camera =>
+				{
+					let focus = scenes.active().selected_position().unwrap_or(Point3::new(0.0, 0.0, 0.0));
+					Turntable::orbit_camera(&mut camera, focus, turn);
+				}
+				TurntableMode::Object =>
+				{
+					if !scenes.active_mut().turn_selected(turn)
+					{
+						turntable.toggle();
+						warning!("Turntable stopped, there is no selected object to turn");
+					}
+				}
 			}
-			if key_back
+		}
+
+		// Update the view matrix uniform buffer
+		let view_matrix = camera.generate_view_matrix();
+		mainpass.update_view_matrix(&view_matrix, &projection_matrix);
+
+		// Do the main rendering
+		let camera_frustum = Frustum::from_matrix(&(projection_matrix * view_matrix));
+		let cull_frustum = frozen_frustum.unwrap_or(camera_frustum);
+		let main_cmd_buf = {
+			scope!("begin_frame");
+			mainpass.begin_frame(&renderstate, &frame_graph)
+		};
+		{
+			scope!("draw");
+			draw_stats = scenes.active().draw(
+				&renderstate,
+				&mainpass,
+				main_cmd_buf,
+				&view_matrix,
+				&projection_matrix,
+				&cull_frustum,
+				alpha,
+			);
+		}
+		if mainpass.has_cube_depth()
+		{
+			scope!("cube_depth");
+			let views = cube_face_views(Point3::from(cfg.cube_depth_position));
+			let projection = cube_face_projection(CUBE_NEAR_PLANE, CUBE_FAR_PLANE);
+			let scene = scenes.active();
+			let result = mainpass.render_cube_depth(&renderstate, &frame_graph, views, &projection, |mp, cmd_buf| {
+				scene.draw_depth(&renderstate, mp, cmd_buf)
+			});
+			if let Err(e) = result
 			{
-				let translation = camera.get_cam_front() * -1.0;
-				camera.translate(translation * move_speed);
+				warning!("{}", e);
 			}
-			if key_right
+		}
+		{
+			scope!("end_frame");
+			mainpass.end_frame(&renderstate);
+		}
+		if let Some(path) = screenshot_path.take()
+		{
+			let saved = mainpass.read_render_image(&renderstate).and_then(|(width, height, pixels)| {
+				image::save_buffer(&path, &pixels, width, height, image::ColorType::RGBA(8))
+					.map_err(|e| format!("Failed to save {}: {}", path, e))
+			});
+			match saved
 			{
-				let translation = camera.get_cam_right();
-				camera.translate(translation * move_speed);
+				Ok(()) => info!("Saved a screenshot to {}", path),
+				Err(e) => warning!("{}", e),
 			}
-			if key_up
+		}
+
+		// Present the rendered image
+		{
+			scope!("present_image");
+			if let (true, Some(face)) = (show_depth, cube_depth_face)
 			{
-				let translation = camera.get_world_up_vector();
-				camera.translate(translation * move_speed);
+				let (cube_image, face_view, format) =
+					mainpass.cube_depth_texture(face).expect("Cube face view without cube depth");
+				presentpass.present_depth(
+					&renderstate,
+					&frame_graph,
+					cube_image,
+					frame_graph.cube_depth,
+					face_view,
+					format,
+					CUBE_NEAR_PLANE,
+					CUBE_FAR_PLANE,
+				);
 			}
-			if key_down
+			else if show_depth
 			{
-				let translation = camera.get_world_up_vector() * -1.0;
-				camera.translate(translation * move_speed);
+				let (depth_image, depth_view, format) = mainpass.depth_image();
+				presentpass.present_depth(
+					&renderstate,
+					&frame_graph,
+					depth_image,
+					frame_graph.main_depth,
+					depth_view,
+					format,
+					NEAR_PLANE,
+					FAR_PLANE,
+				);
 			}
+			else if let Some(target) = gbuffer_view
+			{
+				let image = mainpass.gbuffer_target(target).expect("G-buffer view without a G-buffer");
+				presentpass.present_gbuffer(&renderstate, &frame_graph, image, target, cfg.gbuffer_position_range);
+			}
+			else if show_overdraw
+			{
+				presentpass.present_overdraw(&renderstate, &frame_graph, &mut mainpass.render_image);
+			}
+			else
+			{
+				presentpass.present_image(&renderstate, &frame_graph, &mut mainpass.render_image);
+			}
+		}
+		framecount += 1;
+		profiler::end_frame();
 
-			// animation, physics engine, scene progression etc. goes here
-			scene.update();
-
-			accumulator -= delta_time;
-			elapsed_time += delta_time;
+		if cfg.stress_objects > 0
+		{
+			stress_frame_times.add(duration_as_secs(frame_time));
+		}
+		if let Some(frames) = args.benchmark
+		{
+			// The first frame time includes the startup
+			if framecount > 1
+			{
+				benchmark_frame_times.add(duration_as_secs(frame_time));
+			}
+			if benchmark_frame_times.count() >= frames
+			{
+				running = false;
+			}
 		}
 
-		// Update the view matrix uniform buffer
-		let view_matrix = camera.generate_view_matrix();
-		let view_matrix_buf_size = size_of::<Matrix4<f32>>() as u64;
-		unsafe {
-			let mem_ptr = renderstate
-				.device
-				.map_memory(mainpass.view_matrix_ub_mem, 0, view_matrix_buf_size, vk::MemoryMapFlags::empty())
-				.expect("Failed to view matrix uniform memory");
-			let mut mem_align = Align::new(mem_ptr, align_of::<Matrix4<f32>>() as u64, view_matrix_buf_size);
-			mem_align.copy_from_slice(&[view_matrix]);
-			renderstate.device.unmap_memory(mainpass.view_matrix_ub_mem);
+		if framecount % 100 == 0
+		{
+			if cfg.stress_objects > 0
+			{
+				// Only CPU frame times for now, there are no GPU timestamps yet
+				println!("Stress test, {} objects: {}", scenes.active().object_count(), stress_frame_times);
+				stress_frame_times = FrameTimes::new();
+			}
+			if mainpass.occlusion_enabled()
+			{
+				info!("Occlusion culled {} objects", mainpass.occlusion_culled_count());
+			}
+			if cfg.draw_stats
+			{
+				println!(
+					"Draws: {} calls, {} triangles, {} pipeline binds, {} descriptor set binds, {} culled, {} proxies",
+					draw_stats.draw_calls,
+					draw_stats.triangles,
+					draw_stats.pipeline_binds,
+					draw_stats.descriptor_set_binds,
+					draw_stats.culled,
+					draw_stats.occlusion_proxies
+				);
+			}
+			if profiler::is_enabled()
+			{
+				println!("{}", profiler::take_report());
+			}
 		}
 
-		// Do the main rendering
-		let main_cmd_buf = mainpass.begin_frame(&renderstate);
-		scene.draw(main_cmd_buf, mainpass.pipeline_layout, &view_matrix, &projection_matrix);
-		mainpass.end_frame(&renderstate);
+		renderstate.poll_events(|ev| {
+			match ev
+			{
+				winit::Event::WindowEvent {
+					event: winit::WindowEvent::Closed,
+					..
+				} => running = false,
+				winit::Event::WindowEvent {
+					event: winit::WindowEvent::HiDPIFactorChanged(factor),
+					..
+				} => new_hidpi_factor = Some(factor),
+				winit::Event::WindowEvent {
+					event: winit::WindowEvent::Resized(..),
+					..
+				} => window_resized = true,
+				_ => (),
+			}
 
-		// Present the rendered image
-		presentpass.present_image(&renderstate, &mut mainpass.render_image);
-		framecount += 1;
+			let consumed = {
+				let mut handlers: [&mut InputHandler; 3] = [&mut console, &mut debug_input, &mut camera_input];
+				dispatch_event(&mut handlers, &ev)
+			};
+			if !consumed
+			{
+				if let Some(scancode) = key_pressed(&ev)
+				{
+					verbose!("Pressed {}", scancode);
+				}
+			}
+		});
 
-		if framecount % 100 == 0
+		// The console takes over the keyboard, so stop the camera
+		if console.is_open()
+		{
+			camera_input.release_keys();
+		}
+
+		let look = camera_input.take_look();
+		camera.yaw(look.x);
+		camera.pitch(look.y);
+
+		// Moving the camera by hand takes over from the turntable
+		let moved = look.x != 0.0 || look.y != 0.0 || camera_input.roll_direction() != 0.0;
+		if (moved || camera_input.move_direction(&camera) != Vector3::new(0.0, 0.0, 0.0)) && turntable.pause()
 		{
-			// let frame_time_ms = frame_time.subsec_nanos() as f64 / 1_000_000.0;
-			// println!(
-			//    "frametime: {}ms => {} FPS",
-			//    frame_time_ms,
-			//    1_000.0 / frame_time_ms
-			// );
+			info!("Turntable paused, press U to resume");
 		}
 
-		renderstate.event_loop.poll_events(|ev| match ev
+		for action in debug_input.take_actions()
 		{
-			winit::Event::WindowEvent {
-				event,
-				..
-			} => match event
+			match action
 			{
-				winit::WindowEvent::Closed => running = false,
-				winit::WindowEvent::Focused(has_focus) =>
+				DebugAction::Quit => running = false,
+				DebugAction::ToggleCinematic =>
 				{
-					cursor_captured = has_focus;
-					cursor_dirty = true;
+					if camera.is_following_spline()
+					{
+						camera.stop_following_spline();
+					}
+					else
+					{
+						camera.follow_spline(spline.clone(), cfg.spline_speed, cfg.spline_ease, cfg.spline_end_mode);
+					}
 				}
-				// Keyboard events
-				winit::WindowEvent::KeyboardInput {
-					input,
-					..
-				} => match input.state
+				DebugAction::ToggleTurntable =>
 				{
-					winit::ElementState::Pressed => match input.scancode
+					let running = turntable.toggle();
+					if running && turntable.mode() == TurntableMode::Camera
 					{
-						W_SCAN_CODE =>
-						{
-							key_forward = true;
-						}
-						A_SCAN_CODE =>
-						{
-							key_left = true;
-						}
-						S_SCAN_CODE =>
-						{
-							key_back = true;
-						}
-						D_SCAN_CODE =>
-						{
-							key_right = true;
-						}
-						SPACE_SCAN_CODE =>
-						{
-							key_up = true;
-						}
-						LCTRL_SCAN_CODE =>
-						{
-							key_down = true;
-						}
-						F_SCAN_CODE =>
-						{
-							cursor_captured = !cursor_captured;
-							cursor_dirty = true;
-						}
-						UP_SCAN_CODE =>
-						{
-							camera.pitch(5.0);
-						}
-						LEFT_SCAN_CODE =>
-						{
-							camera.yaw(-5.0);
-						}
-						DOWN_SCAN_CODE =>
-						{
-							camera.pitch(-5.0);
-						}
-						RIGHT_SCAN_CODE =>
-						{
-							camera.yaw(5.0);
-						}
-						ESC_SCAN_CODE =>
+						// The spline would move the camera away again
+						camera.stop_following_spline();
+					}
+					info!("Turntable: {}", running);
+				}
+				DebugAction::ToggleDepthView =>
+				{
+					// With cube depth, the depth view steps through the cube faces before it is turned off
+					if show_depth && mainpass.has_cube_depth()
+					{
+						cube_depth_face = match cube_depth_face
 						{
-							running = false;
-						}
-						LSHIFT_SCAN_CODE =>
+							None => Some(0),
+							Some(face) if face + 1 < CUBE_FACES => Some(face + 1),
+							Some(_) => None,
+						};
+						show_depth = cube_depth_face.is_some();
+					}
+					else
+					{
+						show_depth = !show_depth;
+					}
+					frame_graph = FrameGraph::new(presented_image(show_depth, cube_depth_face, gbuffer_view));
+				}
+				DebugAction::CycleGBufferView =>
+				{
+					if mainpass.has_gbuffer()
+					{
+						gbuffer_view = match gbuffer_view
 						{
-							key_sprint = true;
-						}
-						_ =>
+							None => Some(GBufferTarget::Normal),
+							Some(GBufferTarget::Normal) => Some(GBufferTarget::Position),
+							Some(GBufferTarget::Position) => None,
+						};
+						match gbuffer_view
 						{
-							println!("Pressed {}", input.scancode);
+							Some(target) => info!("G-buffer view: {:?}", target),
+							None => info!("G-buffer view: Albedo"),
 						}
-					},
-					winit::ElementState::Released => match input.scancode
+						frame_graph = FrameGraph::new(presented_image(show_depth, cube_depth_face, gbuffer_view));
+					}
+					else
+					{
+						info!("The G-buffer is not rendered, enable gbuffer in the config to view it");
+					}
+				}
+				DebugAction::ToggleOverdraw => show_overdraw = mainpass.toggle_overdraw(),
+				DebugAction::CycleScaling =>
+				{
+					let mode = presentpass.scaling_mode().next();
+					info!("Present scaling: {:?}", mode);
+					presentpass.set_scaling_mode(mode);
+					title_dirty = true;
+				}
+				DebugAction::TogglePresentFilter =>
+				{
+					let filter = presentpass.toggle_filter();
+					info!("Present filter: {:?}", filter);
+					title_dirty = true;
+				}
+				DebugAction::CycleBackground =>
+				{
+					let background = mainpass.background().next();
+					mainpass.set_background(background);
+					info!("Background: {:?}", background);
+				}
+				DebugAction::ToggleProjection =>
+				{
+					cfg.projection = cfg.projection.toggled();
+					info!("Projection: {:?}", cfg.projection);
+					projection_matrix = generate_projection_matrix(&cfg, mainpass.render_size());
+				}
+				DebugAction::FreezeCulling =>
+				{
+					frozen_frustum = match frozen_frustum
+					{
+						Some(_) => None,
+						None => Some(Frustum::from_matrix(&(projection_matrix * camera.generate_view_matrix()))),
+					};
+					if let Err(e) = scenes
+						.active_mut()
+						.set_debug_frustum(&renderstate, &mainpass, frozen_frustum.as_ref())
+					{
+						warning!("{}", e);
+					}
+					title_dirty = true;
+					info!("Culling frozen: {}", frozen_frustum.is_some());
+				}
+				DebugAction::ToggleAxisGizmo =>
+				{
+					let shown = scenes.active_mut().toggle_axis_gizmo();
+					info!("Axis gizmo: {}", shown);
+					if shown && mainpass.is_recording_parallel()
+					{
+						warning!("The axis gizmo is not drawn with parallel recording");
+					}
+				}
+				DebugAction::ToggleMinimap =>
+				{
+					let shown = scenes.active_mut().toggle_minimap();
+					info!("Minimap: {}", shown);
+					if shown && mainpass.is_recording_parallel()
+					{
+						warning!("The minimap is not drawn with parallel recording");
+					}
+				}
+				DebugAction::ToggleBounds =>
+				{
+					let shown = scenes.active_mut().toggle_bounds();
+					info!("Bounding spheres: {}", shown);
+					if shown && mainpass.is_recording_parallel()
+					{
+						warning!("The bounding spheres are not drawn with parallel recording");
+					}
+				}
+				DebugAction::ToggleMaterialPreview =>
+				{
+					match scenes.active_mut().toggle_material_preview(&renderstate, &mainpass, &camera)
+					{
+						Ok(shown) => info!("Material preview: {}", shown),
+						Err(e) => warning!("{}", e),
+					}
+				}
+				DebugAction::ToggleNormalMaps =>
+				{
+					normal_maps = scenes.active_mut().toggle_normal_maps(&renderstate);
+					info!("Normal maps: {}", normal_maps);
+					title_dirty = true;
+				}
+				DebugAction::ResetCameraRoll => camera.set_roll(Rad(0.0)),
+				DebugAction::SelectNext => match scenes.active_mut().select_next()
+				{
+					Some(selected) => info!("Selected object {}", selected),
+					None => info!("Selection cleared"),
+				},
+				DebugAction::ToggleSolo => match scenes.active_mut().toggle_solo()
+				{
+					Ok(solo) => info!("Solo: {}", solo),
+					Err(e) => warning!("{}", e),
+				},
+				DebugAction::ToggleHideSelected => match scenes.active_mut().toggle_hide_selected()
+				{
+					Ok(hidden) => info!("Selected object hidden: {}", hidden),
+					Err(e) => warning!("{}", e),
+				},
+				DebugAction::RenderScale(change) =>
+				{
+					let scale = mainpass.render_scale() + change;
+					mainpass.set_render_scale(scale);
+					info!("Render scale: {}", mainpass.render_scale());
+				}
+				DebugAction::Exposure(change) =>
+				{
+					let exposure = presentpass.exposure() + change;
+					presentpass.set_exposure(exposure);
+					info!("Exposure: {:.1}", presentpass.exposure());
+				}
+				DebugAction::TimeScale(factor) =>
+				{
+					// Pause with time_scale 0 in the console, as doubling 0 would get stuck
+					time_scale = (time_scale * factor).max(1.0 / 64.0).min(MAX_TIME_SCALE);
+					info!("Time scale: {}", time_scale);
+				}
+				DebugAction::Tessellation(factor) => match scenes.active_mut().scale_tessellation(&renderstate, factor)
+				{
+					0 => warning!("There are no spheres or planes to tessellate"),
+					count =>
 					{
-						W_SCAN_CODE =>
+						info!("Tessellated {} objects, {} triangles", count, scenes.active().triangle_count());
+						title_dirty = true;
+					}
+				},
+				DebugAction::SwitchScene(index) => match scenes.switch_to(index, &mut camera, &mut lighting)
+				{
+					Ok(()) =>
+					{
+						// Each scene keeps its own debug state, apart from the frozen frustum
+						if let Err(e) = scenes
+							.active_mut()
+							.set_debug_frustum(&renderstate, &mainpass, frozen_frustum.as_ref())
 						{
-							key_forward = false;
+							warning!("{}", e);
 						}
-						A_SCAN_CODE =>
+						normal_maps = scenes.active().normal_maps_enabled();
+						mainpass.set_lighting(&renderstate, &lighting.uniforms());
+						info!("Scene {}: {}", index + 1, scenes.active_name());
+						base_title = format!("{} - {}", cfg.title(), scenes.active_name());
+						title_dirty = true;
+					}
+					Err(e) => warning!("{}", e),
+				},
+			}
+		}
+
+		if console.take_submitted()
+		{
+			match console.submit(&mut cfg)
+			{
+				Ok(Some(Command::Set(name))) =>
+				{
+					// Apply the settings that take effect at runtime
+					match name.as_str()
+					{
+						"render_scale" => mainpass.set_render_scale(cfg.render_scale),
+						"exposure" => presentpass.set_exposure(cfg.exposure),
+						"time_scale" => time_scale = cfg.time_scale.max(0.0).min(MAX_TIME_SCALE),
+						"present_scaling" =>
 						{
-							key_left = false;
+							presentpass.set_scaling_mode(cfg.present_scaling);
+							title_dirty = true;
 						}
-						S_SCAN_CODE =>
+						"light_direction" | "light_color" | "light_intensity" | "ambient_color" =>
 						{
-							key_back = false;
+							match name.as_str()
+							{
+								"light_direction" => lighting.direction = cfg.light_direction,
+								"light_color" => lighting.color = cfg.light_color,
+								"light_intensity" => lighting.intensity = cfg.light_intensity,
+								_ => lighting.ambient = cfg.ambient_color,
+							}
+							mainpass.set_lighting(&renderstate, &lighting.uniforms())
 						}
-						D_SCAN_CODE =>
+						"fog_mode" | "fog_color" | "fog_start" | "fog_end" | "fog_density" =>
 						{
-							key_right = false;
+							mainpass.set_fog(&renderstate, &fog(&cfg))
 						}
-						SPACE_SCAN_CODE =>
+						"clear_color" => mainpass.set_clear_color(cfg.clear_color),
+						"verbosity" => log::set_verbosity(cfg.verbosity),
+						"background" => mainpass.set_background(cfg.background),
+						"sky_horizon_color" | "sky_zenith_color" =>
 						{
-							key_up = false;
+							mainpass.set_sky_colors(cfg.sky_horizon_color, cfg.sky_zenith_color)
 						}
-						LCTRL_SCAN_CODE =>
+						"fov_horizontal" | "fov_vertical" | "projection" | "ortho_height" =>
 						{
-							key_down = false;
+							projection_matrix = generate_projection_matrix(&cfg, mainpass.render_size())
 						}
-						LSHIFT_SCAN_CODE =>
+						// Read every frame
+						"cube_depth_position" => (),
+						_ => info!("{} will take effect after a restart", name),
+					}
+					info!("Set {}", name);
+				}
+				Ok(Some(Command::Spawn(kind, position))) => match kind.as_str()
+				{
+					"cube" =>
+					{
+						let room = scenes.free_object_slots();
+						let scene = scenes.active_mut();
+						match scene.add_cuboid(&renderstate, &mainpass, Point3::from(position), 1.0, room)
 						{
-							key_sprint = false;
+							Ok(()) => title_dirty = true,
+							Err(e) => warning!("{}", e),
 						}
-						_ => (),
-					},
+					}
+					_ => warning!("Unknown object kind: {}", kind),
 				},
-				// Mouse presses
-				winit::WindowEvent::MouseInput {
-					button,
-					..
-				} => if cursor_captured
+				Ok(Some(Command::Screenshot(path))) =>
 				{
-					match button
+					screenshot_path = Some(path.unwrap_or_else(next_screenshot_path));
+				}
+				Ok(Some(Command::Save(path))) => match scenes.active().save_to_json(&path, &camera, &lighting)
+				{
+					Ok(count) => info!("Saved {} objects to {}", count, path),
+					Err(e) => warning!("{}", e),
+				},
+				Ok(Some(Command::Load(path))) =>
+				{
+					// The active scene is only freed once the new one has loaded, so both must fit at once
+					let max_objects = scenes.free_object_slots();
+					let loaded = Scene::load_from_json(
+						&renderstate,
+						&mainpass,
+						&cfg,
+						&mut camera,
+						&mut lighting,
+						&path,
+						max_objects,
+					);
+					match loaded
 					{
-						winit::MouseButton::Left =>
+						Ok(loaded) =>
 						{
-							println!("Left mouse!");
+							// The old objects may still be in use
+							renderstate.device.device_wait_idle().unwrap();
+							scenes.replace_active(loaded, &path, &camera, &lighting);
+							if let Err(e) = scenes
+								.active_mut()
+								.set_debug_frustum(&renderstate, &mainpass, frozen_frustum.as_ref())
+							{
+								warning!("{}", e);
+							}
+							// Loaded scenes start with their normal maps
+							if !normal_maps
+							{
+								scenes.active_mut().toggle_normal_maps(&renderstate);
+							}
+							mainpass.set_lighting(&renderstate, &lighting.uniforms());
+							info!("Loaded {} objects from {}", scenes.active().object_count(), path);
+							base_title = format!("{} - {}", cfg.title(), path);
+							title_dirty = true;
 						}
-						winit::MouseButton::Right =>
-						{
-							println!("Right mouse!");
-						}
-						_ => (),
+						Err(e) => warning!("{}", e),
 					}
+				}
+				Ok(Some(Command::Preload(path))) => match scenes.preload(&renderstate, &mainpass, &cfg, &path)
+				{
+					Ok(index) => info!("Loaded {} as scene {}", path, index + 1),
+					Err(e) => warning!("{}", e),
 				},
-				_ => (),
-			},
-
-			winit::Event::DeviceEvent {
-				event,
-				..
-			} => match event
-			{
-				// Mouse Movement
-				// Use DeviceEvent as it gives raw unfiltered physical motion
-				winit::DeviceEvent::MouseMotion {
-					delta,
-					..
-				} => if cursor_captured
+				Ok(Some(Command::Unload(index))) =>
 				{
-					// println!("Mouse moved x: {} y: {}", delta.0, delta.1);
-					let mut dir_change = Vector2 {
-						x: (last_mouse_position.x + delta.0),
-						y: (last_mouse_position.y + delta.1),
-					};
-					last_mouse_position.x = delta.0;
-					last_mouse_position.y = delta.1;
-
-					// Update camera.
-					dir_change *= mouse_sensitivity;
-					camera.yaw(match cfg.mouse_invert_x
+					// The scene may still be in use by the last frames
+					renderstate.device.device_wait_idle().unwrap();
+					match scenes.unload(index)
 					{
-						true => -dir_change.x,
-						false => dir_change.x,
-					} as f32);
-					camera.pitch(match cfg.mouse_invert_y
+						Ok(name) => info!("Unloaded scene {}: {}", index + 1, name),
+						Err(e) => warning!("{}", e),
+					}
+				}
+				Ok(Some(Command::Scenes)) => scenes.print_scenes(),
+				Ok(Some(Command::CastsShadow(id, casts_shadow))) =>
+				{
+					if let Err(e) = scenes.active_mut().set_casts_shadow(id, casts_shadow)
 					{
-						true => dir_change.y,
-						false => -dir_change.y,
-					} as f32);
-				},
-				_ => (),
-			},
-			_ => (),
-		});
+						warning!("{}", e);
+					}
+				}
+				Ok(Some(Command::SetVisible(id, visible))) =>
+				{
+					match scenes.active_mut().set_visible(id, visible)
+					{
+						Ok(()) => title_dirty = true,
+						Err(e) => warning!("{}", e),
+					}
+				}
+				Ok(Some(Command::SetLayer(id, layer))) =>
+				{
+					if let Err(e) = scenes.active_mut().set_object_layer(id, layer)
+					{
+						warning!("{}", e);
+					}
+				}
+				Ok(Some(Command::Help)) => Console::print_help(),
+				Ok(None) => (),
+				Err(e) => warning!("{}", e),
+			}
+		}
+
+		if title_dirty
+		{
+			window_title = status_title(
+				&base_title,
+				frozen_frustum.is_some(),
+				presentpass.filter(),
+				normal_maps,
+				scenes.active().triangle_count(),
+			);
+			title_dirty = false;
+			if !console.is_open()
+			{
+				renderstate.set_window_title(&window_title);
+			}
+		}
 
-		if cursor_dirty
+		if console.take_dirty()
 		{
-			if cursor_captured
+			if console.is_open()
 			{
-				renderstate.window.set_cursor_state(winit::CursorState::Grab).expect("Failed to grab pointer");
-				renderstate.window.set_cursor(winit::MouseCursor::NoneCursor);
+				renderstate.set_window_title(&console.prompt());
 			}
 			else
 			{
-				renderstate.window.set_cursor_state(winit::CursorState::Normal).expect("Failed to return pointer");
-				renderstate.window.set_cursor(winit::MouseCursor::Default);
+				renderstate.set_window_title(&window_title);
+			}
+		}
+
+		if let Some(factor) = new_hidpi_factor.take()
+		{
+			renderstate.hidpi_factor = factor;
+			window_resized = true;
+		}
+		if window_resized
+		{
+			// The physical size of the surface changed, so recreate everything sized by it. A minimized
+			// window has no size, so wait until it is restored.
+			let (render_width, render_height) = renderstate.base_render_size(&cfg);
+			if render_width > 0 && render_height > 0
+			{
+				mainpass.resize(&renderstate, render_width, render_height);
+				presentpass.recreate_swapchain(&renderstate);
+				projection_matrix = generate_projection_matrix(&cfg, mainpass.render_size());
+				window_resized = false;
+			}
+		}
+
+		if camera_input.take_cursor_dirty()
+		{
+			if let Some(window) = renderstate.window()
+			{
+				if camera_input.is_cursor_captured()
+				{
+					window.set_cursor_state(winit::CursorState::Grab).expect("Failed to grab pointer");
+					window.set_cursor(winit::MouseCursor::NoneCursor);
+				}
+				else
+				{
+					window.set_cursor_state(winit::CursorState::Normal).expect("Failed to return pointer");
+					window.set_cursor(winit::MouseCursor::Default);
+				}
 			}
-			cursor_dirty = false;
 		}
 	}
 
+	if let Some(frames) = args.benchmark
+	{
+		println!("Benchmark, {} frames: {}", frames, benchmark_frame_times);
+	}
+
+	// Remember the exposure for the next run, the rest of the Config may hold overrides and runtime changes
+	cfg.exposure = presentpass.exposure();
+	if let Err(e) = cfg.save_field(options_file, "exposure")
+	{
+		warning!("Failed to save config file ({}): {}", options_file, e);
+	}
+
 	// cleanup
+	{
+		// The scenes go first, the renderer parts time themselves as they are dropped
+		teardown_scope!("Scenes");
+		drop(scenes);
+	}
 }
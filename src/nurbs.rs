@@ -1,5 +1,9 @@
-use cgmath::Point3;
+use cgmath::{Point3, Vector3};
 use cgmath::prelude::*;
+use serde_json;
+use std::fs::File;
+use std::io::{BufWriter, Error};
+use std::io::prelude::*;
 
 #[derive(Copy, Clone)]
 #[allow(dead_code)]
@@ -11,6 +15,23 @@ pub enum Order
 	QUARTIC = 5,
 }
 
+impl Order
+{
+	/// Returns the order with the given value, from 2 for linear to 5 for quartic.
+	pub fn from_value(value: usize) -> Option<Order>
+	{
+		match value
+		{
+			2 => Some(Order::LINEAR),
+			3 => Some(Order::QUADRATIC),
+			4 => Some(Order::CUBIC),
+			5 => Some(Order::QUARTIC),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Clone)]
 pub struct NURBSpline
 {
 	order: Order,
@@ -38,6 +59,34 @@ impl NURBSpline
 		spline
 	}
 
+	/// Initializes a new NURBSpline like new(), but fails if there are fewer control points than the
+	/// order.
+	pub fn from_points(order: Order, controlpoints: Vec<Point3<f64>>) -> Result<NURBSpline, String>
+	{
+		if controlpoints.len() < order as usize
+		{
+			return Err(format!(
+				"A spline of order {} needs at least {} control points, got {}",
+				order as usize,
+				order as usize,
+				controlpoints.len()
+			));
+		}
+		Ok(NURBSpline::new(order, controlpoints))
+	}
+
+	/// Reads the control points from a JSON file and initializes a NURBSpline with them.
+	///
+	/// The file holds an array of points in the format of the saved scenes, e.g. [[1, 0, 0], [0, 1, 0]].
+	pub fn from_file(filename: &str, order: Order) -> Result<NURBSpline, String>
+	{
+		let file = File::open(filename).map_err(|e| format!("Failed to open {}: {}", filename, e))?;
+		let points: Vec<[f64; 3]> =
+			serde_json::from_reader(file).map_err(|e| format!("Failed to parse {}: {}", filename, e))?;
+		let controlpoints = points.into_iter().map(Point3::from).collect();
+		NURBSpline::from_points(order, controlpoints).map_err(|e| format!("{} in {}", e, filename))
+	}
+
 	/// Returns the evaluation limit for the NURBSpline.
 	///
 	/// The spline cannot be evaluated at any point equal to or greater than this limit.
@@ -68,6 +117,102 @@ impl NURBSpline
 		result
 	}
 
+	/// Approximates the derivative (tangent) of the NURBSpline at the given value.
+	///
+	/// Uses central differences, falling back to one-sided differences at the ends of the spline.
+	pub fn derivative_at(&self, u: f64) -> Vector3<f64>
+	{
+		debug_assert!(u < self.eval_limit());
+
+		let h = 1e-4;
+		let u0 = (u - h).max(0.0);
+		let u1 = (u + h).min(self.eval_limit() - h);
+		if u1 <= u0
+		{
+			return Vector3::new(0.0, 0.0, 0.0);
+		}
+		(self.evaluate_at(u1) - self.evaluate_at(u0)) / (u1 - u0)
+	}
+
+	/// Evaluates the NURBSpline at the given number of evenly spaced values, including both ends.
+	pub fn sample(&self, samples: usize) -> Vec<Point3<f64>>
+	{
+		debug_assert!(samples >= 2);
+
+		// The limit itself can't be evaluated, so stop just short of it
+		let end = self.eval_limit() - 1e-9;
+		(0..samples)
+			.map(|i| self.evaluate_at(end * i as f64 / (samples - 1) as f64))
+			.collect()
+	}
+
+	/// Samples the NURBSpline densely where it bends and sparsely where it is straight.
+	///
+	/// Each knot span is split in half until the curve deviates less than max_error from the straight
+	/// line between the samples, checked at the middle and quarter points. Includes both ends.
+	pub fn sample_adaptive(&self, max_error: f64) -> Vec<Point3<f64>>
+	{
+		debug_assert!(max_error > 0.0);
+
+		let end = self.eval_limit() - 1e-9;
+		let mut points = vec![self.evaluate_at(0.0)];
+		let spans = end.ceil() as usize;
+		for span in 0..spans
+		{
+			let u0 = span as f64;
+			let u1 = (span as f64 + 1.0).min(end);
+			self.subdivide(u0, u1, max_error, 0, &mut points);
+		}
+		points
+	}
+
+	/// Appends the samples after u0 up to and including u1, see sample_adaptive().
+	fn subdivide(&self, u0: f64, u1: f64, max_error: f64, depth: u32, points: &mut Vec<Point3<f64>>)
+	{
+		// Limits the number of samples for curves with kinks, where the error never gets small
+		const MAX_DEPTH: u32 = 12;
+
+		let start = self.evaluate_at(u0);
+		let end = self.evaluate_at(u1);
+		let chord = end - start;
+		let distance_to_chord = |u: f64| {
+			let offset = self.evaluate_at(u) - start;
+			let length2 = chord.magnitude2();
+			if length2 <= 1e-24
+			{
+				return offset.magnitude();
+			}
+			let t = (offset.dot(chord) / length2).max(0.0).min(1.0);
+			(offset - chord * t).magnitude()
+		};
+		let within_error = [0.25, 0.5, 0.75]
+			.iter()
+			.all(|&fraction| distance_to_chord(u0 + (u1 - u0) * fraction) < max_error);
+
+		if within_error || depth >= MAX_DEPTH
+		{
+			points.push(end);
+		}
+		else
+		{
+			let middle = (u0 + u1) / 2.0;
+			self.subdivide(u0, middle, max_error, depth + 1, points);
+			self.subdivide(middle, u1, max_error, depth + 1, points);
+		}
+	}
+
+	/// Samples the NURBSpline and writes the points to a CSV file, one "x,y,z" line per point.
+	pub fn export_samples(&self, filename: &str, samples: usize) -> Result<(), Error>
+	{
+		let mut file = BufWriter::new(File::create(filename)?);
+		writeln!(file, "x,y,z")?;
+		for point in self.sample(samples).iter()
+		{
+			writeln!(file, "{},{},{}", point.x, point.y, point.z)?;
+		}
+		Ok(())
+	}
+
 	/// Cox-de Boor recursion formula.
 	///
 	/// This returns the contribution of the given control point index, order and value to
@@ -140,3 +285,42 @@ impl NURBSpline
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn adaptive_sampling_follows_the_curvature()
+	{
+		let points = |middle: Point3<f64>| vec![Point3::new(0.0, 0.0, 0.0), middle, Point3::new(10.0, 10.0, 0.0)];
+		// Nearly on the line between the ends
+		let straight = NURBSpline::new(Order::QUADRATIC, points(Point3::new(5.0, 5.0001, 0.0)));
+		// A right angle at the middle control point
+		let turn = NURBSpline::new(Order::QUADRATIC, points(Point3::new(10.0, 0.0, 0.0)));
+
+		let straight_samples = straight.sample_adaptive(0.001);
+		let turn_samples = turn.sample_adaptive(0.001);
+		assert!(straight_samples.len() <= 3, "{} samples", straight_samples.len());
+		assert!(turn_samples.len() >= 16, "{} samples", turn_samples.len());
+	}
+
+	#[test]
+	fn adaptive_sampling_includes_both_ends()
+	{
+		let spline = NURBSpline::new(
+			Order::CUBIC,
+			vec![
+				Point3::new(0.0, 0.0, 0.0),
+				Point3::new(1.0, 2.0, 0.0),
+				Point3::new(3.0, -1.0, 1.0),
+				Point3::new(4.0, 0.0, 2.0),
+				Point3::new(6.0, 1.0, 0.0),
+			],
+		);
+		let samples = spline.sample_adaptive(0.01);
+		assert_eq!(samples[0], spline.evaluate_at(0.0));
+		assert_eq!(samples[samples.len() - 1], spline.evaluate_at(spline.eval_limit() - 1e-9));
+	}
+}
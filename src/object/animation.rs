@@ -0,0 +1,161 @@
+use cgmath::{Point3, Quaternion};
+use cgmath::prelude::*;
+use object::SplineEndMode;
+use std::cmp::Ordering;
+
+/// The transform of an object at a point in time.
+#[derive(Clone, Copy)]
+pub struct Keyframe
+{
+	/// Time in seconds since the start of the animation.
+	pub time: f32,
+	pub position: Point3<f32>,
+	pub rotation: Quaternion<f32>,
+}
+
+/// A single animation channel moving an object through a list of keyframes.
+///
+/// Positions follow a Catmull-Rom spline, which (unlike the NURBSpline) passes through every
+/// keyframe. Rotations are interpolated with slerp.
+pub struct Animation
+{
+	keyframes: Vec<Keyframe>,
+	end_mode: SplineEndMode,
+}
+
+impl Animation
+{
+	/// Creates a new animation. The keyframes are sorted by time.
+	///
+	/// Fails if there are no keyframes or a time isn't a finite number.
+	pub fn new(mut keyframes: Vec<Keyframe>, end_mode: SplineEndMode) -> Result<Animation, String>
+	{
+		if keyframes.is_empty()
+		{
+			return Err(String::from("An animation needs at least one keyframe"));
+		}
+		if let Some(keyframe) = keyframes.iter().find(|keyframe| !keyframe.time.is_finite())
+		{
+			return Err(format!("Invalid keyframe time: {}", keyframe.time));
+		}
+		// All times are finite, so they are ordered
+		keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+
+		Ok(Animation {
+			keyframes: keyframes,
+			end_mode: end_mode,
+		})
+	}
+
+	/// Returns the time of the last keyframe.
+	pub fn duration(&self) -> f32
+	{
+		self.keyframes[self.keyframes.len() - 1].time
+	}
+
+	/// Maps the time since the start of the animation to a time within the keyframes.
+	fn local_time(&self, time: f32) -> f32
+	{
+		let start = self.keyframes[0].time;
+		let length = self.duration() - start;
+		if length <= 0.0 || time <= start
+		{
+			return time.max(start);
+		}
+
+		let t = time - start;
+		match self.end_mode
+		{
+			SplineEndMode::Stop => start + t.min(length),
+			SplineEndMode::Loop => start + t % length,
+			SplineEndMode::PingPong =>
+			{
+				let t = t % (2.0 * length);
+				if t > length
+				{
+					start + 2.0 * length - t
+				}
+				else
+				{
+					start + t
+				}
+			}
+		}
+	}
+
+	/// Returns the interpolated position and rotation at the given time in seconds.
+	pub fn sample(&self, time: f32) -> (Point3<f32>, Quaternion<f32>)
+	{
+		let time = self.local_time(time);
+		let last = self.keyframes.len() - 1;
+
+		// Find the keyframes before and after the time
+		let next = self.keyframes.iter().position(|keyframe| keyframe.time > time).unwrap_or(last);
+		if next == 0 || time >= self.keyframes[last].time
+		{
+			let keyframe = if next == 0 { &self.keyframes[0] } else { &self.keyframes[last] };
+			return (keyframe.position, keyframe.rotation);
+		}
+		let current = next - 1;
+
+		let k1 = &self.keyframes[current];
+		let k2 = &self.keyframes[next];
+		let t = (time - k1.time) / (k2.time - k1.time);
+
+		// Duplicate the end points for the outer control points
+		let p0 = self.keyframes[current.saturating_sub(1)].position.to_vec();
+		let p1 = k1.position.to_vec();
+		let p2 = k2.position.to_vec();
+		let p3 = self.keyframes[(next + 1).min(last)].position.to_vec();
+
+		// Uniform Catmull-Rom
+		let t2 = t * t;
+		let t3 = t2 * t;
+		let position = (p1 * 2.0 + (p2 - p0) * t + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2 +
+			(p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3) * 0.5;
+
+		(Point3::from_vec(position), k1.rotation.slerp(k2.rotation, t))
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use cgmath::{Deg, Vector3};
+	use std::f32;
+
+	fn keyframes() -> Vec<Keyframe>
+	{
+		(0..4)
+			.map(|i| Keyframe {
+				time: i as f32 * 0.5,
+				position: Point3::new(i as f32, if i % 2 == 0 { 0.0 } else { 1.0 }, -2.0),
+				rotation: Quaternion::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), Deg(30.0 * i as f32)),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn sampling_at_a_keyframe_time_gives_the_keyframe()
+	{
+		let mut shuffled = keyframes();
+		shuffled.swap(0, 2);
+		let animation = Animation::new(shuffled, SplineEndMode::Stop).unwrap();
+		for keyframe in keyframes().iter()
+		{
+			let (position, rotation) = animation.sample(keyframe.time);
+			assert!((position - keyframe.position).magnitude() < 1e-5);
+			assert!(rotation.dot(keyframe.rotation).abs() > 1.0 - 1e-5);
+		}
+	}
+
+	#[test]
+	fn invalid_keyframes_are_rejected()
+	{
+		assert!(Animation::new(Vec::new(), SplineEndMode::Loop).is_err());
+		let mut nan_time = keyframes();
+		nan_time[1].time = f32::NAN;
+		assert!(Animation::new(nan_time, SplineEndMode::Loop).is_err());
+	}
+}
@@ -1,6 +1,30 @@
-use cgmath::{Deg, Euler, Matrix4, Point3, Quaternion, Vector3};
+use cgmath::{Deg, Euler, Matrix4, Point3, Quaternion, Rad, Vector3};
 use cgmath::prelude::*;
+use nurbs::NURBSpline;
 use object::{Position, Rotation};
+use std::f32;
+
+/// What to do when a camera following a spline reaches the end of it.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SplineEndMode
+{
+	Stop,
+	Loop,
+	PingPong,
+}
+
+/// State for a camera travelling along a spline.
+struct SplinePath
+{
+	spline: NURBSpline,
+	// Progress along the spline in [0, 1]
+	progress: f64,
+	// Spline parameter units per second
+	speed: f64,
+	ease: bool,
+	end_mode: SplineEndMode,
+	forward: bool,
+}
 
 pub struct Camera
 {
@@ -10,11 +34,14 @@ pub struct Camera
 	right: Vector3<f32>,
 	up: Vector3<f32>,
 	world_up: Vector3<f32>,
+	// Rotation around the front vector, applied on top of yaw and pitch
+	roll: Rad<f32>,
+	spline_path: Option<SplinePath>,
 }
 
 impl Camera
 {
-	/// Updates the front, right and up-vectors based on the camera's pitch and yaw.
+	/// Updates the front, right and up-vectors based on the camera's pitch, yaw and roll.
 	fn update(&mut self)
 	{
 		self.front = self.get_front_vector();
@@ -22,6 +49,13 @@ impl Camera
 		self.right.normalize();
 		self.up = self.right.cross(self.front);
 		self.up.normalize();
+
+		if self.roll != Rad(0.0)
+		{
+			let roll = Quaternion::from_axis_angle(self.front, self.roll);
+			self.right = roll.rotate_vector(self.right);
+			self.up = roll.rotate_vector(self.up);
+		}
 	}
 
 	/// Creates a new Camera struct
@@ -52,6 +86,8 @@ impl Camera
 				y: 1.0,
 				z: 0.0,
 			},
+			roll: Rad(0.0),
+			spline_path: None,
 		};
 		camera.update();
 		camera
@@ -89,6 +125,152 @@ impl Camera
 		self.update();
 	}
 
+	/// Rolls the camera around its front vector, positive angles turn the up vector clockwise as seen
+	/// through the camera.
+	///
+	/// Yaw still turns around the world up vector and pitch around the unrolled right vector, so
+	/// rolling only tilts the view.
+	pub fn roll(&mut self, angle: Rad<f32>)
+	{
+		self.roll = Rad((self.roll + angle).0 % (2.0 * f32::consts::PI));
+		self.update();
+	}
+
+	/// Sets the absolute roll of the camera.
+	pub fn set_roll(&mut self, roll: Rad<f32>)
+	{
+		self.roll = roll;
+		self.update();
+	}
+
+	/// Returns the current roll of the camera.
+	#[allow(dead_code)]
+	pub fn get_roll(&self) -> Rad<f32>
+	{
+		self.roll
+	}
+
+	/// Rotates the camera to look along the given direction.
+	pub fn face_direction(&mut self, direction: Vector3<f32>)
+	{
+		if direction.magnitude2() == 0.0
+		{
+			return;
+		}
+		let direction = direction.normalize();
+		// Inverse of get_front_vector()
+		let yaw = direction.z.atan2(direction.x);
+		let pitch = direction.y.max(-1.0).min(1.0).asin();
+		self.rotation = Quaternion::from(Euler::new(Rad(yaw), Rad(pitch), Rad(0.0)));
+		self.update();
+	}
+
+	/// Sets the absolute yaw and pitch of the camera.
+	pub fn set_orientation(&mut self, yaw: Deg<f32>, pitch: Deg<f32>)
+	{
+		self.rotation = Quaternion::from(Euler::new(yaw, pitch, Deg(0.0)));
+		self.update();
+	}
+
+	/// Rotates the camera to look at the given point.
+	pub fn look_at(&mut self, target: Point3<f32>)
+	{
+		let direction = target - self.position;
+		self.face_direction(direction);
+	}
+
+	/// Makes the camera travel along the spline, facing along its tangent.
+	///
+	/// Speed is given in spline parameter units per second. If ease is set, the camera eases in and
+	/// out at the endpoints of the spline.
+	pub fn follow_spline(&mut self, spline: NURBSpline, speed: f64, ease: bool, end_mode: SplineEndMode)
+	{
+		self.spline_path = Some(SplinePath {
+			spline: spline,
+			progress: 0.0,
+			speed: speed,
+			ease: ease,
+			end_mode: end_mode,
+			forward: true,
+		});
+		self.advance_spline(0.0);
+	}
+
+	/// Stops following the current spline, leaving the camera where it is.
+	pub fn stop_following_spline(&mut self)
+	{
+		self.spline_path = None;
+	}
+
+	/// Returns true if the camera is currently following a spline.
+	pub fn is_following_spline(&self) -> bool
+	{
+		self.spline_path.is_some()
+	}
+
+	/// Moves the camera along its spline by the given time in seconds.
+	///
+	/// Does nothing if the camera is not following a spline.
+	pub fn advance_spline(&mut self, time: f64)
+	{
+		let (position, tangent, finished) = match self.spline_path
+		{
+			Some(ref mut path) =>
+			{
+				let limit = path.spline.eval_limit();
+				let step = path.speed * time / limit;
+				if path.forward
+				{
+					path.progress += step;
+				}
+				else
+				{
+					path.progress -= step;
+				}
+
+				let mut finished = false;
+				if path.progress >= 1.0 || path.progress <= 0.0
+				{
+					match path.end_mode
+					{
+						SplineEndMode::Stop => finished = true,
+						SplineEndMode::Loop => path.progress -= path.progress.floor(),
+						SplineEndMode::PingPong => path.forward = !path.forward,
+					}
+					path.progress = path.progress.max(0.0).min(1.0);
+				}
+
+				// Smoothstep the progress for easing in and out
+				let t = path.progress;
+				let t = if path.ease
+				{
+					t * t * (3.0 - 2.0 * t)
+				}
+				else
+				{
+					t
+				};
+				// The spline can't be evaluated at its limit
+				let u = (t * limit).min(limit - 1e-6);
+
+				let mut tangent = path.spline.derivative_at(u);
+				if !path.forward
+				{
+					tangent = -tangent;
+				}
+				(path.spline.evaluate_at(u), tangent, finished)
+			}
+			None => return,
+		};
+
+		self.position = Point3::new(position.x as f32, position.y as f32, position.z as f32);
+		self.face_direction(Vector3::new(tangent.x as f32, tangent.y as f32, tangent.z as f32));
+		if finished
+		{
+			self.spline_path = None;
+		}
+	}
+
 	pub fn generate_view_matrix(&self) -> Matrix4<f32>
 	{
 		Matrix4::look_at_dir(self.position, self.front, self.up)
@@ -120,3 +302,32 @@ impl Rotation for Camera
 		self.rotation = rotation;
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn assert_close(actual: Vector3<f32>, expected: Vector3<f32>)
+	{
+		assert!((actual - expected).magnitude() < 1e-5, "{:?} != {:?}", actual, expected);
+	}
+
+	#[test]
+	fn rolling_90_degrees_turns_the_up_vector_sideways()
+	{
+		let mut camera = Camera::new(Point3::new(0.0, 0.0, 0.0));
+		let (front, right, up) = (camera.front, camera.right, camera.up);
+		assert_close(up, camera.get_world_up_vector());
+
+		// Clockwise as seen through the camera, so up turns to where right was
+		camera.roll(Rad(f32::consts::FRAC_PI_2));
+		assert_close(camera.front, front);
+		assert_close(camera.up, right);
+		assert_close(camera.right, -up);
+		assert!(camera.up.dot(camera.get_world_up_vector()).abs() < 1e-5);
+
+		camera.roll(Rad(-f32::consts::FRAC_PI_2));
+		assert_close(camera.up, up);
+	}
+}
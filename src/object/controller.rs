@@ -0,0 +1,55 @@
+use cgmath::Vector3;
+use cgmath::prelude::*;
+use object::{Camera, Position};
+
+/// Velocities below this (in units per second) are snapped to zero to avoid endless drift.
+const MIN_VELOCITY: f32 = 0.01;
+
+/// Moves a camera with inertia: it accelerates towards the requested velocity and is damped to a
+/// halt when no movement is requested.
+pub struct CameraController
+{
+	velocity: Vector3<f32>,
+	// Rate (per second) at which the velocity approaches the target velocity
+	acceleration: f32,
+	// Rate (per second) at which the velocity decays without input
+	damping: f32,
+}
+
+impl CameraController
+{
+	/// Creates a new CameraController at rest.
+	pub fn new(acceleration: f32, damping: f32) -> CameraController
+	{
+		CameraController {
+			velocity: Vector3::zero(),
+			acceleration: acceleration,
+			damping: damping,
+		}
+	}
+
+	/// Updates the velocity towards the target velocity and moves the camera.
+	///
+	/// The target velocity is in units per second and time is in seconds. Exponential smoothing is
+	/// used, so the result is independent of the update rate.
+	pub fn update(&mut self, camera: &mut Camera, target_velocity: Vector3<f32>, time: f32)
+	{
+		if target_velocity.magnitude2() > 0.0
+		{
+			let blend = 1.0 - (-self.acceleration * time).exp();
+			self.velocity += (target_velocity - self.velocity) * blend;
+		}
+		else
+		{
+			self.velocity *= (-self.damping * time).exp();
+		}
+
+		if self.velocity.magnitude() < MIN_VELOCITY
+		{
+			self.velocity = Vector3::zero();
+			return;
+		}
+
+		camera.translate(self.velocity * time);
+	}
+}
@@ -2,36 +2,259 @@ use ash::Device;
 use ash::version::{DeviceV1_0, V1_0};
 use ash::vk;
 use cgmath::{Deg, Matrix4, Point3, Quaternion, Rotation3, Vector3};
-use object::{Drawable, Position, Rotation};
-use renderer::{MainPass, RenderState, Texture};
-use std::{mem, ptr, slice, f32};
+use object::{Drawable, Material, Position, Rotation};
+use object::mesh::{Mesh, Vertex};
+use renderer::{AlphaMode, ColorSpace, DepthBias, DrawCommand, MainPass, PipelineKind, RenderState, Texture};
+use std::{ptr, f32};
 use std::rc::Rc;
 
-#[derive(Clone, Copy)]
-#[allow(dead_code)] // not going to change vertices after creation
-pub struct Vertex
+/// Number of simplified levels generated for detailed meshes.
+const LOD_LEVELS: usize = 3;
+/// Meshes with fewer triangles than this aren't worth simplifying.
+const LOD_MIN_TRIANGLES: usize = 256;
+/// Distance from the camera at which each following LOD level is used.
+const LOD_DISTANCE_STEP: f32 = 15.0;
+/// Most segments, rings or cells of a generated sphere or plane, so its vertices fit in 16 bit indices.
+const MAX_TESSELLATION: u16 = 254;
+
+/// Textures of the generated meshes.
+const METAL_PANEL_COLOR: &'static str = "assets/thirdparty/textures/Metal_Panel_004/Metal_Panel_004_COLOR.jpg";
+const METAL_PANEL_NORMAL: &'static str = "assets/thirdparty/textures/Metal_Panel_004/Metal_Panel_004_NORM.jpg";
+const CUBE_COLOR: &'static str = "assets/original/textures/cubemap.png";
+const CUBE_NORMAL: &'static str = "assets/original/textures/cubemap_normals.png";
+const FRONT_NORMAL: &'static str = "assets/original/textures/front_normal.png";
+const PLACEHOLDER: &'static str = "assets/original/textures/purple.png";
+
+/// How the mesh of a DrawObject was generated, so it can be saved and created again.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MeshSource
 {
-	pos: [f32; 3],
-	normal: [f32; 3],
-	tangent: [f32; 3],
-	bitangent: [f32; 3],
-	tex_uv: [f32; 2],
+	Cuboid
+	{
+		width: f32,
+		height: f32,
+		depth: f32,
+	},
+	Plane
+	{
+		width: f32,
+		height: f32,
+		cells: u16,
+		strip: bool,
+	},
+	Quad
+	{
+		width: f32,
+		height: f32,
+	},
+	TransparentQuad
+	{
+		width: f32,
+		height: f32,
+		texture: String,
+		alpha_mode: AlphaMode,
+	},
+	ColoredTriangle
+	{
+		size: f32,
+	},
+	Sphere
+	{
+		radius: f32,
+		segments: u16,
+		rings: u16,
+	},
+	Lines
+	{
+		points: Vec<[f32; 3]>,
+		indices: Vec<u16>,
+		color: [f32; 4],
+	},
+	Polygon
+	{
+		outline: Vec<[f32; 2]>,
+		color: [f32; 4],
+	},
+	AxisGizmo,
+	/// Built from a mesh passed in directly, which can't be created again.
+	Custom,
 }
 
-pub struct DrawObject
+impl MeshSource
+{
+	/// Returns the textures the object loads, in the order they are loaded.
+	pub fn texture_paths(&self) -> Vec<&str>
+	{
+		match *self
+		{
+			MeshSource::Cuboid { .. } => vec![CUBE_COLOR, CUBE_NORMAL],
+			MeshSource::Plane { .. } | MeshSource::Quad { .. } | MeshSource::Sphere { .. } =>
+			{
+				vec![METAL_PANEL_COLOR, METAL_PANEL_NORMAL]
+			}
+			MeshSource::TransparentQuad { ref texture, .. } => vec![texture, FRONT_NORMAL],
+			MeshSource::ColoredTriangle { .. }
+			| MeshSource::Lines { .. }
+			| MeshSource::Polygon { .. }
+			| MeshSource::AxisGizmo => vec![PLACEHOLDER, PLACEHOLDER],
+			MeshSource::Custom => Vec::new(),
+		}
+	}
+
+	/// Checks that the sphere or plane fits in 16 bit indices and has enough segments and rings or
+	/// cells to be a closed or flat surface. Saved scenes can contain any values.
+	pub fn validate(&self) -> Result<(), String>
+	{
+		match *self
+		{
+			MeshSource::Plane { cells, .. } if cells < 1 || cells > MAX_TESSELLATION =>
+			{
+				Err(format!("A plane needs 1 to {} cells, not {}", MAX_TESSELLATION, cells))
+			}
+			MeshSource::Sphere { segments, rings, .. }
+				if segments < 3 || rings < 2 || segments > MAX_TESSELLATION || rings > MAX_TESSELLATION =>
+			{
+				Err(format!(
+					"A sphere needs 3 to {} segments and 2 to {} rings, not {} and {}",
+					MAX_TESSELLATION, MAX_TESSELLATION, segments, rings
+				))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// Returns the sphere or plane with its segments and rings or its cells scaled by the factor, or None
+	/// for the other meshes, which have a fixed tessellation.
+	pub fn tessellated(&self, factor: f32) -> Option<MeshSource>
+	{
+		// Clamped before converting, casting an out of range float to an integer isn't defined
+		let scale = |count: u16, min: u16| {
+			(count as f32 * factor).round().max(min as f32).min(MAX_TESSELLATION as f32) as u16
+		};
+		match *self
+		{
+			MeshSource::Plane { width, height, cells, strip } => Some(MeshSource::Plane {
+				width: width,
+				height: height,
+				cells: scale(cells, 1),
+				strip: strip,
+			}),
+			MeshSource::Sphere { radius, segments, rings } => Some(MeshSource::Sphere {
+				radius: radius,
+				segments: scale(segments, 3),
+				rings: scale(rings, 2),
+			}),
+			_ => None,
+		}
+	}
+}
+
+/// The GPU buffers of a mesh.
+struct MeshBuffers
 {
 	vertices: vk::Buffer,
 	vertex_mem: vk::DeviceMemory,
 	indices: vk::Buffer,
 	index_mem: vk::DeviceMemory,
 	num_indices: u32,
+	num_triangles: usize,
+}
+
+impl MeshBuffers
+{
+	/// Uploads the mesh to device local buffers.
+	fn new(rs: &RenderState, mesh: &Mesh) -> MeshBuffers
+	{
+		// Create buffer for vertices
+		let (vert_buffer, vert_mem) = rs.create_buffer_and_upload(
+			vk::BUFFER_USAGE_VERTEX_BUFFER_BIT,
+			vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT,
+			&mesh.vertices,
+			true,
+		);
+
+		// Create buffer for indices
+		let (idx_buffer, idx_mem) = rs.create_buffer_and_upload(
+			vk::BUFFER_USAGE_INDEX_BUFFER_BIT,
+			vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT,
+			&mesh.indices,
+			true,
+		);
+
+		MeshBuffers {
+			vertices: vert_buffer,
+			vertex_mem: vert_mem,
+			indices: idx_buffer,
+			index_mem: idx_mem,
+			num_indices: mesh.indices.len() as u32,
+			num_triangles: mesh.triangle_count(),
+		}
+	}
+
+	/// Uploads the mesh, followed by its simplified LOD levels if it is a detailed triangle list and
+	/// simplify is set.
+	fn new_with_lods(rs: &RenderState, mesh: &Mesh, simplify: bool) -> Vec<MeshBuffers>
+	{
+		let mut lods = vec![MeshBuffers::new(rs, mesh)];
+		let is_list = mesh.topology() == vk::PrimitiveTopology::TriangleList;
+		if simplify && is_list && mesh.triangle_count() >= LOD_MIN_TRIANGLES
+		{
+			for lod in mesh.generate_lods(LOD_LEVELS).iter()
+			{
+				lods.push(MeshBuffers::new(rs, lod));
+			}
+		}
+		lods
+	}
+
+	/// Frees the buffers, which must not be in use by the GPU.
+	fn destroy(&self, device: &Device<V1_0>)
+	{
+		unsafe {
+			device.destroy_buffer(self.indices, None);
+			device.free_memory(self.index_mem, None);
+			device.destroy_buffer(self.vertices, None);
+			device.free_memory(self.vertex_mem, None);
+		}
+	}
+}
+
+pub struct DrawObject
+{
+	// The full detail mesh followed by progressively simplified ones
+	lods: Vec<MeshBuffers>,
+	// Bounding box drawn instead of the mesh while occluded, if occlusion culling is enabled
+	occlusion_proxy: Option<MeshBuffers>,
 
 	position: Point3<f32>,
 	rotation: Quaternion<f32>,
+	// Transform at the previous simulation step, used for interpolation
+	previous_position: Point3<f32>,
+	previous_rotation: Quaternion<f32>,
 
+	descriptor_pool: vk::DescriptorPool,
 	descriptor_sets: Vec<vk::DescriptorSet>,
-	texture: Texture,
-	normal_map: Texture,
+	// None if loading failed and the shared fallback from the RenderState is used instead
+	texture: Option<Texture>,
+	normal_map: Option<Texture>,
+	// Loaded from the material, the shared white texture is bound without one
+	specular_map: Option<Texture>,
+	// Set to draw with the flat normal map instead of the loaded one
+	flat_normal_map: bool,
+	material: Material,
+	// The textures are placeholders unless this is PipelineKind::Textured
+	pipeline_kind: PipelineKind,
+	// Radius of a sphere around the position containing the whole mesh
+	bounding_radius: f32,
+	// Objects are drawn in order of ascending layer
+	layer: i32,
+	// Non-zero for decals, to draw them in front of the surface they lie on
+	depth_bias: DepthBias,
+	// False to leave the object out of the depth only draws for shadows
+	casts_shadow: bool,
+	// False to skip all draws of the object, while keeping its resources
+	visible: bool,
+	source: MeshSource,
 
 	// Keep a pointer to the device for cleanup
 	device: Rc<Device<V1_0>>,
@@ -39,38 +262,19 @@ pub struct DrawObject
 
 impl Drawable for DrawObject
 {
-	fn draw(
-		&self, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout, view_matrix: &Matrix4<f32>,
-		projection_matrix: &Matrix4<f32>,
-	)
+	fn get_model_matrix(&self, alpha: f32) -> Matrix4<f32>
 	{
-		let model_rotation_matrix = Matrix4::from(self.rotation);
-		let model_translation_matrix = Matrix4::from_translation(self.get_position() - Point3::new(0.0, 0.0, 0.0));
+		let position = self.previous_position + (self.position - self.previous_position) * alpha;
+		let rotation = self.previous_rotation.nlerp(self.rotation, alpha);
+		let model_rotation_matrix = Matrix4::from(rotation);
+		let model_translation_matrix = Matrix4::from_translation(position - Point3::new(0.0, 0.0, 0.0));
 		// The order of multiplication here is important!
-		let model_matrix = model_translation_matrix * model_rotation_matrix;
-		let mv_matrix = view_matrix * model_matrix;
-		let mvp_matrix = projection_matrix * mv_matrix;
-		let matrices = [model_matrix, mvp_matrix];
-
-		let matrices_bytes;
-		unsafe {
-			matrices_bytes = slice::from_raw_parts(matrices.as_ptr() as *const u32, mem::size_of_val(&matrices));
-		}
+		model_translation_matrix * model_rotation_matrix
+	}
 
-		unsafe {
-			self.device.cmd_push_constants(cmd_buf, pipeline_layout, vk::SHADER_STAGE_VERTEX_BIT, 0, matrices_bytes);
-			self.device.cmd_bind_descriptor_sets(
-				cmd_buf,
-				vk::PipelineBindPoint::Graphics,
-				pipeline_layout,
-				0,
-				&self.descriptor_sets[..],
-				&[],
-			);
-			self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[self.vertices], &[0]);
-			self.device.cmd_bind_index_buffer(cmd_buf, self.indices, 0, vk::IndexType::Uint16);
-			self.device.cmd_draw_indexed(cmd_buf, self.num_indices, 1, 0, 0, 1);
-		}
+	fn draw(&self, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout)
+	{
+		self.draw_lod(cmd_buf, pipeline_layout, 0);
 	}
 }
 
@@ -102,100 +306,688 @@ impl Rotation for DrawObject
 
 impl DrawObject
 {
-	fn new(
-		rs: &RenderState, mp: &MainPass, position: Point3<f32>, vertices: &[Vertex], indices: &[u16],
-		texture_path: &str, normalmap_path: &str,
-	) -> DrawObject
+	/// Stores the current transform as the previous one.
+	///
+	/// Must be called before each simulation step so draw() can interpolate between the steps.
+	pub fn store_previous_transform(&mut self)
 	{
-		// Create buffer for vertices
-		let (vert_buffer, vert_mem) = rs.create_buffer_and_upload(
-			vk::BUFFER_USAGE_VERTEX_BUFFER_BIT,
-			vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT,
-			&vertices,
-			true,
-		);
+		self.previous_position = self.position;
+		self.previous_rotation = self.rotation;
+	}
 
-		// Create buffer for indices
-		let (idx_buffer, idx_mem) = rs.create_buffer_and_upload(
-			vk::BUFFER_USAGE_INDEX_BUFFER_BIT,
-			vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT,
-			&indices,
-			true,
-		);
+	/// Rotates the object globally between simulation steps, turning the previous transform along so
+	/// draw() doesn't interpolate the turn away.
+	pub fn turn(&mut self, rotation: Quaternion<f32>)
+	{
+		self.globally_rotate(rotation);
+		self.previous_rotation = rotation * self.previous_rotation;
+	}
 
-		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
-			s_type: vk::StructureType::DescriptorSetAllocateInfo,
-			p_next: ptr::null(),
-			descriptor_pool: mp.descriptor_pool,
-			descriptor_set_count: 1,
-			p_set_layouts: &mp.descriptor_set_layouts[0],
+	pub fn material(&self) -> &Material
+	{
+		&self.material
+	}
+
+	/// Sets the material, recreating the samplers of the object's textures if their options changed, and
+	/// loading the specular map if it changed.
+	///
+	/// The descriptor set and the old specular map must not be in use by the GPU when either changes.
+	pub fn set_material(&mut self, rs: &RenderState, material: Material)
+	{
+		let mut textures_changed = false;
+		if material.sampler != self.material.sampler
+		{
+			let textures = self.texture.iter_mut().chain(self.normal_map.iter_mut());
+			for texture in textures.chain(self.specular_map.iter_mut())
+			{
+				rs.set_sampler_options(texture, &material.sampler);
+				textures_changed = true;
+			}
+		}
+		if material.specular_map != self.material.specular_map
+		{
+			if let Some(old) = self.specular_map.take()
+			{
+				unsafe {
+					rs.device.destroy_sampler(old.sampler, None);
+					rs.device.destroy_image_view(old.view, None);
+					rs.device.destroy_image(old.image, None);
+					rs.device.free_memory(old.memory, None);
+				}
+			}
+			self.specular_map = material.specular_map.as_ref().and_then(|path| {
+				rs.load_image(path, ColorSpace::Linear, false)
+					.map_err(|e| warning!("{}, using the material's specular color and shininess", e))
+					.ok()
+			});
+			if let Some(ref mut specular_map) = self.specular_map
+			{
+				rs.set_sampler_options(specular_map, &material.sampler);
+			}
+			textures_changed = true;
+		}
+		if textures_changed
+		{
+			self.write_texture_descriptors(rs);
+		}
+		self.material = material;
+	}
+
+	/// Returns the pipeline the object must be drawn with.
+	pub fn pipeline_kind(&self) -> PipelineKind
+	{
+		self.pipeline_kind
+	}
+
+	/// Makes the object draw on top of everything, e.g. for debug gizmos.
+	///
+	/// Overlays are drawn with their vertex colors.
+	pub fn set_overlay(&mut self)
+	{
+		self.pipeline_kind = PipelineKind::Overlay;
+	}
+
+	/// Returns the render layer, objects in lower layers are drawn first.
+	pub fn layer(&self) -> i32
+	{
+		self.layer
+	}
+
+	/// Sets the render layer. All objects start in layer 0.
+	pub fn set_layer(&mut self, layer: i32)
+	{
+		self.layer = layer;
+	}
+
+	/// Returns true if the object is drawn into shadow maps. All objects start casting shadows.
+	pub fn casts_shadow(&self) -> bool
+	{
+		self.casts_shadow
+	}
+
+	/// Sets whether the object is drawn into shadow maps, it is drawn in the main pass either way.
+	pub fn set_casts_shadow(&mut self, casts_shadow: bool)
+	{
+		self.casts_shadow = casts_shadow;
+	}
+
+	/// Returns false if the object is hidden. All objects start visible.
+	pub fn is_visible(&self) -> bool
+	{
+		self.visible
+	}
+
+	/// Sets whether the object is drawn at all, in the main pass as well as into shadow maps.
+	pub fn set_visible(&mut self, visible: bool)
+	{
+		self.visible = visible;
+	}
+
+	/// Returns the depth bias the object is drawn with.
+	pub fn depth_bias(&self) -> DepthBias
+	{
+		self.depth_bias
+	}
+
+	/// Sets the depth bias, e.g. to turn the object into a decal that doesn't z-fight with the surface
+	/// it lies on. Only the pipelines for the scene's objects support it, not the depth only one.
+	pub fn set_depth_bias(&mut self, bias: DepthBias)
+	{
+		self.depth_bias = bias;
+	}
+
+	/// Returns how the mesh was generated.
+	pub fn source(&self) -> &MeshSource
+	{
+		&self.source
+	}
+
+	/// Creates the object described by the mesh source again.
+	///
+	/// Fails for MeshSource::Custom, whose mesh isn't known, and for invalid sources, see
+	/// MeshSource::validate().
+	pub fn from_source(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, source: &MeshSource
+	) -> Result<DrawObject, String>
+	{
+		source.validate()?;
+		match *source
+		{
+			MeshSource::Cuboid { width, height, depth } =>
+			{
+				DrawObject::new_cuboid(rs, mp, position, width, height, depth)
+			}
+			MeshSource::Plane { width, height, cells, strip } =>
+			{
+				DrawObject::new_plane(rs, mp, position, width, height, cells, strip)
+			}
+			MeshSource::Quad { width, height } => DrawObject::new_quad(rs, mp, position, width, height),
+			MeshSource::TransparentQuad { width, height, ref texture, alpha_mode } =>
+			{
+				DrawObject::new_transparent_quad(rs, mp, position, width, height, texture, alpha_mode)
+			}
+			MeshSource::ColoredTriangle { size } => DrawObject::new_colored_triangle(rs, mp, position, size),
+			MeshSource::Sphere { radius, segments, rings } =>
+			{
+				DrawObject::new_sphere(rs, mp, position, radius, segments, rings)
+			}
+			MeshSource::Lines { ref points, ref indices, color } =>
+			{
+				let points: Vec<Point3<f32>> = points.iter().map(|&point| Point3::from(point)).collect();
+				let mut object = DrawObject::new_lines(rs, mp, &points, indices.clone(), color)?;
+				object.set_position(position);
+				object.previous_position = position;
+				Ok(object)
+			}
+			MeshSource::Polygon { ref outline, color } => DrawObject::new_polygon(rs, mp, position, outline, color),
+			MeshSource::AxisGizmo => DrawObject::new_axis_gizmo(rs, mp),
+			MeshSource::Custom => Err(String::from("Objects with a custom mesh can't be created again")),
+		}
+	}
+
+	/// Generates the sphere or plane again with its tessellation scaled by the factor, see
+	/// MeshSource::tessellated(), and uploads the new mesh. Returns false for the other meshes.
+	///
+	/// The old buffers are freed, so they must not be in use by the GPU.
+	pub fn scale_tessellation(&mut self, rs: &RenderState, factor: f32) -> bool
+	{
+		let source = match self.source.tessellated(factor)
+		{
+			Some(source) => source,
+			None => return false,
 		};
-		let descriptor_sets;
-		unsafe {
-			descriptor_sets = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap();
+		let mut mesh = match source
+		{
+			MeshSource::Plane { width, height, cells, strip } => Mesh::plane(width, height, cells, strip),
+			MeshSource::Sphere { radius, segments, rings } => Mesh::sphere(radius, segments, rings),
+			_ => unreachable!(),
+		};
+		mesh.clean_up(&rs.mesh_cleanup);
+
+		let lods = MeshBuffers::new_with_lods(rs, &mesh, true);
+		for old in self.lods.iter()
+		{
+			old.destroy(&self.device);
 		}
+		self.lods = lods;
+		self.source = source;
+		true
+	}
+
+	/// Returns the radius of a sphere around the object's position containing the whole mesh.
+	pub fn bounding_radius(&self) -> f32
+	{
+		self.bounding_radius
+	}
 
-		let texture = rs.load_image(texture_path, true);
+	/// Binds the loaded normal map if enabled, otherwise the flat one, to compare the lighting with and
+	/// without normal mapping.
+	///
+	/// The descriptor set must not be in use by the GPU.
+	pub fn set_normal_map_enabled(&mut self, rs: &RenderState, enabled: bool)
+	{
+		self.flat_normal_map = !enabled;
+		self.write_texture_descriptors(rs);
+	}
+
+	/// Points the descriptor set at the textures, or the shared fallbacks for the missing ones.
+	fn write_texture_descriptors(&self, rs: &RenderState)
+	{
+		let texture = self.texture.as_ref().unwrap_or(rs.default_texture());
 		let texture_descriptor = vk::DescriptorImageInfo {
 			image_layout: texture.current_layout,
 			image_view: texture.view,
 			sampler: texture.sampler,
 		};
-
-		let normal_map = rs.load_image(normalmap_path, false);
+		let normal_map = match self.normal_map
+		{
+			Some(ref normal_map) if !self.flat_normal_map => normal_map,
+			_ => rs.flat_normal_texture(),
+		};
 		let normal_descriptor = vk::DescriptorImageInfo {
 			image_layout: normal_map.current_layout,
 			image_view: normal_map.view,
 			sampler: normal_map.sampler,
 		};
+		let specular_map = self.specular_map.as_ref().unwrap_or(rs.white_texture());
+		let specular_descriptor = vk::DescriptorImageInfo {
+			image_layout: specular_map.current_layout,
+			image_view: specular_map.view,
+			sampler: specular_map.sampler,
+		};
 
-		let write_desc_sets = [
-			vk::WriteDescriptorSet {
-				s_type: vk::StructureType::WriteDescriptorSet,
-				p_next: ptr::null(),
-				dst_set: descriptor_sets[0],
-				dst_binding: 0,
-				dst_array_element: 0,
-				descriptor_count: 1,
-				descriptor_type: vk::DescriptorType::CombinedImageSampler,
-				p_image_info: &texture_descriptor,
-				p_buffer_info: ptr::null(),
-				p_texel_buffer_view: ptr::null(),
-			},
-			vk::WriteDescriptorSet {
-				s_type: vk::StructureType::WriteDescriptorSet,
-				p_next: ptr::null(),
-				dst_set: descriptor_sets[0],
-				dst_binding: 1,
-				dst_array_element: 0,
-				descriptor_count: 1,
-				descriptor_type: vk::DescriptorType::CombinedImageSampler,
-				p_image_info: &normal_descriptor,
-				p_buffer_info: ptr::null(),
-				p_texel_buffer_view: ptr::null(),
-			},
-		];
+		let image_infos = [texture_descriptor, normal_descriptor, specular_descriptor];
+		let write_desc_sets = texture_descriptor_writes(self.descriptor_sets[0], &image_infos);
 		unsafe {
 			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
 		}
+	}
 
-		DrawObject {
-			vertices: vert_buffer,
-			vertex_mem: vert_mem,
-			indices: idx_buffer,
-			index_mem: idx_mem,
-			num_indices: indices.len() as u32,
+	/// Returns the LOD level to use at the given distance from the camera.
+	pub fn lod_for_distance(&self, distance: f32) -> usize
+	{
+		let level = (distance / LOD_DISTANCE_STEP) as usize;
+		level.min(self.lods.len() - 1)
+	}
+
+	/// Draws the given LOD level of the object.
+	///
+	/// The per-object uniforms must have been bound before calling this.
+	pub fn draw_lod(&self, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout, lod: usize)
+	{
+		let mesh = &self.lods[lod];
+		unsafe {
+			self.device.cmd_bind_descriptor_sets(
+				cmd_buf,
+				vk::PipelineBindPoint::Graphics,
+				pipeline_layout,
+				0,
+				&self.descriptor_sets[..],
+				&[],
+			);
+			self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[mesh.vertices], &[0]);
+			self.device.cmd_bind_index_buffer(cmd_buf, mesh.indices, 0, vk::IndexType::Uint16);
+			self.device.cmd_draw_indexed(cmd_buf, mesh.num_indices, 1, 0, 0, 1);
+		}
+	}
+
+	/// Returns the number of triangles drawn by draw_lod(), 0 for lines.
+	pub fn triangle_count(&self, lod: usize) -> usize
+	{
+		self.lods[lod].num_triangles
+	}
+
+	/// Returns the number of triangles drawn by draw_occlusion_proxy().
+	pub fn occlusion_proxy_triangle_count(&self) -> usize
+	{
+		self.occlusion_proxy.as_ref().map_or(0, |proxy| proxy.num_triangles)
+	}
+
+	/// Draws the bounding box of the object, with the occlusion proxy pipeline bound.
+	///
+	/// Does nothing if the object has no proxy because occlusion culling is disabled.
+	pub fn draw_occlusion_proxy(&self, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout)
+	{
+		if let Some(ref proxy) = self.occlusion_proxy
+		{
+			unsafe {
+				self.device.cmd_bind_descriptor_sets(
+					cmd_buf,
+					vk::PipelineBindPoint::Graphics,
+					pipeline_layout,
+					0,
+					&self.descriptor_sets[..],
+					&[],
+				);
+				self.device.cmd_bind_vertex_buffers(cmd_buf, 0, &[proxy.vertices], &[0]);
+				self.device.cmd_bind_index_buffer(cmd_buf, proxy.indices, 0, vk::IndexType::Uint16);
+				self.device.cmd_draw_indexed(cmd_buf, proxy.num_indices, 1, 0, 0, 1);
+			}
+		}
+	}
+
+	/// Returns the draw of the given LOD level as a DrawCommand, for recording on another thread.
+	///
+	/// The object index selects the per-object uniforms.
+	pub fn draw_command(&self, lod: usize, object_index: usize) -> DrawCommand
+	{
+		let mesh = &self.lods[lod];
+		DrawCommand {
+			descriptor_set: self.descriptor_sets[0],
+			vertices: mesh.vertices,
+			indices: mesh.indices,
+			num_indices: mesh.num_indices,
+			object_index: object_index,
+			pipeline_kind: self.pipeline_kind,
+			depth_bias: self.depth_bias,
+		}
+	}
+
+	fn new(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, mesh: &Mesh, texture_path: &str,
+		normalmap_path: &str,
+	) -> Result<DrawObject, String>
+	{
+		DrawObject::new_with_alpha(rs, mp, position, mesh, texture_path, normalmap_path, AlphaMode::Opaque, true)
+	}
+
+	/// Creates a textured draw object, blended according to the alpha mode of the color texture.
+	///
+	/// Detailed triangle lists get simplified LOD levels if simplify is set. Fails if the descriptor pool
+	/// of the MainPass has no room for another object.
+	fn new_with_alpha(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, mesh: &Mesh, texture_path: &str,
+		normalmap_path: &str, alpha_mode: AlphaMode, simplify: bool,
+	) -> Result<DrawObject, String>
+	{
+		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
+			s_type: vk::StructureType::DescriptorSetAllocateInfo,
+			p_next: ptr::null(),
+			descriptor_pool: mp.descriptor_pool,
+			descriptor_set_count: 1,
+			p_set_layouts: &mp.descriptor_set_layouts[0],
+		};
+		let descriptor_sets;
+		unsafe {
+			descriptor_sets = rs.device
+				.allocate_descriptor_sets(&desc_alloc_info)
+				.map_err(|e| format!("Failed to allocate the descriptor set of an object: {:?}", e))?;
+		}
+		let mut mesh = mesh.clone();
+		mesh.clean_up(&rs.mesh_cleanup);
+		let mesh = &mesh;
+		let lods = MeshBuffers::new_with_lods(rs, mesh, simplify);
+
+		// The blended pipelines only draw triangle lists
+		debug_assert!(alpha_mode == AlphaMode::Opaque || mesh.topology() == vk::PrimitiveTopology::TriangleList);
+		let texture = rs
+			.load_image(texture_path, ColorSpace::Srgb, alpha_mode == AlphaMode::Premultiplied)
+			.map_err(|e| warning!("{}, using the default texture", e))
+			.ok();
+
+		let normal_map = rs
+			.load_image(normalmap_path, ColorSpace::Linear, false)
+			.map_err(|e| warning!("{}, using a flat normal map", e))
+			.ok();
+		let rotation = Quaternion::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), Deg(0.0));
+		let (min, max) = mesh.bounds();
+		let bounding_radius = mesh.bounding_radius();
+		let occlusion_proxy = if mp.occlusion_enabled()
+		{
+			Some(MeshBuffers::new(rs, &Mesh::bounding_box(min, max)))
+		}
+		else
+		{
+			None
+		};
+
+		let object = DrawObject {
+			lods: lods,
+			occlusion_proxy: occlusion_proxy,
 			position: position,
-			rotation: Quaternion::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), Deg(0.0)),
+			rotation: rotation,
+			previous_position: position,
+			previous_rotation: rotation,
+			descriptor_pool: mp.descriptor_pool,
 			descriptor_sets: descriptor_sets,
 			texture: texture,
 			normal_map: normal_map,
+			specular_map: None,
+			flat_normal_map: false,
+			material: Material::default(),
+			pipeline_kind: match alpha_mode
+			{
+				AlphaMode::Straight => PipelineKind::Blended,
+				AlphaMode::Premultiplied => PipelineKind::BlendedPremultiplied,
+				AlphaMode::Opaque if mesh.topology() == vk::PrimitiveTopology::TriangleStrip =>
+				{
+					PipelineKind::TexturedStrip
+				}
+				AlphaMode::Opaque => PipelineKind::Textured,
+			},
+			bounding_radius: bounding_radius,
+			layer: 0,
+			depth_bias: DepthBias::default(),
+			casts_shadow: true,
+			visible: true,
+			source: MeshSource::Custom,
 			device: Rc::clone(&rs.device),
+		};
+		object.write_texture_descriptors(rs);
+		Ok(object)
+	}
+
+	/// Creates a new draw object colored by its vertex colors.
+	///
+	/// Triangle fans are converted to a triangle list if the device can't draw them, and line lists to
+	/// quads if the MainPass draws lines as quads.
+	pub fn new_vertex_colored(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, mesh: &Mesh
+	) -> Result<DrawObject, String>
+	{
+		debug_assert!(mesh.topology() != vk::PrimitiveTopology::TriangleStrip);
+		let pipeline_kind = match mesh.topology()
+		{
+			vk::PrimitiveTopology::LineList => PipelineKind::Line,
+			vk::PrimitiveTopology::TriangleFan if mp.supports_triangle_fans() => PipelineKind::VertexColorFan,
+			_ => PipelineKind::VertexColor,
+		};
+		let line_quads = mesh.topology() == vk::PrimitiveTopology::LineList && mp.draws_line_quads();
+		let converted;
+		let mesh = match mesh.topology()
+		{
+			vk::PrimitiveTopology::TriangleFan if !mp.supports_triangle_fans() =>
+			{
+				converted = mesh.fan_to_list();
+				&converted
+			}
+			vk::PrimitiveTopology::LineList if line_quads =>
+			{
+				converted = mesh.line_quads();
+				&converted
+			}
+			_ => mesh,
+		};
+		// The descriptor set must still be valid, so bind a tiny texture that is never sampled. Simplifying
+		// the line quads would tear the lines apart.
+		let mut object = DrawObject::new_with_alpha(
+			rs,
+			mp,
+			position,
+			mesh,
+			PLACEHOLDER,
+			PLACEHOLDER,
+			AlphaMode::Opaque,
+			!line_quads,
+		)?;
+		object.pipeline_kind = pipeline_kind;
+		Ok(object)
+	}
+
+	/// Creates a convex polygon in the xy-plane facing +z, filled in a single color with a triangle fan.
+	///
+	/// The outline must be counter-clockwise.
+	pub fn new_polygon(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, outline: &[[f32; 2]], color: [f32; 4]
+	) -> Result<DrawObject, String>
+	{
+		let mesh = Mesh::polygon_fan(outline, color);
+		let mut object = DrawObject::new_vertex_colored(rs, mp, position, &mesh)?;
+		object.source = MeshSource::Polygon {
+			outline: outline.to_vec(),
+			color: color,
+		};
+		Ok(object)
+	}
+
+	/// Creates a new line list draw object, in a single color. Each pair of indices is a line between
+	/// two of the points.
+	pub fn new_lines(
+		rs: &RenderState, mp: &MainPass, points: &[Point3<f32>], indices: Vec<u16>, color: [f32; 4]
+	) -> Result<DrawObject, String>
+	{
+		let vertices = points
+			.iter()
+			.map(|point| Vertex {
+				pos: [point.x, point.y, point.z],
+				normal: [0.0, 0.0, 1.0],
+				tangent: [1.0, 0.0, 0.0],
+				bitangent: [0.0, 1.0, 0.0],
+				tex_uv: [0.0, 0.0],
+				color: color,
+			})
+			.collect();
+		let source = MeshSource::Lines {
+			points: points.iter().map(|&point| point.into()).collect(),
+			indices: indices.clone(),
+			color: color,
+		};
+		let mesh = Mesh::new_lines(vertices, indices);
+
+		let mut object = DrawObject::new_vertex_colored(rs, mp, Point3::new(0.0, 0.0, 0.0), &mesh)?;
+		object.source = source;
+		Ok(object)
+	}
+
+	/// Creates the outline of a sphere with a radius of one, as three orthogonal circles.
+	pub fn new_sphere_outline(
+		rs: &RenderState, mp: &MainPass, segments: u16, color: [f32; 4]
+	) -> Result<DrawObject, String>
+	{
+		let mut points = Vec::with_capacity(segments as usize * 3);
+		let mut indices = Vec::with_capacity(segments as usize * 6);
+		for axis in 0..3
+		{
+			let first = points.len() as u16;
+			for segment in 0..segments
+			{
+				let angle = segment as f32 / segments as f32 * 2.0 * f32::consts::PI;
+				let (sin, cos) = angle.sin_cos();
+				points.push(match axis
+				{
+					0 => Point3::new(0.0, cos, sin),
+					1 => Point3::new(cos, 0.0, sin),
+					_ => Point3::new(cos, sin, 0.0),
+				});
+				indices.push(first + segment);
+				indices.push(first + (segment + 1) % segments);
+			}
 		}
+
+		DrawObject::new_lines(rs, mp, &points, indices, color)
 	}
 
-	/// Creates a new quad draw object.
-	pub fn new_quad(rs: &RenderState, mp: &MainPass, position: Point3<f32>, width: f32, height: f32) -> DrawObject
+	/// Creates the lines of an axis gizmo, one unit long along X (red), Y (green) and Z (blue).
+	pub fn new_axis_gizmo(rs: &RenderState, mp: &MainPass) -> Result<DrawObject, String>
+	{
+		let axes = [
+			([1.0, 0.0, 0.0], [1.0, 0.0, 0.0, 1.0]),
+			([0.0, 1.0, 0.0], [0.0, 1.0, 0.0, 1.0]),
+			([0.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]),
+		];
+		let mut vertices = Vec::with_capacity(axes.len() * 2);
+		for &(end, color) in axes.iter()
+		{
+			for &pos in [[0.0, 0.0, 0.0], end].iter()
+			{
+				vertices.push(Vertex {
+					pos: pos,
+					normal: [0.0, 0.0, 1.0],
+					tangent: [1.0, 0.0, 0.0],
+					bitangent: [0.0, 1.0, 0.0],
+					tex_uv: [0.0, 0.0],
+					color: color,
+				});
+			}
+		}
+		let mesh = Mesh::new_lines(vertices, (0..axes.len() as u16 * 2).collect());
+
+		let mut object = DrawObject::new_vertex_colored(rs, mp, Point3::new(0.0, 0.0, 0.0), &mesh)?;
+		object.source = MeshSource::AxisGizmo;
+		Ok(object)
+	}
+
+	/// Creates a new line strip draw object through the given points, in a single color.
+	pub fn new_line_strip(
+		rs: &RenderState, mp: &MainPass, points: &[Point3<f32>], color: [f32; 4]
+	) -> Result<DrawObject, String>
+	{
+		let mut indices = Vec::with_capacity(points.len().saturating_sub(1) * 2);
+		for i in 1..points.len()
+		{
+			indices.push((i - 1) as u16);
+			indices.push(i as u16);
+		}
+
+		DrawObject::new_lines(rs, mp, points, indices, color)
+	}
+
+	/// Creates a new plane draw object split into cells x cells quads, optionally built from triangle
+	/// strips.
+	pub fn new_plane(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, width: f32, height: f32, cells: u16, strip: bool
+	) -> Result<DrawObject, String>
+	{
+		let mesh = Mesh::plane(width, height, cells, strip);
+
+		let mut object = DrawObject::new(
+			rs,
+			mp,
+			position,
+			&mesh,
+			METAL_PANEL_COLOR,
+			METAL_PANEL_NORMAL,
+		)?;
+		object.source = MeshSource::Plane {
+			width: width,
+			height: height,
+			cells: cells,
+			strip: strip,
+		};
+		Ok(object)
+	}
+
+	/// Creates a textured UV sphere, see Mesh::sphere().
+	pub fn new_sphere(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, radius: f32, segments: u16, rings: u16
+	) -> Result<DrawObject, String>
+	{
+		let mesh = Mesh::sphere(radius, segments, rings);
+
+		let mut object = DrawObject::new(
+			rs,
+			mp,
+			position,
+			&mesh,
+			METAL_PANEL_COLOR,
+			METAL_PANEL_NORMAL,
+		)?;
+		object.source = MeshSource::Sphere {
+			radius: radius,
+			segments: segments,
+			rings: rings,
+		};
+		Ok(object)
+	}
+
+	/// Creates a new triangle draw object with a red, a green and a blue corner.
+	pub fn new_colored_triangle(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, size: f32
+	) -> Result<DrawObject, String>
+	{
+		let mesh = DrawObject::colored_triangle_mesh(size);
+		let mut object = DrawObject::new_vertex_colored(rs, mp, position, &mesh)?;
+		object.source = MeshSource::ColoredTriangle {
+			size: size,
+		};
+		Ok(object)
+	}
+
+	/// Returns the mesh of new_colored_triangle(), with the red, green and blue corners in that order.
+	fn colored_triangle_mesh(size: f32) -> Mesh
+	{
+		let corners = [
+			([-size, -size, 0.0], [1.0, 0.0, 0.0, 1.0]),
+			([size, -size, 0.0], [0.0, 1.0, 0.0, 1.0]),
+			([0.0, size, 0.0], [0.0, 0.0, 1.0, 1.0]),
+		];
+		let vertices = corners
+			.iter()
+			.map(|&(pos, color)| Vertex {
+				pos: pos,
+				normal: [0.0, 0.0, 1.0],
+				tangent: [1.0, 0.0, 0.0],
+				bitangent: [0.0, 1.0, 0.0],
+				tex_uv: [0.0, 0.0],
+				color: color,
+			})
+			.collect();
+
+		Mesh::new(vertices, vec![0, 1, 2])
+	}
+
+	/// Returns a quad mesh in the xy-plane facing +z, spanning [-width, width] x [-height, height].
+	fn quad_mesh(width: f32, height: f32) -> Mesh
 	{
 		let vertices = [
 			Vertex {
@@ -204,6 +996,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.0, 0.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [width, -height, 0.0],
@@ -211,6 +1004,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [1.0, 0.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [-width, height, 0.0],
@@ -218,6 +1012,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.0, 1.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [width, height, 0.0],
@@ -225,24 +1020,70 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [1.0, 1.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 		];
 		let indices = [0u16, 1, 3, 0, 3, 2];
+		Mesh::new(vertices.to_vec(), indices.to_vec())
+	}
+
+	/// Creates a new quad draw object.
+	pub fn new_quad(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, width: f32, height: f32
+	) -> Result<DrawObject, String>
+	{
+		let mesh = DrawObject::quad_mesh(width, height);
 
-		DrawObject::new(
+		let mut object = DrawObject::new(
 			rs,
 			mp,
 			position,
-			&vertices,
-			&indices,
-			"assets/thirdparty/textures/Metal_Panel_004/Metal_Panel_004_COLOR.jpg",
-			"assets/thirdparty/textures/Metal_Panel_004/Metal_Panel_004_NORM.jpg",
-		)
+			&mesh,
+			METAL_PANEL_COLOR,
+			METAL_PANEL_NORMAL,
+		)?;
+		object.source = MeshSource::Quad {
+			width: width,
+			height: height,
+		};
+		Ok(object)
+	}
+
+	/// Creates a new quad draw object showing a transparent texture, blended with the given alpha mode.
+	pub fn new_transparent_quad(
+		rs: &RenderState, mp: &MainPass, position: Point3<f32>, width: f32, height: f32, texture_path: &str,
+		alpha_mode: AlphaMode,
+	) -> Result<DrawObject, String>
+	{
+		// Flip the texture so the image is upright
+		let mut mesh = DrawObject::quad_mesh(width, height);
+		for vertex in mesh.vertices.iter_mut()
+		{
+			vertex.tex_uv[1] = 1.0 - vertex.tex_uv[1];
+		}
+
+		let mut object = DrawObject::new_with_alpha(
+			rs,
+			mp,
+			position,
+			&mesh,
+			texture_path,
+			FRONT_NORMAL,
+			alpha_mode,
+			true,
+		)?;
+		object.source = MeshSource::TransparentQuad {
+			width: width,
+			height: height,
+			texture: String::from(texture_path),
+			alpha_mode: alpha_mode,
+		};
+		Ok(object)
 	}
 
 	pub fn new_cuboid(
 		rs: &RenderState, mp: &MainPass, position: Point3<f32>, width: f32, height: f32, depth: f32
-	) -> DrawObject
+	) -> Result<DrawObject, String>
 	{
 		let h_width = width / 2.0;
 		let h_height = height / 2.0;
@@ -255,6 +1096,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.25, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, -h_height, h_depth], // Lower Right Front
@@ -262,6 +1104,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.5, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [-h_width, h_height, h_depth], // Upper Left Front
@@ -269,6 +1112,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.25, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, h_height, h_depth], // Upper Right Front
@@ -276,6 +1120,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.5, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			// Back
 			Vertex {
@@ -284,6 +1129,7 @@ impl DrawObject
 				tangent: [-1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.75, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [-h_width, -h_height, -h_depth], // Lower Left Back
@@ -291,6 +1137,7 @@ impl DrawObject
 				tangent: [-1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [1.0, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, h_height, -h_depth], // Upper Right Back
@@ -298,6 +1145,7 @@ impl DrawObject
 				tangent: [-1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.75, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [-h_width, h_height, -h_depth], // Upper Left Back
@@ -305,6 +1153,7 @@ impl DrawObject
 				tangent: [-1.0, 0.0, 0.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [1.0, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			// Top
 			Vertex {
@@ -313,6 +1162,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 0.0, -1.0],
 				tex_uv: [0.25, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, h_height, h_depth], // Upper Right Front
@@ -320,6 +1170,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 0.0, -1.0],
 				tex_uv: [0.5, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [-h_width, h_height, -h_depth], // Upper Left Back
@@ -327,6 +1178,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 0.0, -1.0],
 				tex_uv: [0.25, 0.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, h_height, -h_depth], // Upper Right Back
@@ -334,6 +1186,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 0.0, -1.0],
 				tex_uv: [0.5, 0.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			// Bottom
 			Vertex {
@@ -342,6 +1195,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 0.0, 1.0],
 				tex_uv: [0.25, 1.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, -h_height, -h_depth], // Lower Right Back
@@ -349,6 +1203,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 0.0, 1.0],
 				tex_uv: [0.5, 1.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [-h_width, -h_height, h_depth], // Lower Left Front
@@ -356,6 +1211,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 0.0, 1.0],
 				tex_uv: [0.25, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, -h_height, h_depth], // Lower Right Front
@@ -363,6 +1219,7 @@ impl DrawObject
 				tangent: [1.0, 0.0, 0.0],
 				bitangent: [0.0, 0.0, 1.0],
 				tex_uv: [0.5, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			// Right
 			Vertex {
@@ -371,6 +1228,7 @@ impl DrawObject
 				tangent: [0.0, 0.0, -1.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.5, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, -h_height, -h_depth], // Lower Right Back
@@ -378,6 +1236,7 @@ impl DrawObject
 				tangent: [0.0, 0.0, -1.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.75, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, h_height, h_depth], // Upper Right Front
@@ -385,6 +1244,7 @@ impl DrawObject
 				tangent: [0.0, 0.0, -1.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.5, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [h_width, h_height, -h_depth], // Upper Right Back
@@ -392,6 +1252,7 @@ impl DrawObject
 				tangent: [0.0, 0.0, -1.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.75, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			// Left
 			Vertex {
@@ -400,6 +1261,7 @@ impl DrawObject
 				tangent: [0.0, 0.0, 1.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.0, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [-h_width, -h_height, h_depth], // Lower Left Front
@@ -407,6 +1269,7 @@ impl DrawObject
 				tangent: [0.0, 0.0, 1.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.25, 2.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [-h_width, h_height, -h_depth], // Upper Left Back
@@ -414,6 +1277,7 @@ impl DrawObject
 				tangent: [0.0, 0.0, 1.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.0, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 			Vertex {
 				pos: [-h_width, h_height, h_depth], // Upper Left Front
@@ -421,6 +1285,7 @@ impl DrawObject
 				tangent: [0.0, 0.0, 1.0],
 				bitangent: [0.0, 1.0, 0.0],
 				tex_uv: [0.25, 1.0 / 3.0],
+				color: [1.0, 1.0, 1.0, 1.0],
 			},
 		];
 		let indices = [
@@ -428,42 +1293,144 @@ impl DrawObject
 			/* Bottom */ 12, 13, 14, 14, 13, 15, /* Right */ 16, 17, 18, 18, 17, 19, /* Left */ 20, 21,
 			22, 22, 21, 23,
 		];
+		let mesh = Mesh::new(vertices.to_vec(), indices.to_vec());
 
-		DrawObject::new(
+		let mut object = DrawObject::new(
 			rs,
 			mp,
 			position,
-			&vertices,
-			&indices,
-			"assets/original/textures/cubemap.png",
-			"assets/original/textures/cubemap_normals.png",
-		)
+			&mesh,
+			CUBE_COLOR,
+			CUBE_NORMAL,
+		)?;
+		object.source = MeshSource::Cuboid {
+			width: width,
+			height: height,
+			depth: depth,
+		};
+		Ok(object)
 	}
 }
 
 impl Drop for DrawObject
 {
-	/// Drops the DrawObject by freeing the index and vertex buffers.
+	/// Drops the DrawObject by freeing the index and vertex buffers of all LOD levels and the proxy.
 	fn drop(&mut self)
 	{
 		// We cannot have the last reference to device at this point
 		debug_assert!(1 < Rc::strong_count(&self.device));
 
 		unsafe {
-			self.device.destroy_sampler(self.normal_map.sampler, None);
-			self.device.destroy_image_view(self.normal_map.view, None);
-			self.device.destroy_image(self.normal_map.image, None);
-			self.device.free_memory(self.normal_map.memory, None);
-
-			self.device.destroy_sampler(self.texture.sampler, None);
-			self.device.destroy_image_view(self.texture.view, None);
-			self.device.destroy_image(self.texture.image, None);
-			self.device.free_memory(self.texture.memory, None);
-
-			self.device.destroy_buffer(self.indices, None);
-			self.device.free_memory(self.index_mem, None);
-			self.device.destroy_buffer(self.vertices, None);
-			self.device.free_memory(self.vertex_mem, None);
+			self.device.free_descriptor_sets(self.descriptor_pool, &self.descriptor_sets);
+
+			// The fallback textures belong to the RenderState
+			let textures = self.normal_map.iter().chain(self.texture.iter());
+			for texture in textures.chain(self.specular_map.iter())
+			{
+				self.device.destroy_sampler(texture.sampler, None);
+				self.device.destroy_image_view(texture.view, None);
+				self.device.destroy_image(texture.image, None);
+				self.device.free_memory(texture.memory, None);
+			}
 		}
+
+		for mesh in self.lods.iter().chain(self.occlusion_proxy.iter())
+		{
+			mesh.destroy(&self.device);
+		}
+	}
+}
+
+/// Returns the writes binding the color texture, normal map and specular map to bindings 0, 1 and 2 of the
+/// set. The image infos must outlive the writes.
+fn texture_descriptor_writes(
+	set: vk::DescriptorSet,
+	image_infos: &[vk::DescriptorImageInfo; 3],
+) -> Vec<vk::WriteDescriptorSet>
+{
+	image_infos
+		.iter()
+		.enumerate()
+		.map(|(binding, image_info)| vk::WriteDescriptorSet {
+			s_type: vk::StructureType::WriteDescriptorSet,
+			p_next: ptr::null(),
+			dst_set: set,
+			dst_binding: binding as u32,
+			dst_array_element: 0,
+			descriptor_count: 1,
+			descriptor_type: vk::DescriptorType::CombinedImageSampler,
+			p_image_info: image_info,
+			p_buffer_info: ptr::null(),
+			p_texel_buffer_view: ptr::null(),
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn texture_descriptors_bind_three_image_samplers()
+	{
+		let image_info = vk::DescriptorImageInfo {
+			image_layout: vk::ImageLayout::ShaderReadOnlyOptimal,
+			image_view: vk::ImageView::null(),
+			sampler: vk::Sampler::null(),
+		};
+		let image_infos = [image_info; 3];
+		let writes = texture_descriptor_writes(vk::DescriptorSet::null(), &image_infos);
+		assert_eq!(writes.len(), 3);
+		for (binding, write) in writes.iter().enumerate()
+		{
+			assert_eq!(write.dst_binding, binding as u32);
+			assert_eq!(write.descriptor_count, 1);
+			assert_eq!(write.descriptor_type, vk::DescriptorType::CombinedImageSampler);
+			assert_eq!(write.p_image_info, &image_infos[binding] as *const vk::DescriptorImageInfo);
+		}
+	}
+
+	#[test]
+	fn colored_triangle_feeds_its_colors_to_the_color_attribute()
+	{
+		let mesh = DrawObject::colored_triangle_mesh(0.5);
+		// Location 5 of phong.vert
+		let attributes = Vertex::attribute_descriptions();
+		let color = attributes.iter().find(|attribute| attribute.location == 5).unwrap();
+		assert_eq!(color.format, vk::Format::R32g32b32a32Sfloat);
+		let expected = [[1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]];
+		for (vertex, expected) in mesh.vertices.iter().zip(expected.iter())
+		{
+			// What the vertex input reads from the vertex buffer at the attribute offset
+			let read = unsafe {
+				let bytes = (vertex as *const Vertex as *const u8).offset(color.offset as isize);
+				*(bytes as *const [f32; 4])
+			};
+			assert_eq!(read, *expected);
+		}
+	}
+
+	#[test]
+	fn validate_rejects_meshes_overflowing_the_indices()
+	{
+		let plane = |cells: u16| MeshSource::Plane {
+			width: 1.0,
+			height: 1.0,
+			cells: cells,
+			strip: false,
+		};
+		let sphere = |segments: u16, rings: u16| MeshSource::Sphere {
+			radius: 1.0,
+			segments: segments,
+			rings: rings,
+		};
+		assert!(plane(254).validate().is_ok());
+		assert!(plane(255).validate().is_err());
+		assert!(plane(0).validate().is_err());
+		assert!(sphere(3, 2).validate().is_ok());
+		assert!(sphere(2, 8).validate().is_err());
+		assert!(sphere(16, 1).validate().is_err());
+		assert!(sphere(255, 8).validate().is_err());
 	}
 }
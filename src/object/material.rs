@@ -0,0 +1,62 @@
+use renderer::SamplerOptions;
+
+/// Surface parameters for the Phong shading, in addition to the color and normal textures.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Material
+{
+	pub specular_color: [f32; 3],
+	/// Specular exponent, higher values give smaller and sharper highlights.
+	pub shininess: f32,
+	/// How the object's own textures are sampled.
+	#[serde(default)]
+	pub sampler: SamplerOptions,
+	/// Texture varying the highlights across the surface: its rgb scales the specular color and its
+	/// alpha the shininess. Without one, the scalars above apply everywhere.
+	#[serde(default)]
+	pub specular_map: Option<String>,
+}
+
+impl Default for Material
+{
+	/// Generates a neutral material, matching the previously hardcoded shading.
+	fn default() -> Material
+	{
+		Material {
+			specular_color: [1.0, 1.0, 1.0],
+			shininess: 50.0,
+			sampler: SamplerOptions::default(),
+			specular_map: None,
+		}
+	}
+}
+
+impl Material
+{
+	/// Returns a material with strong, tight highlights.
+	pub fn shiny() -> Material
+	{
+		Material {
+			specular_color: [1.0, 1.0, 1.0],
+			shininess: 200.0,
+			sampler: SamplerOptions::default(),
+			specular_map: None,
+		}
+	}
+
+	/// Returns a material without highlights.
+	pub fn matte() -> Material
+	{
+		Material {
+			specular_color: [0.0, 0.0, 0.0],
+			shininess: 1.0,
+			sampler: SamplerOptions::default(),
+			specular_map: None,
+		}
+	}
+
+	/// Packs the specular color and shininess into one vec4, as stored in the object uniforms.
+	pub fn specular_params(&self) -> [f32; 4]
+	{
+		[self.specular_color[0], self.specular_color[1], self.specular_color[2], self.shininess]
+	}
+}
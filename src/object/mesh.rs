@@ -0,0 +1,1043 @@
+use ash::vk;
+use cgmath::{Vector3, Vector4};
+use cgmath::prelude::*;
+use object::compute_tangents;
+use std::collections::{HashMap, HashSet};
+use std::f32;
+use std::mem::size_of;
+
+/// Grid resolution (cells per axis) of the first LOD level, halved for each following level.
+const LOD_GRID_RESOLUTION: u32 = 32;
+/// Index that starts a new strip in strip meshes.
+pub const PRIMITIVE_RESTART_INDEX: u16 = 0xFFFF;
+/// Triangles with at most this area are removed by Mesh::clean_up() if enabled.
+const DEGENERATE_AREA: f32 = 1e-8;
+
+#[derive(Clone, Copy)]
+pub struct Vertex
+{
+	pub pos: [f32; 3],
+	pub normal: [f32; 3],
+	pub tangent: [f32; 3],
+	pub bitangent: [f32; 3],
+	pub tex_uv: [f32; 2],
+	/// Only used by the vertex color pipeline.
+	pub color: [f32; 4],
+}
+
+impl Vertex
+{
+	/// Returns the description of the vertex buffer binding.
+	pub fn binding_description() -> vk::VertexInputBindingDescription
+	{
+		vk::VertexInputBindingDescription {
+			binding: 0,
+			stride: size_of::<Vertex>() as u32,
+			input_rate: vk::VertexInputRate::Vertex,
+		}
+	}
+
+	/// Returns the descriptions of the vertex attributes, in shader location order.
+	pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 6]
+	{
+		let float_size = size_of::<f32>() as u32;
+		let attribute = |location: u32, format: vk::Format, offset: u32| vk::VertexInputAttributeDescription {
+			binding: 0,
+			location: location,
+			format: format,
+			offset: offset * float_size,
+		};
+
+		[
+			attribute(0, vk::Format::R32g32b32Sfloat, 0), // pos
+			attribute(1, vk::Format::R32g32b32Sfloat, 3), // normal
+			attribute(2, vk::Format::R32g32b32Sfloat, 6), // tangent
+			attribute(3, vk::Format::R32g32b32Sfloat, 9), // bitangent
+			attribute(4, vk::Format::R32g32Sfloat, 12), // tex_uv
+			attribute(5, vk::Format::R32g32b32a32Sfloat, 14), // color
+		]
+	}
+
+	/// Returns true if all attributes of both vertices are within epsilon of each other.
+	fn approx_eq(&self, other: &Vertex, epsilon: f32) -> bool
+	{
+		let close = |a: &[f32], b: &[f32]| a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() <= epsilon);
+		close(&self.pos, &other.pos) && close(&self.normal, &other.normal) &&
+			close(&self.tangent, &other.tangent) && close(&self.bitangent, &other.bitangent) &&
+			close(&self.tex_uv, &other.tex_uv) && close(&self.color, &other.color)
+	}
+}
+
+/// Which way the front faces of a loaded mesh are wound, the loader option for Mesh::orient().
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Winding
+{
+	/// Keep the triangles, counter-clockwise triangles are the front faces like the pipelines expect.
+	CounterClockwise,
+	/// Reverse every triangle, for models made with clockwise front faces.
+	Clockwise,
+	/// Reverse the triangles if most of them face inwards, which renders models inside out.
+	Auto,
+}
+
+/// How meshes are cleaned up before objects are created from them, see Mesh::clean_up().
+#[derive(Clone, Copy, Debug)]
+pub struct MeshCleanup
+{
+	/// Vertices equal within this in all attributes are merged by Mesh::weld(), None keeps them.
+	pub weld_epsilon: Option<f32>,
+	/// Remove the zero area triangles of triangle lists, see Mesh::remove_degenerate_triangles().
+	pub remove_degenerate_triangles: bool,
+	/// Winding of the front faces of triangle lists, see Mesh::orient().
+	pub winding: Winding,
+}
+
+/// Indexed geometry kept on the CPU.
+#[derive(Clone)]
+pub struct Mesh
+{
+	pub vertices: Vec<Vertex>,
+	pub indices: Vec<u16>,
+	topology: vk::PrimitiveTopology,
+}
+
+/// Normalizes the vector unless it is zero, e.g. when opposing directions cancelled out.
+fn normalized(v: Vector3<f32>) -> Vector3<f32>
+{
+	if v.magnitude2() > 0.0
+	{
+		v.normalize()
+	}
+	else
+	{
+		v
+	}
+}
+
+/// Sums up vertices so they can be averaged.
+struct VertexCluster
+{
+	pos: Vector3<f32>,
+	normal: Vector3<f32>,
+	tangent: Vector3<f32>,
+	bitangent: Vector3<f32>,
+	tex_uv: [f32; 2],
+	color: Vector4<f32>,
+	count: u32,
+}
+
+impl Mesh
+{
+	/// Creates a new mesh from a triangle list.
+	pub fn new(vertices: Vec<Vertex>, indices: Vec<u16>) -> Mesh
+	{
+		debug_assert!(indices.len() % 3 == 0);
+		Mesh {
+			vertices: vertices,
+			indices: indices,
+			topology: vk::PrimitiveTopology::TriangleList,
+		}
+	}
+
+	/// Creates a new mesh from a line list.
+	///
+	/// Line meshes can't be simplified with generate_lods().
+	pub fn new_lines(vertices: Vec<Vertex>, indices: Vec<u16>) -> Mesh
+	{
+		debug_assert!(indices.len() % 2 == 0);
+		Mesh {
+			vertices: vertices,
+			indices: indices,
+			topology: vk::PrimitiveTopology::LineList,
+		}
+	}
+
+	/// Creates a new mesh from triangle strips, separated by PRIMITIVE_RESTART_INDEX.
+	///
+	/// Strip meshes can't be simplified with generate_lods().
+	pub fn new_strip(vertices: Vec<Vertex>, indices: Vec<u16>) -> Mesh
+	{
+		Mesh {
+			vertices: vertices,
+			indices: indices,
+			topology: vk::PrimitiveTopology::TriangleStrip,
+		}
+	}
+
+	/// Creates a new mesh from a triangle fan, where every triangle shares the first index.
+	///
+	/// Fan meshes can't be simplified with generate_lods().
+	pub fn new_fan(vertices: Vec<Vertex>, indices: Vec<u16>) -> Mesh
+	{
+		debug_assert!(indices.len() >= 3);
+		Mesh {
+			vertices: vertices,
+			indices: indices,
+			topology: vk::PrimitiveTopology::TriangleFan,
+		}
+	}
+
+	/// Creates a triangle fan filling a convex polygon in the xy-plane facing +z, in a single color.
+	///
+	/// The outline must be counter-clockwise, the fan starts at its first point.
+	pub fn polygon_fan(outline: &[[f32; 2]], color: [f32; 4]) -> Mesh
+	{
+		let vertices = outline
+			.iter()
+			.map(|point| Vertex {
+				pos: [point[0], point[1], 0.0],
+				normal: [0.0, 0.0, 1.0],
+				tangent: [1.0, 0.0, 0.0],
+				bitangent: [0.0, 1.0, 0.0],
+				tex_uv: [0.0, 0.0],
+				color: color,
+			})
+			.collect();
+		let indices = (0..outline.len() as u16).collect();
+
+		Mesh::new_fan(vertices, indices)
+	}
+
+	/// Returns the fan as a triangle list, for devices that can't draw triangle fans.
+	pub fn fan_to_list(&self) -> Mesh
+	{
+		debug_assert!(self.topology == vk::PrimitiveTopology::TriangleFan);
+		let mut indices = Vec::with_capacity(self.triangle_count() * 3);
+		for pair in self.indices[1..].windows(2)
+		{
+			indices.extend_from_slice(&[self.indices[0], pair[0], pair[1]]);
+		}
+
+		Mesh::new(self.vertices.clone(), indices)
+	}
+
+	/// Turns a line list into a triangle list with a quad per segment, for drawing wide lines on devices
+	/// without wide line support.
+	///
+	/// The quads have no width yet, the line pipeline moves the corners apart on screen. Each corner
+	/// keeps the other end of its segment in the normal and the side to move to in the texture U.
+	pub fn line_quads(&self) -> Mesh
+	{
+		debug_assert!(self.topology == vk::PrimitiveTopology::LineList);
+		debug_assert!(self.indices.len() / 2 * 4 <= u16::max_value() as usize + 1);
+		let mut vertices = Vec::with_capacity(self.indices.len() * 2);
+		let mut indices = Vec::with_capacity(self.indices.len() * 3);
+		for segment in self.indices.chunks(2)
+		{
+			let (start, end) = (&self.vertices[segment[0] as usize], &self.vertices[segment[1] as usize]);
+			let first = vertices.len() as u16;
+			// Seen from the end the sides swap, so this goes around the quad
+			for &(corner, other, side) in [(start, end, 1.0), (start, end, -1.0), (end, start, 1.0), (end, start, -1.0)]
+				.iter()
+			{
+				vertices.push(Vertex {
+					normal: other.pos,
+					tex_uv: [side, 0.0],
+					..*corner
+				});
+			}
+			indices.extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+		}
+
+		Mesh::new(vertices, indices)
+	}
+
+	/// Creates a box spanning the given corners, with only positions set. Meant for proxies like
+	/// occlusion query bounds, which are never shaded.
+	pub fn bounding_box(min: Vector3<f32>, max: Vector3<f32>) -> Mesh
+	{
+		let vertices = (0..8)
+			.map(|i| Vertex {
+				pos: [
+					if i & 1 == 0 { min.x } else { max.x },
+					if i & 2 == 0 { min.y } else { max.y },
+					if i & 4 == 0 { min.z } else { max.z },
+				],
+				normal: [0.0, 0.0, 1.0],
+				tangent: [1.0, 0.0, 0.0],
+				bitangent: [0.0, 1.0, 0.0],
+				tex_uv: [0.0, 0.0],
+				color: [1.0, 1.0, 1.0, 1.0],
+			})
+			.collect();
+		// Two triangles per side, bit 0 of the index is x, bit 1 is y and bit 2 is z
+		let indices = vec![
+			0, 2, 1, 1, 2, 3, // -z
+			4, 5, 6, 5, 7, 6, // +z
+			0, 1, 4, 1, 5, 4, // -y
+			2, 6, 3, 3, 6, 7, // +y
+			0, 4, 2, 2, 4, 6, // -x
+			1, 3, 5, 3, 7, 5, // +x
+		];
+
+		Mesh::new(vertices, indices)
+	}
+
+	/// Creates a plane in the xy-plane facing +z, split into cells x cells quads.
+	///
+	/// The plane spans [-width, width] x [-height, height], with texture coordinates going from 0 to 1.
+	/// With strip set it is built from one triangle strip per row of cells, which needs fewer
+	/// indices than the triangle list.
+	pub fn plane(width: f32, height: f32, cells: u16, strip: bool) -> Mesh
+	{
+		debug_assert!(cells > 0);
+		let row_length = cells + 1;
+		let mut vertices = Vec::with_capacity(row_length as usize * row_length as usize);
+		for y in 0..row_length
+		{
+			for x in 0..row_length
+			{
+				let u = x as f32 / cells as f32;
+				let v = y as f32 / cells as f32;
+				vertices.push(Vertex {
+					pos: [(2.0 * u - 1.0) * width, (2.0 * v - 1.0) * height, 0.0],
+					normal: [0.0, 0.0, 1.0],
+					tangent: [1.0, 0.0, 0.0],
+					bitangent: [0.0, 1.0, 0.0],
+					tex_uv: [u, v],
+					color: [1.0, 1.0, 1.0, 1.0],
+				});
+			}
+		}
+
+		let mut indices = Vec::new();
+		for y in 0..cells
+		{
+			let row = y * row_length;
+			let next_row = row + row_length;
+			if strip
+			{
+				if y > 0
+				{
+					indices.push(PRIMITIVE_RESTART_INDEX);
+				}
+				// Zig-zag between the rows, starting at the upper one to keep the list's winding
+				for x in 0..row_length
+				{
+					indices.push(next_row + x);
+					indices.push(row + x);
+				}
+			}
+			else
+			{
+				for x in 0..cells
+				{
+					indices.extend_from_slice(&[row + x, row + x + 1, next_row + x + 1]);
+					indices.extend_from_slice(&[row + x, next_row + x + 1, next_row + x]);
+				}
+			}
+		}
+
+		if strip
+		{
+			Mesh::new_strip(vertices, indices)
+		}
+		else
+		{
+			Mesh::new(vertices, indices)
+		}
+	}
+
+	/// Generates a UV sphere around the origin, with the texture wrapped around it once.
+	///
+	/// Segments are the slices around the vertical axis and rings the slices from pole to pole.
+	pub fn sphere(radius: f32, segments: u16, rings: u16) -> Mesh
+	{
+		debug_assert!(segments >= 3 && rings >= 2);
+		// The seam and the poles have a vertex per segment, so the texture coordinates can differ
+		let row_length = segments + 1;
+		let mut vertices = Vec::with_capacity(row_length as usize * (rings as usize + 1));
+		for ring in 0..rings + 1
+		{
+			let v = ring as f32 / rings as f32;
+			let (ring_sin, ring_cos) = (v * f32::consts::PI).sin_cos();
+			for segment in 0..row_length
+			{
+				let u = segment as f32 / segments as f32;
+				let (segment_sin, segment_cos) = (u * 2.0 * f32::consts::PI).sin_cos();
+				let normal = [ring_sin * segment_cos, ring_cos, ring_sin * segment_sin];
+				vertices.push(Vertex {
+					pos: [normal[0] * radius, normal[1] * radius, normal[2] * radius],
+					normal: normal,
+					tangent: [1.0, 0.0, 0.0],
+					bitangent: [0.0, 1.0, 0.0],
+					tex_uv: [u, v],
+					color: [1.0, 1.0, 1.0, 1.0],
+				});
+			}
+		}
+
+		let mut indices = Vec::new();
+		for ring in 0..rings
+		{
+			let row = ring * row_length;
+			let next_row = row + row_length;
+			for segment in 0..segments
+			{
+				// The triangles touching the poles would have no area
+				if ring > 0
+				{
+					indices.extend_from_slice(&[row + segment, row + segment + 1, next_row + segment]);
+				}
+				if ring < rings - 1
+				{
+					indices.extend_from_slice(&[row + segment + 1, next_row + segment + 1, next_row + segment]);
+				}
+			}
+		}
+
+		let mut mesh = Mesh::new(vertices, indices);
+		mesh.compute_tangents();
+		mesh
+	}
+
+	/// Recomputes the tangents and bitangents from the positions, normals and texture coordinates.
+	///
+	/// Only works for triangle lists, loaders should call this after filling in the other attributes.
+	pub fn compute_tangents(&mut self)
+	{
+		debug_assert!(self.topology == vk::PrimitiveTopology::TriangleList);
+		let positions: Vec<[f32; 3]> = self.vertices.iter().map(|vertex| vertex.pos).collect();
+		let normals: Vec<[f32; 3]> = self.vertices.iter().map(|vertex| vertex.normal).collect();
+		let texcoords: Vec<[f32; 2]> = self.vertices.iter().map(|vertex| vertex.tex_uv).collect();
+		let (tangents, bitangents) = compute_tangents(&positions, &normals, &texcoords, &self.indices);
+		for (vertex, (tangent, bitangent)) in self.vertices.iter_mut().zip(tangents.into_iter().zip(bitangents))
+		{
+			vertex.tangent = tangent;
+			vertex.bitangent = bitangent;
+		}
+	}
+
+	/// Returns how the indices are assembled into primitives.
+	pub fn topology(&self) -> vk::PrimitiveTopology
+	{
+		self.topology
+	}
+
+	/// Returns the number of triangles in the mesh.
+	pub fn triangle_count(&self) -> usize
+	{
+		match self.topology
+		{
+			vk::PrimitiveTopology::TriangleList => self.indices.len() / 3,
+			vk::PrimitiveTopology::TriangleStrip => self.indices
+				.split(|&index| index == PRIMITIVE_RESTART_INDEX)
+				.map(|strip| strip.len().saturating_sub(2))
+				.sum(),
+			vk::PrimitiveTopology::TriangleFan => self.indices.len().saturating_sub(2),
+			_ => 0,
+		}
+	}
+
+	/// Returns the minimum and maximum corners of the axis aligned bounding box.
+	pub fn bounds(&self) -> (Vector3<f32>, Vector3<f32>)
+	{
+		let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+		let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+		for vertex in self.vertices.iter()
+		{
+			for axis in 0..3
+			{
+				min[axis] = min[axis].min(vertex.pos[axis]);
+				max[axis] = max[axis].max(vertex.pos[axis]);
+			}
+		}
+
+		(min, max)
+	}
+
+	/// Returns the distance from the origin to the farthest corner of the bounding box, the radius of a
+	/// sphere around the origin containing the mesh in any rotation.
+	pub fn bounding_radius(&self) -> f32
+	{
+		let (min, max) = self.bounds();
+		let farthest = Vector3::new(
+			min.x.abs().max(max.x.abs()),
+			min.y.abs().max(max.y.abs()),
+			min.z.abs().max(max.z.abs()),
+		);
+		farthest.magnitude()
+	}
+
+	/// Merges the vertices that are equal within epsilon in all attributes, and rebuilds the indices
+	/// to match.
+	///
+	/// Meshes with duplicated vertices, like triangle soups from model files, get smaller and make
+	/// better use of the vertex cache. Returns the number of vertices removed.
+	pub fn weld(&mut self, epsilon: f32) -> usize
+	{
+		// Bucket the kept vertices by position, so only the neighbouring cells have to be compared
+		let cell_size = epsilon.max(f32::EPSILON) * 2.0;
+		let cell_of = |pos: &[f32; 3]| {
+			(
+				(pos[0] / cell_size).floor() as i64,
+				(pos[1] / cell_size).floor() as i64,
+				(pos[2] / cell_size).floor() as i64,
+			)
+		};
+		let mut cells: HashMap<(i64, i64, i64), Vec<u16>> = HashMap::new();
+		let mut welded: Vec<Vertex> = Vec::with_capacity(self.vertices.len());
+		let mut remap: Vec<u16> = Vec::with_capacity(self.vertices.len());
+		for vertex in self.vertices.iter()
+		{
+			let (x, y, z) = cell_of(&vertex.pos);
+			let mut found = None;
+			'search: for dx in -1..2
+			{
+				for dy in -1..2
+				{
+					for dz in -1..2
+					{
+						if let Some(candidates) = cells.get(&(x + dx, y + dy, z + dz))
+						{
+							found = candidates.iter().cloned().find(|&i| welded[i as usize].approx_eq(vertex, epsilon));
+							if found.is_some()
+							{
+								break 'search;
+							}
+						}
+					}
+				}
+			}
+
+			let index = match found
+			{
+				Some(index) => index,
+				None =>
+				{
+					let index = welded.len() as u16;
+					welded.push(*vertex);
+					cells.entry((x, y, z)).or_insert_with(Vec::new).push(index);
+					index
+				}
+			};
+			remap.push(index);
+		}
+
+		let removed = self.vertices.len() - welded.len();
+		for index in self.indices.iter_mut()
+		{
+			// Keep the strip separators
+			if *index != PRIMITIVE_RESTART_INDEX
+			{
+				*index = remap[*index as usize];
+			}
+		}
+		self.vertices = welded;
+
+		removed
+	}
+
+	/// Removes the triangles with an area of at most epsilon, and returns how many were removed.
+	///
+	/// Model files can contain zero area triangles, which cost time to rasterize without covering any
+	/// pixels. Loaders should call this before compute_tangents(). Only works for triangle lists.
+	pub fn remove_degenerate_triangles(&mut self, epsilon: f32) -> usize
+	{
+		debug_assert!(self.topology == vk::PrimitiveTopology::TriangleList);
+		let vertices = &self.vertices;
+		let indices: Vec<u16> = self.indices
+			.chunks(3)
+			.filter(|triangle| {
+				let a = Vector3::from(vertices[triangle[0] as usize].pos);
+				let b = Vector3::from(vertices[triangle[1] as usize].pos);
+				let c = Vector3::from(vertices[triangle[2] as usize].pos);
+				(b - a).cross(c - a).magnitude() * 0.5 > epsilon
+			})
+			.flat_map(|triangle| triangle.iter().cloned())
+			.collect();
+
+		let removed = (self.indices.len() - indices.len()) / 3;
+		self.indices = indices;
+
+		removed
+	}
+
+	/// Counts the triangles facing outwards and inwards, and returns both counts in that order.
+	///
+	/// A triangle faces outwards if its counter-clockwise front face points along its vertex normals.
+	/// For triangles without vertex normals, it has to point away from the center of the bounds instead,
+	/// which works for closed, roughly convex meshes. Only works for triangle lists.
+	pub fn count_facing(&self) -> (usize, usize)
+	{
+		debug_assert!(self.topology == vk::PrimitiveTopology::TriangleList);
+		let (min, max) = self.bounds();
+		let center = (min + max) * 0.5;
+		let mut outwards = 0;
+		let mut inwards = 0;
+		for triangle in self.indices.chunks(3)
+		{
+			let corners: Vec<&Vertex> = triangle.iter().map(|&index| &self.vertices[index as usize]).collect();
+			let a = Vector3::from(corners[0].pos);
+			let b = Vector3::from(corners[1].pos);
+			let c = Vector3::from(corners[2].pos);
+			let face_normal = (b - a).cross(c - a);
+
+			let mut outside = corners
+				.iter()
+				.fold(Vector3::new(0.0, 0.0, 0.0), |sum, corner| sum + Vector3::from(corner.normal));
+			if outside.magnitude2() == 0.0
+			{
+				outside = (a + b + c) / 3.0 - center;
+			}
+
+			// Degenerate triangles and ones seen edge-on don't count either way
+			let facing = face_normal.dot(outside);
+			if facing > 0.0
+			{
+				outwards += 1;
+			}
+			else if facing < 0.0
+			{
+				inwards += 1;
+			}
+		}
+
+		(outwards, inwards)
+	}
+
+	/// Reverses every triangle, which turns the front faces into back faces. Only works for triangle lists.
+	pub fn flip_winding(&mut self)
+	{
+		debug_assert!(self.topology == vk::PrimitiveTopology::TriangleList);
+		for triangle in self.indices.chunks_mut(3)
+		{
+			triangle.swap(1, 2);
+		}
+	}
+
+	/// Orients the triangles as given by the loader option, and returns true if they were reversed.
+	///
+	/// Auto decides by count_facing() and prints when it flips the mesh. Loaders should call this after
+	/// remove_degenerate_triangles(). Only works for triangle lists.
+	pub fn orient(&mut self, winding: Winding) -> bool
+	{
+		let flip = match winding
+		{
+			Winding::CounterClockwise => false,
+			Winding::Clockwise => true,
+			Winding::Auto =>
+			{
+				let (outwards, inwards) = self.count_facing();
+				if inwards > outwards
+				{
+					info!(
+						"Flipped the winding of a mesh, {} of its {} triangles faced inwards",
+						inwards,
+						outwards + inwards
+					);
+				}
+				inwards > outwards
+			}
+		};
+
+		if flip
+		{
+			self.flip_winding();
+		}
+		flip
+	}
+
+	/// Cleans up the mesh as configured, before it is uploaded.
+	pub fn clean_up(&mut self, cleanup: &MeshCleanup)
+	{
+		if let Some(epsilon) = cleanup.weld_epsilon
+		{
+			let removed = self.weld(epsilon);
+			if removed > 0
+			{
+				verbose!("Welded {} duplicated vertices of a mesh", removed);
+			}
+		}
+
+		// After welding, which can collapse triangles
+		if cleanup.remove_degenerate_triangles && self.topology == vk::PrimitiveTopology::TriangleList
+		{
+			let removed = self.remove_degenerate_triangles(DEGENERATE_AREA);
+			if removed > 0
+			{
+				verbose!("Removed {} degenerate triangles of a mesh", removed);
+			}
+		}
+
+		if self.topology == vk::PrimitiveTopology::TriangleList
+		{
+			self.orient(cleanup.winding);
+		}
+	}
+
+	/// Generates up to the given number of progressively simplified versions of the mesh.
+	///
+	/// Every returned level has fewer triangles than the one before it (the first is compared to
+	/// this mesh), so fewer levels are returned if the mesh can't be simplified any further.
+	pub fn generate_lods(&self, levels: usize) -> Vec<Mesh>
+	{
+		debug_assert!(self.topology == vk::PrimitiveTopology::TriangleList);
+		let mut lods: Vec<Mesh> = Vec::new();
+		let mut resolution = LOD_GRID_RESOLUTION;
+		while lods.len() < levels && resolution > 0
+		{
+			let lod = self.cluster_vertices(resolution);
+			resolution /= 2;
+
+			let previous_count = lods.last().unwrap_or(self).triangle_count();
+			// A level without triangles would make the object disappear
+			if lod.triangle_count() > 0 && lod.triangle_count() < previous_count
+			{
+				lods.push(lod);
+			}
+		}
+
+		lods
+	}
+
+	/// Simplifies the mesh by merging all vertices inside each cell of a grid laid over the
+	/// bounding box, and removing the triangles that collapse.
+	fn cluster_vertices(&self, resolution: u32) -> Mesh
+	{
+		let (min, max) = self.bounds();
+		let extent = max - min;
+
+		// Assign every vertex to a cluster
+		let mut cell_to_cluster: HashMap<(u32, u32, u32), usize> = HashMap::new();
+		let mut clusters: Vec<VertexCluster> = Vec::new();
+		let mut remap: Vec<usize> = Vec::with_capacity(self.vertices.len());
+		for vertex in self.vertices.iter()
+		{
+			let mut cell = [0u32; 3];
+			for axis in 0..3
+			{
+				if extent[axis] > 0.0
+				{
+					let t = (vertex.pos[axis] - min[axis]) / extent[axis];
+					cell[axis] = ((t * resolution as f32) as u32).min(resolution - 1);
+				}
+			}
+
+			let next_cluster = clusters.len();
+			let cluster_idx = *cell_to_cluster.entry((cell[0], cell[1], cell[2])).or_insert(next_cluster);
+			if cluster_idx == next_cluster
+			{
+				clusters.push(VertexCluster {
+					pos: Vector3::zero(),
+					normal: Vector3::zero(),
+					tangent: Vector3::zero(),
+					bitangent: Vector3::zero(),
+					// Averaging texture coordinates across seams makes a mess, so keep the first one
+					tex_uv: vertex.tex_uv,
+					color: Vector4::zero(),
+					count: 0,
+				});
+			}
+			let cluster = &mut clusters[cluster_idx];
+			cluster.pos += Vector3::from(vertex.pos);
+			cluster.normal += Vector3::from(vertex.normal);
+			cluster.tangent += Vector3::from(vertex.tangent);
+			cluster.bitangent += Vector3::from(vertex.bitangent);
+			cluster.color += Vector4::from(vertex.color);
+			cluster.count += 1;
+			remap.push(cluster_idx);
+		}
+
+		let vertices = clusters
+			.iter()
+			.map(|cluster| Vertex {
+				pos: (cluster.pos / cluster.count as f32).into(),
+				normal: normalized(cluster.normal).into(),
+				tangent: normalized(cluster.tangent).into(),
+				bitangent: normalized(cluster.bitangent).into(),
+				tex_uv: cluster.tex_uv,
+				color: (cluster.color / cluster.count as f32).into(),
+			})
+			.collect();
+
+		// Keep the triangles that still have three distinct corners, once each
+		let mut indices = Vec::new();
+		let mut seen = HashSet::new();
+		for triangle in self.indices.chunks(3)
+		{
+			let a = remap[triangle[0] as usize];
+			let b = remap[triangle[1] as usize];
+			let c = remap[triangle[2] as usize];
+			if a == b || b == c || a == c
+			{
+				continue;
+			}
+
+			let mut key = [a, b, c];
+			key.sort();
+			if seen.insert(key)
+			{
+				indices.push(a as u16);
+				indices.push(b as u16);
+				indices.push(c as u16);
+			}
+		}
+
+		Mesh::new(vertices, indices)
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	/// Returns a white vertex at the given position, facing +Z.
+	fn vertex(x: f32, y: f32, z: f32) -> Vertex
+	{
+		Vertex {
+			pos: [x, y, z],
+			normal: [0.0, 0.0, 1.0],
+			tangent: [1.0, 0.0, 0.0],
+			bitangent: [0.0, 1.0, 0.0],
+			tex_uv: [0.0, 0.0],
+			color: [1.0, 1.0, 1.0, 1.0],
+		}
+	}
+
+	/// Returns a cube of size 2 as a triangle soup, 6 vertices for each face with the face normal.
+	fn cube_soup() -> Mesh
+	{
+		let mut vertices = Vec::new();
+		for axis in 0..3
+		{
+			for &side in [-1.0, 1.0].iter()
+			{
+				// Counter-clockwise seen from outside, which reverses the corners of the negative sides
+				let mut corners = vec![(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)];
+				if side < 0.0
+				{
+					corners.reverse();
+				}
+				let face: Vec<Vertex> = corners
+					.iter()
+					.map(|&(u, v)| {
+						let mut corner = vertex(0.0, 0.0, 0.0);
+						corner.pos[axis] = side;
+						corner.pos[(axis + 1) % 3] = u;
+						corner.pos[(axis + 2) % 3] = v;
+						corner.normal = [0.0; 3];
+						corner.normal[axis] = side;
+						corner
+					})
+					.collect();
+				vertices.extend([0, 1, 2, 0, 2, 3].iter().map(|&i| face[i]));
+			}
+		}
+		let indices = (0..vertices.len() as u16).collect();
+		Mesh::new(vertices, indices)
+	}
+
+	#[test]
+	fn welding_a_cube_soup_keeps_a_vertex_per_face_corner()
+	{
+		let mut mesh = cube_soup();
+		assert_eq!(mesh.vertices.len(), 36);
+		let (min, max) = mesh.bounds();
+
+		assert_eq!(mesh.weld(1e-5), 12);
+		assert_eq!(mesh.vertices.len(), 24);
+		assert_eq!(mesh.triangle_count(), 12);
+		assert_eq!(mesh.bounds(), (min, max));
+		assert!(mesh.indices.iter().all(|&index| (index as usize) < mesh.vertices.len()));
+	}
+
+	#[test]
+	fn clean_up_removes_degenerate_triangles_if_enabled()
+	{
+		// The second triangle has all its corners on a line
+		let vertices = vec![
+			vertex(0.0, 0.0, 0.0),
+			vertex(1.0, 0.0, 0.0),
+			vertex(0.0, 1.0, 0.0),
+			vertex(2.0, 0.0, 0.0),
+		];
+		let mesh = Mesh::new(vertices, vec![0, 1, 2, 0, 1, 3]);
+		let mut cleanup = MeshCleanup {
+			weld_epsilon: None,
+			remove_degenerate_triangles: false,
+			winding: Winding::CounterClockwise,
+		};
+
+		let mut kept = mesh.clone();
+		kept.clean_up(&cleanup);
+		assert_eq!(kept.triangle_count(), 2);
+
+		cleanup.remove_degenerate_triangles = true;
+		let mut cleaned = mesh.clone();
+		cleaned.clean_up(&cleanup);
+		assert_eq!(cleaned.indices, vec![0, 1, 2]);
+	}
+
+	#[test]
+	fn auto_winding_flips_inside_out_meshes_only()
+	{
+		let cleanup = MeshCleanup {
+			weld_epsilon: None,
+			remove_degenerate_triangles: false,
+			winding: Winding::Auto,
+		};
+
+		let mut mesh = cube_soup();
+		mesh.clean_up(&cleanup);
+		assert_eq!(mesh.indices, cube_soup().indices);
+
+		let mut inside_out = cube_soup();
+		inside_out.flip_winding();
+		assert_eq!(inside_out.count_facing(), (0, 12));
+		inside_out.clean_up(&cleanup);
+		assert_eq!(inside_out.indices, cube_soup().indices);
+	}
+
+	#[test]
+	fn each_lod_is_simpler_and_keeps_the_bounds()
+	{
+		let mesh = Mesh::sphere(1.0, 32, 16);
+		let lods = mesh.generate_lods(3);
+		assert!(!lods.is_empty());
+
+		let mut previous_count = mesh.triangle_count();
+		for lod in lods.iter()
+		{
+			assert!(lod.triangle_count() > 0 && lod.triangle_count() < previous_count);
+			previous_count = lod.triangle_count();
+		}
+
+		// Clustering averages the vertices of each cell, which pulls the bounds in by less than a cell
+		let (min, max) = mesh.bounds();
+		let (lod_min, lod_max) = lods.last().unwrap().bounds();
+		for axis in 0..3
+		{
+			assert!((lod_min[axis] - min[axis]).abs() < 0.25);
+			assert!((lod_max[axis] - max[axis]).abs() < 0.25);
+		}
+	}
+
+	/// Returns the triangles of a list or strip mesh, each rotated to start at its lowest index so
+	/// equal triangles with the same winding compare equal.
+	fn sorted_triangles(mesh: &Mesh) -> Vec<[u16; 3]>
+	{
+		let mut triangles = Vec::new();
+		if mesh.topology() == vk::PrimitiveTopology::TriangleStrip
+		{
+			for strip in mesh.indices.split(|&index| index == PRIMITIVE_RESTART_INDEX)
+			{
+				for i in 0..strip.len().saturating_sub(2)
+				{
+					// Every other triangle of a strip is wound the other way
+					if i % 2 == 0
+					{
+						triangles.push([strip[i], strip[i + 1], strip[i + 2]]);
+					}
+					else
+					{
+						triangles.push([strip[i + 1], strip[i], strip[i + 2]]);
+					}
+				}
+			}
+		}
+		else
+		{
+			triangles.extend(mesh.indices.chunks(3).map(|t| [t[0], t[1], t[2]]));
+		}
+
+		let mut rotated: Vec<[u16; 3]> = triangles
+			.iter()
+			.map(|t| {
+				let first = (0..3).min_by_key(|&i| t[i]).unwrap();
+				[t[first], t[(first + 1) % 3], t[(first + 2) % 3]]
+			})
+			.collect();
+		rotated.sort();
+		rotated
+	}
+
+	#[test]
+	fn strip_and_list_planes_have_the_same_triangles()
+	{
+		let list = Mesh::plane(2.0, 1.0, 5, false);
+		let strip = Mesh::plane(2.0, 1.0, 5, true);
+		assert_eq!(strip.topology(), vk::PrimitiveTopology::TriangleStrip);
+		assert!(strip.indices.len() < list.indices.len());
+		assert_eq!(strip.triangle_count(), list.triangle_count());
+		assert_eq!(sorted_triangles(&strip), sorted_triangles(&list));
+	}
+
+	#[test]
+	fn line_quads_cover_the_line_footprint()
+	{
+		let lines = Mesh::new_lines(vec![vertex(0.0, 0.0, 0.0), vertex(2.0, 0.0, 0.0)], vec![0, 1]);
+		let quads = lines.line_quads();
+		assert_eq!(quads.topology(), vk::PrimitiveTopology::TriangleList);
+		assert_eq!(quads.vertices.len(), 4);
+
+		// Moves the corners apart like phong.vert does, for a line 1 wide seen straight on
+		let half_width = 0.5;
+		let corners: Vec<Vector3<f32>> = quads
+			.vertices
+			.iter()
+			.map(|vertex| {
+				let pos = Vector3::from(vertex.pos);
+				let direction = (Vector3::from(vertex.normal) - pos).normalize();
+				pos + Vector3::new(-direction.y, direction.x, 0.0) * half_width * vertex.tex_uv[0]
+			})
+			.collect();
+		// Both triangles wind the same way and together cover the 2 by 1 rectangle around the line
+		let mut area = 0.0;
+		for triangle in quads.indices.chunks(3)
+		{
+			let corner = |i: usize| corners[triangle[i] as usize];
+			let doubled_area = (corner(1) - corner(0)).cross(corner(2) - corner(0)).z;
+			assert!(doubled_area > 0.0);
+			area += doubled_area / 2.0;
+		}
+		assert!((area - 2.0).abs() < 1e-5);
+		for corner in corners
+		{
+			assert!(corner.x == 0.0 || corner.x == 2.0);
+			assert!((corner.y.abs() - half_width).abs() < 1e-6);
+		}
+	}
+
+	#[test]
+	fn hexagon_fan_covers_the_hexagon()
+	{
+		let outline: Vec<[f32; 2]> = (0..6)
+			.map(|i| {
+				let angle = i as f32 * f32::consts::PI / 3.0;
+				[angle.cos(), angle.sin()]
+			})
+			.collect();
+		let fan = Mesh::polygon_fan(&outline, [1.0, 0.0, 0.0, 1.0]);
+		assert_eq!(fan.topology(), vk::PrimitiveTopology::TriangleFan);
+		assert_eq!(fan.vertices.len(), 6);
+		assert_eq!(fan.triangle_count(), 4);
+
+		let list = fan.fan_to_list();
+		assert_eq!(list.indices, vec![0, 1, 2, 0, 2, 3, 0, 3, 4, 0, 4, 5]);
+		// All counter-clockwise, together as large as the hexagon
+		let mut area = 0.0;
+		for triangle in list.indices.chunks(3)
+		{
+			let a = Vector3::from(list.vertices[triangle[0] as usize].pos);
+			let b = Vector3::from(list.vertices[triangle[1] as usize].pos);
+			let c = Vector3::from(list.vertices[triangle[2] as usize].pos);
+			let doubled_area = (b - a).cross(c - a).z;
+			assert!(doubled_area > 0.0);
+			area += doubled_area / 2.0;
+		}
+		assert!((area - 3.0 * 3.0f32.sqrt() / 2.0).abs() < 1e-5);
+	}
+
+	#[test]
+	fn bounding_radius_reaches_the_farthest_corner()
+	{
+		// Neither min nor max is the farthest corner, that is (-1, 2, 0)
+		let mesh = Mesh::new(
+			vec![vertex(-1.0, 0.0, 0.0), vertex(0.5, 2.0, 0.0), vertex(0.0, 0.0, 0.0)],
+			vec![0, 1, 2],
+		);
+		assert!((mesh.bounding_radius() - 5.0f32.sqrt()).abs() < 1e-6);
+	}
+}
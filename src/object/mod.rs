@@ -1,8 +1,19 @@
+mod animation;
 mod camera;
+mod controller;
 pub mod draw;
+mod material;
+pub mod mesh;
+mod tangents;
+mod turntable;
 
-pub use self::camera::Camera;
-pub use self::draw::DrawObject;
+pub use self::animation::{Animation, Keyframe};
+pub use self::camera::{Camera, SplineEndMode};
+pub use self::controller::CameraController;
+pub use self::draw::{DrawObject, MeshSource};
+pub use self::material::Material;
+pub use self::tangents::compute_tangents;
+pub use self::turntable::{Turntable, TurntableMode};
 
 use ash::vk;
 use cgmath::{Euler, Matrix4, Point3, Quaternion, Vector3};
@@ -10,11 +21,15 @@ use cgmath::prelude::*;
 
 pub trait Drawable
 {
+	/// Returns the model matrix of the given object.
+	///
+	/// The alpha value (in [0, 1]) blends between the previous and current simulation state.
+	fn get_model_matrix(&self, alpha: f32) -> Matrix4<f32>;
+
 	/// Draws the given object.
-	fn draw(
-		&self, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout, view_matrix: &Matrix4<f32>,
-		projection_matrix: &Matrix4<f32>,
-	);
+	///
+	/// The per-object uniforms must have been bound before calling this.
+	fn draw(&self, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout);
 }
 
 pub trait Position
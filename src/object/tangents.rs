@@ -0,0 +1,122 @@
+use cgmath::{Vector2, Vector3};
+use cgmath::prelude::*;
+
+/// Computes per-vertex tangents and bitangents for normal mapping from a triangle list.
+///
+/// Each triangle contributes the directions in which its texture coordinates increase, and every
+/// vertex averages the contributions of the triangles using it. The tangent is then made
+/// orthonormal to the normal (Gram-Schmidt), and the bitangent completes the basis while keeping the
+/// handedness of the texture mapping. Vertices without usable texture coordinates get an arbitrary
+/// basis around the normal.
+pub fn compute_tangents(
+	positions: &[[f32; 3]], normals: &[[f32; 3]], texcoords: &[[f32; 2]], indices: &[u16]
+) -> (Vec<[f32; 3]>, Vec<[f32; 3]>)
+{
+	debug_assert!(positions.len() == normals.len() && positions.len() == texcoords.len());
+	debug_assert!(indices.len() % 3 == 0);
+
+	let mut tangent_sums = vec![Vector3::new(0.0, 0.0, 0.0); positions.len()];
+	let mut bitangent_sums = vec![Vector3::new(0.0, 0.0, 0.0); positions.len()];
+	for triangle in indices.chunks(3)
+	{
+		let (a, b, c) = (triangle[0] as usize, triangle[1] as usize, triangle[2] as usize);
+		let edge1 = Vector3::from(positions[b]) - Vector3::from(positions[a]);
+		let edge2 = Vector3::from(positions[c]) - Vector3::from(positions[a]);
+		let delta_uv1 = Vector2::from(texcoords[b]) - Vector2::from(texcoords[a]);
+		let delta_uv2 = Vector2::from(texcoords[c]) - Vector2::from(texcoords[a]);
+
+		// Skip triangles whose texture coordinates don't span an area
+		let determinant = delta_uv1.x * delta_uv2.y - delta_uv2.x * delta_uv1.y;
+		if determinant.abs() <= 1e-12
+		{
+			continue;
+		}
+
+		let tangent = (edge1 * delta_uv2.y - edge2 * delta_uv1.y) / determinant;
+		let bitangent = (edge2 * delta_uv1.x - edge1 * delta_uv2.x) / determinant;
+		for &vertex in [a, b, c].iter()
+		{
+			tangent_sums[vertex] += tangent;
+			bitangent_sums[vertex] += bitangent;
+		}
+	}
+
+	let mut tangents = Vec::with_capacity(positions.len());
+	let mut bitangents = Vec::with_capacity(positions.len());
+	for i in 0..positions.len()
+	{
+		let normal = Vector3::from(normals[i]);
+		let mut tangent = tangent_sums[i] - normal * normal.dot(tangent_sums[i]);
+		if tangent.magnitude2() <= 1e-12
+		{
+			// Any direction perpendicular to the normal will do
+			let axis = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+			tangent = axis - normal * normal.dot(axis);
+		}
+		let tangent = tangent.normalize();
+
+		let mut bitangent = normal.cross(tangent);
+		// Mirrored texture coordinates flip the bitangent
+		if bitangent.dot(bitangent_sums[i]) < 0.0
+		{
+			bitangent = -bitangent;
+		}
+
+		tangents.push(tangent.into());
+		bitangents.push(bitangent.into());
+	}
+
+	(tangents, bitangents)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	/// Returns the tangents and bitangents of a unit quad in the xy-plane facing +z, with the texture
+	/// coordinates of each corner given by the function.
+	fn quad(uv: &Fn(f32, f32) -> [f32; 2]) -> (Vec<[f32; 3]>, Vec<[f32; 3]>)
+	{
+		let corners = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+		let positions: Vec<[f32; 3]> = corners.iter().map(|&(x, y)| [x, y, 0.0]).collect();
+		let normals = vec![[0.0, 0.0, 1.0]; 4];
+		let texcoords: Vec<[f32; 2]> = corners.iter().map(|&(x, y)| uv(x, y)).collect();
+		compute_tangents(&positions, &normals, &texcoords, &[0, 1, 2, 0, 2, 3])
+	}
+
+	fn assert_close(actual: [f32; 3], expected: [f32; 3])
+	{
+		assert!((Vector3::from(actual) - Vector3::from(expected)).magnitude() < 1e-5, "{:?}", actual);
+	}
+
+	#[test]
+	fn tangent_points_along_increasing_u()
+	{
+		let (tangents, bitangents) = quad(&|x, y| [x, y]);
+		for i in 0..4
+		{
+			assert_close(tangents[i], [1.0, 0.0, 0.0]);
+			assert_close(bitangents[i], [0.0, 1.0, 0.0]);
+		}
+
+		// The texture turned by 90 degrees, u now increases along +y
+		let (tangents, bitangents) = quad(&|x, y| [y, 1.0 - x]);
+		for i in 0..4
+		{
+			assert_close(tangents[i], [0.0, 1.0, 0.0]);
+			assert_close(bitangents[i], [-1.0, 0.0, 0.0]);
+		}
+	}
+
+	#[test]
+	fn mirrored_texture_flips_the_bitangent()
+	{
+		let (tangents, bitangents) = quad(&|x, y| [x, 1.0 - y]);
+		for i in 0..4
+		{
+			assert_close(tangents[i], [1.0, 0.0, 0.0]);
+			assert_close(bitangents[i], [0.0, -1.0, 0.0]);
+		}
+	}
+}
@@ -0,0 +1,112 @@
+use cgmath::{Deg, Point3, Quaternion, Vector3};
+use cgmath::prelude::*;
+use object::{Camera, Position};
+
+/// What the turntable turns.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TurntableMode
+{
+	/// Orbits the camera around the selected object, or the origin without a selection.
+	Camera,
+	/// Turns the selected object in place.
+	Object,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum TurntableState
+{
+	Off,
+	Running,
+	/// Stopped by manual camera movement until resumed.
+	Paused,
+}
+
+/// Slowly and steadily turns the camera or an object around an axis, for inspecting or recording a
+/// scene without touching the controls.
+pub struct Turntable
+{
+	state: TurntableState,
+	mode: TurntableMode,
+	axis: Vector3<f32>,
+	// Turn per second
+	speed: Deg<f32>,
+}
+
+impl Turntable
+{
+	/// Creates a turntable that is off. The speed is in degrees per second.
+	pub fn new(mode: TurntableMode, axis: [f32; 3], speed: f32) -> Turntable
+	{
+		let mut axis = Vector3::from(axis);
+		if axis.magnitude2() > 0.0
+		{
+			axis = axis.normalize();
+		}
+		else
+		{
+			warning!("The turntable axis has no length, turning around the Y axis instead");
+			axis = Vector3::unit_y();
+		}
+
+		Turntable {
+			state: TurntableState::Off,
+			mode: mode,
+			axis: axis,
+			speed: Deg(speed),
+		}
+	}
+
+	/// Starts or resumes the turntable, or stops it if it is running. Returns whether it runs now.
+	pub fn toggle(&mut self) -> bool
+	{
+		self.state = match self.state
+		{
+			TurntableState::Running => TurntableState::Off,
+			TurntableState::Off | TurntableState::Paused => TurntableState::Running,
+		};
+		self.is_running()
+	}
+
+	/// Pauses the turntable until the next toggle(). Returns true if it was running.
+	pub fn pause(&mut self) -> bool
+	{
+		if self.state != TurntableState::Running
+		{
+			return false;
+		}
+		self.state = TurntableState::Paused;
+		true
+	}
+
+	pub fn is_running(&self) -> bool
+	{
+		self.state == TurntableState::Running
+	}
+
+	pub fn mode(&self) -> TurntableMode
+	{
+		self.mode
+	}
+
+	/// Returns the turn for the given time in seconds, or None when the turntable isn't running.
+	///
+	/// Pass the frame time, so the speed doesn't depend on the frame rate.
+	pub fn rotation(&self, time: f32) -> Option<Quaternion<f32>>
+	{
+		if !self.is_running()
+		{
+			return None;
+		}
+		Some(Quaternion::from_axis_angle(self.axis, self.speed * time))
+	}
+
+	/// Orbits the camera around the focus point by the rotation, turning it along so the focus stays
+	/// at the same spot on screen.
+	pub fn orbit_camera(camera: &mut Camera, focus: Point3<f32>, rotation: Quaternion<f32>)
+	{
+		let offset = camera.get_position() - focus;
+		camera.set_position(focus + rotation.rotate_vector(offset));
+		let front = rotation.rotate_vector(camera.get_cam_front());
+		camera.face_direction(front);
+	}
+}
@@ -0,0 +1,228 @@
+use std::cell::RefCell;
+use std::f32;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Times the rest of the enclosing block under the given name, if profiling is enabled.
+///
+/// e.g. `scope!("scene.update");` at the start of a block.
+macro_rules! scope {
+	($name:expr) => {
+		let _scope_guard = ::profiler::ScopeGuard::new($name);
+	};
+}
+
+/// Prints the time from here to the end of the enclosing block, if teardown timing is enabled.
+///
+/// e.g. `teardown_scope!("MainPass");` at the start of a Drop impl.
+macro_rules! teardown_scope {
+	($name:expr) => {
+		let _teardown_guard = ::profiler::TeardownGuard::new($name);
+	};
+}
+
+/// Accumulated CPU timings of the named scopes, since the last report.
+struct Profiler
+{
+	enabled: bool,
+	/// Print the teardown scopes as they end.
+	teardown_timing: bool,
+	frames: u32,
+	/// Name, total time and number of times entered, in the order the scopes were first seen.
+	scopes: Vec<(&'static str, Duration, u32)>,
+}
+
+thread_local! {
+	static PROFILER: RefCell<Profiler> = RefCell::new(Profiler {
+		enabled: false,
+		teardown_timing: false,
+		frames: 0,
+		scopes: Vec::new(),
+	});
+}
+
+/// Records the time from its creation until it is dropped. Create it with scope!().
+pub struct ScopeGuard
+{
+	name: &'static str,
+	// None while profiling is disabled, so nothing is recorded
+	start: Option<Instant>,
+}
+
+impl ScopeGuard
+{
+	pub fn new(name: &'static str) -> ScopeGuard
+	{
+		let enabled = PROFILER.with(|profiler| profiler.borrow().enabled);
+		ScopeGuard {
+			name: name,
+			start: if enabled { Some(Instant::now()) } else { None },
+		}
+	}
+}
+
+impl Drop for ScopeGuard
+{
+	fn drop(&mut self)
+	{
+		if let Some(start) = self.start
+		{
+			let elapsed = start.elapsed();
+			PROFILER.with(|profiler| {
+				let mut profiler = profiler.borrow_mut();
+				match profiler.scopes.iter().position(|scope| scope.0 == self.name)
+				{
+					Some(i) =>
+					{
+						profiler.scopes[i].1 += elapsed;
+						profiler.scopes[i].2 += 1;
+					}
+					None => profiler.scopes.push((self.name, elapsed, 1)),
+				}
+			});
+		}
+	}
+}
+
+/// Prints the time from its creation until it is dropped, without adding it to the report. Create it
+/// with teardown_scope!().
+pub struct TeardownGuard
+{
+	name: &'static str,
+	// None while teardown timing is disabled
+	start: Option<Instant>,
+}
+
+impl TeardownGuard
+{
+	pub fn new(name: &'static str) -> TeardownGuard
+	{
+		let enabled = PROFILER.with(|profiler| profiler.borrow().teardown_timing);
+		TeardownGuard {
+			name: name,
+			start: if enabled { Some(Instant::now()) } else { None },
+		}
+	}
+}
+
+impl Drop for TeardownGuard
+{
+	fn drop(&mut self)
+	{
+		if let Some(start) = self.start
+		{
+			let elapsed = start.elapsed();
+			let elapsed_ms = elapsed.as_secs() as f32 * 1000.0 + elapsed.subsec_nanos() as f32 / 1_000_000.0;
+			println!("Teardown: {} took {:.3}ms", self.name, elapsed_ms);
+		}
+	}
+}
+
+/// Turns printing the teardown scopes on or off for the current thread, for finding slow cleanup on
+/// exit. This is independent of set_enabled().
+pub fn set_teardown_timing(enabled: bool)
+{
+	PROFILER.with(|profiler| profiler.borrow_mut().teardown_timing = enabled);
+}
+
+/// Turns recording on or off for the current thread. Scopes only cost a flag check while off.
+pub fn set_enabled(enabled: bool)
+{
+	PROFILER.with(|profiler| profiler.borrow_mut().enabled = enabled);
+}
+
+/// Returns true if scopes are being recorded on the current thread.
+pub fn is_enabled() -> bool
+{
+	PROFILER.with(|profiler| profiler.borrow().enabled)
+}
+
+/// Marks the end of a frame, the report averages the timings over the frames.
+pub fn end_frame()
+{
+	PROFILER.with(|profiler| {
+		let mut profiler = profiler.borrow_mut();
+		if profiler.enabled
+		{
+			profiler.frames += 1;
+		}
+	});
+}
+
+/// Returns the average time per frame spent in each scope since the last report, and starts over.
+pub fn take_report() -> String
+{
+	PROFILER.with(|profiler| {
+		let mut profiler = profiler.borrow_mut();
+		let frames = profiler.frames.max(1);
+		let lines: Vec<String> = profiler
+			.scopes
+			.iter()
+			.map(|&(name, total, count)| {
+				let total_ms = total.as_secs() as f32 * 1000.0 + total.subsec_nanos() as f32 / 1_000_000.0;
+				format!("  {}: {:.3}ms ({} calls)", name, total_ms / frames as f32, count)
+			})
+			.collect();
+		profiler.frames = 0;
+		profiler.scopes.clear();
+		format!("CPU time per frame over {} frames:\n{}", frames, lines.join("\n"))
+	})
+}
+
+/// Average, minimum and maximum of a series of frame times.
+pub struct FrameTimes
+{
+	count: u32,
+	// All in seconds
+	total: f32,
+	min: f32,
+	max: f32,
+}
+
+impl FrameTimes
+{
+	pub fn new() -> FrameTimes
+	{
+		FrameTimes {
+			count: 0,
+			total: 0.0,
+			min: f32::MAX,
+			max: 0.0,
+		}
+	}
+
+	/// Adds a frame time in seconds.
+	pub fn add(&mut self, secs: f32)
+	{
+		self.count += 1;
+		self.total += secs;
+		self.min = self.min.min(secs);
+		self.max = self.max.max(secs);
+	}
+
+	/// Returns the number of frame times added.
+	pub fn count(&self) -> u32
+	{
+		self.count
+	}
+}
+
+impl fmt::Display for FrameTimes
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		if self.count == 0
+		{
+			return write!(f, "no frames");
+		}
+		let average = self.total / self.count as f32;
+		write!(
+			f,
+			"frametime avg {:.2}ms ({:.0} FPS), min {:.2}ms, max {:.2}ms",
+			average * 1000.0,
+			1.0 / average,
+			self.min * 1000.0,
+			self.max * 1000.0
+		)
+	}
+}
@@ -0,0 +1,156 @@
+use image;
+use std::path::Path;
+
+/// How much a rendered frame may differ from its reference and still pass.
+///
+/// Drivers may round differently, so single pixels are allowed to be slightly off, and a small
+/// fraction of the pixels may be off by more (e.g. along triangle edges).
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerance
+{
+	/// Largest difference in any channel for a pixel to count as matching.
+	pub pixel_threshold: u8,
+	/// Fraction of the pixels allowed to not match, between 0 and 1.
+	pub max_failing_fraction: f32,
+}
+
+impl Default for Tolerance
+{
+	fn default() -> Tolerance
+	{
+		Tolerance {
+			pixel_threshold: 2,
+			max_failing_fraction: 0.001,
+		}
+	}
+}
+
+/// Returns a 64 bit FNV-1a hash of the pixels.
+///
+/// Only identical frames have the same hash, so use compare_pixels() to allow for driver differences.
+pub fn frame_hash(pixels: &[u8]) -> u64
+{
+	pixels.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Returns the fraction of the RGBA8 pixels differing by more than the threshold in any channel.
+pub fn compare_pixels(actual: &[u8], reference: &[u8], pixel_threshold: u8) -> f32
+{
+	debug_assert!(actual.len() == reference.len());
+	let pixel_count = actual.len() / 4;
+	if pixel_count == 0
+	{
+		return 0.0;
+	}
+
+	let failing = actual
+		.chunks(4)
+		.zip(reference.chunks(4))
+		.filter(|&(a, b)| a.iter().zip(b.iter()).any(|(&x, &y)| (x as i16 - y as i16).abs() > pixel_threshold as i16))
+		.count();
+	failing as f32 / pixel_count as f32
+}
+
+/// Compares a rendered RGBA8 frame against the reference image at the given path.
+///
+/// If there is no reference yet, the frame is saved as the new reference. Returns a description of
+/// the result, or an error if the frame doesn't match.
+pub fn check_frame(
+	reference_path: &str, width: u32, height: u32, pixels: &[u8], tolerance: &Tolerance
+) -> Result<String, String>
+{
+	let hash = frame_hash(pixels);
+	if !Path::new(reference_path).exists()
+	{
+		image::save_buffer(reference_path, pixels, width, height, image::ColorType::RGBA(8))
+			.map_err(|e| format!("Failed to save the reference image {}: {}", reference_path, e))?;
+		return Ok(format!("Saved new reference image {} (hash {:016x})", reference_path, hash));
+	}
+
+	let reference = image::open(reference_path)
+		.map_err(|e| format!("Failed to load the reference image {}: {}", reference_path, e))?
+		.to_rgba();
+	if reference.dimensions() != (width, height)
+	{
+		return Err(format!(
+			"Frame is {}x{}, but the reference image {} is {}x{}",
+			width,
+			height,
+			reference_path,
+			reference.width(),
+			reference.height()
+		));
+	}
+
+	let reference_pixels = reference.into_raw();
+	if frame_hash(&reference_pixels) == hash
+	{
+		return Ok(format!("Frame matches {} exactly (hash {:016x})", reference_path, hash));
+	}
+
+	let failing = compare_pixels(pixels, &reference_pixels, tolerance.pixel_threshold);
+	if failing > tolerance.max_failing_fraction
+	{
+		Err(format!(
+			"Frame differs from {}: {:.3}% of the pixels are off by more than {} (allowed {:.3}%)",
+			reference_path,
+			failing * 100.0,
+			tolerance.pixel_threshold,
+			tolerance.max_failing_fraction * 100.0
+		))
+	}
+	else
+	{
+		Ok(format!(
+			"Frame matches {} within tolerance, {:.3}% of the pixels differ (hash {:016x})",
+			reference_path,
+			failing * 100.0,
+			hash
+		))
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::env;
+	use std::fs;
+
+	/// A fixed 4x4 RGBA8 frame.
+	fn pixels() -> Vec<u8>
+	{
+		(0..4 * 4 * 4).map(|i| (i * 37 % 256) as u8).collect()
+	}
+
+	#[test]
+	fn frame_hash_is_stable()
+	{
+		// The FNV-1a reference values, so hashes stay comparable with earlier runs
+		assert_eq!(frame_hash(&[]), 0xcbf29ce484222325);
+		assert_eq!(frame_hash(b"a"), 0xaf63dc4c8601ec8c);
+		assert_eq!(frame_hash(b"foobar"), 0x85944171f73967e8);
+		assert_eq!(frame_hash(&pixels()), 0x21c600b58bb4e465);
+
+		let mut changed = pixels();
+		changed[5] ^= 1;
+		assert!(frame_hash(&changed) != frame_hash(&pixels()));
+	}
+
+	#[test]
+	fn check_frame_saves_then_matches_the_reference()
+	{
+		let path = env::temp_dir().join("project_peril_regression_test.png");
+		let path = path.to_str().unwrap();
+		let _ = fs::remove_file(path);
+
+		let pixels = pixels();
+		assert!(check_frame(path, 4, 4, &pixels, &Tolerance::default()).unwrap().starts_with("Saved"));
+		assert!(check_frame(path, 4, 4, &pixels, &Tolerance::default()).unwrap().contains("exactly"));
+
+		let mut wrong = pixels.clone();
+		wrong[0] = wrong[0].wrapping_add(100);
+		assert!(check_frame(path, 4, 4, &wrong, &Tolerance::default()).is_err());
+		fs::remove_file(path).unwrap();
+	}
+}
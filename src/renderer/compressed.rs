@@ -0,0 +1,322 @@
+use ash::vk;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+const DDS_MAGIC: &'static [u8] = b"DDS ";
+const DDS_HEADER_SIZE: usize = 128;
+const DDS_DX10_HEADER_SIZE: usize = 20;
+
+const KTX_MAGIC: &'static [u8] = &[0xAB, 0x4B, 0x54, 0x58, 0x20, 0x31, 0x31, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+const KTX_HEADER_SIZE: usize = 64;
+const KTX_ENDIANNESS_LE: u32 = 0x04030201;
+
+/// Block compressed image data with all of its mip levels, ready to be copied into an image.
+pub struct CompressedImage
+{
+	pub format: vk::Format,
+	pub width: u32,
+	pub height: u32,
+	pub data: Vec<u8>,
+	/// Byte offset of each mip level in data, starting with the full size level.
+	pub mip_offsets: Vec<vk::DeviceSize>,
+}
+
+/// Reads a little endian u32 at the given byte offset.
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, String>
+{
+	if offset.checked_add(4).map_or(true, |end| end > bytes.len())
+	{
+		return Err(String::from("Unexpected end of file"));
+	}
+
+	Ok(
+		bytes[offset] as u32 | (bytes[offset + 1] as u32) << 8 | (bytes[offset + 2] as u32) << 16
+			| (bytes[offset + 3] as u32) << 24,
+	)
+}
+
+/// Returns the size in bytes of one 4x4 block of the format.
+fn block_size(format: vk::Format) -> usize
+{
+	match format
+	{
+		vk::Format::Bc1RgbaUnormBlock | vk::Format::Bc1RgbaSrgbBlock => 8,
+		_ => 16,
+	}
+}
+
+/// Returns the size in bytes of a mip level of the given dimensions, or None if it doesn't fit in a usize.
+fn level_size(format: vk::Format, width: u32, height: u32) -> Option<usize>
+{
+	let blocks_x = ((width as u64 + 3) / 4).max(1);
+	let blocks_y = ((height as u64 + 3) / 4).max(1);
+	let size = blocks_x.checked_mul(blocks_y)?.checked_mul(block_size(format) as u64)?;
+	if size > usize::max_value() as u64
+	{
+		return None;
+	}
+	Some(size as usize)
+}
+
+/// Returns the number of mip levels down to 1x1, the most a file can sensibly have.
+fn max_mip_count(width: u32, height: u32) -> u32
+{
+	32 - width.max(height).leading_zeros()
+}
+
+/// Returns true if the path has the extension of a file CompressedImage::load() reads.
+pub fn is_compressed_path(path: &str) -> bool
+{
+	match Path::new(path).extension().and_then(|extension| extension.to_str())
+	{
+		Some(extension) => extension.eq_ignore_ascii_case("dds") || extension.eq_ignore_ascii_case("ktx"),
+		None => false,
+	}
+}
+
+/// Maps a DXGI_FORMAT from a DX10 DDS header to a Vulkan format.
+fn format_from_dxgi(dxgi_format: u32) -> Option<vk::Format>
+{
+	match dxgi_format
+	{
+		71 => Some(vk::Format::Bc1RgbaUnormBlock),
+		72 => Some(vk::Format::Bc1RgbaSrgbBlock),
+		77 => Some(vk::Format::Bc3UnormBlock),
+		78 => Some(vk::Format::Bc3SrgbBlock),
+		83 => Some(vk::Format::Bc5UnormBlock),
+		98 => Some(vk::Format::Bc7UnormBlock),
+		99 => Some(vk::Format::Bc7SrgbBlock),
+		_ => None,
+	}
+}
+
+/// Maps an OpenGL internal format from a KTX header to a Vulkan format.
+fn format_from_gl(gl_internal_format: u32) -> Option<vk::Format>
+{
+	match gl_internal_format
+	{
+		0x83F0 | 0x83F1 => Some(vk::Format::Bc1RgbaUnormBlock),
+		0x8C4C | 0x8C4D => Some(vk::Format::Bc1RgbaSrgbBlock),
+		0x83F3 => Some(vk::Format::Bc3UnormBlock),
+		0x8C4F => Some(vk::Format::Bc3SrgbBlock),
+		0x8DBD => Some(vk::Format::Bc5UnormBlock),
+		0x8E8C => Some(vk::Format::Bc7UnormBlock),
+		0x8E8D => Some(vk::Format::Bc7SrgbBlock),
+		_ => None,
+	}
+}
+
+/// Parses a DDS file. Only 2D textures without arrays or cube faces are supported.
+fn parse_dds(bytes: &[u8]) -> Result<CompressedImage, String>
+{
+	if !bytes.starts_with(DDS_MAGIC) || bytes.len() < DDS_HEADER_SIZE
+	{
+		return Err(String::from("Truncated DDS header"));
+	}
+
+	let height = read_u32(bytes, 12)?;
+	let width = read_u32(bytes, 16)?;
+	if width == 0 || height == 0
+	{
+		return Err(format!("Invalid size {}x{}", width, height));
+	}
+	// Levels past 1x1 don't exist, a larger count is a broken header
+	let mip_count = read_u32(bytes, 28)?.max(1).min(max_mip_count(width, height));
+	let four_cc = &bytes[84..88];
+
+	let (format, data_start) = match four_cc
+	{
+		b"DXT1" => (vk::Format::Bc1RgbaUnormBlock, DDS_HEADER_SIZE),
+		b"DXT5" => (vk::Format::Bc3UnormBlock, DDS_HEADER_SIZE),
+		b"ATI2" | b"BC5U" => (vk::Format::Bc5UnormBlock, DDS_HEADER_SIZE),
+		b"DX10" =>
+		{
+			let dxgi_format = read_u32(bytes, DDS_HEADER_SIZE)?;
+			match format_from_dxgi(dxgi_format)
+			{
+				Some(format) => (format, DDS_HEADER_SIZE + DDS_DX10_HEADER_SIZE),
+				None => return Err(format!("Unsupported DXGI format {}", dxgi_format)),
+			}
+		}
+		_ => return Err(format!("Unsupported FourCC {:?}", String::from_utf8_lossy(four_cc))),
+	};
+
+	// Mip levels are stored tightly packed, largest first
+	let mut mip_offsets = Vec::with_capacity(mip_count as usize);
+	let mut offset: usize = 0;
+	for level in 0..mip_count
+	{
+		mip_offsets.push(offset as vk::DeviceSize);
+		offset = level_size(format, (width >> level).max(1), (height >> level).max(1))
+			.and_then(|size| offset.checked_add(size))
+			.ok_or_else(|| format!("Invalid size {}x{}", width, height))?;
+	}
+	let data_end = match data_start.checked_add(offset)
+	{
+		Some(data_end) if data_end <= bytes.len() => data_end,
+		_ => return Err(String::from("Unexpected end of file")),
+	};
+
+	Ok(CompressedImage {
+		format: format,
+		width: width,
+		height: height,
+		data: bytes[data_start..data_end].to_vec(),
+		mip_offsets: mip_offsets,
+	})
+}
+
+/// Parses a KTX (version 1) file. Only little endian 2D textures without arrays or cube faces are
+/// supported.
+fn parse_ktx(bytes: &[u8]) -> Result<CompressedImage, String>
+{
+	if !bytes.starts_with(KTX_MAGIC) || bytes.len() < KTX_HEADER_SIZE
+	{
+		return Err(String::from("Truncated KTX header"));
+	}
+
+	if read_u32(bytes, 12)? != KTX_ENDIANNESS_LE
+	{
+		return Err(String::from("Big endian KTX files are not supported"));
+	}
+
+	let gl_internal_format = read_u32(bytes, 28)?;
+	let width = read_u32(bytes, 36)?;
+	let height = read_u32(bytes, 40)?;
+	if width == 0 || height == 0
+	{
+		return Err(format!("Invalid size {}x{}", width, height));
+	}
+	let mip_count = read_u32(bytes, 56)?.max(1).min(max_mip_count(width, height));
+	let key_value_bytes = read_u32(bytes, 60)? as usize;
+
+	let format = match format_from_gl(gl_internal_format)
+	{
+		Some(format) => format,
+		None => return Err(format!("Unsupported glInternalFormat {:#X}", gl_internal_format)),
+	};
+
+	// Every mip level is prefixed by its size and padded to 4 bytes
+	let mut data = Vec::new();
+	let mut mip_offsets = Vec::with_capacity(mip_count as usize);
+	let mut offset = KTX_HEADER_SIZE.saturating_add(key_value_bytes);
+	for _ in 0..mip_count
+	{
+		let image_size = read_u32(bytes, offset)? as usize;
+		offset += 4;
+		let image_end = match offset.checked_add(image_size)
+		{
+			Some(image_end) if image_end <= bytes.len() => image_end,
+			_ => return Err(String::from("Unexpected end of file")),
+		};
+
+		mip_offsets.push(data.len() as vk::DeviceSize);
+		data.extend_from_slice(&bytes[offset..image_end]);
+		// Padding past the end of the file only matters if another level follows, which read_u32 checks
+		offset = image_end.saturating_add(3) & !3;
+	}
+
+	Ok(CompressedImage {
+		format: format,
+		width: width,
+		height: height,
+		data: data,
+		mip_offsets: mip_offsets,
+	})
+}
+
+impl CompressedImage
+{
+	/// Loads a block compressed image from a DDS or KTX file, detected by the file's magic number.
+	pub fn load(path: &str) -> Result<CompressedImage, String>
+	{
+		let mut bytes = Vec::new();
+		File::open(Path::new(path))
+			.and_then(|mut file| file.read_to_end(&mut bytes))
+			.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+		let image = if bytes.starts_with(DDS_MAGIC)
+		{
+			parse_dds(&bytes)
+		}
+		else if bytes.starts_with(KTX_MAGIC)
+		{
+			parse_ktx(&bytes)
+		}
+		else
+		{
+			Err(String::from("Not a DDS or KTX file"))
+		};
+
+		image.map_err(|e| format!("Failed to load {}: {}", path, e))
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn write_u32(bytes: &mut [u8], offset: usize, value: u32)
+	{
+		for i in 0..4
+		{
+			bytes[offset + i] = (value >> (8 * i)) as u8;
+		}
+	}
+
+	/// Returns a DXT1 DDS file header followed by data_size bytes of block data.
+	fn dds(width: u32, height: u32, mip_count: u32, data_size: usize) -> Vec<u8>
+	{
+		let mut bytes = vec![0; DDS_HEADER_SIZE + data_size];
+		bytes[0..4].copy_from_slice(DDS_MAGIC);
+		write_u32(&mut bytes, 12, height);
+		write_u32(&mut bytes, 16, width);
+		write_u32(&mut bytes, 28, mip_count);
+		bytes[84..88].copy_from_slice(b"DXT1");
+		bytes
+	}
+
+	#[test]
+	fn dds_mip_levels_are_packed()
+	{
+		// 8x8, 4x4, 2x2 and 1x1, each at least one 8 byte block
+		let image = parse_dds(&dds(8, 8, 4, 56)).unwrap();
+		assert_eq!(image.format, vk::Format::Bc1RgbaUnormBlock);
+		assert_eq!(image.mip_offsets, vec![0, 32, 40, 48]);
+		assert_eq!(image.data.len(), 56);
+	}
+
+	#[test]
+	fn dds_mip_count_is_clamped()
+	{
+		let image = parse_dds(&dds(8, 8, 1000, 56)).unwrap();
+		assert_eq!(image.mip_offsets.len(), 4);
+	}
+
+	#[test]
+	fn broken_dds_files_are_errors()
+	{
+		assert!(parse_dds(b"DDS ").is_err());
+		assert!(parse_dds(&dds(8, 8, 1, 32)[..100]).is_err());
+		assert!(parse_dds(&dds(8, 8, 4, 55)).is_err());
+		assert!(parse_dds(&dds(0, 8, 1, 32)).is_err());
+		assert!(parse_dds(&dds(u32::max_value(), u32::max_value(), 32, 32)).is_err());
+	}
+
+	#[test]
+	fn truncated_ktx_is_an_error()
+	{
+		assert!(parse_ktx(KTX_MAGIC).is_err());
+	}
+
+	#[test]
+	fn compressed_paths_are_detected()
+	{
+		assert!(is_compressed_path("textures/brick.dds"));
+		assert!(is_compressed_path("textures/brick.KTX"));
+		assert!(!is_compressed_path("textures/brick.png"));
+		assert!(!is_compressed_path("textures/dds"));
+	}
+}
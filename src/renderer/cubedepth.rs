@@ -0,0 +1,524 @@
+use ash::Device;
+use ash::util::Align;
+use ash::version::{DeviceV1_0, V1_0};
+use ash::vk;
+use cgmath::{perspective, Deg, Matrix4, Point3, SquareMatrix, Vector3};
+use std::mem::size_of;
+use std::ptr;
+use std::rc::Rc;
+
+use renderer::{RenderState, Texture};
+use renderer::graph::FrameGraph;
+use renderer::mainpass::{align_up, light_block_size};
+
+/// Number of faces, and thereby array layers, of a cube map.
+pub const CUBE_FACES: usize = 6;
+/// How long render() waits for the previous frame's faces in nanoseconds, before skipping them a frame.
+const FENCE_TIMEOUT: u64 = 1_000_000_000;
+
+/// Returns the view matrices looking out from the position through each cube face, in the layer
+/// order +x, -x, +y, -y, +z, -z.
+pub fn cube_face_views(position: Point3<f32>) -> [Matrix4<f32>; CUBE_FACES]
+{
+	let faces = [
+		(Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0)),
+		(Vector3::new(-1.0, 0.0, 0.0), Vector3::new(0.0, -1.0, 0.0)),
+		(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 0.0, 1.0)),
+		(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 0.0, -1.0)),
+		(Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, -1.0, 0.0)),
+		(Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.0, -1.0, 0.0)),
+	];
+	let mut views = [Matrix4::identity(); CUBE_FACES];
+	for (view, &(direction, up)) in views.iter_mut().zip(faces.iter())
+	{
+		*view = Matrix4::look_at(position, position + direction, up);
+	}
+
+	views
+}
+
+/// Returns the projection covering one cube face between the near and far planes.
+///
+/// Unlike the main projection Y isn't flipped, as the faces look with -Y up to match the cube map
+/// layout. This mirrors the winding, so the sides facing away from the position are drawn, which keeps
+/// surfaces from shadowing themselves.
+pub fn cube_face_projection(near: f32, far: f32) -> Matrix4<f32>
+{
+	// Maps the GL depth range of [-1, 1] to [0, 1]
+	let vulkan_depth = Matrix4::new(1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.5, 1.0);
+	vulkan_depth * perspective(Deg(90.0), 1.0, near, far)
+}
+
+/// A cube depth texture, rendered one face at a time with the depth only pipeline of the MainPass.
+///
+/// Every face has its own view-projection matrix in a shared uniform buffer, bound through a
+/// descriptor set using the MainPass view matrix layout. This way the MainPass pipeline layout and
+/// per-object uniforms are reused as they are.
+pub struct CubeDepthTarget
+{
+	pub texture: Texture,
+	pub format: vk::Format,
+	face_views: Vec<vk::ImageView>,
+	framebuffers: Vec<vk::Framebuffer>,
+	renderpass: vk::RenderPass,
+	pipeline: vk::Pipeline,
+	commandbuffer: vk::CommandBuffer,
+	// Signalled when the last submission is done, so the command buffer and matrices can be reused
+	fence: vk::Fence,
+	// Set by render() until take_recorded() hands the command buffer to end_frame()
+	recorded: bool,
+	scissor: vk::Rect2D,
+
+	view_ub: vk::Buffer,
+	view_ub_mem: vk::DeviceMemory,
+	view_ub_stride: vk::DeviceSize,
+	view_ds: Vec<vk::DescriptorSet>,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device<V1_0>>,
+}
+
+impl CubeDepthTarget
+{
+	/// Creates a depth only renderpass. The layers stay in the depth attachment layout, the frame graph
+	/// transitions them for sampling.
+	pub fn create_renderpass(rs: &RenderState, depth_format: vk::Format) -> vk::RenderPass
+	{
+		let depth_attachment = vk::AttachmentDescription {
+			format: depth_format,
+			flags: vk::AttachmentDescriptionFlags::empty(),
+			samples: vk::SAMPLE_COUNT_1_BIT,
+			load_op: vk::AttachmentLoadOp::Clear,
+			store_op: vk::AttachmentStoreOp::Store,
+			stencil_load_op: vk::AttachmentLoadOp::DontCare,
+			stencil_store_op: vk::AttachmentStoreOp::DontCare,
+			initial_layout: vk::ImageLayout::DepthStencilAttachmentOptimal,
+			final_layout: vk::ImageLayout::DepthStencilAttachmentOptimal,
+		};
+		let depth_attachment_ref = vk::AttachmentReference {
+			attachment: 0,
+			layout: vk::ImageLayout::DepthStencilAttachmentOptimal,
+		};
+		let subpass = vk::SubpassDescription {
+			color_attachment_count: 0,
+			p_color_attachments: ptr::null(),
+			p_depth_stencil_attachment: &depth_attachment_ref,
+			flags: Default::default(),
+			pipeline_bind_point: vk::PipelineBindPoint::Graphics,
+			input_attachment_count: 0,
+			p_input_attachments: ptr::null(),
+			p_resolve_attachments: ptr::null(),
+			preserve_attachment_count: 0,
+			p_preserve_attachments: ptr::null(),
+		};
+		let renderpass_create_info = vk::RenderPassCreateInfo {
+			s_type: vk::StructureType::RenderPassCreateInfo,
+			p_next: ptr::null(),
+			flags: Default::default(),
+			attachment_count: 1,
+			p_attachments: &depth_attachment,
+			subpass_count: 1,
+			p_subpasses: &subpass,
+			dependency_count: 0,
+			p_dependencies: ptr::null(),
+		};
+		let renderpass;
+		unsafe {
+			renderpass = rs.device.create_render_pass(&renderpass_create_info, None).unwrap();
+		}
+
+		renderpass
+	}
+
+	/// Creates the cube depth texture with a framebuffer and view matrix descriptor set per face.
+	///
+	/// The descriptor sets are allocated from the MainPass pool, and share its light uniform buffer.
+	pub fn new(
+		rs: &RenderState, size: u32, depth_format: vk::Format, renderpass: vk::RenderPass, pipeline: vk::Pipeline,
+		descriptor_pool: vk::DescriptorPool, view_matrix_layout: vk::DescriptorSetLayout, light_ub: vk::Buffer,
+	) -> CubeDepthTarget
+	{
+		let extent = vk::Extent3D {
+			width: size,
+			height: size,
+			depth: 1,
+		};
+		let texture = rs.create_texture(
+			extent,
+			vk::ImageType::Type2d,
+			vk::ImageViewType::Cube,
+			depth_format,
+			vk::IMAGE_ASPECT_DEPTH_BIT,
+			vk::IMAGE_USAGE_DEPTH_STENCIL_ATTACHMENT_BIT | vk::IMAGE_USAGE_SAMPLED_BIT,
+			vk::ACCESS_SHADER_READ_BIT,
+			vk::ImageLayout::ShaderReadOnlyOptimal,
+			vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+			None,
+		);
+
+		// Each face is rendered through a view of its own layer
+		let mut face_views = Vec::with_capacity(CUBE_FACES);
+		let mut framebuffers = Vec::with_capacity(CUBE_FACES);
+		for face in 0..CUBE_FACES
+		{
+			let create_view_info = vk::ImageViewCreateInfo {
+				s_type: vk::StructureType::ImageViewCreateInfo,
+				p_next: ptr::null(),
+				flags: Default::default(),
+				view_type: vk::ImageViewType::Type2d,
+				format: depth_format,
+				components: vk::ComponentMapping {
+					r: vk::ComponentSwizzle::R,
+					g: vk::ComponentSwizzle::G,
+					b: vk::ComponentSwizzle::B,
+					a: vk::ComponentSwizzle::A,
+				},
+				subresource_range: vk::ImageSubresourceRange {
+					aspect_mask: vk::IMAGE_ASPECT_DEPTH_BIT,
+					base_mip_level: 0,
+					level_count: 1,
+					base_array_layer: face as u32,
+					layer_count: 1,
+				},
+				image: texture.image,
+			};
+			let face_view;
+			unsafe {
+				face_view = rs.device.create_image_view(&create_view_info, None).unwrap();
+			}
+			let frame_buffer_create_info = vk::FramebufferCreateInfo {
+				s_type: vk::StructureType::FramebufferCreateInfo,
+				p_next: ptr::null(),
+				flags: Default::default(),
+				render_pass: renderpass,
+				attachment_count: 1,
+				p_attachments: &face_view,
+				width: size,
+				height: size,
+				layers: 1,
+			};
+			let framebuffer;
+			unsafe {
+				framebuffer = rs.device.create_framebuffer(&frame_buffer_create_info, None).unwrap();
+			}
+			face_views.push(face_view);
+			framebuffers.push(framebuffer);
+		}
+
+		// One view-projection matrix per face
+		let view_ub_stride = align_up(
+			size_of::<Matrix4<f32>>() as u64,
+			rs.device_properties.limits.min_uniform_buffer_offset_alignment,
+		);
+		let (view_ub, view_ub_mem) = rs.create_buffer(
+			vk::BUFFER_USAGE_UNIFORM_BUFFER_BIT,
+			vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT,
+			view_ub_stride * CUBE_FACES as u64,
+		);
+		let layouts = [view_matrix_layout; CUBE_FACES];
+		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
+			s_type: vk::StructureType::DescriptorSetAllocateInfo,
+			p_next: ptr::null(),
+			descriptor_pool: descriptor_pool,
+			descriptor_set_count: layouts.len() as u32,
+			p_set_layouts: layouts.as_ptr(),
+		};
+		let view_ds;
+		unsafe {
+			view_ds = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap();
+		}
+		let light_ub_descriptor = vk::DescriptorBufferInfo {
+			buffer: light_ub,
+			offset: 0,
+			range: light_block_size(),
+		};
+		for (face, &ds) in view_ds.iter().enumerate()
+		{
+			let view_ub_descriptor = vk::DescriptorBufferInfo {
+				buffer: view_ub,
+				offset: view_ub_stride * face as u64,
+				range: size_of::<Matrix4<f32>>() as u64,
+			};
+			let write_desc_sets = [
+				vk::WriteDescriptorSet {
+					s_type: vk::StructureType::WriteDescriptorSet,
+					p_next: ptr::null(),
+					dst_set: ds,
+					dst_binding: 0,
+					dst_array_element: 0,
+					descriptor_count: 1,
+					descriptor_type: vk::DescriptorType::UniformBuffer,
+					p_image_info: ptr::null(),
+					p_buffer_info: &view_ub_descriptor,
+					p_texel_buffer_view: ptr::null(),
+				},
+				vk::WriteDescriptorSet {
+					s_type: vk::StructureType::WriteDescriptorSet,
+					p_next: ptr::null(),
+					dst_set: ds,
+					dst_binding: 1,
+					dst_array_element: 0,
+					descriptor_count: 1,
+					descriptor_type: vk::DescriptorType::UniformBuffer,
+					p_image_info: ptr::null(),
+					p_buffer_info: &light_ub_descriptor,
+					p_texel_buffer_view: ptr::null(),
+				},
+			];
+			unsafe {
+				rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+			}
+		}
+
+		let command_buffer_allocate_info = vk::CommandBufferAllocateInfo {
+			s_type: vk::StructureType::CommandBufferAllocateInfo,
+			p_next: ptr::null(),
+			command_buffer_count: 1,
+			command_pool: rs.commandpool,
+			level: vk::CommandBufferLevel::Primary,
+		};
+		let commandbuffers;
+		unsafe {
+			commandbuffers = rs.device.allocate_command_buffers(&command_buffer_allocate_info).unwrap();
+		}
+		let fence_create_info = vk::FenceCreateInfo {
+			s_type: vk::StructureType::FenceCreateInfo,
+			p_next: ptr::null(),
+			flags: vk::FENCE_CREATE_SIGNALED_BIT,
+		};
+		let fence;
+		unsafe {
+			fence = rs.device.create_fence(&fence_create_info, None).expect("Failed to create cube depth fence");
+		}
+
+		CubeDepthTarget {
+			texture: texture,
+			format: depth_format,
+			face_views: face_views,
+			framebuffers: framebuffers,
+			renderpass: renderpass,
+			pipeline: pipeline,
+			commandbuffer: commandbuffers[0],
+			fence: fence,
+			recorded: false,
+			scissor: vk::Rect2D {
+				offset: vk::Offset2D {
+					x: 0,
+					y: 0,
+				},
+				extent: vk::Extent2D {
+					width: size,
+					height: size,
+				},
+			},
+
+			view_ub: view_ub,
+			view_ub_mem: view_ub_mem,
+			view_ub_stride: view_ub_stride,
+			view_ds: view_ds,
+
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	/// Returns the view of a single face, which is rendered to and can be sampled as a 2D image.
+	pub fn face_view(&self, face: usize) -> vk::ImageView
+	{
+		self.face_views[face]
+	}
+
+	/// Records the depth of every face, to be submitted with the frame, see take_recorded().
+	///
+	/// The draw function is called once per face and should record the object draws, see
+	/// MainPass::render_cube_depth(). The texture is transitioned as planned by the frame graph. Waits
+	/// for the previous submission first, as it uses the same command buffer and matrices, and fails if
+	/// that takes longer than FENCE_TIMEOUT.
+	pub fn render<F>(
+		&mut self, rs: &RenderState, frame: &FrameGraph, pipeline_layout: vk::PipelineLayout,
+		views: &[Matrix4<f32>; CUBE_FACES], projection: &Matrix4<f32>, draw: F,
+	) -> Result<(), String>
+	where
+		F: Fn(vk::CommandBuffer),
+	{
+		// Normally long done, as the present pass waits for the frame it was submitted with
+		unsafe {
+			match rs.device.wait_for_fences(&[self.fence], true, FENCE_TIMEOUT)
+			{
+				Ok(_) => (),
+				Err(vk::Result::Timeout) =>
+				{
+					return Err(String::from("Skipped the cube depth, the last faces are still rendering"));
+				}
+				Err(e) => return Err(format!("Wait for cube depth fence failed: {:?}", e)),
+			}
+		}
+
+		let view_projections: Vec<Matrix4<f32>> = views.iter().map(|view| projection * view).collect();
+		let size = self.view_ub_stride * CUBE_FACES as u64;
+		unsafe {
+			let mem_ptr = rs.device
+				.map_memory(self.view_ub_mem, 0, size, vk::MemoryMapFlags::empty())
+				.expect("Failed to map cube view matrix memory");
+			// Align pads each element to the stride
+			let mut mem_align = Align::new(mem_ptr, self.view_ub_stride, size);
+			mem_align.copy_from_slice(&view_projections);
+			rs.device.unmap_memory(self.view_ub_mem);
+		}
+
+		let cmd_buf_begin_info = vk::CommandBufferBeginInfo {
+			s_type: vk::StructureType::CommandBufferBeginInfo,
+			p_next: ptr::null(),
+			p_inheritance_info: ptr::null(),
+			flags: vk::COMMAND_BUFFER_USAGE_ONE_TIME_SUBMIT_BIT,
+		};
+		let cmd_buf = self.commandbuffer;
+		unsafe {
+			rs.device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
+		}
+		rs.push_debug_label(cmd_buf, "Cube depth", [0.5, 0.0, 1.0, 1.0]);
+		frame.graph.record_transitions(rs, frame.cube_pass, cmd_buf, &mut [(frame.cube_depth, &mut self.texture)]);
+
+		let viewport = vk::Viewport {
+			x: 0.0,
+			y: 0.0,
+			width: self.scissor.extent.width as f32,
+			height: self.scissor.extent.height as f32,
+			min_depth: 0.0,
+			max_depth: 1.0,
+		};
+		let clear_value = vk::ClearValue::new_depth_stencil(vk::ClearDepthStencilValue {
+			depth: 1.0,
+			stencil: 0,
+		});
+		for face in 0..CUBE_FACES
+		{
+			let render_pass_begin_info = vk::RenderPassBeginInfo {
+				s_type: vk::StructureType::RenderPassBeginInfo,
+				p_next: ptr::null(),
+				render_pass: self.renderpass,
+				framebuffer: self.framebuffers[face],
+				render_area: self.scissor,
+				clear_value_count: 1,
+				p_clear_values: &clear_value,
+			};
+			unsafe {
+				rs.device.cmd_begin_render_pass(cmd_buf, &render_pass_begin_info, vk::SubpassContents::Inline);
+				rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::Graphics, self.pipeline);
+				rs.device.cmd_set_viewport(cmd_buf, &[viewport]);
+				rs.device.cmd_set_scissor(cmd_buf, &[self.scissor]);
+				rs.device.cmd_bind_descriptor_sets(
+					cmd_buf,
+					vk::PipelineBindPoint::Graphics,
+					pipeline_layout,
+					1,
+					&self.view_ds[face..face + 1],
+					&[],
+				);
+			}
+			draw(cmd_buf);
+			unsafe {
+				rs.device.cmd_end_render_pass(cmd_buf);
+			}
+		}
+
+		rs.pop_debug_label(cmd_buf);
+		unsafe {
+			rs.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
+		}
+
+		self.recorded = true;
+		Ok(())
+	}
+
+	/// Returns the command buffer recorded by render() and the fence to signal when it finishes, if
+	/// there is one that wasn't submitted yet.
+	///
+	/// The fence is reset, so it must be passed to the submission.
+	pub fn take_recorded(&mut self, rs: &RenderState) -> Option<(vk::CommandBuffer, vk::Fence)>
+	{
+		if !self.recorded
+		{
+			return None;
+		}
+
+		self.recorded = false;
+		unsafe {
+			rs.device.reset_fences(&[self.fence]).expect("Reset cube depth fence failed");
+		}
+		Some((self.commandbuffer, self.fence))
+	}
+}
+
+impl Drop for CubeDepthTarget
+{
+	fn drop(&mut self)
+	{
+		teardown_scope!("CubeDepthTarget");
+
+		unsafe {
+			// Always wait for device idle
+			self.device.device_wait_idle().unwrap();
+
+			for &framebuffer in self.framebuffers.iter()
+			{
+				self.device.destroy_framebuffer(framebuffer, None);
+			}
+			for &face_view in self.face_views.iter()
+			{
+				self.device.destroy_image_view(face_view, None);
+			}
+			self.device.destroy_sampler(self.texture.sampler, None);
+			self.device.destroy_image_view(self.texture.view, None);
+			self.device.destroy_image(self.texture.image, None);
+			self.device.free_memory(self.texture.memory, None);
+
+			self.device.destroy_buffer(self.view_ub, None);
+			self.device.free_memory(self.view_ub_mem, None);
+
+			self.device.destroy_fence(self.fence, None);
+			self.device.destroy_pipeline(self.pipeline, None);
+			self.device.destroy_render_pass(self.renderpass, None);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use cgmath::Vector4;
+	use cgmath::prelude::*;
+
+	#[test]
+	fn faces_look_along_their_axes()
+	{
+		let position = Point3::new(1.0, 2.0, 3.0);
+		let views = cube_face_views(position);
+		let directions = [
+			Vector3::new(1.0, 0.0, 0.0),
+			Vector3::new(-1.0, 0.0, 0.0),
+			Vector3::new(0.0, 1.0, 0.0),
+			Vector3::new(0.0, -1.0, 0.0),
+			Vector3::new(0.0, 0.0, 1.0),
+			Vector3::new(0.0, 0.0, -1.0),
+		];
+		for (view, direction) in views.iter().zip(directions.iter())
+		{
+			// Straight ahead is -Z in view space
+			let ahead = *view * (position + *direction * 2.0).to_homogeneous();
+			assert!((ahead - Vector4::new(0.0, 0.0, -2.0, 1.0)).magnitude() < 1e-5);
+		}
+	}
+
+	#[test]
+	fn projection_maps_near_and_far_to_the_depth_range()
+	{
+		let projection = cube_face_projection(0.5, 50.0);
+		let depth = |distance: f32| {
+			let clip = projection * Vector4::new(0.0, 0.0, -distance, 1.0);
+			clip.z / clip.w
+		};
+		assert!(depth(0.5).abs() < 1e-5);
+		assert!((depth(50.0) - 1.0).abs() < 1e-5);
+	}
+}
@@ -0,0 +1,101 @@
+use ash::{Entry, Instance};
+use ash::version::{EntryV1_0, V1_0};
+use ash::vk;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::ptr;
+
+// VK_EXT_debug_utils is not exposed by ash, so define the parts we need here.
+const STRUCTURE_TYPE_DEBUG_UTILS_LABEL_EXT: u32 = 1000128002;
+
+#[repr(C)]
+struct DebugUtilsLabelEXT
+{
+	s_type: u32,
+	p_next: *const vk::c_void,
+	p_label_name: *const vk::c_char,
+	color: [f32; 4],
+}
+
+type PfnCmdBeginDebugUtilsLabelEXT = unsafe extern "system" fn(vk::CommandBuffer, *const DebugUtilsLabelEXT);
+type PfnCmdEndDebugUtilsLabelEXT = unsafe extern "system" fn(vk::CommandBuffer);
+
+/// Command buffer labels for GPU captures (RenderDoc etc.), using VK_EXT_debug_utils.
+///
+/// All functions are no-ops if the extension isn't available, so the labels can be left in always.
+pub struct DebugLabels
+{
+	begin_label: Option<PfnCmdBeginDebugUtilsLabelEXT>,
+	end_label: Option<PfnCmdEndDebugUtilsLabelEXT>,
+}
+
+impl DebugLabels
+{
+	/// Returns the name of the instance extension required for debug labels.
+	pub fn name() -> &'static CStr
+	{
+		CStr::from_bytes_with_nul(b"VK_EXT_debug_utils\0").unwrap()
+	}
+
+	/// Creates DebugLabels that do nothing.
+	pub fn disabled() -> DebugLabels
+	{
+		DebugLabels {
+			begin_label: None,
+			end_label: None,
+		}
+	}
+
+	/// Loads the label functions from the instance.
+	///
+	/// The extension must have been enabled on the instance.
+	pub fn new(entry: &Entry<V1_0>, instance: &Instance<V1_0>) -> DebugLabels
+	{
+		let begin_name = CString::new("vkCmdBeginDebugUtilsLabelEXT").unwrap();
+		let end_name = CString::new("vkCmdEndDebugUtilsLabelEXT").unwrap();
+		unsafe {
+			let begin_ptr: *const vk::c_void =
+				mem::transmute(entry.static_fn().get_instance_proc_addr(instance.handle(), begin_name.as_ptr()));
+			let end_ptr: *const vk::c_void =
+				mem::transmute(entry.static_fn().get_instance_proc_addr(instance.handle(), end_name.as_ptr()));
+			if begin_ptr.is_null() || end_ptr.is_null()
+			{
+				return DebugLabels::disabled();
+			}
+
+			DebugLabels {
+				begin_label: Some(mem::transmute(begin_ptr)),
+				end_label: Some(mem::transmute(end_ptr)),
+			}
+		}
+	}
+
+	/// Opens a labeled region in the command buffer.
+	pub fn push(&self, cmd_buf: vk::CommandBuffer, name: &str, color: [f32; 4])
+	{
+		if let Some(begin_label) = self.begin_label
+		{
+			let label_name = CString::new(name).unwrap();
+			let label = DebugUtilsLabelEXT {
+				s_type: STRUCTURE_TYPE_DEBUG_UTILS_LABEL_EXT,
+				p_next: ptr::null(),
+				p_label_name: label_name.as_ptr(),
+				color: color,
+			};
+			unsafe {
+				begin_label(cmd_buf, &label);
+			}
+		}
+	}
+
+	/// Closes the last opened labeled region in the command buffer.
+	pub fn pop(&self, cmd_buf: vk::CommandBuffer)
+	{
+		if let Some(end_label) = self.end_label
+		{
+			unsafe {
+				end_label(cmd_buf);
+			}
+		}
+	}
+}
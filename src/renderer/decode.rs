@@ -0,0 +1,134 @@
+use image;
+use image::RgbaImage;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+type DecodeResult = Result<RgbaImage, String>;
+
+/// A path to decode, and where to send the pixels.
+type DecodeJob = (String, Sender<DecodeResult>);
+
+/// Decodes images on background threads, so the main thread only has to upload them.
+///
+/// Decodes are started with request() and picked up in the same order with take(). Images that
+/// weren't requested are decoded by take() on the calling thread, as they are without any threads.
+pub struct ImageDecoder
+{
+	// None without threads, dropped first to let the threads finish
+	jobs: Option<Sender<DecodeJob>>,
+	threads: Vec<thread::JoinHandle<()>>,
+	// Decodes started but not taken yet, per path
+	pending: RefCell<HashMap<String, VecDeque<Receiver<DecodeResult>>>>,
+}
+
+impl ImageDecoder
+{
+	/// Starts the decode threads, 0 decodes every image on the thread calling take().
+	pub fn new(threads: usize) -> ImageDecoder
+	{
+		if threads == 0
+		{
+			return ImageDecoder {
+				jobs: None,
+				threads: Vec::new(),
+				pending: RefCell::new(HashMap::new()),
+			};
+		}
+
+		let (jobs, job_receiver) = channel::<DecodeJob>();
+		// The threads take turns waiting for the next job
+		let job_receiver = Arc::new(Mutex::new(job_receiver));
+		let threads = (0..threads)
+			.map(|i| {
+				let job_receiver = Arc::clone(&job_receiver);
+				thread::Builder::new()
+					.name(format!("decode{}", i))
+					.spawn(move || loop {
+						let job = job_receiver.lock().expect("Decode job queue poisoned").recv();
+						match job
+						{
+							Ok((path, result)) =>
+							{
+								// The requester may be gone already, which is fine
+								let _ = result.send(decode(&path));
+							}
+							Err(_) => break,
+						}
+					})
+					.expect("Failed to start a decode thread")
+			})
+			.collect();
+
+		ImageDecoder {
+			jobs: Some(jobs),
+			threads: threads,
+			pending: RefCell::new(HashMap::new()),
+		}
+	}
+
+	/// Starts decoding the image on a decode thread. Does nothing without threads.
+	///
+	/// Each request is meant for one take() of the same path.
+	pub fn request(&self, path: &str)
+	{
+		if let Some(ref jobs) = self.jobs
+		{
+			let (result, receiver) = channel();
+			if jobs.send((String::from(path), result)).is_ok()
+			{
+				self.pending
+					.borrow_mut()
+					.entry(String::from(path))
+					.or_insert_with(VecDeque::new)
+					.push_back(receiver);
+			}
+		}
+	}
+
+	/// Returns the decoded image, waiting for the oldest request of the path if there is one and
+	/// decoding it right away otherwise.
+	pub fn take(&self, path: &str) -> DecodeResult
+	{
+		let receiver = {
+			let mut pending = self.pending.borrow_mut();
+			let receiver = pending.get_mut(path).and_then(|receivers| receivers.pop_front());
+			if pending.get(path).map(|receivers| receivers.is_empty()).unwrap_or(false)
+			{
+				pending.remove(path);
+			}
+			receiver
+		};
+		match receiver
+		{
+			Some(receiver) => receiver.recv().unwrap_or_else(|_| decode(path)),
+			None => decode(path),
+		}
+	}
+}
+
+impl Drop for ImageDecoder
+{
+	fn drop(&mut self)
+	{
+		teardown_scope!("ImageDecoder");
+
+		// Closing the queue ends the threads once the queued images are done
+		self.jobs = None;
+		self.pending.borrow_mut().clear();
+		for thread in self.threads.drain(..)
+		{
+			let _ = thread.join();
+		}
+	}
+}
+
+/// Decodes the image file to RGBA8 pixels.
+fn decode(path: &str) -> DecodeResult
+{
+	image::open(path)
+		.map(|image| image.to_rgba())
+		.map_err(|e| format!("Failed to load texture {}: {}", path, e))
+}
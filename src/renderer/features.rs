@@ -0,0 +1,60 @@
+use ash::vk;
+
+/// The optional device features used by the renderer.
+///
+/// Populated from what the physical device supports, and then holds the features that were actually
+/// enabled on the logical device. Subsystems should check this before relying on a feature.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceFeatures
+{
+	pub sampler_anisotropy: bool,
+	pub wide_lines: bool,
+	pub fill_mode_non_solid: bool,
+	pub sampler_array_indexing: bool,
+	pub depth_clamp: bool,
+	pub shader_clip_distance: bool,
+}
+
+impl DeviceFeatures
+{
+	/// Picks the features we want out of the ones supported by the physical device.
+	pub fn from_supported(supported: &vk::PhysicalDeviceFeatures) -> DeviceFeatures
+	{
+		DeviceFeatures {
+			sampler_anisotropy: supported.sampler_anisotropy != 0,
+			wide_lines: supported.wide_lines != 0,
+			fill_mode_non_solid: supported.fill_mode_non_solid != 0,
+			sampler_array_indexing: supported.shader_sampled_image_array_dynamic_indexing != 0,
+			depth_clamp: supported.depth_clamp != 0,
+			shader_clip_distance: supported.shader_clip_distance != 0,
+		}
+	}
+
+	/// Generates the vk::PhysicalDeviceFeatures to pass on device creation.
+	pub fn to_vk(&self) -> vk::PhysicalDeviceFeatures
+	{
+		vk::PhysicalDeviceFeatures {
+			sampler_anisotropy: self.sampler_anisotropy as vk::Bool32,
+			wide_lines: self.wide_lines as vk::Bool32,
+			fill_mode_non_solid: self.fill_mode_non_solid as vk::Bool32,
+			shader_sampled_image_array_dynamic_indexing: self.sampler_array_indexing as vk::Bool32,
+			depth_clamp: self.depth_clamp as vk::Bool32,
+			shader_clip_distance: self.shader_clip_distance as vk::Bool32,
+			..Default::default()
+		}
+	}
+
+	/// Returns the names of the enabled features, for the startup report.
+	pub fn enabled_names(&self) -> Vec<&'static str>
+	{
+		let features = [
+			("sampler anisotropy", self.sampler_anisotropy),
+			("wide lines", self.wide_lines),
+			("fill mode non-solid", self.fill_mode_non_solid),
+			("sampler array indexing", self.sampler_array_indexing),
+			("depth clamp", self.depth_clamp),
+			("shader clip distance", self.shader_clip_distance),
+		];
+		features.iter().filter(|&&(_, enabled)| enabled).map(|&(name, _)| name).collect()
+	}
+}
@@ -0,0 +1,83 @@
+use ash::Device;
+use ash::version::{DeviceV1_0, V1_0};
+use ash::vk;
+
+use renderer::{RenderState, Texture};
+
+/// Format of the normal and position targets. Signed, and precise enough for world positions.
+pub const GBUFFER_FORMAT: vk::Format = vk::Format::R16g16b16a16Sfloat;
+
+/// A G-buffer target next to the albedo, which is rendered to the regular color image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GBufferTarget
+{
+	/// World space normal of the surface, with the normal map applied.
+	Normal,
+	/// World space position.
+	Position,
+}
+
+/// The world space normal and position targets the MainPass renders in G-buffer mode, for shading
+/// them in a later pass.
+pub struct GBuffer
+{
+	pub normal: Texture,
+	pub position: Texture,
+}
+
+impl GBuffer
+{
+	/// Creates both targets in the color attachment layout, sampled when presented.
+	pub fn new(rs: &RenderState, render_size: vk::Extent3D) -> GBuffer
+	{
+		let create_target = || {
+			rs.create_texture(
+				render_size,
+				vk::ImageType::Type2d,
+				vk::ImageViewType::Type2d,
+				GBUFFER_FORMAT,
+				vk::IMAGE_ASPECT_COLOR_BIT,
+				vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT | vk::IMAGE_USAGE_SAMPLED_BIT,
+				vk::ACCESS_COLOR_ATTACHMENT_READ_BIT | vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT,
+				vk::ImageLayout::ColorAttachmentOptimal,
+				vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
+				None,
+			)
+		};
+
+		GBuffer {
+			normal: create_target(),
+			position: create_target(),
+		}
+	}
+
+	/// Returns the views in the order of the renderpass attachments.
+	pub fn views(&self) -> [vk::ImageView; 2]
+	{
+		[self.normal.view, self.position.view]
+	}
+
+	/// Returns the texture of the given target.
+	pub fn target(&mut self, target: GBufferTarget) -> &mut Texture
+	{
+		match target
+		{
+			GBufferTarget::Normal => &mut self.normal,
+			GBufferTarget::Position => &mut self.position,
+		}
+	}
+
+	/// Destroys both targets. They must not be in use by the GPU.
+	pub fn destroy(&self, device: &Device<V1_0>)
+	{
+		for texture in [&self.normal, &self.position].iter()
+		{
+			unsafe {
+				device.destroy_sampler(texture.sampler, None);
+				device.destroy_image_view(texture.view, None);
+				device.destroy_image(texture.image, None);
+				device.free_memory(texture.memory, None);
+			}
+		}
+	}
+}
@@ -0,0 +1,344 @@
+use ash::vk;
+
+use renderer::{RenderState, Texture};
+use renderer::gbuffer::GBufferTarget;
+
+/// How a pass uses an image, which determines the layout, access and stage it must be transitioned to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageUsage
+{
+	ColorAttachment,
+	DepthAttachment,
+	/// Sampled in the fragment shader.
+	Sampled,
+	/// Depth sampled in the fragment shader, through a depth-only view.
+	DepthSampled,
+}
+
+impl ImageUsage
+{
+	fn access_mask(&self) -> vk::AccessFlags
+	{
+		match *self
+		{
+			ImageUsage::ColorAttachment => vk::ACCESS_COLOR_ATTACHMENT_READ_BIT | vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT,
+			ImageUsage::DepthAttachment =>
+			{
+				vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_READ_BIT | vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT
+			}
+			ImageUsage::Sampled | ImageUsage::DepthSampled => vk::ACCESS_SHADER_READ_BIT,
+		}
+	}
+
+	fn layout(&self) -> vk::ImageLayout
+	{
+		match *self
+		{
+			ImageUsage::ColorAttachment => vk::ImageLayout::ColorAttachmentOptimal,
+			ImageUsage::DepthAttachment => vk::ImageLayout::DepthStencilAttachmentOptimal,
+			ImageUsage::Sampled => vk::ImageLayout::ShaderReadOnlyOptimal,
+			ImageUsage::DepthSampled => vk::ImageLayout::DepthStencilReadOnlyOptimal,
+		}
+	}
+
+	fn stage(&self) -> vk::PipelineStageFlags
+	{
+		match *self
+		{
+			ImageUsage::ColorAttachment => vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
+			// The depth image may have been sampled by any earlier stage
+			ImageUsage::DepthAttachment => vk::PIPELINE_STAGE_ALL_GRAPHICS_BIT,
+			ImageUsage::Sampled | ImageUsage::DepthSampled => vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+		}
+	}
+}
+
+/// Identifies an image in a RenderGraph.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResourceId(usize);
+
+/// Identifies a pass in a RenderGraph.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PassId(usize);
+
+/// A transition to insert before a pass.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transition
+{
+	pub resource: ResourceId,
+	pub usage: ImageUsage,
+}
+
+struct PassNode
+{
+	name: String,
+	reads: Vec<(ResourceId, ImageUsage)>,
+	writes: Vec<(ResourceId, ImageUsage)>,
+	// Filled in by compile()
+	transitions: Vec<Transition>,
+}
+
+/// A linear render graph, executing the passes in the order they were added.
+///
+/// Each pass declares the images it reads and writes, and the graph works out which image
+/// transitions have to happen before each pass. The images themselves stay owned by the passes,
+/// which hand them to record_transitions() at the start of their command buffer.
+pub struct RenderGraph
+{
+	resources: Vec<String>,
+	passes: Vec<PassNode>,
+	compiled: bool,
+}
+
+impl RenderGraph
+{
+	pub fn new() -> RenderGraph
+	{
+		RenderGraph {
+			resources: Vec::new(),
+			passes: Vec::new(),
+			compiled: false,
+		}
+	}
+
+	/// Declares an image, the name is only used in error messages.
+	pub fn add_resource(&mut self, name: &str) -> ResourceId
+	{
+		self.resources.push(String::from(name));
+		ResourceId(self.resources.len() - 1)
+	}
+
+	/// Adds a pass after all previously added ones.
+	pub fn add_pass(
+		&mut self, name: &str, reads: &[(ResourceId, ImageUsage)], writes: &[(ResourceId, ImageUsage)]
+	) -> PassId
+	{
+		self.passes.push(PassNode {
+			name: String::from(name),
+			reads: reads.to_vec(),
+			writes: writes.to_vec(),
+			transitions: Vec::new(),
+		});
+		self.compiled = false;
+		PassId(self.passes.len() - 1)
+	}
+
+	/// Checks the pass order and works out the transitions before each pass.
+	///
+	/// Fails if a pass reads an image no earlier pass wrote. Every image is transitioned at its first
+	/// use in the frame, as the state it was left in is only known at runtime (transitions to the
+	/// current state are skipped then). After that, it is only transitioned when its usage changes.
+	pub fn compile(&mut self) -> Result<(), String>
+	{
+		let mut current_usage: Vec<Option<ImageUsage>> = vec![None; self.resources.len()];
+		for pass in self.passes.iter_mut()
+		{
+			for &(resource, _) in pass.reads.iter()
+			{
+				if current_usage[resource.0].is_none()
+				{
+					return Err(format!(
+						"Pass {} reads {} before any pass writes it",
+						pass.name, self.resources[resource.0]
+					));
+				}
+			}
+
+			pass.transitions.clear();
+			for &(resource, usage) in pass.reads.iter().chain(pass.writes.iter())
+			{
+				if current_usage[resource.0] != Some(usage)
+				{
+					pass.transitions.push(Transition {
+						resource: resource,
+						usage: usage,
+					});
+					current_usage[resource.0] = Some(usage);
+				}
+			}
+		}
+
+		self.compiled = true;
+		Ok(())
+	}
+
+	/// Returns the transitions to do before the given pass.
+	pub fn transitions(&self, pass: PassId) -> &[Transition]
+	{
+		debug_assert!(self.compiled, "RenderGraph used before compile()");
+		&self.passes[pass.0].transitions
+	}
+
+	/// Records the transitions before the given pass, for the images the pass owns.
+	///
+	/// Must be recorded outside of a renderpass.
+	pub fn record_transitions(
+		&self, rs: &RenderState, pass: PassId, cmd_buf: vk::CommandBuffer, images: &mut [(ResourceId, &mut Texture)]
+	)
+	{
+		for transition in self.transitions(pass).iter()
+		{
+			for image in images.iter_mut().filter(|image| image.0 == transition.resource)
+			{
+				let usage = transition.usage;
+				rs.transition_texture(&mut *image.1, usage.access_mask(), usage.layout(), usage.stage(), Some(cmd_buf));
+			}
+		}
+	}
+}
+
+/// The image shown by the present pass.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PresentedImage
+{
+	Color,
+	Depth,
+	/// A face of the cube depth texture, see MainPass::render_cube_depth().
+	CubeDepth,
+	/// Only rendered if the MainPass was created with a G-buffer.
+	GBuffer(GBufferTarget),
+}
+
+/// The render graph of a frame: the cube depth pass and the main pass rendering the scene, followed
+/// by the present pass showing one of their images.
+pub struct FrameGraph
+{
+	pub graph: RenderGraph,
+	pub cube_pass: PassId,
+	pub main_pass: PassId,
+	pub present_pass: PassId,
+	pub cube_depth: ResourceId,
+	pub main_color: ResourceId,
+	pub main_depth: ResourceId,
+	/// The G-buffer targets next to the color, see MainPass::has_gbuffer().
+	pub main_normal: ResourceId,
+	pub main_position: ResourceId,
+}
+
+impl FrameGraph
+{
+	/// Builds the frame graph, presenting the given image.
+	pub fn new(presented: PresentedImage) -> FrameGraph
+	{
+		let mut graph = RenderGraph::new();
+		let cube_depth = graph.add_resource("cube depth");
+		let main_color = graph.add_resource("main color");
+		let main_depth = graph.add_resource("main depth");
+		let main_normal = graph.add_resource("main normal");
+		let main_position = graph.add_resource("main position");
+		let cube_pass = graph.add_pass("cube depth", &[], &[(cube_depth, ImageUsage::DepthAttachment)]);
+		let main_pass = graph.add_pass(
+			"main",
+			&[],
+			&[
+				(main_color, ImageUsage::ColorAttachment),
+				(main_depth, ImageUsage::DepthAttachment),
+				(main_normal, ImageUsage::ColorAttachment),
+				(main_position, ImageUsage::ColorAttachment),
+			],
+		);
+		let presented = match presented
+		{
+			PresentedImage::Color => (main_color, ImageUsage::Sampled),
+			PresentedImage::Depth => (main_depth, ImageUsage::DepthSampled),
+			PresentedImage::CubeDepth => (cube_depth, ImageUsage::DepthSampled),
+			PresentedImage::GBuffer(GBufferTarget::Normal) => (main_normal, ImageUsage::Sampled),
+			PresentedImage::GBuffer(GBufferTarget::Position) => (main_position, ImageUsage::Sampled),
+		};
+		let present_pass = graph.add_pass("present", &[presented], &[]);
+		graph.compile().expect("Invalid frame graph");
+
+		FrameGraph {
+			graph: graph,
+			cube_pass: cube_pass,
+			main_pass: main_pass,
+			present_pass: present_pass,
+			cube_depth: cube_depth,
+			main_color: main_color,
+			main_depth: main_depth,
+			main_normal: main_normal,
+			main_position: main_position,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn image_written_then_sampled_is_transitioned_between_the_passes()
+	{
+		let mut graph = RenderGraph::new();
+		let color = graph.add_resource("color");
+		let depth = graph.add_resource("depth");
+		let draw = graph.add_pass(
+			"draw",
+			&[],
+			&[(color, ImageUsage::ColorAttachment), (depth, ImageUsage::DepthAttachment)],
+		);
+		let present = graph.add_pass("present", &[(color, ImageUsage::Sampled)], &[]);
+		graph.compile().unwrap();
+
+		// Every image at its first use, then only the one changing usage
+		let first_use = [
+			Transition {
+				resource: color,
+				usage: ImageUsage::ColorAttachment,
+			},
+			Transition {
+				resource: depth,
+				usage: ImageUsage::DepthAttachment,
+			},
+		];
+		assert_eq!(graph.transitions(draw), &first_use[..]);
+		let sampled = [
+			Transition {
+				resource: color,
+				usage: ImageUsage::Sampled,
+			},
+		];
+		assert_eq!(graph.transitions(present), &sampled[..]);
+	}
+
+	#[test]
+	fn unchanged_usage_needs_no_transition()
+	{
+		let mut graph = RenderGraph::new();
+		let color = graph.add_resource("color");
+		graph.add_pass("first", &[], &[(color, ImageUsage::ColorAttachment)]);
+		let second = graph.add_pass("second", &[], &[(color, ImageUsage::ColorAttachment)]);
+		graph.compile().unwrap();
+		assert!(graph.transitions(second).is_empty());
+	}
+
+	#[test]
+	fn reading_before_writing_fails_to_compile()
+	{
+		let mut graph = RenderGraph::new();
+		let color = graph.add_resource("color");
+		graph.add_pass("present", &[(color, ImageUsage::Sampled)], &[]);
+		assert!(graph.compile().is_err());
+	}
+
+	#[test]
+	fn presented_cube_depth_is_sampled_after_the_cube_pass()
+	{
+		let frame = FrameGraph::new(PresentedImage::CubeDepth);
+		let written = [
+			Transition {
+				resource: frame.cube_depth,
+				usage: ImageUsage::DepthAttachment,
+			},
+		];
+		assert_eq!(frame.graph.transitions(frame.cube_pass), &written[..]);
+		let sampled = [
+			Transition {
+				resource: frame.cube_depth,
+				usage: ImageUsage::DepthSampled,
+			},
+		];
+		assert_eq!(frame.graph.transitions(frame.present_pass), &sampled[..]);
+	}
+}
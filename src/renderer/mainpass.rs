@@ -3,15 +3,475 @@ use ash::version::{DeviceV1_0, V1_0};
 use ash::vk;
 use cgmath::Matrix4;
 use std::ffi::CString;
-use std::mem::size_of;
+use std::mem::{align_of, size_of};
 use std::ptr;
 use std::rc::Rc;
+use std::slice;
 
-use object::draw::Vertex;
+use object::mesh::Vertex;
 use renderer::{RenderState, Texture};
+use renderer::cubedepth::{CubeDepthTarget, CUBE_FACES};
+use renderer::gbuffer::{GBuffer, GBufferTarget, GBUFFER_FORMAT};
+use renderer::graph::FrameGraph;
+use renderer::occlusion::OcclusionQueries;
+use renderer::parallel::{DrawCommand, ParallelRecorder, RecordState};
+use renderer::sky::{Background, Sky};
+use renderer::uniform::UniformBuffer;
 
 use config::Config;
 
+// VK_EXT_conservative_rasterization is not exposed by ash, so define the parts we need here.
+const STRUCTURE_TYPE_PIPELINE_RASTERIZATION_CONSERVATIVE_STATE_CREATE_INFO_EXT: u32 = 1000101001;
+const CONSERVATIVE_RASTERIZATION_MODE_OVERESTIMATE_EXT: u32 = 1;
+
+#[repr(C)]
+struct PipelineRasterizationConservativeStateCreateInfoEXT
+{
+	s_type: u32,
+	p_next: *const vk::c_void,
+	flags: u32,
+	conservative_rasterization_mode: u32,
+	extra_primitive_overestimation_size: f32,
+}
+
+/// Max number of objects the per-object uniform buffer has room for.
+pub const MAX_OBJECTS: usize = 256;
+
+/// Bounds for the render scale.
+const MIN_RENDER_SCALE: f32 = 0.25;
+const MAX_RENDER_SCALE: f32 = 2.0;
+
+/// The per-object uniform data, matching the ObjectBlock in the shaders.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ObjectUniforms
+{
+	pub model: Matrix4<f32>,
+	pub mvp: Matrix4<f32>,
+	/// Specular color in rgb, shininess in a.
+	pub specular: [f32; 4],
+}
+
+/// The scene lighting, matching the LightBlock in the shaders.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct LightUniforms
+{
+	/// Direction towards the directional light in xyz.
+	pub direction: [f32; 4],
+	/// Light color in rgb, intensity in a.
+	pub color: [f32; 4],
+	/// Ambient light in rgb.
+	pub ambient: [f32; 4],
+}
+
+/// How fog fades distant fragments towards the fog color.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FogMode
+{
+	Off,
+	/// Fades from none at the start distance to full at the end distance.
+	Linear,
+	/// Fades as 1 - e^(-density * distance), never reaching full fog.
+	Exponential,
+}
+
+/// What happens to the previous contents of the render image when a frame starts.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ColorLoadOp
+{
+	/// Fill with the clear color.
+	Clear,
+	/// Keep the previous frame.
+	Load,
+	/// Leave undefined, which saves bandwidth when something always covers the whole frame.
+	DontCare,
+}
+
+impl ColorLoadOp
+{
+	/// Returns the load op to use. DontCare falls back to Clear without a full frame background, as
+	/// the uncovered pixels would be garbage.
+	fn attachment_load_op(self, full_frame_background: bool) -> vk::AttachmentLoadOp
+	{
+		match self
+		{
+			ColorLoadOp::Clear => vk::AttachmentLoadOp::Clear,
+			ColorLoadOp::Load => vk::AttachmentLoadOp::Load,
+			ColorLoadOp::DontCare if full_frame_background => vk::AttachmentLoadOp::DontCare,
+			ColorLoadOp::DontCare =>
+			{
+				warning!("Nothing covers the whole frame, clearing instead of color_load_op DontCare");
+				vk::AttachmentLoadOp::Clear
+			}
+		}
+	}
+}
+
+/// How line lists are drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LineRendering
+{
+	/// Quads where the line width needs wide line support and the device lacks it, lines otherwise.
+	Auto,
+	/// Rasterized lines, one pixel wide without wide line support.
+	Native,
+	/// Quads expanded to the line width in the vertex shader, which works everywhere.
+	Quads,
+}
+
+/// The push constants of the main pass, matching the LineParams block in phong.vert.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct LinePushConstants
+{
+	/// Half the line width in normalized device coordinates.
+	half_width: [f32; 2],
+}
+
+impl LinePushConstants
+{
+	/// Returns the constants for drawing lines of the given width in pixels into the viewport.
+	fn new(line_width: f32, viewport: &vk::Viewport) -> LinePushConstants
+	{
+		LinePushConstants {
+			half_width: [line_width / viewport.width, line_width / viewport.height],
+		}
+	}
+
+	/// Records pushing the constants, which stay set for all pipelines using the main pass layout.
+	pub fn push(&self, device: &Device<V1_0>, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout)
+	{
+		unsafe {
+			let constant_bytes =
+				slice::from_raw_parts(self as *const LinePushConstants as *const u32, size_of::<LinePushConstants>());
+			device.cmd_push_constants(cmd_buf, pipeline_layout, vk::SHADER_STAGE_VERTEX_BIT, 0, constant_bytes);
+		}
+	}
+}
+
+/// Fog settings. Distances are view space depths in world units, density is per world unit.
+#[derive(Clone, Copy, Debug)]
+pub struct Fog
+{
+	pub mode: FogMode,
+	pub color: [f32; 3],
+	pub start: f32,
+	pub end: f32,
+	pub density: f32,
+}
+
+impl Fog
+{
+	/// Returns the fog uniforms, with the distances and density clamped to sane values.
+	fn uniforms(&self) -> FogUniforms
+	{
+		let mode = match self.mode
+		{
+			FogMode::Off => 0.0,
+			FogMode::Linear => 1.0,
+			FogMode::Exponential => 2.0,
+		};
+		let start = self.start.max(0.0);
+		// Keep the fade from dividing by zero
+		let end = self.end.max(start + 0.001);
+		FogUniforms {
+			color: [self.color[0], self.color[1], self.color[2], 0.0],
+			params: [mode, start, end, self.density.max(0.0)],
+		}
+	}
+}
+
+/// The fog, matching the end of the LightBlock in the shaders.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FogUniforms
+{
+	/// Fog color in rgb.
+	color: [f32; 4],
+	/// Mode (0 off, 1 linear, 2 exponential), start and end distance, density.
+	params: [f32; 4],
+}
+
+/// Size of the LightBlock in the shaders, the lighting followed by the fog.
+pub fn light_block_size() -> u64
+{
+	(size_of::<LightUniforms>() + size_of::<FogUniforms>()) as u64
+}
+
+/// Rounds the size up to the next multiple of the alignment.
+///
+/// Vulkan guarantees that alignments are powers of two.
+pub fn align_up(size: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize
+{
+	debug_assert!(alignment.is_power_of_two());
+	(size + alignment - 1) & !(alignment - 1)
+}
+
+/// How the stencil test compares the reference value with the stored value, see vk::CompareOp.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StencilCompare
+{
+	Never,
+	Less,
+	Equal,
+	LessOrEqual,
+	Greater,
+	NotEqual,
+	GreaterOrEqual,
+	Always,
+}
+
+impl StencilCompare
+{
+	fn vk(self) -> vk::CompareOp
+	{
+		match self
+		{
+			StencilCompare::Never => vk::CompareOp::Never,
+			StencilCompare::Less => vk::CompareOp::Less,
+			StencilCompare::Equal => vk::CompareOp::Equal,
+			StencilCompare::LessOrEqual => vk::CompareOp::LessOrEqual,
+			StencilCompare::Greater => vk::CompareOp::Greater,
+			StencilCompare::NotEqual => vk::CompareOp::NotEqual,
+			StencilCompare::GreaterOrEqual => vk::CompareOp::GreaterOrEqual,
+			StencilCompare::Always => vk::CompareOp::Always,
+		}
+	}
+}
+
+/// What the stencil test does to the stored value, see vk::StencilOp.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StencilAction
+{
+	Keep,
+	Zero,
+	Replace,
+	IncrementAndClamp,
+	DecrementAndClamp,
+	Invert,
+	IncrementAndWrap,
+	DecrementAndWrap,
+}
+
+impl StencilAction
+{
+	fn vk(self) -> vk::StencilOp
+	{
+		match self
+		{
+			StencilAction::Keep => vk::StencilOp::Keep,
+			StencilAction::Zero => vk::StencilOp::Zero,
+			StencilAction::Replace => vk::StencilOp::Replace,
+			StencilAction::IncrementAndClamp => vk::StencilOp::IncrementAndClamp,
+			StencilAction::DecrementAndClamp => vk::StencilOp::DecrementAndClamp,
+			StencilAction::Invert => vk::StencilOp::Invert,
+			StencilAction::IncrementAndWrap => vk::StencilOp::IncrementAndWrap,
+			StencilAction::DecrementAndWrap => vk::StencilOp::DecrementAndWrap,
+		}
+	}
+}
+
+/// Stencil test configuration for the MainPass.
+///
+/// The stencil reference value is dynamic state, see set_stencil_reference().
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StencilConfig
+{
+	pub compare_op: StencilCompare,
+	pub fail_op: StencilAction,
+	pub pass_op: StencilAction,
+	pub depth_fail_op: StencilAction,
+	pub compare_mask: u32,
+	pub write_mask: u32,
+}
+
+impl StencilConfig
+{
+	/// Returns the stencil state of the pipelines, the same for front and back faces.
+	fn op_state(&self) -> vk::StencilOpState
+	{
+		vk::StencilOpState {
+			fail_op: self.fail_op.vk(),
+			pass_op: self.pass_op.vk(),
+			depth_fail_op: self.depth_fail_op.vk(),
+			compare_op: self.compare_op.vk(),
+			compare_mask: self.compare_mask,
+			write_mask: self.write_mask,
+			// Dynamic state
+			reference: 0,
+		}
+	}
+}
+
+impl Default for StencilConfig
+{
+	/// Writes the reference value for every drawn fragment.
+	fn default() -> StencilConfig
+	{
+		StencilConfig {
+			compare_op: StencilCompare::Always,
+			fail_op: StencilAction::Keep,
+			pass_op: StencilAction::Replace,
+			depth_fail_op: StencilAction::Keep,
+			compare_mask: 0xFF,
+			write_mask: 0xFF,
+		}
+	}
+}
+
+/// Depth offset for a draw, the constant and slope scaled factors of vkCmdSetDepthBias().
+///
+/// Negative values move geometry towards the camera, e.g. decals onto the surface they lie on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DepthBias
+{
+	pub constant: f32,
+	pub slope: f32,
+}
+
+/// Returns true if the depth format has a stencil component.
+fn has_stencil(depth_format: vk::Format) -> bool
+{
+	depth_format == vk::Format::D24UnormS8Uint || depth_format == vk::Format::D32SfloatS8Uint ||
+		depth_format == vk::Format::D16UnormS8Uint
+}
+
+/// The pipeline variants objects can be drawn with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PipelineKind
+{
+	Textured,
+	/// Textured triangle strips, separated by primitive restart indices.
+	TexturedStrip,
+	/// Uses the vertex colors instead of the textures.
+	VertexColor,
+	/// Vertex colored, drawn regardless of depth. Should be drawn after all depth tested geometry.
+	Overlay,
+	/// Vertex colored line list, or the quads of Mesh::line_quads() if MainPass::draws_line_quads().
+	Line,
+	/// Textured and blended with straight alpha, without writing depth.
+	Blended,
+	/// Textured and blended with premultiplied alpha, without writing depth.
+	BlendedPremultiplied,
+	/// Vertex colored triangle fan. Only used if MainPass::supports_triangle_fans().
+	VertexColorFan,
+}
+
+impl PipelineKind
+{
+	/// Returns true for the kinds that blend with what is behind them, which must be drawn back to
+	/// front after the opaque geometry.
+	pub fn is_blended(&self) -> bool
+	{
+		*self == PipelineKind::Blended || *self == PipelineKind::BlendedPremultiplied
+	}
+}
+
+/// Corner of the render area for MainPass::set_corner_viewport().
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Corner
+{
+	BottomLeft,
+	BottomRight,
+}
+
+/// How the alpha channel of a texture is stored, which selects the blend factors.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AlphaMode
+{
+	/// Alpha is ignored and nothing is blended.
+	Opaque,
+	/// The color is independent of alpha. Filtering mixes in the color of fully transparent texels,
+	/// which gives dark fringes at the edges.
+	Straight,
+	/// The color has been multiplied by alpha on load, so filtering and blending stay correct at the
+	/// edges.
+	Premultiplied,
+}
+
+/// The state that differs between the pipeline variants of the MainPass.
+#[derive(Clone, Copy, PartialEq)]
+pub struct PipelineOptions
+{
+	pub topology: vk::PrimitiveTopology,
+	pub polygon_mode: vk::PolygonMode,
+	/// Requires VK_EXT_conservative_rasterization to be enabled on the device.
+	pub conservative_raster: bool,
+	pub stencil: Option<StencilConfig>,
+	/// Use the vertex color instead of the textures.
+	pub vertex_color: bool,
+	/// Disabling the depth test also disables depth writes, so the depth buffer is left untouched.
+	pub depth_test: bool,
+	/// Only writes depth, for render passes without color attachments.
+	pub depth_only: bool,
+	/// Only tests depth, writing neither color nor depth and drawing back faces too, for occlusion
+	/// query proxies.
+	pub occlusion_proxy: bool,
+	/// Blends with the color attachment unless Opaque. Blending also disables depth writes.
+	pub alpha_mode: AlphaMode,
+	/// Enables the depth bias as dynamic state, see set_depth_bias().
+	pub depth_bias: bool,
+	/// Width of rasterized lines, above 1 requires the wide lines feature.
+	pub line_width: f32,
+	/// Expands line quads in the vertex shader, see Mesh::line_quads().
+	pub wide_lines: bool,
+	/// Adds a constant per fragment instead of shading, for the overdraw heatmap.
+	pub overdraw: bool,
+	/// Clamps depth to the depth range instead of clipping at the near and far planes. Requires the
+	/// depth clamp feature.
+	pub depth_clamp: bool,
+	/// Writes the G-buffer targets with gbuffer.frag instead of shading, for render passes with G-buffer
+	/// attachments.
+	pub gbuffer: bool,
+	/// Draws the full-screen triangle of sky.vert and sky.frag without vertex input, for the layout
+	/// from Sky::create_pipeline_layout().
+	pub sky: bool,
+}
+
+impl PipelineOptions
+{
+	/// Returns the values of the shader specialization constants.
+	///
+	/// Constant 0 selects the vertex color variant of the fragment shader, constant 1 premultiplied
+	/// alpha, constant 2 the line quads of the vertex shader and constant 3 overdraw counting.
+	fn specialization_data(&self) -> [vk::Bool32; 4]
+	{
+		[
+			self.vertex_color as vk::Bool32,
+			(self.alpha_mode == AlphaMode::Premultiplied) as vk::Bool32,
+			self.wide_lines as vk::Bool32,
+			self.overdraw as vk::Bool32,
+		]
+	}
+}
+
+impl Default for PipelineOptions
+{
+	fn default() -> PipelineOptions
+	{
+		PipelineOptions {
+			topology: vk::PrimitiveTopology::TriangleList,
+			polygon_mode: vk::PolygonMode::Fill,
+			conservative_raster: false,
+			stencil: None,
+			vertex_color: false,
+			depth_test: true,
+			depth_only: false,
+			occlusion_proxy: false,
+			alpha_mode: AlphaMode::Opaque,
+			depth_bias: false,
+			line_width: 1.0,
+			wide_lines: false,
+			overdraw: false,
+			depth_clamp: false,
+			gbuffer: false,
+			sky: false,
+		}
+	}
+}
+
 pub struct MainPass
 {
 	renderpass: vk::RenderPass,
@@ -21,18 +481,74 @@ pub struct MainPass
 	viewport: vk::Viewport,
 	scissor: vk::Rect2D,
 	pipeline: vk::Pipeline,
+	// Conservatively rasterized line quads, so thin debug lines don't drop out, if supported
+	debug_pipeline: Option<vk::Pipeline>,
+	// Pipeline using the vertex colors instead of textures
+	vertex_color_pipeline: vk::Pipeline,
+	// Vertex color pipeline without depth testing, for overlays drawn on top of everything
+	overlay_pipeline: vk::Pipeline,
+	// Vertex color pipeline drawing line lists, or line quads if line_quads is set
+	line_pipeline: vk::Pipeline,
+	// Line width in pixels
+	line_width: f32,
+	line_quads: bool,
+	// Regular pipeline drawing triangle strips
+	strip_pipeline: vk::Pipeline,
+	// Textured pipelines blending with straight and premultiplied alpha
+	blended_pipeline: vk::Pipeline,
+	blended_premultiplied_pipeline: vk::Pipeline,
+	// Vertex color pipeline drawing triangle fans, None on devices without fan support
+	fan_pipeline: Option<vk::Pipeline>,
+	// Overdraw counting pipelines for triangle lists, strips and lines, and fans if supported
+	overdraw_pipelines: [vk::Pipeline; 3],
+	overdraw_fan_pipeline: Option<vk::Pipeline>,
+	// Draw with the overdraw pipelines, see toggle_overdraw()
+	show_overdraw: bool,
 	// one framebuffer/commandbuffer per image
 	framebuffer: vk::Framebuffer,
 	commandbuffer: vk::CommandBuffer,
+	// Copies the written uniforms to device local memory ahead of the main pass, if they live there
+	upload_commandbuffer: vk::CommandBuffer,
 
 	// Image to render to.
 	pub render_image: Texture,
 	depth_image: Texture,
+	// Normal and position targets rendered along with the albedo in render_image, if enabled
+	gbuffer: Option<GBuffer>,
+	// Depth-only view of depth_image used for sampling
+	depth_sample_view: vk::ImageView,
+	depth_format: vk::Format,
+	stencil: Option<StencilConfig>,
+	// Size in physical pixels before applying the render scale
+	base_size: vk::Extent2D,
+	render_scale: f32,
 
-	view_matrix_ub: vk::Buffer,
-	pub view_matrix_ub_mem: vk::DeviceMemory,
+	view_matrix_ub: UniformBuffer,
+	// Last view matrix written to the buffer, to skip uploading it again
+	uploaded_view_matrix: Option<Matrix4<f32>>,
 	view_matrix_ds: Vec<vk::DescriptorSet>,
 
+	// Lighting, bound along with the view matrix
+	light_ub: UniformBuffer,
+	clear_color: [f32; 4],
+	// Gradient drawn before the scene, None if it can't be, see set_background()
+	sky: Option<Sky>,
+	background: Background,
+
+	// Per-object data, indexed with a dynamic offset
+	object_ub: UniformBuffer,
+	object_ub_stride: vk::DeviceSize,
+	object_ds: Vec<vk::DescriptorSet>,
+
+	// Records the draws on several threads, if enabled
+	recorder: Option<ParallelRecorder>,
+
+	// Cube depth texture rendered by render_cube_depth(), if enabled
+	cube_depth: Option<CubeDepthTarget>,
+
+	// Occlusion queries from the previous frame, if enabled
+	occlusion: Option<OcclusionQueries>,
+
 	// Keep a pointer to the device for cleanup
 	device: Rc<Device<V1_0>>,
 }
@@ -40,15 +556,30 @@ pub struct MainPass
 impl MainPass
 {
 	/// Creates a main renderpass.
-	fn create_renderpass(rs: &RenderState, render_format: vk::Format) -> vk::RenderPass
+	///
+	/// With a G-buffer, the normal and position targets follow the color and depth attachments.
+	fn create_renderpass(
+		rs: &RenderState, render_format: vk::Format, depth_format: vk::Format, color_load_op: vk::AttachmentLoadOp,
+		gbuffer: bool,
+	) -> vk::RenderPass
 	{
-		// One attachment, color only. Will produce the presentable image.
-		let renderpass_attachments = [
+		// Only clear the stencil if there is one
+		let stencil_load_op = if has_stencil(depth_format)
+		{
+			vk::AttachmentLoadOp::Clear
+		}
+		else
+		{
+			vk::AttachmentLoadOp::DontCare
+		};
+
+		// Color and depth. The color will produce the presentable image.
+		let mut renderpass_attachments = vec![
 			vk::AttachmentDescription {
 				format: render_format,
 				flags: vk::AttachmentDescriptionFlags::empty(),
 				samples: vk::SAMPLE_COUNT_1_BIT,
-				load_op: vk::AttachmentLoadOp::Clear,
+				load_op: color_load_op,
 				store_op: vk::AttachmentStoreOp::Store,
 				stencil_load_op: vk::AttachmentLoadOp::DontCare,
 				stencil_store_op: vk::AttachmentStoreOp::DontCare,
@@ -56,28 +587,47 @@ impl MainPass
 				final_layout: vk::ImageLayout::ColorAttachmentOptimal,
 			},
 			vk::AttachmentDescription {
-				format: vk::Format::D32Sfloat,
+				format: depth_format,
 				flags: vk::AttachmentDescriptionFlags::empty(),
 				samples: vk::SAMPLE_COUNT_1_BIT,
 				load_op: vk::AttachmentLoadOp::Clear,
 				store_op: vk::AttachmentStoreOp::DontCare,
-				stencil_load_op: vk::AttachmentLoadOp::DontCare,
+				stencil_load_op: stencil_load_op,
 				stencil_store_op: vk::AttachmentStoreOp::DontCare,
 				initial_layout: vk::ImageLayout::DepthStencilAttachmentOptimal,
 				final_layout: vk::ImageLayout::DepthStencilAttachmentOptimal,
 			},
 		];
-		let color_attachment_ref = vk::AttachmentReference {
-			attachment: 0,
-			layout: vk::ImageLayout::ColorAttachmentOptimal,
-		};
+		let mut color_attachment_refs = vec![
+			vk::AttachmentReference {
+				attachment: 0,
+				layout: vk::ImageLayout::ColorAttachmentOptimal,
+			},
+		];
+		if gbuffer
+		{
+			// Nothing is drawn behind the scene, so the targets are always cleared
+			let gbuffer_attachment = vk::AttachmentDescription {
+				format: GBUFFER_FORMAT,
+				load_op: vk::AttachmentLoadOp::Clear,
+				..renderpass_attachments[0].clone()
+			};
+			for attachment in 2..4
+			{
+				renderpass_attachments.push(gbuffer_attachment.clone());
+				color_attachment_refs.push(vk::AttachmentReference {
+					attachment: attachment,
+					layout: vk::ImageLayout::ColorAttachmentOptimal,
+				});
+			}
+		}
 		let depth_attachment_ref = vk::AttachmentReference {
 			attachment: 1,
 			layout: vk::ImageLayout::DepthStencilAttachmentOptimal,
 		};
 		let subpass = vk::SubpassDescription {
-			color_attachment_count: 1,
-			p_color_attachments: &color_attachment_ref,
+			color_attachment_count: color_attachment_refs.len() as u32,
+			p_color_attachments: color_attachment_refs.as_ptr(),
 			p_depth_stencil_attachment: &depth_attachment_ref,
 			flags: Default::default(),
 			pipeline_bind_point: vk::PipelineBindPoint::Graphics,
@@ -108,33 +658,42 @@ impl MainPass
 
 	/// Creates a pipeline for the renderpass.
 	fn create_pipeline(
-		rs: &RenderState, render_size: vk::Extent3D, renderpass: vk::RenderPass
+		rs: &RenderState, render_size: vk::Extent3D, renderpass: vk::RenderPass, stencil: Option<StencilConfig>,
+		gbuffer: bool,
 	) -> (vk::DescriptorPool, Vec<vk::DescriptorSetLayout>, vk::PipelineLayout, vk::Viewport, vk::Rect2D, vk::Pipeline)
 	{
 		// Descriptors
 		let descriptor_sizes = [
 			vk::DescriptorPoolSize {
 				typ: vk::DescriptorType::CombinedImageSampler,
-				descriptor_count: 14,
+				// Color, normal and specular map for every object
+				descriptor_count: 3 * MAX_OBJECTS as u32,
 			},
 			vk::DescriptorPoolSize {
 				typ: vk::DescriptorType::UniformBuffer,
+				// View matrix and lighting, for the main view and every cube depth face
+				descriptor_count: 2 * (1 + CUBE_FACES as u32),
+			},
+			vk::DescriptorPoolSize {
+				typ: vk::DescriptorType::UniformBufferDynamic,
 				descriptor_count: 1,
 			},
 		];
 		let descriptor_pool_info = vk::DescriptorPoolCreateInfo {
 			s_type: vk::StructureType::DescriptorPoolCreateInfo,
 			p_next: ptr::null(),
-			flags: Default::default(),
+			// Objects free their sets when dropped
+			flags: vk::DESCRIPTOR_POOL_CREATE_FREE_DESCRIPTOR_SET_BIT,
 			pool_size_count: descriptor_sizes.len() as u32,
 			p_pool_sizes: descriptor_sizes.as_ptr(),
-			max_sets: 8, // TODO figure out how to properly do this
+			// One set per object, plus the view matrix, object uniform and cube depth face sets
+			max_sets: MAX_OBJECTS as u32 + 2 + CUBE_FACES as u32,
 		};
 		let descriptor_pool;
 		unsafe {
 			descriptor_pool = rs.device.create_descriptor_pool(&descriptor_pool_info, None).unwrap();
 		}
-		let color_normal_tex_dsl_bindings = [
+		let material_tex_dsl_bindings = [
 			vk::DescriptorSetLayoutBinding {
 				binding: 0,
 				descriptor_type: vk::DescriptorType::CombinedImageSampler,
@@ -149,6 +708,13 @@ impl MainPass
 				stage_flags: vk::SHADER_STAGE_FRAGMENT_BIT,
 				p_immutable_samplers: ptr::null(),
 			},
+			vk::DescriptorSetLayoutBinding {
+				binding: 2,
+				descriptor_type: vk::DescriptorType::CombinedImageSampler,
+				descriptor_count: 1,
+				stage_flags: vk::SHADER_STAGE_FRAGMENT_BIT,
+				p_immutable_samplers: ptr::null(),
+			},
 		];
 		let view_matrix_dsl_binding = [
 			vk::DescriptorSetLayoutBinding {
@@ -158,13 +724,29 @@ impl MainPass
 				stage_flags: vk::SHADER_STAGE_VERTEX_BIT,
 				p_immutable_samplers: ptr::null(),
 			},
+			vk::DescriptorSetLayoutBinding {
+				binding: 1,
+				descriptor_type: vk::DescriptorType::UniformBuffer,
+				descriptor_count: 1,
+				stage_flags: vk::SHADER_STAGE_VERTEX_BIT | vk::SHADER_STAGE_FRAGMENT_BIT,
+				p_immutable_samplers: ptr::null(),
+			},
+		];
+		let object_dsl_binding = [
+			vk::DescriptorSetLayoutBinding {
+				binding: 0,
+				descriptor_type: vk::DescriptorType::UniformBufferDynamic,
+				descriptor_count: 1,
+				stage_flags: vk::SHADER_STAGE_VERTEX_BIT | vk::SHADER_STAGE_FRAGMENT_BIT,
+				p_immutable_samplers: ptr::null(),
+			},
 		];
 		let color_normal_tex_info = vk::DescriptorSetLayoutCreateInfo {
 			s_type: vk::StructureType::DescriptorSetLayoutCreateInfo,
 			p_next: ptr::null(),
 			flags: Default::default(),
-			binding_count: color_normal_tex_dsl_bindings.len() as u32,
-			p_bindings: color_normal_tex_dsl_bindings.as_ptr(),
+			binding_count: material_tex_dsl_bindings.len() as u32,
+			p_bindings: material_tex_dsl_bindings.as_ptr(),
 		};
 		let view_matrix_info = vk::DescriptorSetLayoutCreateInfo {
 			s_type: vk::StructureType::DescriptorSetLayoutCreateInfo,
@@ -174,20 +756,28 @@ impl MainPass
 			p_bindings: view_matrix_dsl_binding.as_ptr(),
 		};
 
+		let object_info = vk::DescriptorSetLayoutCreateInfo {
+			s_type: vk::StructureType::DescriptorSetLayoutCreateInfo,
+			p_next: ptr::null(),
+			flags: Default::default(),
+			binding_count: object_dsl_binding.len() as u32,
+			p_bindings: object_dsl_binding.as_ptr(),
+		};
+
 		let descriptor_set_layouts;
 		unsafe {
 			descriptor_set_layouts = [
 				rs.device.create_descriptor_set_layout(&color_normal_tex_info, None).unwrap(),
 				rs.device.create_descriptor_set_layout(&view_matrix_info, None).unwrap(),
+				rs.device.create_descriptor_set_layout(&object_info, None).unwrap(),
 			];
 		}
 
-		let mv_matrices_push_constant = vk::PushConstantRange {
+		let push_constant_range = vk::PushConstantRange {
 			stage_flags: vk::SHADER_STAGE_VERTEX_BIT,
-			size: 2 * size_of::<Matrix4<f32>>() as u32,
 			offset: 0,
+			size: size_of::<LinePushConstants>() as u32,
 		};
-
 		let layout_create_info = vk::PipelineLayoutCreateInfo {
 			s_type: vk::StructureType::PipelineLayoutCreateInfo,
 			p_next: ptr::null(),
@@ -195,7 +785,7 @@ impl MainPass
 			set_layout_count: descriptor_set_layouts.len() as u32,
 			p_set_layouts: descriptor_set_layouts.as_ptr(),
 			push_constant_range_count: 1,
-			p_push_constant_ranges: &mv_matrices_push_constant,
+			p_push_constant_ranges: &push_constant_range,
 		};
 
 		let pipeline_layout;
@@ -203,8 +793,78 @@ impl MainPass
 			pipeline_layout = rs.device.create_pipeline_layout(&layout_create_info, None).unwrap();
 		}
 
-		let vertex_shader_module = rs.load_shader("shaders/phong_vert.spv");
-		let fragment_shader_module = rs.load_shader("shaders/phong_frag.spv");
+		let (viewport, scissor) = MainPass::create_viewport_and_scissor(render_size);
+		let options = PipelineOptions {
+			stencil: stencil,
+			depth_bias: true,
+			gbuffer: gbuffer,
+			..Default::default()
+		};
+		let pipeline = MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &options);
+
+		(descriptor_pool, descriptor_set_layouts.to_vec(), pipeline_layout, viewport, scissor, pipeline)
+	}
+
+	/// Creates a graphics pipeline variant for the renderpass.
+	fn create_graphics_pipeline(
+		rs: &RenderState, render_size: vk::Extent3D, renderpass: vk::RenderPass, pipeline_layout: vk::PipelineLayout,
+		options: &PipelineOptions,
+	) -> vk::Pipeline
+	{
+		let vertex_shader_module = if options.depth_only
+		{
+			rs.load_shader("shaders/depth_only_vert.spv")
+		}
+		else if options.sky
+		{
+			rs.load_shader("shaders/sky_vert.spv")
+		}
+		else
+		{
+			rs.load_shader("shaders/phong_vert.spv")
+		};
+		let fragment_shader_module = if options.gbuffer
+		{
+			rs.load_shader("shaders/gbuffer_frag.spv")
+		}
+		else if options.sky
+		{
+			rs.load_shader("shaders/sky_frag.spv")
+		}
+		else
+		{
+			rs.load_shader("shaders/phong_frag.spv")
+		};
+
+		let specialization_data = options.specialization_data();
+		let specialization_entries = [
+			vk::SpecializationMapEntry {
+				constant_id: 0,
+				offset: 0,
+				size: size_of::<vk::Bool32>(),
+			},
+			vk::SpecializationMapEntry {
+				constant_id: 1,
+				offset: size_of::<vk::Bool32>() as u32,
+				size: size_of::<vk::Bool32>(),
+			},
+			vk::SpecializationMapEntry {
+				constant_id: 2,
+				offset: 2 * size_of::<vk::Bool32>() as u32,
+				size: size_of::<vk::Bool32>(),
+			},
+			vk::SpecializationMapEntry {
+				constant_id: 3,
+				offset: 3 * size_of::<vk::Bool32>() as u32,
+				size: size_of::<vk::Bool32>(),
+			},
+		];
+		let specialization_info = vk::SpecializationInfo {
+			map_entry_count: specialization_entries.len() as u32,
+			p_map_entries: specialization_entries.as_ptr(),
+			data_size: size_of::<[vk::Bool32; 4]>(),
+			p_data: specialization_data.as_ptr() as *const vk::c_void,
+		};
 
 		let shader_entry_name = CString::new("main").unwrap();
 		let shader_stage_create_infos = [
@@ -214,7 +874,7 @@ impl MainPass
 				flags: Default::default(),
 				module: vertex_shader_module,
 				p_name: shader_entry_name.as_ptr(),
-				p_specialization_info: ptr::null(),
+				p_specialization_info: &specialization_info,
 				stage: vk::SHADER_STAGE_VERTEX_BIT,
 			},
 			vk::PipelineShaderStageCreateInfo {
@@ -223,61 +883,30 @@ impl MainPass
 				flags: Default::default(),
 				module: fragment_shader_module,
 				p_name: shader_entry_name.as_ptr(),
-				p_specialization_info: ptr::null(),
+				p_specialization_info: &specialization_info,
 				stage: vk::SHADER_STAGE_FRAGMENT_BIT,
 			},
 		];
 
-		// TODO: These would probably do better to live where the Vertex struct is defined.
-		let vertex_binding_description = vk::VertexInputBindingDescription {
-			binding: 0,
-			stride: size_of::<Vertex>() as u32,
-			input_rate: vk::VertexInputRate::Vertex,
-		};
-
-		let vertex_position_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 0,
-			format: vk::Format::R32g32b32Sfloat,
-			offset: 0 as u32,
-		};
-
-		let vertex_normal_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 1,
-			format: vk::Format::R32g32b32Sfloat,
-			offset: 3 * size_of::<f32>() as u32, // TODO: Make these use offset_of! macro.
-		};
-
-		let vertex_tangent_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 2,
-			format: vk::Format::R32g32b32Sfloat,
-			offset: 6 * size_of::<f32>() as u32, // TODO: Make these use offset_of! macro.
-		};
-
-		let vertex_bitangent_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 3,
-			format: vk::Format::R32g32b32Sfloat,
-			offset: 9 * size_of::<f32>() as u32, // TODO: Make these use offset_of! macro.
+		// Depth only pipelines have no fragment shader
+		let stage_count = if options.depth_only
+		{
+			1
+		}
+		else
+		{
+			shader_stage_create_infos.len()
 		};
 
-		let vertex_texcoord_attribute_description = vk::VertexInputAttributeDescription {
-			binding: 0,
-			location: 4,
-			format: vk::Format::R32g32Sfloat,
-			offset: 12 * size_of::<f32>() as u32, // TODO: Make these use offset_of! macro.
+		// The sky generates its vertices from the vertex index
+		let (vertex_input_binding_descriptions, vertex_input_attribute_descriptions) = if options.sky
+		{
+			(Vec::new(), Vec::new())
+		}
+		else
+		{
+			(vec![Vertex::binding_description()], Vertex::attribute_descriptions().to_vec())
 		};
-
-		let vertex_input_binding_descriptions = [vertex_binding_description];
-		let vertex_input_attribute_descriptions = [
-			vertex_position_attribute_description,
-			vertex_normal_attribute_description,
-			vertex_tangent_attribute_description,
-			vertex_bitangent_attribute_description,
-			vertex_texcoord_attribute_description,
-		];
 		let vertex_input_state_info = vk::PipelineVertexInputStateCreateInfo {
 			s_type: vk::StructureType::PipelineVertexInputStateCreateInfo,
 			p_next: ptr::null(),
@@ -291,27 +920,10 @@ impl MainPass
 			s_type: vk::StructureType::PipelineInputAssemblyStateCreateInfo,
 			p_next: ptr::null(),
 			flags: Default::default(),
-			primitive_restart_enable: 0,
-			topology: vk::PrimitiveTopology::TriangleList,
-		};
-		let viewport = vk::Viewport {
-			x: 0.0,
-			y: 0.0,
-			width: render_size.width as f32,
-			height: render_size.height as f32,
-			min_depth: 0.0,
-			max_depth: 1.0,
-		};
-		let scissor = vk::Rect2D {
-			offset: vk::Offset2D {
-				x: 0,
-				y: 0,
-			},
-			extent: vk::Extent2D {
-				width: render_size.width,
-				height: render_size.height,
-			},
+			primitive_restart_enable: (options.topology == vk::PrimitiveTopology::TriangleStrip) as vk::Bool32,
+			topology: options.topology,
 		};
+		let (viewport, scissor) = MainPass::create_viewport_and_scissor(render_size);
 		let viewport_state_info = vk::PipelineViewportStateCreateInfo {
 			s_type: vk::StructureType::PipelineViewportStateCreateInfo,
 			p_next: ptr::null(),
@@ -321,19 +933,42 @@ impl MainPass
 			viewport_count: 1,
 			p_viewports: &viewport,
 		};
+		let conservative_state_info = PipelineRasterizationConservativeStateCreateInfoEXT {
+			s_type: STRUCTURE_TYPE_PIPELINE_RASTERIZATION_CONSERVATIVE_STATE_CREATE_INFO_EXT,
+			p_next: ptr::null(),
+			flags: 0,
+			conservative_rasterization_mode: CONSERVATIVE_RASTERIZATION_MODE_OVERESTIMATE_EXT,
+			extra_primitive_overestimation_size: 0.0,
+		};
 		let rasterization_info = vk::PipelineRasterizationStateCreateInfo {
 			s_type: vk::StructureType::PipelineRasterizationStateCreateInfo,
-			p_next: ptr::null(),
+			p_next: if options.conservative_raster
+			{
+				&conservative_state_info as *const _ as *const vk::c_void
+			}
+			else
+			{
+				ptr::null()
+			},
 			flags: Default::default(),
-			cull_mode: vk::CULL_MODE_BACK_BIT,
+			// The camera may be inside a proxy, so its back faces must count as well. Line quads face
+			// either way depending on the side they are seen from.
+			cull_mode: if options.occlusion_proxy || options.wide_lines || options.sky
+			{
+				vk::CullModeFlags::empty()
+			}
+			else
+			{
+				vk::CULL_MODE_BACK_BIT
+			},
 			depth_bias_clamp: 0.0,
 			depth_bias_constant_factor: 0.0,
-			depth_bias_enable: 0,
+			depth_bias_enable: options.depth_bias as vk::Bool32,
 			depth_bias_slope_factor: 0.0,
-			depth_clamp_enable: 0,
+			depth_clamp_enable: options.depth_clamp as vk::Bool32,
 			front_face: vk::FrontFace::CounterClockwise,
-			line_width: 1.0,
-			polygon_mode: vk::PolygonMode::Fill,
+			line_width: options.line_width,
+			polygon_mode: options.polygon_mode,
 			rasterizer_discard_enable: 0,
 		};
 		let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
@@ -356,46 +991,106 @@ impl MainPass
 			write_mask: 0,
 			reference: 0,
 		};
+		let stencil_state = match options.stencil
+		{
+			Some(ref stencil) => stencil.op_state(),
+			None => noop_stencil_state,
+		};
 		let depth_state_info = vk::PipelineDepthStencilStateCreateInfo {
 			s_type: vk::StructureType::PipelineDepthStencilStateCreateInfo,
 			p_next: ptr::null(),
 			flags: Default::default(),
-			depth_test_enable: 1,
-			depth_write_enable: 1,
+			depth_test_enable: options.depth_test as vk::Bool32,
+			depth_write_enable: (options.depth_test && !options.occlusion_proxy &&
+				options.alpha_mode == AlphaMode::Opaque) as vk::Bool32,
 			depth_compare_op: vk::CompareOp::LessOrEqual,
 			depth_bounds_test_enable: 0,
-			stencil_test_enable: 0,
-			front: noop_stencil_state.clone(),
-			back: noop_stencil_state.clone(),
+			stencil_test_enable: options.stencil.is_some() as vk::Bool32,
+			front: stencil_state.clone(),
+			back: stencil_state.clone(),
 			max_depth_bounds: 1.0,
 			min_depth_bounds: 0.0,
 		};
-		let color_blend_attachment_states = [
-			vk::PipelineColorBlendAttachmentState {
-				blend_enable: 0,
-				src_color_blend_factor: vk::BlendFactor::SrcColor,
-				dst_color_blend_factor: vk::BlendFactor::OneMinusDstColor,
-				color_blend_op: vk::BlendOp::Add,
-				src_alpha_blend_factor: vk::BlendFactor::Zero,
-				dst_alpha_blend_factor: vk::BlendFactor::Zero,
-				alpha_blend_op: vk::BlendOp::Add,
-				color_write_mask: vk::ColorComponentFlags::all(),
-			},
-		];
-		let color_blend_state = vk::PipelineColorBlendStateCreateInfo {
-			s_type: vk::StructureType::PipelineColorBlendStateCreateInfo,
-			p_next: ptr::null(),
-			flags: Default::default(),
-			logic_op_enable: 0,
-			logic_op: vk::LogicOp::Clear,
-			attachment_count: color_blend_attachment_states.len() as u32,
-			p_attachments: color_blend_attachment_states.as_ptr(),
-			blend_constants: [0.0, 0.0, 0.0, 0.0],
+		// Premultiplied colors already contain their alpha, so only the destination gets weighted. Overdraw
+		// counting simply adds up the fragments.
+		let src_color_blend_factor = match options.alpha_mode
+		{
+			_ if options.overdraw => vk::BlendFactor::One,
+			AlphaMode::Opaque => vk::BlendFactor::SrcColor,
+			AlphaMode::Straight => vk::BlendFactor::SrcAlpha,
+			AlphaMode::Premultiplied => vk::BlendFactor::One,
 		};
-		let dynamic_state = [vk::DynamicState::Viewport, vk::DynamicState::Scissor];
-		let dynamic_state_info = vk::PipelineDynamicStateCreateInfo {
-			s_type: vk::StructureType::PipelineDynamicStateCreateInfo,
-			p_next: ptr::null(),
+		let color_blend_attachment_state = vk::PipelineColorBlendAttachmentState {
+			blend_enable: (options.alpha_mode != AlphaMode::Opaque || options.overdraw) as vk::Bool32,
+			src_color_blend_factor: src_color_blend_factor,
+			dst_color_blend_factor: if options.overdraw
+			{
+				vk::BlendFactor::One
+			}
+			else if options.alpha_mode == AlphaMode::Opaque
+			{
+				vk::BlendFactor::OneMinusDstColor
+			}
+			else
+			{
+				vk::BlendFactor::OneMinusSrcAlpha
+			},
+			color_blend_op: vk::BlendOp::Add,
+			src_alpha_blend_factor: vk::BlendFactor::One,
+			dst_alpha_blend_factor: if options.overdraw
+			{
+				vk::BlendFactor::One
+			}
+			else
+			{
+				vk::BlendFactor::OneMinusSrcAlpha
+			},
+			alpha_blend_op: vk::BlendOp::Add,
+			color_write_mask: if options.occlusion_proxy
+			{
+				vk::ColorComponentFlags::empty()
+			}
+			else
+			{
+				vk::ColorComponentFlags::all()
+			},
+		};
+		// The G-buffer targets are blended like the color
+		let color_attachment_count = if options.depth_only
+		{
+			0
+		}
+		else if options.gbuffer
+		{
+			3
+		}
+		else
+		{
+			1
+		};
+		let color_blend_attachment_states = vec![color_blend_attachment_state; color_attachment_count];
+		let color_blend_state = vk::PipelineColorBlendStateCreateInfo {
+			s_type: vk::StructureType::PipelineColorBlendStateCreateInfo,
+			p_next: ptr::null(),
+			flags: Default::default(),
+			logic_op_enable: 0,
+			logic_op: vk::LogicOp::Clear,
+			attachment_count: color_blend_attachment_states.len() as u32,
+			p_attachments: color_blend_attachment_states.as_ptr(),
+			blend_constants: [0.0, 0.0, 0.0, 0.0],
+		};
+		let mut dynamic_state = vec![vk::DynamicState::Viewport, vk::DynamicState::Scissor];
+		if options.stencil.is_some()
+		{
+			dynamic_state.push(vk::DynamicState::StencilReference);
+		}
+		if options.depth_bias
+		{
+			dynamic_state.push(vk::DynamicState::DepthBias);
+		}
+		let dynamic_state_info = vk::PipelineDynamicStateCreateInfo {
+			s_type: vk::StructureType::PipelineDynamicStateCreateInfo,
+			p_next: ptr::null(),
 			flags: Default::default(),
 			dynamic_state_count: dynamic_state.len() as u32,
 			p_dynamic_states: dynamic_state.as_ptr(),
@@ -404,7 +1099,7 @@ impl MainPass
 			s_type: vk::StructureType::GraphicsPipelineCreateInfo,
 			p_next: ptr::null(),
 			flags: vk::PipelineCreateFlags::empty(),
-			stage_count: shader_stage_create_infos.len() as u32,
+			stage_count: stage_count as u32,
 			p_stages: shader_stage_create_infos.as_ptr(),
 			p_vertex_input_state: &vertex_input_state_info,
 			p_input_assembly_state: &vertex_input_assembly_state_info,
@@ -432,16 +1127,22 @@ impl MainPass
 			rs.device.destroy_shader_module(vertex_shader_module, None);
 		}
 
-		(descriptor_pool, descriptor_set_layouts.to_vec(), pipeline_layout, viewport, scissor, graphics_pipelines[0])
+		graphics_pipelines[0]
 	}
 
 	/// Creates framebuffers for the presentable images, one per image.
+	///
+	/// The G-buffer views are attached after the color and depth, if there is a G-buffer.
 	fn create_framebuffer(
 		rs: &RenderState, render_size: vk::Extent3D, color_view: vk::ImageView, depth_view: vk::ImageView,
-		renderpass: vk::RenderPass,
+		gbuffer: Option<&GBuffer>, renderpass: vk::RenderPass,
 	) -> vk::Framebuffer
 	{
-		let framebuffer_attachments = [color_view, depth_view];
+		let mut framebuffer_attachments = vec![color_view, depth_view];
+		if let Some(gbuffer) = gbuffer
+		{
+			framebuffer_attachments.extend_from_slice(&gbuffer.views());
+		}
 		let frame_buffer_create_info = vk::FramebufferCreateInfo {
 			s_type: vk::StructureType::FramebufferCreateInfo,
 			p_next: ptr::null(),
@@ -478,26 +1179,28 @@ impl MainPass
 		commandbuffers[0]
 	}
 
-	/// Initializes the MainPass based on a RenderState
-	///
-	/// This will set up the renderpass, etc.
-	pub fn init(rs: &RenderState, cfg: &Config) -> MainPass
+	/// Creates the color and depth images to render to.
+	fn create_render_targets(
+		rs: &RenderState, render_size: vk::Extent3D, render_format: vk::Format, depth_format: vk::Format,
+	) -> (Texture, Texture)
 	{
-		let render_format = vk::Format::R8g8b8a8Unorm;
-		let render_size = vk::Extent3D {
-			width: cfg.render_width,
-			height: cfg.render_height,
-			depth: 1,
+		let depth_aspect_mask = if has_stencil(depth_format)
+		{
+			vk::IMAGE_ASPECT_DEPTH_BIT | vk::IMAGE_ASPECT_STENCIL_BIT
+		}
+		else
+		{
+			vk::IMAGE_ASPECT_DEPTH_BIT
 		};
 
-		// Create image to render to.
 		let render_image = rs.create_texture(
 			render_size,
 			vk::ImageType::Type2d,
 			vk::ImageViewType::Type2d,
 			render_format,
 			vk::IMAGE_ASPECT_COLOR_BIT,
-			vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT | vk::IMAGE_USAGE_SAMPLED_BIT,
+			// Transfer source for reading the frame back
+			vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT | vk::IMAGE_USAGE_SAMPLED_BIT | vk::IMAGE_USAGE_TRANSFER_SRC_BIT,
 			vk::ACCESS_COLOR_ATTACHMENT_READ_BIT | vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT,
 			vk::ImageLayout::ColorAttachmentOptimal,
 			vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
@@ -507,27 +1210,333 @@ impl MainPass
 			render_size,
 			vk::ImageType::Type2d,
 			vk::ImageViewType::Type2d,
-			vk::Format::D32Sfloat,
-			vk::IMAGE_ASPECT_DEPTH_BIT,
-			vk::IMAGE_USAGE_DEPTH_STENCIL_ATTACHMENT_BIT,
+			depth_format,
+			depth_aspect_mask,
+			vk::IMAGE_USAGE_DEPTH_STENCIL_ATTACHMENT_BIT | vk::IMAGE_USAGE_SAMPLED_BIT,
 			vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_READ_BIT | vk::ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT,
 			vk::ImageLayout::DepthStencilAttachmentOptimal,
 			vk::PIPELINE_STAGE_ALL_GRAPHICS_BIT,
 			None,
 		);
 
-		let renderpass = MainPass::create_renderpass(rs, render_format);
+		(render_image, depth_image)
+	}
+
+	/// Creates a depth-only view of the depth image, for sampling it in a shader.
+	///
+	/// Views used for sampling may only have a single aspect, so the attachment view can't be used
+	/// for depth/stencil formats.
+	fn create_depth_sample_view(rs: &RenderState, depth_image: &Texture, depth_format: vk::Format) -> vk::ImageView
+	{
+		let create_view_info = vk::ImageViewCreateInfo {
+			s_type: vk::StructureType::ImageViewCreateInfo,
+			p_next: ptr::null(),
+			flags: Default::default(),
+			view_type: vk::ImageViewType::Type2d,
+			format: depth_format,
+			components: vk::ComponentMapping {
+				r: vk::ComponentSwizzle::R,
+				g: vk::ComponentSwizzle::G,
+				b: vk::ComponentSwizzle::B,
+				a: vk::ComponentSwizzle::A,
+			},
+			subresource_range: vk::ImageSubresourceRange {
+				aspect_mask: vk::IMAGE_ASPECT_DEPTH_BIT,
+				base_mip_level: 0,
+				level_count: 1,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+			image: depth_image.image,
+		};
+		let view;
+		unsafe {
+			view = rs.device.create_image_view(&create_view_info, None).unwrap();
+		}
+
+		view
+	}
+
+	/// Applies the render scale to a size, never going below one pixel.
+	fn scale_size(size: vk::Extent2D, scale: f32) -> vk::Extent3D
+	{
+		vk::Extent3D {
+			width: ((size.width as f32 * scale).round() as u32).max(1),
+			height: ((size.height as f32 * scale).round() as u32).max(1),
+			depth: 1,
+		}
+	}
+
+	/// Creates the viewport and scissor covering the whole render area.
+	fn create_viewport_and_scissor(render_size: vk::Extent3D) -> (vk::Viewport, vk::Rect2D)
+	{
+		let viewport = vk::Viewport {
+			x: 0.0,
+			y: 0.0,
+			width: render_size.width as f32,
+			height: render_size.height as f32,
+			min_depth: 0.0,
+			max_depth: 1.0,
+		};
+		let scissor = vk::Rect2D {
+			offset: vk::Offset2D {
+				x: 0,
+				y: 0,
+			},
+			extent: vk::Extent2D {
+				width: render_size.width,
+				height: render_size.height,
+			},
+		};
+
+		(viewport, scissor)
+	}
+
+	/// Initializes the MainPass based on a RenderState
+	///
+	/// This will set up the renderpass, etc.
+	/// Returns the line width to use and whether lines must be drawn as quads to get it.
+	fn pick_line_rendering(rs: &RenderState, cfg: &Config) -> (f32, bool)
+	{
+		let width = cfg.line_width.max(1.0);
+		let max_width = if rs.features.wide_lines
+		{
+			rs.device_properties.limits.line_width_range[1]
+		}
+		else
+		{
+			1.0
+		};
+		match cfg.line_rendering
+		{
+			LineRendering::Quads => (width, true),
+			LineRendering::Auto if width > max_width => (width, true),
+			LineRendering::Native if width > max_width =>
+			{
+				warning!("Lines are limited to a width of {} on this device, use Quads", max_width);
+				(max_width, false)
+			}
+			LineRendering::Auto | LineRendering::Native => (width, false),
+		}
+	}
+
+	/// Returns true if shadow depth should be clamped, warning if the device can't.
+	///
+	/// Casters between the light and the near plane are clipped otherwise, so their shadows lose the
+	/// parts in front of the plane and appear peeled off where they cross it.
+	fn shadow_depth_clamp(rs: &RenderState, cfg: &Config) -> bool
+	{
+		if cfg.shadow_depth_clamp && !rs.features.depth_clamp
+		{
+			warning!("The device doesn't support depth clamp, shadow casters near the light get clipped");
+		}
+		cfg.shadow_depth_clamp && rs.features.depth_clamp
+	}
+
+	pub fn init(rs: &RenderState, cfg: &Config) -> MainPass
+	{
+		let render_format = vk::Format::R8g8b8a8Unorm;
+		// Render at the physical pixel size to avoid blurring on HiDPI displays
+		let (render_width, render_height) = rs.base_render_size(cfg);
+		let base_size = vk::Extent2D {
+			width: render_width,
+			height: render_height,
+		};
+		let render_scale = cfg.render_scale.max(MIN_RENDER_SCALE).min(MAX_RENDER_SCALE);
+		let render_size = MainPass::scale_size(base_size, render_scale);
+
+		// Create image to render to.
+		let depth_format = rs.pick_depth_format(cfg.depth_stencil);
+		let stencil = if has_stencil(depth_format)
+		{
+			Some(cfg.stencil)
+		}
+		else
+		{
+			None
+		};
+		let (render_image, depth_image) =
+			MainPass::create_render_targets(rs, render_size, render_format, depth_format);
+		let depth_sample_view = MainPass::create_depth_sample_view(rs, &depth_image, depth_format);
+		let gbuffer = if cfg.gbuffer
+		{
+			Some(GBuffer::new(rs, render_size))
+		}
+		else
+		{
+			None
+		};
+
+		// The sky covers the whole frame, but it can be switched off at runtime
+		let color_load_op = cfg.color_load_op.attachment_load_op(false);
+		let renderpass = MainPass::create_renderpass(rs, render_format, depth_format, color_load_op, cfg.gbuffer);
 		let (descriptor_pool, descriptor_set_layouts, pipeline_layout, viewport, scissor, pipeline) =
-			MainPass::create_pipeline(rs, render_size, renderpass);
-		let framebuffer =
-			MainPass::create_framebuffer(rs, render_size, render_image.view, depth_image.view, renderpass);
+			MainPass::create_pipeline(rs, render_size, renderpass, stencil, cfg.gbuffer);
+		let framebuffer = MainPass::create_framebuffer(
+			rs,
+			render_size,
+			render_image.view,
+			depth_image.view,
+			gbuffer.as_ref(),
+			renderpass,
+		);
 		let commandbuffer = MainPass::create_commandbuffer(rs);
-
-		let (vmat_buf, vmat_mem) = rs.create_buffer(
-			vk::BUFFER_USAGE_UNIFORM_BUFFER_BIT,
-			vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT,
-			size_of::<Matrix4<f32>>() as u64,
+		let upload_commandbuffer = MainPass::create_commandbuffer(rs);
+		let vertex_color_options = PipelineOptions {
+			stencil: stencil,
+			vertex_color: true,
+			depth_bias: true,
+			gbuffer: cfg.gbuffer,
+			..Default::default()
+		};
+		let vertex_color_pipeline =
+			MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &vertex_color_options);
+		let overlay_options = PipelineOptions {
+			depth_test: false,
+			..vertex_color_options
+		};
+		let overlay_pipeline =
+			MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &overlay_options);
+		let (line_width, line_quads) = MainPass::pick_line_rendering(rs, cfg);
+		let line_options = if line_quads
+		{
+			PipelineOptions {
+				wide_lines: true,
+				..vertex_color_options
+			}
+		}
+		else
+		{
+			PipelineOptions {
+				topology: vk::PrimitiveTopology::LineList,
+				line_width: line_width,
+				..vertex_color_options
+			}
+		};
+		let line_pipeline =
+			MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &line_options);
+		let strip_options = PipelineOptions {
+			topology: vk::PrimitiveTopology::TriangleStrip,
+			stencil: stencil,
+			depth_bias: true,
+			gbuffer: cfg.gbuffer,
+			..Default::default()
+		};
+		let strip_pipeline =
+			MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &strip_options);
+		let blended_options = PipelineOptions {
+			stencil: stencil,
+			alpha_mode: AlphaMode::Straight,
+			depth_bias: true,
+			gbuffer: cfg.gbuffer,
+			..Default::default()
+		};
+		let blended_pipeline =
+			MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &blended_options);
+		let blended_premultiplied_options = PipelineOptions {
+			alpha_mode: AlphaMode::Premultiplied,
+			..blended_options
+		};
+		let blended_premultiplied_pipeline = MainPass::create_graphics_pipeline(
+			rs,
+			render_size,
+			renderpass,
+			pipeline_layout,
+			&blended_premultiplied_options,
 		);
+		// Triangle fans are optional in the portability subset, and MoltenVK doesn't support them
+		let fan_pipeline = if rs.portability_subset
+		{
+			None
+		}
+		else
+		{
+			let options = PipelineOptions {
+				topology: vk::PrimitiveTopology::TriangleFan,
+				..vertex_color_options
+			};
+			Some(MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &options))
+		};
+		// Every fragment counts, so nothing is depth tested
+		let overdraw_options = PipelineOptions {
+			overdraw: true,
+			depth_test: false,
+			depth_bias: true,
+			gbuffer: cfg.gbuffer,
+			..Default::default()
+		};
+		let overdraw_pipelines = [
+			MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &overdraw_options),
+			MainPass::create_graphics_pipeline(
+				rs,
+				render_size,
+				renderpass,
+				pipeline_layout,
+				&PipelineOptions {
+					topology: vk::PrimitiveTopology::TriangleStrip,
+					..overdraw_options
+				},
+			),
+			MainPass::create_graphics_pipeline(
+				rs,
+				render_size,
+				renderpass,
+				pipeline_layout,
+				&PipelineOptions {
+					overdraw: true,
+					depth_test: false,
+					..line_options
+				},
+			),
+		];
+		let overdraw_fan_pipeline = fan_pipeline.map(|_| {
+			let options = PipelineOptions {
+				topology: vk::PrimitiveTopology::TriangleFan,
+				..overdraw_options
+			};
+			MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &options)
+		});
+		// Native lines always cover their pixels, only the thin triangles of line quads can drop out
+		let debug_pipeline = if rs.conservative_raster && line_quads
+		{
+			let options = PipelineOptions {
+				conservative_raster: true,
+				..line_options
+			};
+			Some(MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &options))
+		}
+		else
+		{
+			if rs.conservative_raster
+			{
+				verbose!("Lines are drawn natively, conservative rasterization is not used");
+			}
+			None
+		};
+
+		// The sky only writes the color, and isn't drawn in the secondary command buffers
+		let sky = if cfg.gbuffer || cfg.record_threads > 1
+		{
+			None
+		}
+		else
+		{
+			let sky_layout = Sky::create_pipeline_layout(rs);
+			let options = PipelineOptions {
+				sky: true,
+				depth_test: false,
+				..Default::default()
+			};
+			let sky_pipeline = MainPass::create_graphics_pipeline(rs, render_size, renderpass, sky_layout, &options);
+			Some(Sky::new(rs, sky_layout, sky_pipeline, cfg.sky_horizon_color, cfg.sky_zenith_color))
+		};
+		// Parallel recording is warned about with the other options it skips when the config is loaded
+		if cfg.background == Background::Gradient && cfg.gbuffer
+		{
+			warning!("The gradient sky is not supported with a G-buffer, using the clear color");
+		}
+
+		let view_matrix_ub = UniformBuffer::new(rs, size_of::<Matrix4<f32>>() as u64);
 		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
 			s_type: vk::StructureType::DescriptorSetAllocateInfo,
 			p_next: ptr::null(),
@@ -540,6 +1549,131 @@ impl MainPass
 			view_matrix_ds = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap();
 		}
 
+		// The lighting is only written on changes, so the descriptor only needs to be written once
+		let light_ub = UniformBuffer::new(rs, light_block_size());
+		let light_ub_descriptor = vk::DescriptorBufferInfo {
+			buffer: light_ub.buffer,
+			offset: 0,
+			range: light_block_size(),
+		};
+		let write_desc_sets = [
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WriteDescriptorSet,
+				p_next: ptr::null(),
+				dst_set: view_matrix_ds[0],
+				dst_binding: 1,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::UniformBuffer,
+				p_image_info: ptr::null(),
+				p_buffer_info: &light_ub_descriptor,
+				p_texel_buffer_view: ptr::null(),
+			},
+		];
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+
+		// The dynamic offsets must respect the device's uniform buffer alignment
+		let object_ub_stride = align_up(
+			size_of::<ObjectUniforms>() as u64,
+			rs.device_properties.limits.min_uniform_buffer_offset_alignment,
+		);
+		let object_ub = UniformBuffer::new(rs, object_ub_stride * MAX_OBJECTS as u64);
+		let desc_alloc_info = vk::DescriptorSetAllocateInfo {
+			s_type: vk::StructureType::DescriptorSetAllocateInfo,
+			p_next: ptr::null(),
+			descriptor_pool: descriptor_pool,
+			descriptor_set_count: 1,
+			p_set_layouts: &descriptor_set_layouts[2],
+		};
+		let object_ds;
+		unsafe {
+			object_ds = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap();
+		}
+		// The buffer never changes, so the descriptor only needs to be written once
+		let object_ub_descriptor = vk::DescriptorBufferInfo {
+			buffer: object_ub.buffer,
+			offset: 0,
+			range: size_of::<ObjectUniforms>() as u64,
+		};
+		let write_desc_sets = [
+			vk::WriteDescriptorSet {
+				s_type: vk::StructureType::WriteDescriptorSet,
+				p_next: ptr::null(),
+				dst_set: object_ds[0],
+				dst_binding: 0,
+				dst_array_element: 0,
+				descriptor_count: 1,
+				descriptor_type: vk::DescriptorType::UniformBufferDynamic,
+				p_image_info: ptr::null(),
+				p_buffer_info: &object_ub_descriptor,
+				p_texel_buffer_view: ptr::null(),
+			},
+		];
+		unsafe {
+			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
+
+		let cube_depth = if cfg.cube_depth_size > 0
+		{
+			// Sampled as a whole, so it can't have a stencil aspect
+			let cube_depth_format = rs.pick_depth_format(false);
+			let cube_renderpass = CubeDepthTarget::create_renderpass(rs, cube_depth_format);
+			let cube_size = vk::Extent3D {
+				width: cfg.cube_depth_size,
+				height: cfg.cube_depth_size,
+				depth: 1,
+			};
+			let options = PipelineOptions {
+				depth_only: true,
+				depth_clamp: MainPass::shadow_depth_clamp(rs, cfg),
+				..Default::default()
+			};
+			let cube_pipeline =
+				MainPass::create_graphics_pipeline(rs, cube_size, cube_renderpass, pipeline_layout, &options);
+			Some(CubeDepthTarget::new(
+				rs,
+				cfg.cube_depth_size,
+				cube_depth_format,
+				cube_renderpass,
+				cube_pipeline,
+				descriptor_pool,
+				descriptor_set_layouts[1],
+				light_ub.buffer,
+			))
+		}
+		else
+		{
+			if cfg.shadow_depth_clamp
+			{
+				warning!("shadow_depth_clamp only applies to the cube depth pass, which is disabled");
+			}
+			None
+		};
+
+		// The queries are recorded in the main command buffer, so they don't work with parallel recording
+		let occlusion = if cfg.occlusion_culling && cfg.record_threads > 1
+		{
+			warning!("Occlusion culling is not supported with parallel recording, disabling it");
+			None
+		}
+		else if cfg.occlusion_culling
+		{
+			let proxy_options = PipelineOptions {
+				occlusion_proxy: true,
+				depth_bias: false,
+				..vertex_color_options
+			};
+			let proxy_pipeline =
+				MainPass::create_graphics_pipeline(rs, render_size, renderpass, pipeline_layout, &proxy_options);
+			Some(OcclusionQueries::new(rs, MAX_OBJECTS as u32, proxy_pipeline))
+		}
+		else
+		{
+			None
+		};
+
 		MainPass {
 			renderpass: renderpass,
 			descriptor_pool: descriptor_pool,
@@ -548,25 +1682,211 @@ impl MainPass
 			viewport: viewport,
 			scissor: scissor,
 			pipeline: pipeline,
+			debug_pipeline: debug_pipeline,
+			vertex_color_pipeline: vertex_color_pipeline,
+			overlay_pipeline: overlay_pipeline,
+			line_pipeline: line_pipeline,
+			line_width: line_width,
+			line_quads: line_quads,
+			strip_pipeline: strip_pipeline,
+			blended_pipeline: blended_pipeline,
+			blended_premultiplied_pipeline: blended_premultiplied_pipeline,
+			fan_pipeline: fan_pipeline,
+			overdraw_pipelines: overdraw_pipelines,
+			overdraw_fan_pipeline: overdraw_fan_pipeline,
+			show_overdraw: false,
 			framebuffer: framebuffer,
 			commandbuffer: commandbuffer,
+			upload_commandbuffer: upload_commandbuffer,
 
 			render_image: render_image,
 			depth_image: depth_image,
+			gbuffer: gbuffer,
+			depth_sample_view: depth_sample_view,
+			depth_format: depth_format,
+			stencil: stencil,
+			base_size: base_size,
+			render_scale: render_scale,
 
-			view_matrix_ub: vmat_buf,
-			view_matrix_ub_mem: vmat_mem,
+			view_matrix_ub: view_matrix_ub,
+			uploaded_view_matrix: None,
 			view_matrix_ds: view_matrix_ds,
 
+			light_ub: light_ub,
+			clear_color: [0.0, 1.0, 0.0, 1.0],
+			sky: sky,
+			background: cfg.background,
+
+			object_ub: object_ub,
+			object_ub_stride: object_ub_stride,
+			object_ds: object_ds,
+
+			recorder: if cfg.record_threads > 1
+			{
+				Some(ParallelRecorder::new(rs, cfg.record_threads))
+			}
+			else
+			{
+				None
+			},
+
+			cube_depth: cube_depth,
+			occlusion: occlusion,
+
 			// Keep a pointer to the device for cleanup
 			device: Rc::clone(&rs.device),
 		}
 	}
+
+	/// Releases the render targets and the framebuffer using them.
+	fn cleanup_render_targets(&mut self)
+	{
+		unsafe {
+			// Always wait for device idle
+			self.device.device_wait_idle().unwrap();
+
+			self.device.destroy_framebuffer(self.framebuffer, None);
+
+			self.device.destroy_image_view(self.depth_sample_view, None);
+			self.device.destroy_sampler(self.depth_image.sampler, None);
+			self.device.destroy_image_view(self.depth_image.view, None);
+			self.device.destroy_image(self.depth_image.image, None);
+			self.device.free_memory(self.depth_image.memory, None);
+
+			self.device.destroy_sampler(self.render_image.sampler, None);
+			self.device.destroy_image_view(self.render_image.view, None);
+			self.device.destroy_image(self.render_image.image, None);
+			self.device.free_memory(self.render_image.memory, None);
+		}
+		if let Some(ref gbuffer) = self.gbuffer
+		{
+			gbuffer.destroy(&self.device);
+		}
+	}
+
+	/// Recreates the render targets with the given size in physical pixels.
+	///
+	/// The render scale is applied on top of the given size.
+	pub fn resize(&mut self, rs: &RenderState, width: u32, height: u32)
+	{
+		self.base_size = vk::Extent2D {
+			width: width,
+			height: height,
+		};
+		self.recreate_render_targets(rs);
+	}
+
+	/// Sets the fraction of the base size to render at, clamped to sane bounds.
+	///
+	/// The render targets are recreated at the start of the next frame.
+	pub fn set_render_scale(&mut self, scale: f32)
+	{
+		self.render_scale = scale.max(MIN_RENDER_SCALE).min(MAX_RENDER_SCALE);
+	}
+
+	/// Returns the current render scale.
+	pub fn render_scale(&self) -> f32
+	{
+		self.render_scale
+	}
+
+	/// Recreates the render targets at the scaled base size.
+	///
+	/// The pipeline uses dynamic viewport and scissor, so it is left untouched.
+	fn recreate_render_targets(&mut self, rs: &RenderState)
+	{
+		self.cleanup_render_targets();
+
+		let render_format = vk::Format::R8g8b8a8Unorm;
+		let render_size = MainPass::scale_size(self.base_size, self.render_scale);
+		let (render_image, depth_image) =
+			MainPass::create_render_targets(rs, render_size, render_format, self.depth_format);
+		if self.gbuffer.is_some()
+		{
+			self.gbuffer = Some(GBuffer::new(rs, render_size));
+		}
+		self.framebuffer = MainPass::create_framebuffer(
+			rs,
+			render_size,
+			render_image.view,
+			depth_image.view,
+			self.gbuffer.as_ref(),
+			self.renderpass,
+		);
+		self.depth_sample_view = MainPass::create_depth_sample_view(rs, &depth_image, self.depth_format);
+		self.render_image = render_image;
+		self.depth_image = depth_image;
+		let (viewport, scissor) = MainPass::create_viewport_and_scissor(render_size);
+		self.viewport = viewport;
+		self.scissor = scissor;
+	}
+
+	/// Returns true if the normal and position G-buffer targets are rendered along with the color,
+	/// which then holds the unlit albedo.
+	pub fn has_gbuffer(&self) -> bool
+	{
+		self.gbuffer.is_some()
+	}
+
+	/// Returns the given G-buffer target, or None without a G-buffer.
+	pub fn gbuffer_target(&mut self, target: GBufferTarget) -> Option<&mut Texture>
+	{
+		self.gbuffer.as_mut().map(|gbuffer| gbuffer.target(target))
+	}
+
+	/// Returns the depth image, a depth-only view of it for sampling and its format.
+	pub fn depth_image(&mut self) -> (&mut Texture, vk::ImageView, vk::Format)
+	{
+		(&mut self.depth_image, self.depth_sample_view, self.depth_format)
+	}
+
+	/// Reads the last rendered frame back to the CPU, as RGBA8 pixels.
+	///
+	/// Returns the width, height and pixels. Must be called after end_frame().
+	pub fn read_render_image(&mut self, rs: &RenderState) -> Result<(u32, u32, Vec<u8>), String>
+	{
+		let pixels = rs.read_texture(&mut self.render_image)?;
+		Ok((self.render_image.extent.width, self.render_image.extent.height, pixels))
+	}
+
+	/// Uploads the view matrix used by the following frames, unless it didn't change since the last
+	/// upload.
+	///
+	/// The projection matrix is only used for the view rays of the sky.
+	pub fn update_view_matrix(&mut self, view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>)
+	{
+		if let Some(ref mut sky) = self.sky
+		{
+			sky.set_view_projection(view_matrix, projection_matrix);
+		}
+		if self.uploaded_view_matrix == Some(*view_matrix)
+		{
+			return;
+		}
+
+		self.view_matrix_ub.write(0, align_of::<Matrix4<f32>>() as u64, &[*view_matrix]);
+		self.uploaded_view_matrix = Some(*view_matrix);
+	}
+
+	/// Returns the current render size in physical pixels.
+	pub fn render_size(&self) -> vk::Extent2D
+	{
+		self.scissor.extent
+	}
+
 	/// Begins the main render pass
 	///
-	/// Returns a command buffer to be used in rendering.
-	pub fn begin_frame(&mut self, rs: &RenderState) -> vk::CommandBuffer
+	/// Returns a command buffer to be used in rendering. The render targets are transitioned as
+	/// planned by the frame graph.
+	pub fn begin_frame(&mut self, rs: &RenderState, frame: &FrameGraph) -> vk::CommandBuffer
 	{
+		// Apply a changed render scale
+		let scaled_size = MainPass::scale_size(self.base_size, self.render_scale);
+		if scaled_size.width != self.scissor.extent.width || scaled_size.height != self.scissor.extent.height
+		{
+			self.recreate_render_targets(rs);
+		}
+
 		// Begin commandbuffer
 		let cmd_buf_begin_info = vk::CommandBufferBeginInfo {
 			s_type: vk::StructureType::CommandBufferBeginInfo,
@@ -578,24 +1898,44 @@ impl MainPass
 		unsafe {
 			rs.device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
 		}
+		rs.push_debug_label(cmd_buf, "Main pass", [0.0, 0.5, 1.0, 1.0]);
 
-		// Transition the mainpass output to a renderable image
-		rs.transition_texture(
-			&mut self.render_image,
-			vk::ACCESS_COLOR_ATTACHMENT_READ_BIT | vk::ACCESS_COLOR_ATTACHMENT_WRITE_BIT,
-			vk::ImageLayout::ColorAttachmentOptimal,
-			vk::PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
-			Some(cmd_buf),
-		);
+		{
+			let mut images =
+				vec![(frame.main_color, &mut self.render_image), (frame.main_depth, &mut self.depth_image)];
+			if let Some(ref mut gbuffer) = self.gbuffer
+			{
+				images.push((frame.main_normal, &mut gbuffer.normal));
+				images.push((frame.main_position, &mut gbuffer.position));
+			}
+			frame.graph.record_transitions(rs, frame.main_pass, cmd_buf, &mut images);
+		}
+
+		// Queries can only be reset outside of a renderpass
+		if let Some(ref mut occlusion) = self.occlusion
+		{
+			occlusion.begin_frame(rs, cmd_buf);
+		}
+
+		self.push_line_constants(rs, cmd_buf, &self.viewport);
 
 		// Begin renderpass
-		let clear_values = [
-			vk::ClearValue::new_color(vk::ClearColorValue::new_float32([0.0, 1.0, 0.0, 1.0])),
+		// The overdraw count starts at zero
+		let clear_color = if self.show_overdraw { [0.0; 4] } else { self.clear_color };
+		let mut clear_values = vec![
+			vk::ClearValue::new_color(vk::ClearColorValue::new_float32(clear_color)),
 			vk::ClearValue::new_depth_stencil(vk::ClearDepthStencilValue {
 				depth: 1.0,
 				stencil: 0,
 			}),
 		];
+		if self.gbuffer.is_some()
+		{
+			// Zero normals mark the pixels nothing was drawn to
+			let clear_gbuffer = vk::ClearValue::new_color(vk::ClearColorValue::new_float32([0.0; 4]));
+			clear_values.push(clear_gbuffer);
+			clear_values.push(clear_gbuffer);
+		}
 
 		let render_pass_begin_info = vk::RenderPassBeginInfo {
 			s_type: vk::StructureType::RenderPassBeginInfo,
@@ -608,7 +1948,7 @@ impl MainPass
 		};
 
 		let view_matrix_ub_descriptor = vk::DescriptorBufferInfo {
-			buffer: self.view_matrix_ub,
+			buffer: self.view_matrix_ub.buffer,
 			offset: 0,
 			range: size_of::<Matrix4<f32>>() as u64,
 		};
@@ -630,10 +1970,39 @@ impl MainPass
 		unsafe {
 			// Update the view matrix descriptor set
 			rs.device.update_descriptor_sets(&write_desc_sets, &[]);
+		}
 
+		if self.recorder.is_some()
+		{
+			// The secondary command buffers set up all state themselves
+			unsafe {
+				rs.device.cmd_begin_render_pass(
+					cmd_buf,
+					&render_pass_begin_info,
+					vk::SubpassContents::SecondaryCommandBuffers,
+				);
+			}
+			return cmd_buf;
+		}
+
+		unsafe {
 			// Start the render pass
 			rs.device.cmd_begin_render_pass(cmd_buf, &render_pass_begin_info, vk::SubpassContents::Inline);
 
+			rs.device.cmd_set_viewport(cmd_buf, &[self.viewport]);
+			rs.device.cmd_set_scissor(cmd_buf, &[self.scissor]);
+		}
+
+		// The overdraw count starts at zero, without the sky
+		if self.background == Background::Gradient && !self.show_overdraw
+		{
+			if let Some(ref sky) = self.sky
+			{
+				sky.draw(rs, cmd_buf);
+			}
+		}
+
+		unsafe {
 			rs.device.cmd_bind_descriptor_sets(
 				cmd_buf,
 				vk::PipelineBindPoint::Graphics,
@@ -644,13 +2013,439 @@ impl MainPass
 			);
 
 			// Bind pipeline
-			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::Graphics, self.pipeline);
+			let pipeline = self.pipeline_for(PipelineKind::Textured);
+			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::Graphics, pipeline);
+		}
+		self.set_stencil_reference(rs, cmd_buf, 1);
+		self.set_depth_bias(rs, cmd_buf, DepthBias::default());
+
+		cmd_buf
+	}
+
+	/// Sets the stencil reference value for subsequent draws.
+	///
+	/// Does nothing if the stencil test is disabled.
+	pub fn set_stencil_reference(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, reference: u32)
+	{
+		if self.stencil.is_some()
+		{
+			unsafe {
+				rs.device.cmd_set_stencil_reference(cmd_buf, vk::STENCIL_FRONT_AND_BACK, reference);
+			}
+		}
+	}
+
+	/// Sets the depth bias for subsequent draws.
+	///
+	/// The bias is lost when binding a pipeline without it, like the occlusion proxy pipeline.
+	pub fn set_depth_bias(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, bias: DepthBias)
+	{
+		unsafe {
+			rs.device.cmd_set_depth_bias(cmd_buf, bias.constant, 0.0, bias.slope);
+		}
+	}
+
+	/// Restricts the following draws to a square in the given bottom corner, and clears the depth there
+	/// so they are drawn on top of the scene.
+	///
+	/// The size is in window pixels, so the square keeps its size on screen regardless of the render
+	/// scale. Undo with reset_viewport().
+	pub fn set_corner_viewport(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, size: u32, corner: Corner)
+	{
+		let extent = self.scissor.extent;
+		let size = ((size as f32 * self.render_scale).round() as u32).max(1).min(extent.width).min(extent.height);
+		let x = match corner
+		{
+			Corner::BottomLeft => 0,
+			Corner::BottomRight => extent.width - size,
+		};
+		let scissor = vk::Rect2D {
+			offset: vk::Offset2D {
+				x: x as i32,
+				y: (extent.height - size) as i32,
+			},
+			extent: vk::Extent2D {
+				width: size,
+				height: size,
+			},
+		};
+		let viewport = vk::Viewport {
+			x: x as f32,
+			y: (extent.height - size) as f32,
+			width: size as f32,
+			height: size as f32,
+			min_depth: 0.0,
+			max_depth: 1.0,
+		};
+		let clear_depth = vk::ClearAttachment {
+			aspect_mask: vk::IMAGE_ASPECT_DEPTH_BIT,
+			color_attachment: 0,
+			clear_value: vk::ClearValue::new_depth_stencil(vk::ClearDepthStencilValue {
+				depth: 1.0,
+				stencil: 0,
+			}),
+		};
+		let clear_rect = vk::ClearRect {
+			rect: scissor,
+			base_array_layer: 0,
+			layer_count: 1,
+		};
+		unsafe {
+			rs.device.cmd_set_viewport(cmd_buf, &[viewport]);
+			rs.device.cmd_set_scissor(cmd_buf, &[scissor]);
+			rs.device.cmd_clear_attachments(cmd_buf, &[clear_depth], &[clear_rect]);
+		}
+		self.push_line_constants(rs, cmd_buf, &viewport);
+	}
 
+	/// Restores the viewport and scissor covering the whole render area.
+	pub fn reset_viewport(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer)
+	{
+		unsafe {
 			rs.device.cmd_set_viewport(cmd_buf, &[self.viewport]);
 			rs.device.cmd_set_scissor(cmd_buf, &[self.scissor]);
 		}
+		self.push_line_constants(rs, cmd_buf, &self.viewport);
+	}
 
-		cmd_buf
+	/// Pushes the line width for the viewport if lines are drawn as quads, as the quads are expanded in
+	/// normalized device coordinates.
+	fn push_line_constants(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, viewport: &vk::Viewport)
+	{
+		if self.line_quads
+		{
+			LinePushConstants::new(self.line_width, viewport).push(&rs.device, cmd_buf, self.pipeline_layout);
+		}
+	}
+
+	/// Returns true if draws are recorded on several threads.
+	///
+	/// In that case the draws must be passed to execute_parallel() instead of being recorded into
+	/// the command buffer returned by begin_frame().
+	pub fn is_recording_parallel(&self) -> bool
+	{
+		self.recorder.is_some()
+	}
+
+	/// Records the draws split across the recording threads and executes them.
+	///
+	/// The per-object uniforms must have been uploaded before calling this.
+	pub fn execute_parallel(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, commands: &[DrawCommand])
+	{
+		let recorder = self.recorder.as_ref().expect("Parallel recording is not enabled");
+		let state = RecordState {
+			renderpass: self.renderpass,
+			framebuffer: self.framebuffer,
+			pipelines: [
+				self.pipeline_for(PipelineKind::Textured),
+				self.pipeline_for(PipelineKind::TexturedStrip),
+				self.pipeline_for(PipelineKind::VertexColor),
+				self.pipeline_for(PipelineKind::Overlay),
+				self.pipeline_for(PipelineKind::Line),
+				self.pipeline_for(PipelineKind::Blended),
+				self.pipeline_for(PipelineKind::BlendedPremultiplied),
+				self.pipeline_for(PipelineKind::VertexColorFan),
+			],
+			pipeline_layout: self.pipeline_layout,
+			view_matrix_ds: self.view_matrix_ds[0],
+			object_ds: self.object_ds[0],
+			object_ub_stride: self.object_ub_stride,
+			viewport: self.viewport,
+			scissor: self.scissor,
+			stencil_reference: self.stencil.map(|_| 1),
+			line_constants: if self.line_quads
+			{
+				Some(LinePushConstants::new(self.line_width, &self.viewport))
+			}
+			else
+			{
+				None
+			},
+		};
+		let secondary_buffers = recorder.record(state, commands);
+		if !secondary_buffers.is_empty()
+		{
+			unsafe {
+				rs.device.cmd_execute_commands(cmd_buf, &secondary_buffers);
+			}
+		}
+	}
+
+	/// Renders the scene depth into each layer of the cube depth texture, one face per view matrix.
+	///
+	/// The draw function records the object draws for one face, e.g. with Scene::draw_depth(). It is
+	/// called with the depth only pipeline and face view bound, so it only has to bind the objects.
+	/// The faces are submitted by end_frame(), after this frame's uniform uploads and ahead of the main
+	/// pass, so this must be called between begin_frame() and end_frame().
+	///
+	/// Returns an error if cube depth rendering is disabled in the Config, or the faces of the last
+	/// frame are still rendering.
+	pub fn render_cube_depth<F>(
+		&mut self, rs: &RenderState, frame: &FrameGraph, views: [Matrix4<f32>; CUBE_FACES],
+		projection: &Matrix4<f32>, draw: F,
+	) -> Result<(), String>
+	where
+		F: Fn(&MainPass, vk::CommandBuffer),
+	{
+		// Taken out for the duration, so the draw function can borrow the MainPass
+		let mut target = match self.cube_depth.take()
+		{
+			Some(target) => target,
+			None => return Err(String::from("Cube depth rendering is disabled, set cube_depth_size")),
+		};
+		let pipeline_layout = self.pipeline_layout;
+		let result = {
+			let mainpass = &*self;
+			target.render(rs, frame, pipeline_layout, &views, projection, |cmd_buf| draw(mainpass, cmd_buf))
+		};
+		self.cube_depth = Some(target);
+		result
+	}
+
+	/// Returns true if cube depth rendering is enabled, see render_cube_depth().
+	pub fn has_cube_depth(&self) -> bool
+	{
+		self.cube_depth.is_some()
+	}
+
+	/// Returns the cube depth texture, a depth-only view of one face for sampling and the format, if
+	/// enabled.
+	pub fn cube_depth_texture(&mut self, face: usize) -> Option<(&mut Texture, vk::ImageView, vk::Format)>
+	{
+		self.cube_depth.as_mut().map(|target| {
+			let view = target.face_view(face);
+			(&mut target.texture, view, target.format)
+		})
+	}
+
+	/// Uploads new lighting, used from the next frame on.
+	pub fn set_lighting(&self, _rs: &RenderState, light: &LightUniforms)
+	{
+		self.light_ub.write(0, align_of::<LightUniforms>() as u64, &[*light]);
+	}
+
+	/// Uploads new fog settings, used from the next frame on.
+	pub fn set_fog(&self, _rs: &RenderState, fog: &Fog)
+	{
+		// The fog follows the lighting in the same buffer
+		let offset = size_of::<LightUniforms>() as u64;
+		self.light_ub.write(offset, align_of::<FogUniforms>() as u64, &[fog.uniforms()]);
+	}
+
+	/// Sets the color the render image is cleared to.
+	pub fn set_clear_color(&mut self, color: [f32; 4])
+	{
+		self.clear_color = color;
+	}
+
+	/// Sets what is drawn behind the scene. The gradient sky falls back to the clear color with a G-buffer
+	/// or parallel recording.
+	pub fn set_background(&mut self, background: Background)
+	{
+		if background == Background::Gradient && self.sky.is_none()
+		{
+			warning!("The gradient sky is not supported with a G-buffer or parallel recording");
+		}
+		self.background = background;
+	}
+
+	pub fn background(&self) -> Background
+	{
+		self.background
+	}
+
+	/// Sets the colors of the gradient sky, in linear rgb.
+	pub fn set_sky_colors(&mut self, horizon_color: [f32; 3], zenith_color: [f32; 3])
+	{
+		if let Some(ref mut sky) = self.sky
+		{
+			sky.set_colors(horizon_color, zenith_color);
+		}
+	}
+
+	/// Uploads the per-object uniforms for all objects to be drawn this frame.
+	///
+	/// Object i is later selected by calling bind_object(i).
+	pub fn upload_object_uniforms(&self, _rs: &RenderState, uniforms: &[ObjectUniforms])
+	{
+		assert!(uniforms.len() <= MAX_OBJECTS, "Too many objects for the per-object uniform buffer");
+		if uniforms.is_empty()
+		{
+			return;
+		}
+
+		// Each element is padded to the stride, for the dynamic offsets
+		self.object_ub.write(0, self.object_ub_stride, uniforms);
+	}
+
+	/// Binds the per-object uniforms uploaded at the given index.
+	pub fn bind_object(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, index: usize)
+	{
+		let dynamic_offset = (self.object_ub_stride * index as u64) as u32;
+		unsafe {
+			rs.device.cmd_bind_descriptor_sets(
+				cmd_buf,
+				vk::PipelineBindPoint::Graphics,
+				self.pipeline_layout,
+				2,
+				&self.object_ds[..],
+				&[dynamic_offset],
+			);
+		}
+	}
+
+	/// Returns true if occlusion culling is enabled.
+	pub fn occlusion_enabled(&self) -> bool
+	{
+		self.occlusion.is_some()
+	}
+
+	/// Returns false if the object at the given index was occluded in the previous frame.
+	pub fn is_likely_visible(&self, index: usize) -> bool
+	{
+		self.occlusion.as_ref().map_or(true, |occlusion| occlusion.is_likely_visible(index))
+	}
+
+	/// Returns the number of objects skipped by occlusion culling in the previous frame.
+	pub fn occlusion_culled_count(&self) -> usize
+	{
+		self.occlusion.as_ref().map_or(0, |occlusion| occlusion.culled_count())
+	}
+
+	/// Starts the occlusion query for the object at the given index, if occlusion culling is enabled.
+	pub fn begin_occlusion_query(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, index: usize)
+	{
+		if let Some(ref occlusion) = self.occlusion
+		{
+			occlusion.begin_query(rs, cmd_buf, index);
+		}
+	}
+
+	/// Ends the occlusion query started by begin_occlusion_query().
+	pub fn end_occlusion_query(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, index: usize)
+	{
+		if let Some(ref occlusion) = self.occlusion
+		{
+			occlusion.end_query(rs, cmd_buf, index);
+		}
+	}
+
+	/// Binds the pipeline for drawing occlusion proxies, if occlusion culling is enabled.
+	pub fn bind_occlusion_proxy_pipeline(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer)
+	{
+		if let Some(ref occlusion) = self.occlusion
+		{
+			unsafe {
+				rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::Graphics, occlusion.proxy_pipeline);
+			}
+		}
+	}
+
+	/// Returns the pipeline of the given kind.
+	fn pipeline_for(&self, kind: PipelineKind) -> vk::Pipeline
+	{
+		if self.show_overdraw
+		{
+			return match kind
+			{
+				PipelineKind::TexturedStrip => self.overdraw_pipelines[1],
+				PipelineKind::Line => self.overdraw_pipelines[2],
+				PipelineKind::VertexColorFan => self.overdraw_fan_pipeline.unwrap_or(self.overdraw_pipelines[0]),
+				_ => self.overdraw_pipelines[0],
+			};
+		}
+		match kind
+		{
+			PipelineKind::Textured => self.pipeline,
+			PipelineKind::TexturedStrip => self.strip_pipeline,
+			PipelineKind::VertexColor => self.vertex_color_pipeline,
+			PipelineKind::Overlay => self.overlay_pipeline,
+			PipelineKind::Line => self.debug_pipeline.unwrap_or(self.line_pipeline),
+			PipelineKind::Blended => self.blended_pipeline,
+			PipelineKind::BlendedPremultiplied => self.blended_premultiplied_pipeline,
+			// No object uses fans without support, the fallback only fills the parallel recording state
+			PipelineKind::VertexColorFan => self.fan_pipeline.unwrap_or(self.vertex_color_pipeline),
+		}
+	}
+
+	/// Switches between shading and counting the fragments drawn into each pixel, returning true if
+	/// the overdraw is counted now.
+	///
+	/// Every fragment adds 1/16 to the render image without any depth testing, so 16 stacked fragments
+	/// saturate it. Present it with PresentPass::present_overdraw() to see it as a heatmap.
+	pub fn toggle_overdraw(&mut self) -> bool
+	{
+		self.show_overdraw = !self.show_overdraw;
+		self.show_overdraw
+	}
+
+	/// Returns true if line lists must be converted to quads with Mesh::line_quads() before drawing them
+	/// with PipelineKind::Line.
+	pub fn draws_line_quads(&self) -> bool
+	{
+		self.line_quads
+	}
+
+	/// Returns true if meshes can be drawn as triangle fans, otherwise they must be converted to lists.
+	pub fn supports_triangle_fans(&self) -> bool
+	{
+		self.fan_pipeline.is_some()
+	}
+
+	/// Binds the pipeline of the given kind.
+	pub fn bind_pipeline_kind(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, kind: PipelineKind)
+	{
+		unsafe {
+			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::Graphics, self.pipeline_for(kind));
+		}
+	}
+
+	/// Returns true if uniforms were written since the last upload to device local memory.
+	fn has_pending_uniform_uploads(&self) -> bool
+	{
+		self.view_matrix_ub.has_pending_upload() || self.light_ub.has_pending_upload() ||
+			self.object_ub.has_pending_upload()
+	}
+
+	/// Records copying the uniforms written since the last upload to device local memory.
+	///
+	/// Must be recorded outside of a renderpass.
+	fn record_uniform_uploads(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer)
+	{
+		let shader_stages = vk::PIPELINE_STAGE_VERTEX_SHADER_BIT | vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT;
+		unsafe {
+			// The previous frame may still be reading the uniforms
+			rs.device.cmd_pipeline_barrier(
+				cmd_buf,
+				shader_stages,
+				vk::PIPELINE_STAGE_TRANSFER_BIT,
+				vk::DependencyFlags::empty(),
+				&[],
+				&[],
+				&[],
+			);
+		}
+		self.view_matrix_ub.record_upload(cmd_buf);
+		self.light_ub.record_upload(cmd_buf);
+		self.object_ub.record_upload(cmd_buf);
+
+		let barrier = vk::MemoryBarrier {
+			s_type: vk::StructureType::MemoryBarrier,
+			p_next: ptr::null(),
+			src_access_mask: vk::ACCESS_TRANSFER_WRITE_BIT,
+			dst_access_mask: vk::ACCESS_UNIFORM_READ_BIT,
+		};
+		unsafe {
+			rs.device.cmd_pipeline_barrier(
+				cmd_buf,
+				vk::PIPELINE_STAGE_TRANSFER_BIT,
+				shader_stages,
+				vk::DependencyFlags::empty(),
+				&[barrier],
+				&[],
+				&[],
+			);
+		}
 	}
 
 	/// Ends the main render frame
@@ -661,23 +2456,54 @@ impl MainPass
 		unsafe {
 			// End render pass and command buffer
 			rs.device.cmd_end_render_pass(cmd_buf);
+		}
+		rs.pop_debug_label(cmd_buf);
+		unsafe {
 			rs.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
 		}
 
-		// Send the work off to the GPU
+		// Send the work off to the GPU, copying the uniforms written this frame first if needed, and
+		// rendering the cube depth before the main pass
+		let cube_depth = self.cube_depth.as_mut().and_then(|target| target.take_recorded(rs));
+		let mut command_buffers = Vec::with_capacity(3);
+		if self.has_pending_uniform_uploads()
+		{
+			let upload_cmd_buf = self.upload_commandbuffer;
+			let cmd_buf_begin_info = vk::CommandBufferBeginInfo {
+				s_type: vk::StructureType::CommandBufferBeginInfo,
+				p_next: ptr::null(),
+				p_inheritance_info: ptr::null(),
+				flags: vk::COMMAND_BUFFER_USAGE_SIMULTANEOUS_USE_BIT,
+			};
+			unsafe {
+				rs.device.begin_command_buffer(upload_cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
+			}
+			self.record_uniform_uploads(rs, upload_cmd_buf);
+			unsafe {
+				rs.device.end_command_buffer(upload_cmd_buf).expect("End commandbuffer");
+			}
+			command_buffers.push(upload_cmd_buf);
+		}
+		let mut fence = vk::Fence::null();
+		if let Some((cube_cmd_buf, cube_fence)) = cube_depth
+		{
+			command_buffers.push(cube_cmd_buf);
+			fence = cube_fence;
+		}
+		command_buffers.push(cmd_buf);
 		let submit_info = vk::SubmitInfo {
 			s_type: vk::StructureType::SubmitInfo,
 			p_next: ptr::null(),
 			wait_semaphore_count: 0,
 			p_wait_semaphores: ptr::null(),
 			p_wait_dst_stage_mask: ptr::null(),
-			command_buffer_count: 1,
-			p_command_buffers: &cmd_buf,
+			command_buffer_count: command_buffers.len() as u32,
+			p_command_buffers: command_buffers.as_ptr(),
 			signal_semaphore_count: 0,
 			p_signal_semaphores: ptr::null(),
 		};
 		unsafe {
-			rs.device.queue_submit(rs.graphics_queue, &[submit_info], vk::Fence::null()).expect("queue submit failed.");
+			rs.device.queue_submit(rs.graphics_queue, &[submit_info], fence).expect("queue submit failed.");
 		}
 	}
 }
@@ -686,29 +2512,34 @@ impl Drop for MainPass
 {
 	fn drop(&mut self)
 	{
+		teardown_scope!("MainPass");
+
 		// We cannot have the last reference to device at this point
 		debug_assert!(1 < Rc::strong_count(&self.device));
 
-		unsafe {
-			// Always wait for device idle
-			self.device.device_wait_idle().unwrap();
-
-			self.device.destroy_buffer(self.view_matrix_ub, None);
-			self.device.free_memory(self.view_matrix_ub_mem, None);
-
-			self.device.destroy_sampler(self.depth_image.sampler, None);
-			self.device.destroy_image_view(self.depth_image.view, None);
-			self.device.destroy_image(self.depth_image.image, None);
-			self.device.free_memory(self.depth_image.memory, None);
-
-			self.device.destroy_sampler(self.render_image.sampler, None);
-			self.device.destroy_image_view(self.render_image.view, None);
-			self.device.destroy_image(self.render_image.image, None);
-			self.device.free_memory(self.render_image.memory, None);
-
-			self.device.destroy_framebuffer(self.framebuffer, None);
+		// Already contains a device wait
+		self.cleanup_render_targets();
 
+		unsafe {
+			self.device.destroy_pipeline(self.vertex_color_pipeline, None);
+			self.device.destroy_pipeline(self.overlay_pipeline, None);
+			self.device.destroy_pipeline(self.line_pipeline, None);
+			self.device.destroy_pipeline(self.strip_pipeline, None);
+			self.device.destroy_pipeline(self.blended_pipeline, None);
+			self.device.destroy_pipeline(self.blended_premultiplied_pipeline, None);
 			self.device.destroy_pipeline(self.pipeline, None);
+			if let Some(debug_pipeline) = self.debug_pipeline
+			{
+				self.device.destroy_pipeline(debug_pipeline, None);
+			}
+			if let Some(fan_pipeline) = self.fan_pipeline
+			{
+				self.device.destroy_pipeline(fan_pipeline, None);
+			}
+			for &pipeline in self.overdraw_pipelines.iter().chain(self.overdraw_fan_pipeline.iter())
+			{
+				self.device.destroy_pipeline(pipeline, None);
+			}
 			self.device.destroy_pipeline_layout(self.pipeline_layout, None);
 
 			for &dset_layout in self.descriptor_set_layouts.iter()
@@ -722,3 +2553,60 @@ impl Drop for MainPass
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn vertex_color_pipeline_selects_the_vertex_color_shader_variant()
+	{
+		let textured = PipelineOptions::default();
+		assert_eq!(textured.specialization_data()[0], 0);
+		let vertex_color = PipelineOptions {
+			vertex_color: true,
+			..Default::default()
+		};
+		// USE_VERTEX_COLOR in phong.frag, the rest of the constants are left alone
+		assert_eq!(vertex_color.specialization_data()[0], 1);
+		assert_eq!(vertex_color.specialization_data()[1..], textured.specialization_data()[1..]);
+	}
+
+	#[test]
+	fn align_up_rounds_to_the_next_multiple()
+	{
+		for &alignment in [64, 256].iter()
+		{
+			assert_eq!(align_up(0, alignment), 0);
+			assert_eq!(align_up(1, alignment), alignment);
+			assert_eq!(align_up(alignment - 1, alignment), alignment);
+			assert_eq!(align_up(alignment, alignment), alignment);
+			assert_eq!(align_up(alignment + 1, alignment), 2 * alignment);
+			assert_eq!(align_up(3 * alignment, alignment), 3 * alignment);
+		}
+		// e.g. a 208 byte uniform struct
+		assert_eq!(align_up(208, 64), 256);
+		assert_eq!(align_up(208, 256), 256);
+	}
+
+	#[test]
+	fn stencil_config_reaches_the_op_state()
+	{
+		let stencil = StencilConfig {
+			compare_op: StencilCompare::NotEqual,
+			fail_op: StencilAction::Zero,
+			pass_op: StencilAction::IncrementAndClamp,
+			depth_fail_op: StencilAction::Invert,
+			compare_mask: 0x0F,
+			write_mask: 0xF0,
+		};
+		let state = stencil.op_state();
+		assert_eq!(state.compare_op, vk::CompareOp::NotEqual);
+		assert_eq!(state.fail_op, vk::StencilOp::Zero);
+		assert_eq!(state.pass_op, vk::StencilOp::IncrementAndClamp);
+		assert_eq!(state.depth_fail_op, vk::StencilOp::Invert);
+		assert_eq!(state.compare_mask, 0x0F);
+		assert_eq!(state.write_mask, 0xF0);
+	}
+}
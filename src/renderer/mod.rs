@@ -4,24 +4,184 @@ use ash::util::Align;
 use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0, V1_0};
 use ash::vk;
 use image;
+use log::StartupReport;
+use std::cell::RefCell;
 use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io::prelude::*;
-use std::mem::{align_of, size_of};
+use std::mem;
+use std::mem::{align_of, size_of, transmute};
 use std::path::Path;
 use std::ptr;
 use std::rc::Rc;
+use std::time::Duration;
 use winit;
 use winit::EventsLoop;
 use winit::Window;
 
+mod compressed;
+mod cubedepth;
+mod debuglabel;
+mod decode;
+mod features;
+mod gbuffer;
+mod graph;
 mod mainpass;
+mod occlusion;
+mod parallel;
 mod presentpass;
-
-pub use self::mainpass::MainPass;
-pub use self::presentpass::PresentPass;
+mod sky;
+mod staging;
+mod uniform;
+
+use self::compressed::CompressedImage;
+use self::debuglabel::DebugLabels;
+use self::decode::ImageDecoder;
+use self::staging::StagingBuffer;
+pub use self::cubedepth::{cube_face_projection, cube_face_views, CUBE_FACES};
+pub use self::features::DeviceFeatures;
+pub use self::gbuffer::GBufferTarget;
+pub use self::graph::{FrameGraph, PresentedImage};
+pub use self::mainpass::{AlphaMode, ColorLoadOp, Corner, DepthBias, Fog, FogMode, LightUniforms, LineRendering,
+                         MainPass, ObjectUniforms, PipelineKind, StencilConfig, MAX_OBJECTS};
+pub use self::parallel::DrawCommand;
+pub use self::presentpass::{PresentPass, ScalingMode};
+pub use self::sky::Background;
+pub use self::uniform::UniformMemory;
 
 use config::Config;
+use object::mesh::MeshCleanup;
+
+// VK_KHR_portability_enumeration is newer than ash, so define the parts we need here.
+const PORTABILITY_ENUMERATION_EXTENSION_NAME: &'static [u8] = b"VK_KHR_portability_enumeration\0";
+const PORTABILITY_SUBSET_EXTENSION_NAME: &'static [u8] = b"VK_KHR_portability_subset\0";
+const GET_PHYSICAL_DEVICE_PROPERTIES_2_EXTENSION_NAME: &'static [u8] =
+	b"VK_KHR_get_physical_device_properties2\0";
+const INSTANCE_CREATE_ENUMERATE_PORTABILITY_BIT_KHR: vk::Flags = 0x1;
+
+/// How the values of a texture are encoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorSpace
+{
+	/// Colors authored in sRGB, decoded to linear by the sampler before lighting.
+	Srgb,
+	/// Data that must be sampled as is, like normal maps and masks.
+	Linear,
+}
+
+impl ColorSpace
+{
+	/// Returns the RGBA8 format sampling in this color space.
+	pub fn rgba8_format(&self) -> vk::Format
+	{
+		match *self
+		{
+			ColorSpace::Srgb => vk::Format::R8g8b8a8Srgb,
+			ColorSpace::Linear => vk::Format::R8g8b8a8Unorm,
+		}
+	}
+}
+
+/// How a texture is sampled, trading sharpness against shimmering.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplerOptions
+{
+	/// Added to the mip level, negative values sharpen and positive values blur.
+	pub mip_lod_bias: f32,
+	/// Highest anisotropy to filter with, 1 disables anisotropic filtering.
+	pub max_anisotropy: f32,
+}
+
+impl Default for SamplerOptions
+{
+	fn default() -> SamplerOptions
+	{
+		SamplerOptions {
+			mip_lod_bias: 0.0,
+			max_anisotropy: 16.0,
+		}
+	}
+}
+
+/// Returns the create info of a linearly filtered sampler with the given options, clamped to the highest
+/// anisotropy and LOD bias the device supports.
+fn sampler_create_info(
+	mip_levels: u32,
+	options: &SamplerOptions,
+	max_anisotropy: f32,
+	max_lod_bias: f32,
+) -> vk::SamplerCreateInfo
+{
+	let anisotropy = options.max_anisotropy.max(1.0).min(max_anisotropy.max(1.0));
+	vk::SamplerCreateInfo {
+		s_type: vk::StructureType::SamplerCreateInfo,
+		p_next: ptr::null(),
+		flags: Default::default(),
+		mag_filter: vk::Filter::Linear,
+		min_filter: vk::Filter::Linear,
+		mipmap_mode: vk::SamplerMipmapMode::Linear,
+		address_mode_u: vk::SamplerAddressMode::MirroredRepeat,
+		address_mode_v: vk::SamplerAddressMode::MirroredRepeat,
+		address_mode_w: vk::SamplerAddressMode::MirroredRepeat,
+		mip_lod_bias: options.mip_lod_bias.max(-max_lod_bias).min(max_lod_bias),
+		min_lod: 0.0,
+		max_lod: (mip_levels - 1) as f32,
+		anisotropy_enable: (anisotropy > 1.0) as vk::Bool32,
+		max_anisotropy: anisotropy,
+		border_color: vk::BorderColor::FloatOpaqueWhite,
+		compare_enable: 0,
+		compare_op: vk::CompareOp::Never,
+		unnormalized_coordinates: 0,
+	}
+}
+
+/// Multiplies the color of the RGBA8 pixels by their alpha.
+///
+/// sRGB colors are multiplied in linear space, like the blending does.
+fn premultiply_alpha(pixels: &mut [u8], color_space: ColorSpace)
+{
+	let srgb = color_space == ColorSpace::Srgb;
+	let to_linear = |c: f32| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+	let to_srgb = |c: f32| if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+	for pixel in pixels.chunks_mut(4)
+	{
+		let alpha = pixel[3] as f32 / 255.0;
+		for channel in pixel[..3].iter_mut()
+		{
+			let value = *channel as f32 / 255.0;
+			let premultiplied = if srgb { to_srgb(to_linear(value) * alpha) } else { value * alpha };
+			*channel = (premultiplied * 255.0).round() as u8;
+		}
+	}
+}
+
+/// How long reading a texture back to the CPU may take before giving up on it.
+const READBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the timeout in nanoseconds, as wait_for_fences() takes it.
+fn timeout_nanos(timeout: Duration) -> u64
+{
+	timeout.as_secs().saturating_mul(1_000_000_000).saturating_add(timeout.subsec_nanos() as u64)
+}
+/// Size in pixels of the generated missing texture, and of each of its checkers.
+const CHECKERBOARD_SIZE: u32 = 64;
+const CHECKER_SIZE: u32 = 8;
+
+/// Generates RGBA8 pixels of a magenta and black checkerboard, which stands out as a missing texture.
+fn checkerboard_pixels() -> Vec<u8>
+{
+	let mut pixels = Vec::with_capacity((CHECKERBOARD_SIZE * CHECKERBOARD_SIZE * 4) as usize);
+	for y in 0..CHECKERBOARD_SIZE
+	{
+		for x in 0..CHECKERBOARD_SIZE
+		{
+			let magenta = (x / CHECKER_SIZE + y / CHECKER_SIZE) % 2 == 0;
+			pixels.extend_from_slice(if magenta { &[255, 0, 255, 255] } else { &[0, 0, 0, 255] });
+		}
+	}
+	pixels
+}
 
 pub struct Texture
 {
@@ -29,11 +189,38 @@ pub struct Texture
 	pub memory: vk::DeviceMemory,
 	pub view: vk::ImageView,
 	pub sampler: vk::Sampler,
+	pub extent: vk::Extent3D,
+	mip_levels: u32,
+	array_layers: u32,
+	aspect_mask: vk::ImageAspectFlags,
 	current_access_mask: vk::AccessFlags,
 	pub current_layout: vk::ImageLayout,
 	current_stage: vk::PipelineStageFlags,
 }
 
+/// A submission of submit_and_wait() that didn't finish in time, cleaned up once it has.
+struct PendingSubmission
+{
+	fence: vk::Fence,
+	cmd_buf: vk::CommandBuffer,
+	buffers: Vec<(vk::Buffer, vk::DeviceMemory)>,
+}
+
+impl PendingSubmission
+{
+	/// Destroys the fence and the buffers and frees the command buffer. The submission must have finished.
+	unsafe fn destroy(&self, device: &Device<V1_0>, commandpool: vk::CommandPool)
+	{
+		device.destroy_fence(self.fence, None);
+		device.free_command_buffers(commandpool, &[self.cmd_buf]);
+		for &(buffer, memory) in self.buffers.iter()
+		{
+			device.destroy_buffer(buffer, None);
+			device.free_memory(memory, None);
+		}
+	}
+}
+
 pub struct RenderState
 {
 	// Vulkan device
@@ -41,37 +228,104 @@ pub struct RenderState
 	instance: Instance<V1_0>,
 	debug_report_loader: Option<DebugReport>,
 	debug_callback: Option<vk::DebugReportCallbackEXT>,
+	debug_labels: DebugLabels,
 	pdevice: vk::PhysicalDevice,
 	pub device: Rc<Device<V1_0>>,
 	device_memory_properties: vk::PhysicalDeviceMemoryProperties,
+	device_properties: vk::PhysicalDeviceProperties,
+	// The lower of the requested and the device API version
+	api_version: u32,
+	// Names of the enabled extensions, for the startup report
+	enabled_instance_extensions: Vec<String>,
+	enabled_device_extensions: Vec<String>,
+	pub features: DeviceFeatures,
+	pub conservative_raster: bool,
+	/// The device only implements the Vulkan portability subset (e.g. MoltenVK on macOS).
+	pub portability_subset: bool,
+	/// Memory the uniform buffers are kept in.
+	pub uniform_memory: UniformMemory,
+	// Largest texture dimension to upload, bigger images are downscaled
+	max_texture_size: u32,
+	/// Applied to the meshes of new objects.
+	pub mesh_cleanup: MeshCleanup,
 	queue_family_index: u32,
 	graphics_queue: vk::Queue,
 
-	// Window
-	pub event_loop: EventsLoop,
-	pub window: Window,
+	// Window and its event loop, None if headless
+	window: Option<(EventsLoop, Window)>,
+	pub hidpi_factor: f32,
 
 	// Pools
 	commandpool: vk::CommandPool,
+
+	// Decodes texture images in the background
+	decoder: ImageDecoder,
+	// Shared by the uploads of textures and device local buffers
+	staging: RefCell<StagingBuffer>,
+	// Submissions of submit_and_wait() that timed out
+	timed_out: RefCell<Vec<PendingSubmission>>,
+
+	// Shared fallbacks for textures that fail to load, created once in init()
+	default_texture: Option<Texture>,
+	flat_normal_texture: Option<Texture>,
+	white_texture: Option<Texture>,
 }
 
 impl RenderState
 {
-	/// Lists the extensions required by the application.
-	fn extension_names() -> Vec<*const i8>
+	/// Lists the extensions required by the application, the surface ones only when there is a window.
+	fn extension_names(windowed: bool) -> Vec<&'static CStr>
 	{
-		let mut extensions = vec![Surface::name().as_ptr(), XlibSurface::name().as_ptr()];
+		let mut extensions = if windowed
+		{
+			vec![Surface::name(), XlibSurface::name()]
+		}
+		else
+		{
+			Vec::new()
+		};
 		if cfg!(feature = "debug_layer")
 		{
-			extensions.push(DebugReport::name().as_ptr());
+			extensions.push(DebugReport::name());
 		}
 		extensions
 	}
 
+	/// Checks whether the given instance extension is available.
+	fn instance_supports_extension(entry: &Entry<V1_0>, name: &CStr) -> bool
+	{
+		let extensions = match entry.enumerate_instance_extension_properties()
+		{
+			Ok(extensions) => extensions,
+			Err(_) => return false,
+		};
+		extensions.iter().any(|ext| {
+			let ext_name;
+			unsafe {
+				ext_name = CStr::from_ptr(ext.extension_name.as_ptr());
+			}
+			ext_name == name
+		})
+	}
+
+	/// Converts extension names from the Config to C strings.
+	fn extension_cstrings(names: &[String]) -> Result<Vec<CString>, String>
+	{
+		names
+			.iter()
+			.map(|name| CString::new(name.as_str()).map_err(|_| format!("Invalid extension name {:?}", name)))
+			.collect()
+	}
+
 	/// Creates a Vulkan instance.
-	fn create_instance(cfg: &Config, entry: &Entry<V1_0>) -> Instance<V1_0>
+	///
+	/// With portability enumerate set, devices that only implement the portability subset are listed too.
+	fn create_instance(
+		cfg: &Config, entry: &Entry<V1_0>, extra_extensions: &[&CStr], portability_enumerate: bool, windowed: bool
+	) -> Instance<V1_0>
 	{
 		// Application info
+		let (api_major, api_minor) = cfg.vulkan_api_version().unwrap_or((1, 0));
 		let app_name = CString::new(cfg.app_name.clone()).unwrap();
 		let raw_name = app_name.as_ptr();
 		let appinfo = vk::ApplicationInfo {
@@ -81,7 +335,7 @@ impl RenderState
 			application_version: cfg.app_version,
 			p_engine_name: raw_name,
 			engine_version: cfg.app_version,
-			api_version: vk_make_version!(1, 0, 57),
+			api_version: vk_make_version!(api_major, api_minor, 0),
 		};
 
 		// Layers
@@ -90,7 +344,7 @@ impl RenderState
 		// Only enable debug layers if requested
 		if cfg!(feature = "debug_layer")
 		{
-			println!("Debug layers:");
+			verbose!("Debug layers:");
 			let available_layers = entry.enumerate_instance_layer_properties().unwrap();
 			for layer in available_layers.iter()
 			{
@@ -98,26 +352,40 @@ impl RenderState
 				unsafe {
 					layer_name = CStr::from_ptr(layer.layer_name.as_ptr());
 				}
-				println!("Found layer {:?}", layer_name);
+				verbose!("Found layer {:?}", layer_name);
 				for req_layer in requested_layers.iter()
 				{
 					if layer_name == req_layer.as_c_str()
 					{
-						println!("Will enable {:?}", req_layer);
+						verbose!("Will enable {:?}", req_layer);
 						layer_names_raw.push(req_layer.as_ptr());
 					}
 				}
 			}
 
-			println!("Will enable {} debug layers", layer_names_raw.len());
+			verbose!("Will enable {} debug layers", layer_names_raw.len());
 		}
 
 		// Instance
-		let extension_names_raw = RenderState::extension_names();
+		let mut extension_names_raw: Vec<*const i8> =
+			RenderState::extension_names(windowed).iter().map(|name| name.as_ptr()).collect();
+		for extension in extra_extensions.iter()
+		{
+			extension_names_raw.push(extension.as_ptr());
+		}
+		// ash knows no valid InstanceCreateFlags bits, so from_flags() would reject this
+		let flags: vk::InstanceCreateFlags = if portability_enumerate
+		{
+			unsafe { transmute(INSTANCE_CREATE_ENUMERATE_PORTABILITY_BIT_KHR) }
+		}
+		else
+		{
+			Default::default()
+		};
 		let create_info = vk::InstanceCreateInfo {
 			s_type: vk::StructureType::InstanceCreateInfo,
 			p_next: ptr::null(),
-			flags: Default::default(),
+			flags: flags,
 			p_application_info: &appinfo,
 			pp_enabled_layer_names: layer_names_raw.as_ptr(),
 			enabled_layer_count: layer_names_raw.len() as u32,
@@ -166,9 +434,20 @@ impl RenderState
 	}
 
 	/// Selects a physical device (and queue index) for the Vulkan instance.
-	fn pick_physical_device(instance: &Instance<V1_0>) -> (vk::PhysicalDevice, u32)
+	///
+	/// Returns an error message if there is no device with a graphics queue.
+	fn pick_physical_device(instance: &Instance<V1_0>) -> Result<(vk::PhysicalDevice, u32), String>
 	{
-		let pdevices = instance.enumerate_physical_devices().expect("Failed to find GPU with Vulkan support");
+		let pdevices = instance
+			.enumerate_physical_devices()
+			.map_err(|e| format!("Failed to enumerate Vulkan devices ({:?}), are the GPU drivers installed?", e))?;
+		if pdevices.is_empty()
+		{
+			return Err(String::from(
+				"No Vulkan-capable GPU found (0 devices), install the GPU's Vulkan drivers and try again",
+			));
+		}
+
 		let (pdevice, queue_family_index) = pdevices
 			.iter()
 			.map(|pdevice| {
@@ -190,14 +469,40 @@ impl RenderState
 			})
 			.filter_map(|v| v)
 			.nth(0)
-			.expect("Couldn't find suitable device.");
+			.ok_or_else(|| {
+				format!(
+					"None of the {} Vulkan devices found can render graphics, install the GPU's Vulkan drivers and try \
+					 again",
+					pdevices.len()
+				)
+			})?;
+
+		Ok((pdevice, queue_family_index as u32))
+	}
 
-		(pdevice, queue_family_index as u32)
+	/// Checks whether the physical device supports the given device extension.
+	fn device_supports_extension(instance: &Instance<V1_0>, pdevice: vk::PhysicalDevice, name: &CStr) -> bool
+	{
+		let extensions = match instance.enumerate_device_extension_properties(pdevice)
+		{
+			Ok(extensions) => extensions,
+			Err(_) => return false,
+		};
+		extensions.iter().any(|ext| {
+			let ext_name;
+			unsafe {
+				ext_name = CStr::from_ptr(ext.extension_name.as_ptr());
+			}
+			ext_name == name
+		})
 	}
 
 	/// Creates a Vulkan device (logical) based on the instance and physical device.
+	///
+	/// The swapchain extension is only enabled when there is a window to present to.
 	fn create_logical_device(
-		instance: &Instance<V1_0>, pdevice: vk::PhysicalDevice, queue_family_index: u32
+		instance: &Instance<V1_0>, pdevice: vk::PhysicalDevice, queue_family_index: u32, features: &DeviceFeatures,
+		extra_extensions: &[&CStr], windowed: bool,
 	) -> Device<V1_0>
 	{
 		let queue_priorities = [1.0]; // One queue of priority 1.0
@@ -209,12 +514,16 @@ impl RenderState
 			p_queue_priorities: queue_priorities.as_ptr(),
 			queue_count: queue_priorities.len() as u32,
 		};
-		let device_extension_names_raw = [Swapchain::name().as_ptr()]; // VK_KHR_swapchain
-		let features = vk::PhysicalDeviceFeatures {
-			shader_clip_distance: vk::VK_TRUE,
-			// Can request more stuff here later
-			..Default::default()
-		};
+		let mut device_extension_names_raw = Vec::new();
+		if windowed
+		{
+			device_extension_names_raw.push(Swapchain::name().as_ptr()); // VK_KHR_swapchain
+		}
+		for extension in extra_extensions.iter()
+		{
+			device_extension_names_raw.push(extension.as_ptr());
+		}
+		let features = features.to_vk();
 		let device_create_info = vk::DeviceCreateInfo {
 			s_type: vk::StructureType::DeviceCreateInfo,
 			p_next: ptr::null(),
@@ -251,22 +560,94 @@ impl RenderState
 		(commandpool)
 	}
 
-	/// Initializes the RenderState based in the passed Config.
-	pub fn init(cfg: &Config) -> RenderState
+	/// Initializes the RenderState based in the passed Config, with a window to present to.
+	///
+	/// Returns an error message if no suitable Vulkan device is found.
+	pub fn init(cfg: &Config) -> Result<RenderState, String>
 	{
 		// Window and event handler
 		let event_loop = winit::EventsLoop::new();
 		let window = winit::WindowBuilder::new()
-			.with_title(format!("{} {}", cfg.app_name, cfg.version_to_string()))
+			.with_title(cfg.title())
 			.with_dimensions(cfg.window_width, cfg.window_height)
 			.build(&event_loop)
 			.unwrap();
+		if let Some(ref path) = cfg.window_icon
+		{
+			RenderState::set_window_icon(&window, path);
+		}
+
+		RenderState::create(cfg, Some((event_loop, window)))
+	}
+
+	/// Initializes the RenderState without a window, for offscreen rendering only.
+	///
+	/// The MainPass works as usual, but there is nothing a PresentPass could present to. Sizes are
+	/// not scaled, as if the HiDPI factor was 1.
+	pub fn init_headless(cfg: &Config) -> Result<RenderState, String>
+	{
+		RenderState::create(cfg, None)
+	}
+
+	/// Sets up Vulkan, with the surface and swapchain extensions only if there is a window.
+	fn create(cfg: &Config, window: Option<(EventsLoop, Window)>) -> Result<RenderState, String>
+	{
+		let windowed = window.is_some();
+		let hidpi_factor = window.as_ref().map(|&(_, ref window)| window.hidpi_factor()).unwrap_or(1.0);
 
 		// ash entry point
 		let entry: Entry<V1_0> = Entry::new().unwrap();
 
 		// Vulkan init
-		let instance = RenderState::create_instance(&cfg, &entry);
+		let requested_instance_extensions = RenderState::extension_cstrings(&cfg.instance_extensions)?;
+		let requested_device_extensions = RenderState::extension_cstrings(&cfg.device_extensions)?;
+		let mut instance_extensions = Vec::new();
+		let debug_labels_available = RenderState::instance_supports_extension(&entry, DebugLabels::name());
+		if debug_labels_available
+		{
+			instance_extensions.push(DebugLabels::name());
+		}
+		let portability_enumeration_name = CStr::from_bytes_with_nul(PORTABILITY_ENUMERATION_EXTENSION_NAME).unwrap();
+		let properties2_name = CStr::from_bytes_with_nul(GET_PHYSICAL_DEVICE_PROPERTIES_2_EXTENSION_NAME).unwrap();
+		let portability_enumerate = cfg.portability_enumeration &&
+			RenderState::instance_supports_extension(&entry, portability_enumeration_name);
+		if portability_enumerate
+		{
+			instance_extensions.push(portability_enumeration_name);
+			// Required by VK_KHR_portability_subset
+			if RenderState::instance_supports_extension(&entry, properties2_name)
+			{
+				instance_extensions.push(properties2_name);
+			}
+		}
+		let missing: Vec<&str> = cfg
+			.instance_extensions
+			.iter()
+			.zip(requested_instance_extensions.iter())
+			.filter(|&(_, name)| !RenderState::instance_supports_extension(&entry, name))
+			.map(|(name, _)| name.as_str())
+			.collect();
+		if !missing.is_empty()
+		{
+			return Err(format!("Required instance extensions not available: {}", missing.join(", ")));
+		}
+		for name in requested_instance_extensions.iter()
+		{
+			if !instance_extensions.contains(&name.as_c_str())
+			{
+				instance_extensions.push(name.as_c_str());
+			}
+		}
+		let instance =
+			RenderState::create_instance(&cfg, &entry, &instance_extensions, portability_enumerate, windowed);
+		let debug_labels = if debug_labels_available
+		{
+			DebugLabels::new(&entry, &instance)
+		}
+		else
+		{
+			DebugLabels::disabled()
+		};
 		let mut debug_report_loader = None;
 		let mut debug_callback = None;
 		if cfg!(feature = "debug_layer")
@@ -275,9 +656,92 @@ impl RenderState
 			debug_report_loader = Some(loader);
 			debug_callback = Some(callback);
 		}
-		let (pdevice, queue_family_index) = RenderState::pick_physical_device(&instance);
+		let picked = RenderState::pick_physical_device(&instance).and_then(|(pdevice, queue_family_index)| {
+			let missing: Vec<&str> = cfg
+				.device_extensions
+				.iter()
+				.zip(requested_device_extensions.iter())
+				.filter(|&(_, name)| !RenderState::device_supports_extension(&instance, pdevice, name))
+				.map(|(name, _)| name.as_str())
+				.collect();
+			if missing.is_empty()
+			{
+				Ok((pdevice, queue_family_index))
+			}
+			else
+			{
+				Err(format!("Required device extensions not available: {}", missing.join(", ")))
+			}
+		});
+		let (pdevice, queue_family_index) = match picked
+		{
+			Ok(picked) => picked,
+			Err(e) =>
+			{
+				unsafe {
+					if let (Some(loader), Some(callback)) = (debug_report_loader, debug_callback)
+					{
+						loader.destroy_debug_report_callback_ext(callback, None);
+					}
+					instance.destroy_instance(None);
+				}
+				return Err(e);
+			}
+		};
 		let device_memory_properties = instance.get_physical_device_memory_properties(pdevice);
-		let device = RenderState::create_logical_device(&instance, pdevice, queue_family_index);
+		let device_properties = instance.get_physical_device_properties(pdevice);
+		let mut features = DeviceFeatures::from_supported(&instance.get_physical_device_features(pdevice));
+		let mut device_extensions = Vec::new();
+
+		// The portability subset extension must be enabled if the device exposes it
+		let portability_subset_name = CStr::from_bytes_with_nul(PORTABILITY_SUBSET_EXTENSION_NAME).unwrap();
+		let portability_subset = RenderState::device_supports_extension(&instance, pdevice, portability_subset_name);
+		if portability_subset
+		{
+			device_extensions.push(portability_subset_name);
+			// MoltenVK reports wide lines but only draws them one pixel wide
+			features.wide_lines = false;
+			verbose!("Portability subset device detected, running in portability mode");
+		}
+
+		let conservative_raster_name = CString::new("VK_EXT_conservative_rasterization").unwrap();
+		let conservative_raster = cfg!(feature = "conservative_raster") &&
+			RenderState::device_supports_extension(&instance, pdevice, &conservative_raster_name);
+		if conservative_raster
+		{
+			device_extensions.push(conservative_raster_name.as_c_str());
+			verbose!("Conservative rasterization enabled for line quads");
+		}
+		else if cfg!(feature = "conservative_raster")
+		{
+			verbose!("Conservative rasterization not supported, line quads will be rasterized normally");
+		}
+		for name in requested_device_extensions.iter()
+		{
+			if !device_extensions.contains(&name.as_c_str())
+			{
+				device_extensions.push(name.as_c_str());
+			}
+		}
+
+		// A newer instance version doesn't make older devices support more
+		let (api_major, api_minor) = cfg.vulkan_api_version().unwrap_or((1, 0));
+		let requested_api_version = vk_make_version!(api_major, api_minor, 0);
+		let effective_api_version = requested_api_version.min(device_properties.api_version);
+		let mut enabled_instance_extensions: Vec<&CStr> = RenderState::extension_names(windowed);
+		enabled_instance_extensions.extend(instance_extensions.iter().cloned());
+		// create_logical_device() adds the swapchain extension
+		let mut enabled_device_extensions: Vec<&CStr> = if windowed { vec![Swapchain::name()] } else { Vec::new() };
+		enabled_device_extensions.extend(device_extensions.iter().cloned());
+
+		let device = RenderState::create_logical_device(
+			&instance,
+			pdevice,
+			queue_family_index,
+			&features,
+			&device_extensions,
+			windowed,
+		);
 		let graphics_queue;
 		unsafe {
 			graphics_queue = device.get_device_queue(queue_family_index, 0);
@@ -285,27 +749,264 @@ impl RenderState
 
 		// Other stuff
 		let commandpool = RenderState::create_pools(&device, queue_family_index);
+		let staging = StagingBuffer::new(&device, cfg.staging_buffer_size);
+
+		let max_texture_size = match cfg.max_texture_size
+		{
+			Some(size) => size.min(device_properties.limits.max_image_dimension2_d),
+			None => device_properties.limits.max_image_dimension2_d,
+		};
 
-		// Return the RenderState
-		RenderState {
+		let uniform_memory = cfg.uniform_memory.unwrap_or(UniformMemory::HostCoherent);
+
+		let mut rs = RenderState {
 			// Vulkan device
 			entry: entry,
 			instance: instance,
 			debug_report_loader: debug_report_loader,
 			debug_callback: debug_callback,
+			debug_labels: debug_labels,
 			pdevice: pdevice,
 			device: Rc::new(device),
 			device_memory_properties: device_memory_properties,
+			device_properties: device_properties,
+			api_version: effective_api_version,
+			enabled_instance_extensions: enabled_instance_extensions
+				.iter()
+				.map(|name| name.to_string_lossy().into_owned())
+				.collect(),
+			enabled_device_extensions: enabled_device_extensions
+				.iter()
+				.map(|name| name.to_string_lossy().into_owned())
+				.collect(),
+			features: features,
+			conservative_raster: conservative_raster,
+			portability_subset: portability_subset,
+			uniform_memory: uniform_memory,
+			max_texture_size: max_texture_size,
+			mesh_cleanup: MeshCleanup {
+				weld_epsilon: cfg.mesh_weld_epsilon,
+				remove_degenerate_triangles: cfg.remove_degenerate_triangles,
+				winding: cfg.mesh_winding,
+			},
 			queue_family_index: queue_family_index,
 			graphics_queue: graphics_queue,
 
 			// Window
-			event_loop: event_loop,
 			window: window,
+			hidpi_factor: hidpi_factor,
 
 			// Pools
 			commandpool: commandpool,
+
+			decoder: ImageDecoder::new(cfg.decode_threads),
+			staging: RefCell::new(staging),
+			timed_out: RefCell::new(Vec::new()),
+
+			default_texture: None,
+			flat_normal_texture: None,
+			white_texture: None,
+		};
+
+		// The configured missing texture, or a generated checkerboard if there is none
+		let default_texture = match cfg.missing_texture
+		{
+			Some(ref path) => rs.load_image(path, ColorSpace::Srgb, false).map_err(|e| warning!("{}", e)).ok(),
+			None => None,
+		};
+		rs.default_texture = Some(default_texture.unwrap_or_else(|| {
+			rs.create_rgba_texture(CHECKERBOARD_SIZE, CHECKERBOARD_SIZE, &checkerboard_pixels(), ColorSpace::Srgb)
+		}));
+		// Points straight out of the surface
+		rs.flat_normal_texture = Some(rs.create_rgba_texture(1, 1, &[128, 128, 255, 255], ColorSpace::Linear));
+		// Leaves the factors it is multiplied with unchanged
+		rs.white_texture = Some(rs.create_rgba_texture(1, 1, &[255, 255, 255, 255], ColorSpace::Linear));
+
+		Ok(rs)
+	}
+
+	/// Returns true if the RenderState was created without a window by init_headless().
+	pub fn is_headless(&self) -> bool
+	{
+		self.window.is_none()
+	}
+
+	/// Describes the device and what was enabled on it. The PresentPass adds the swapchain.
+	pub fn startup_report(&self) -> StartupReport
+	{
+		let device_name;
+		unsafe {
+			device_name = CStr::from_ptr(self.device_properties.device_name.as_ptr());
 		}
+
+		let mut report = StartupReport::new();
+		report.add(
+			"Device",
+			format!("{} ({:?})", device_name.to_string_lossy(), self.device_properties.device_type),
+		);
+		report.add(
+			"Vulkan API",
+			format!("{}.{}", vk_version_major!(self.api_version), vk_version_minor!(self.api_version)),
+		);
+		report.add_list("Device features", &self.features.enabled_names());
+		report.add_list("Instance extensions", &self.enabled_instance_extensions);
+		report.add_list("Device extensions", &self.enabled_device_extensions);
+		report.add("Portability subset", self.portability_subset);
+		if cfg!(feature = "conservative_raster")
+		{
+			report.add("Conservative raster", self.conservative_raster);
+		}
+		report.add("Uniform memory", format!("{:?}", self.uniform_memory));
+		report.add("Max texture size", self.max_texture_size);
+		report
+	}
+
+	/// Returns the window rendered to, or None if headless.
+	pub fn window(&self) -> Option<&Window>
+	{
+		self.window.as_ref().map(|&(_, ref window)| window)
+	}
+
+	/// Changes the title of the window, does nothing if headless.
+	pub fn set_window_title(&self, title: &str)
+	{
+		if let Some((_, ref window)) = self.window
+		{
+			window.set_title(title);
+		}
+	}
+
+	/// Sets the window icon from an image file, where the platform supports it.
+	///
+	/// winit 0.11 has no window icons on any platform, so for now the image is only checked.
+	fn set_window_icon(_window: &Window, path: &str)
+	{
+		match image::open(path)
+		{
+			Ok(_) => warning!("Window icons are not supported by this winit version, ignoring {}", path),
+			Err(e) => warning!("Failed to load the window icon {}: {}", path, e),
+		}
+	}
+
+	/// Passes the pending window events to the callback, does nothing if headless.
+	pub fn poll_events<F>(&mut self, callback: F)
+	where
+		F: FnMut(winit::Event),
+	{
+		if let Some((ref mut event_loop, _)) = self.window
+		{
+			event_loop.poll_events(callback);
+		}
+	}
+
+	/// Returns the texture to use in place of a color texture that failed to load.
+	///
+	/// It is owned by the RenderState, so it must not be destroyed by its users.
+	pub fn default_texture(&self) -> &Texture
+	{
+		self.default_texture.as_ref().expect("Default texture not created")
+	}
+
+	/// Returns the flat normal map to use in place of a normal map that failed to load.
+	///
+	/// It is owned by the RenderState, so it must not be destroyed by its users.
+	pub fn flat_normal_texture(&self) -> &Texture
+	{
+		self.flat_normal_texture.as_ref().expect("Flat normal texture not created")
+	}
+
+	/// Returns a white texture, to use in place of a specular map that is missing or failed to load.
+	///
+	/// It is owned by the RenderState, so it must not be destroyed by its users.
+	pub fn white_texture(&self) -> &Texture
+	{
+		self.white_texture.as_ref().expect("White texture not created")
+	}
+
+	/// Converts a size in logical pixels (as given in the Config) to physical pixels.
+	///
+	/// On HiDPI displays the drawable surface is larger than the logical window size by the HiDPI factor.
+	pub fn to_physical_size(&self, width: u32, height: u32) -> (u32, u32)
+	{
+		(
+			(width as f32 * self.hidpi_factor).round() as u32,
+			(height as f32 * self.hidpi_factor).round() as u32,
+		)
+	}
+
+	/// Returns the size in physical pixels the render scale of the MainPass applies to: the inner size of
+	/// the window the swapchain is sized by, or the configured render size if headless.
+	pub fn base_render_size(&self, cfg: &Config) -> (u32, u32)
+	{
+		match self.window().and_then(|window| window.get_inner_size())
+		{
+			Some(size) => size,
+			None => self.to_physical_size(cfg.render_width, cfg.render_height),
+		}
+	}
+
+	/// Returns the first of the candidate formats that supports all the features with the given tiling,
+	/// or None if none of them does.
+	pub fn find_supported_depth_format(
+		&self, candidates: &[vk::Format], tiling: vk::ImageTiling, features: vk::FormatFeatureFlags
+	) -> Option<vk::Format>
+	{
+		candidates.iter().cloned().find(|&format| {
+			let properties = self.instance.get_physical_device_format_properties(self.pdevice, format);
+			if tiling == vk::ImageTiling::Linear
+			{
+				properties.linear_tiling_features.subset(features)
+			}
+			else
+			{
+				properties.optimal_tiling_features.subset(features)
+			}
+		})
+	}
+
+	/// Returns true if images of the format can be sampled with linear filtering, which is optional for
+	/// depth formats.
+	pub fn supports_linear_filter(&self, format: vk::Format) -> bool
+	{
+		let properties = self.instance.get_physical_device_format_properties(self.pdevice, format);
+		properties.optimal_tiling_features.subset(vk::FORMAT_FEATURE_SAMPLED_IMAGE_FILTER_LINEAR_BIT)
+	}
+
+	/// Picks the depth format of the depth images, which are rendered to and then sampled. Use the
+	/// returned format for both the images and the renderpasses.
+	///
+	/// If stencil is requested, a combined depth/stencil format is picked when available.
+	pub fn pick_depth_format(&self, stencil: bool) -> vk::Format
+	{
+		let features = vk::FORMAT_FEATURE_DEPTH_STENCIL_ATTACHMENT_BIT | vk::FORMAT_FEATURE_SAMPLED_IMAGE_BIT;
+		let depth_formats = [vk::Format::D32Sfloat, vk::Format::X8D24UnormPack32, vk::Format::D16Unorm];
+		if stencil
+		{
+			let stencil_formats = [vk::Format::D24UnormS8Uint, vk::Format::D32SfloatS8Uint, vk::Format::D16UnormS8Uint];
+			match self.find_supported_depth_format(&stencil_formats, vk::ImageTiling::Optimal, features)
+			{
+				Some(format) => return format,
+				None => warning!("No supported depth/stencil format found, falling back to depth only."),
+			}
+		}
+
+		// D16Unorm has to support both features on every device
+		self.find_supported_depth_format(&depth_formats, vk::ImageTiling::Optimal, features)
+			.unwrap_or(vk::Format::D16Unorm)
+	}
+
+	/// Opens a labeled region in the command buffer, visible in GPU captures.
+	///
+	/// Does nothing if VK_EXT_debug_utils isn't available.
+	pub fn push_debug_label(&self, cmd_buf: vk::CommandBuffer, name: &str, color: [f32; 4])
+	{
+		self.debug_labels.push(cmd_buf, name, color);
+	}
+
+	/// Closes the last labeled region opened with push_debug_label().
+	pub fn pop_debug_label(&self, cmd_buf: vk::CommandBuffer)
+	{
+		self.debug_labels.pop(cmd_buf);
 	}
 
 	/// Returns a suitable memory type for the requirements based in the physical Vulkan device.
@@ -351,6 +1052,8 @@ impl RenderState
 	}
 
 	/// Ends the small GPU operation commandbuffer and sends the commands to the GPU.
+	///
+	/// These commands are the only ones reading the staging buffer, so the submission signals its fence.
 	fn end_single_time_commands(&self, cmd_buf: vk::CommandBuffer)
 	{
 		unsafe {
@@ -369,14 +1072,106 @@ impl RenderState
 			p_signal_semaphores: ptr::null(),
 		};
 		unsafe {
-			self.device
-				.queue_submit(self.graphics_queue, &[submit_info], vk::Fence::null())
-				.expect("queue submit failed.");
+			let fence = self.staging.borrow().submit_fence(&self.device);
+			self.device.queue_submit(self.graphics_queue, &[submit_info], fence).expect("queue submit failed.");
 			self.device.queue_wait_idle(self.graphics_queue).expect("queue wait failed.");
 			self.device.free_command_buffers(self.commandpool, &[cmd_buf]);
 		}
 	}
 
+	/// Submits a command buffer from begin_single_time_commands() and waits for it to finish executing,
+	/// for at most the given timeout.
+	///
+	/// Uses a dedicated fence, so only this submission is waited for instead of the whole device. The
+	/// buffers are the ones only this submission uses, e.g. for a readback. They are handed back if it
+	/// finished, and otherwise destroyed along with the command buffer once that is safe, which is on a
+	/// later call or on drop if it timed out.
+	pub fn submit_and_wait(
+		&self, cmd_buf: vk::CommandBuffer, timeout: Duration, buffers: Vec<(vk::Buffer, vk::DeviceMemory)>
+	) -> Result<Vec<(vk::Buffer, vk::DeviceMemory)>, String>
+	{
+		self.destroy_finished_submissions();
+
+		let fence_create_info = vk::FenceCreateInfo {
+			s_type: vk::StructureType::FenceCreateInfo,
+			p_next: ptr::null(),
+			flags: vk::FenceCreateFlags::empty(),
+		};
+		let mut submission = PendingSubmission {
+			fence: vk::Fence::null(),
+			cmd_buf: cmd_buf,
+			buffers: buffers,
+		};
+		unsafe {
+			self.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
+			match self.device.create_fence(&fence_create_info, None)
+			{
+				Ok(fence) => submission.fence = fence,
+				Err(e) =>
+				{
+					// Destroying the null fence does nothing
+					submission.destroy(&self.device, self.commandpool);
+					return Err(format!("Create fence failed: {:?}", e));
+				}
+			}
+		}
+
+		let submit_info = vk::SubmitInfo {
+			s_type: vk::StructureType::SubmitInfo,
+			p_next: ptr::null(),
+			wait_semaphore_count: 0,
+			p_wait_semaphores: ptr::null(),
+			p_wait_dst_stage_mask: ptr::null(),
+			command_buffer_count: 1,
+			p_command_buffers: &cmd_buf,
+			signal_semaphore_count: 0,
+			p_signal_semaphores: ptr::null(),
+		};
+		unsafe {
+			if let Err(e) = self.device.queue_submit(self.graphics_queue, &[submit_info], submission.fence)
+			{
+				submission.destroy(&self.device, self.commandpool);
+				return Err(format!("Queue submit failed: {:?}", e));
+			}
+
+			match self.device.wait_for_fences(&[submission.fence], true, timeout_nanos(timeout))
+			{
+				Ok(_) =>
+				{
+					let buffers = mem::replace(&mut submission.buffers, Vec::new());
+					submission.destroy(&self.device, self.commandpool);
+					Ok(buffers)
+				}
+				// Still in use by the GPU, so cleaned up once it finished
+				Err(vk::Result::Timeout) =>
+				{
+					self.timed_out.borrow_mut().push(submission);
+					Err(format!("Submission did not finish within {:?}", timeout))
+				}
+				Err(e) =>
+				{
+					submission.destroy(&self.device, self.commandpool);
+					Err(format!("Wait for fence failed: {:?}", e))
+				}
+			}
+		}
+	}
+
+	/// Cleans up the timed out submissions of submit_and_wait() that have finished since.
+	fn destroy_finished_submissions(&self)
+	{
+		let device = &self.device;
+		let commandpool = self.commandpool;
+		self.timed_out.borrow_mut().retain(|submission| unsafe {
+			let finished = device.wait_for_fences(&[submission.fence], true, 0).is_ok();
+			if finished
+			{
+				submission.destroy(device, commandpool);
+			}
+			!finished
+		});
+	}
+
 	/// Creates a vk::Buffer based on the requirements.
 	fn create_buffer(
 		&self, usage: vk::BufferUsageFlags, properties: vk::MemoryPropertyFlags, buffersize: vk::DeviceSize
@@ -421,57 +1216,17 @@ impl RenderState
 		optimal_layout: bool,
 	) -> (vk::Buffer, vk::DeviceMemory)
 	{
-		let mut buffer;
-		let mut memory;
 		let buffersize: vk::DeviceSize = (size_of::<T>() * upload_data.len()) as u64;
 
-		// Create a temporary staging buffer
+		// For optimal buffers: create a new buffer with the optimal layout and copy the data over from
+		// the staging buffer
 		if optimal_layout
 		{
 			debug_assert!((properties & vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT) == vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT);
 
-			let (buf, mem) = self.create_buffer(
-				vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
-				vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT,
-				buffersize,
-			);
-			buffer = buf;
-			memory = mem;
-		// Create the actual buffer
-		}
-		else
-		{
-			debug_assert!(
-				(properties & (vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)) ==
-					(vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)
-			);
-
-			let (buf, mem) = self.create_buffer(usage, properties, buffersize);
-			buffer = buf;
-			memory = mem;
-		}
-
-		// Upload data to the buffer we just created
-		unsafe {
-			let mem_ptr = self.device
-				.map_memory(memory, 0, buffersize, vk::MemoryMapFlags::empty())
-				.expect("Failed to map index memory");
-			let mut mem_align = Align::new(mem_ptr, align_of::<T>() as u64, buffersize);
-			mem_align.copy_from_slice(upload_data);
-			self.device.unmap_memory(memory);
-		}
-
-		// For optimal buffers: create a new buffer with the optimal layout and copy the staging
-		// buffer into it
-		if optimal_layout
-		{
-			let staging_buffer = buffer;
-			let staging_memory = memory;
-
-			// Create final buffer
-			let (buf, mem) = self.create_buffer(vk::BUFFER_USAGE_TRANSFER_DST_BIT | usage, properties, buffersize);
-			buffer = buf;
-			memory = mem;
+			let staging_buffer = self.staging.borrow_mut().write(self, upload_data);
+			let (buffer, memory) =
+				self.create_buffer(vk::BUFFER_USAGE_TRANSFER_DST_BIT | usage, properties, buffersize);
 
 			// Copy contents
 			let cmd_buf = self.begin_single_time_commands();
@@ -485,11 +1240,23 @@ impl RenderState
 			}
 			self.end_single_time_commands(cmd_buf);
 
-			// Free staging buffer
-			unsafe {
-				self.device.destroy_buffer(staging_buffer, None);
-				self.device.free_memory(staging_memory, None);
-			}
+			return (buffer, memory);
+		}
+
+		debug_assert!(
+			(properties & (vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)) ==
+				(vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT)
+		);
+		let (buffer, memory) = self.create_buffer(usage, properties, buffersize);
+
+		// Upload data to the buffer we just created
+		unsafe {
+			let mem_ptr = self.device
+				.map_memory(memory, 0, buffersize, vk::MemoryMapFlags::empty())
+				.expect("Failed to map index memory");
+			let mut mem_align = Align::new(mem_ptr, align_of::<T>() as u64, buffersize);
+			mem_align.copy_from_slice(upload_data);
+			self.device.unmap_memory(memory);
 		}
 
 		(buffer, memory)
@@ -518,16 +1285,33 @@ impl RenderState
 
 	/// Creates a texture, view and sampler based on the passed options.
 	///
-	/// A vk::Buffer can optionally be passed to fill the texture with initial data.
+	/// A vk::Buffer can optionally be passed to fill the texture with initial data, along with the
+	/// offset of each mip level in it. The texture gets one mip level per offset.
+	///
+	/// Cube views get a cube compatible image with six array layers, the initial data only fills the first one.
 	fn create_texture(
 		&self, texture_dimensions: vk::Extent3D, texture_type: vk::ImageType, texture_view_type: vk::ImageViewType,
 		texture_format: vk::Format, texture_aspect_mask: vk::ImageAspectFlags, mut texture_usage: vk::ImageUsageFlags,
 		initial_access_mask: vk::AccessFlags, initial_layout: vk::ImageLayout, initial_stage: vk::PipelineStageFlags,
-		upload_buffer: Option<vk::Buffer>,
+		upload: Option<(vk::Buffer, &[vk::DeviceSize])>,
 	) -> Texture
 	{
+		let mip_levels = match upload
+		{
+			Some((_, mip_offsets)) => mip_offsets.len().max(1) as u32,
+			None => 1,
+		};
+		let (array_layers, image_flags) = if texture_view_type == vk::ImageViewType::Cube
+		{
+			(6, vk::IMAGE_CREATE_CUBE_COMPATIBLE_BIT)
+		}
+		else
+		{
+			(1, vk::ImageCreateFlags::empty())
+		};
+
 		// In case we need to upload to the texture, mark it for transfer dst
-		if upload_buffer.is_some()
+		if upload.is_some()
 		{
 			texture_usage |= vk::IMAGE_USAGE_TRANSFER_DST_BIT;
 		}
@@ -535,12 +1319,12 @@ impl RenderState
 		let texture_create_info = vk::ImageCreateInfo {
 			s_type: vk::StructureType::ImageCreateInfo,
 			p_next: ptr::null(),
-			flags: Default::default(),
+			flags: image_flags,
 			image_type: texture_type,
 			format: texture_format,
 			extent: texture_dimensions,
-			mip_levels: 1,
-			array_layers: 1,
+			mip_levels: mip_levels,
+			array_layers: array_layers,
 			samples: vk::SAMPLE_COUNT_1_BIT,
 			tiling: vk::ImageTiling::Optimal,
 			usage: texture_usage,
@@ -572,10 +1356,10 @@ impl RenderState
 
 		// Transition the Image and potentially upload
 		let cmd_buf = self.begin_single_time_commands();
-		match upload_buffer
+		match upload
 		{
 			// In case we need to upload some texture data
-			Some(image_upload_buffer) =>
+			Some((image_upload_buffer, mip_offsets)) =>
 			{
 				// First transition the Image to TransferDstOptimal
 				let texture_barrier = vk::ImageMemoryBarrier {
@@ -591,9 +1375,9 @@ impl RenderState
 					subresource_range: vk::ImageSubresourceRange {
 						aspect_mask: texture_aspect_mask,
 						base_mip_level: 0,
-						level_count: 1,
+						level_count: mip_levels,
 						base_array_layer: 0,
-						layer_count: 1,
+						layer_count: array_layers,
 					},
 				};
 				unsafe {
@@ -607,31 +1391,39 @@ impl RenderState
 						&[texture_barrier],
 					);
 				}
-				// Copy buffer data to image
-				let buffer_copy_region = vk::BufferImageCopy {
-					buffer_offset: 0,
-					buffer_row_length: 0,
-					buffer_image_height: 0,
-					image_subresource: vk::ImageSubresourceLayers {
-						aspect_mask: texture_aspect_mask,
-						mip_level: 0,
-						base_array_layer: 0,
-						layer_count: 1,
-					},
-					image_extent: texture_dimensions,
-					image_offset: vk::Offset3D {
-						x: 0,
-						y: 0,
-						z: 0,
-					},
-				};
+				// Copy buffer data to image, one region per mip level
+				let buffer_copy_regions: Vec<vk::BufferImageCopy> = mip_offsets
+					.iter()
+					.enumerate()
+					.map(|(level, &offset)| vk::BufferImageCopy {
+						buffer_offset: offset,
+						buffer_row_length: 0,
+						buffer_image_height: 0,
+						image_subresource: vk::ImageSubresourceLayers {
+							aspect_mask: texture_aspect_mask,
+							mip_level: level as u32,
+							base_array_layer: 0,
+							layer_count: 1,
+						},
+						image_extent: vk::Extent3D {
+							width: (texture_dimensions.width >> level).max(1),
+							height: (texture_dimensions.height >> level).max(1),
+							depth: (texture_dimensions.depth >> level).max(1),
+						},
+						image_offset: vk::Offset3D {
+							x: 0,
+							y: 0,
+							z: 0,
+						},
+					})
+					.collect();
 				unsafe {
 					self.device.cmd_copy_buffer_to_image(
 						cmd_buf,
 						image_upload_buffer,
 						texture_image,
 						vk::ImageLayout::TransferDstOptimal,
-						&[buffer_copy_region],
+						&buffer_copy_regions,
 					);
 				}
 				// Finally transition the Image to the correct layout
@@ -648,9 +1440,9 @@ impl RenderState
 					subresource_range: vk::ImageSubresourceRange {
 						aspect_mask: texture_aspect_mask,
 						base_mip_level: 0,
-						level_count: 1,
+						level_count: mip_levels,
 						base_array_layer: 0,
-						layer_count: 1,
+						layer_count: array_layers,
 					},
 				};
 				unsafe {
@@ -681,9 +1473,9 @@ impl RenderState
 					subresource_range: vk::ImageSubresourceRange {
 						aspect_mask: texture_aspect_mask,
 						base_mip_level: 0,
-						level_count: 1,
+						level_count: mip_levels,
 						base_array_layer: 0,
-						layer_count: 1,
+						layer_count: array_layers,
 					},
 				};
 				unsafe {
@@ -717,9 +1509,9 @@ impl RenderState
 			subresource_range: vk::ImageSubresourceRange {
 				aspect_mask: texture_aspect_mask,
 				base_mip_level: 0,
-				level_count: 1,
+				level_count: mip_levels,
 				base_array_layer: 0,
-				layer_count: 1,
+				layer_count: array_layers,
 			},
 			image: texture_image,
 		};
@@ -728,95 +1520,252 @@ impl RenderState
 			texture_view = self.device.create_image_view(&tex_image_view_info, None).unwrap();
 		}
 
-		// Create sampler
-		let sampler_info = vk::SamplerCreateInfo {
-			s_type: vk::StructureType::SamplerCreateInfo,
-			p_next: ptr::null(),
-			flags: Default::default(),
-			mag_filter: vk::Filter::Linear,
-			min_filter: vk::Filter::Linear,
-			mipmap_mode: vk::SamplerMipmapMode::Linear,
-			address_mode_u: vk::SamplerAddressMode::MirroredRepeat,
-			address_mode_v: vk::SamplerAddressMode::MirroredRepeat,
-			address_mode_w: vk::SamplerAddressMode::MirroredRepeat,
-			mip_lod_bias: 0.0,
-			min_lod: 0.0,
-			max_lod: 0.0,
-			anisotropy_enable: 0,
-			max_anisotropy: 1.0,
-			border_color: vk::BorderColor::FloatOpaqueWhite,
-			compare_enable: 0,
-			compare_op: vk::CompareOp::Never,
-			unnormalized_coordinates: 0,
-		};
-		let sampler;
-		unsafe {
-			sampler = self.device.create_sampler(&sampler_info, None).unwrap();
-		}
+		let sampler = self.create_sampler(mip_levels, &SamplerOptions::default());
 
 		Texture {
 			image: texture_image,
 			memory: texture_memory,
 			view: texture_view,
 			sampler: sampler,
+			extent: texture_dimensions,
+			mip_levels: mip_levels,
+			array_layers: array_layers,
+			aspect_mask: texture_aspect_mask,
 			current_access_mask: initial_access_mask,
 			current_layout: initial_layout,
 			current_stage: initial_stage,
 		}
 	}
 
+	/// Creates a linearly filtered sampler for a texture with the given number of mip levels.
+	///
+	/// The bias and anisotropy are clamped to what the device supports.
+	fn create_sampler(&self, mip_levels: u32, options: &SamplerOptions) -> vk::Sampler
+	{
+		let limits = &self.device_properties.limits;
+		let max_anisotropy = if self.features.sampler_anisotropy
+		{
+			limits.max_sampler_anisotropy
+		}
+		else
+		{
+			1.0
+		};
+		let sampler_info = sampler_create_info(mip_levels, options, max_anisotropy, limits.max_sampler_lod_bias);
+		let sampler;
+		unsafe {
+			sampler = self.device.create_sampler(&sampler_info, None).unwrap();
+		}
+		sampler
+	}
+
+	/// Replaces the sampler of the texture with one using the given options.
+	///
+	/// The texture must not be in use by the GPU, and descriptor sets using it have to be updated.
+	pub fn set_sampler_options(&self, texture: &mut Texture, options: &SamplerOptions)
+	{
+		unsafe {
+			self.device.destroy_sampler(texture.sampler, None);
+		}
+		texture.sampler = self.create_sampler(texture.mip_levels, options);
+	}
+
 	/// Loads the image given by the path into read only texture.
 	///
+	/// Color textures should use ColorSpace::Srgb and data like normal maps ColorSpace::Linear. With
+	/// premultiply set, the colors are multiplied by alpha for drawing with AlphaMode::Premultiplied.
+	///
+	/// DDS and KTX files are loaded with load_compressed_texture(), their format decides the color space
+	/// and they can't be premultiplied.
+	///
+	/// Fails if the image can't be loaded, callers can fall back to default_texture() then.
+	///
 	/// Note: The caller is responsible for cleaning up the returned vulkan types.
-	pub fn load_image(&self, path: &str, srgb: bool) -> Texture
+	pub fn load_image(&self, path: &str, color_space: ColorSpace, premultiply: bool) -> Result<Texture, String>
 	{
-		// Load the image data into a vk::Buffer
-		let image = image::open(path).unwrap().to_rgba();
-		let image_extent;
+		if compressed::is_compressed_path(path)
 		{
-			let image_dims = image.dimensions();
-			image_extent = vk::Extent3D {
-				width: image_dims.0,
-				height: image_dims.1,
-				depth: 1,
-			};
+			if premultiply
+			{
+				return Err(format!("{}: Compressed textures can't be premultiplied", path));
+			}
+			return self.load_compressed_texture(path);
 		}
-		let image_data = image.into_raw();
-		let (image_buffer, image_memory) = self.create_buffer_and_upload(
-			vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
-			vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT,
-			&image_data,
-			false,
-		);
+
+		let mut image = self.decoder.take(path)?;
+		// Before any resizing, so the filter doesn't pick up the color of transparent pixels
+		if premultiply
+		{
+			premultiply_alpha(&mut image, color_space);
+		}
+		let (width, height) = image.dimensions();
+		if width > self.max_texture_size || height > self.max_texture_size
+		{
+			// Scale the larger side down to the limit, keeping the aspect ratio
+			let scale = self.max_texture_size as f32 / width.max(height) as f32;
+			let new_width = ((width as f32 * scale) as u32).max(1);
+			let new_height = ((height as f32 * scale) as u32).max(1);
+			warning!(
+				"Texture {} ({}x{}) exceeds the max texture size of {}, downscaling to {}x{}",
+				path, width, height, self.max_texture_size, new_width, new_height
+			);
+			image = image::imageops::resize(&image, new_width, new_height, image::FilterType::Lanczos3);
+		}
+		let (width, height) = image.dimensions();
+
+		Ok(self.create_rgba_texture(width, height, &image.into_raw(), color_space))
+	}
+
+	/// Starts decoding the image on a decode thread, for a later load_image() of the same path.
+	///
+	/// Each request is picked up by one load_image(). Does nothing if decode_threads is 0 or the image is
+	/// compressed, as those are uploaded as is.
+	pub fn request_image(&self, path: &str)
+	{
+		if !compressed::is_compressed_path(path)
+		{
+			self.decoder.request(path);
+		}
+	}
+
+	/// Creates a read only texture from RGBA8 pixels.
+	///
+	/// Note: The caller is responsible for cleaning up the returned vulkan types.
+	fn create_rgba_texture(&self, width: u32, height: u32, pixels: &[u8], color_space: ColorSpace) -> Texture
+	{
+		let image_extent = vk::Extent3D {
+			width: width,
+			height: height,
+			depth: 1,
+		};
+		// Load the image data into the staging buffer
+		let image_buffer = self.staging.borrow_mut().write(self, pixels);
 
 		// Create a texture from the buffer data
 		let texture = self.create_texture(
 			image_extent,
 			vk::ImageType::Type2d,
 			vk::ImageViewType::Type2d,
-			if srgb
-			{
-				vk::Format::R8g8b8a8Srgb
-			}
-			else
-			{
-				vk::Format::R8g8b8a8Unorm
+			color_space.rgba8_format(),
+			vk::IMAGE_ASPECT_COLOR_BIT,
+			vk::IMAGE_USAGE_SAMPLED_BIT,
+			vk::ACCESS_SHADER_READ_BIT,
+			vk::ImageLayout::ShaderReadOnlyOptimal,
+			vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+			Some((image_buffer, &[0])),
+		);
+
+		texture
+	}
+
+	/// Loads a BCn compressed texture with all its mip levels from a DDS or KTX file.
+	///
+	/// The compressed data is uploaded as is. Fails if the file can't be parsed, exceeds the max texture
+	/// size or the device can't sample the format.
+	fn load_compressed_texture(&self, path: &str) -> Result<Texture, String>
+	{
+		let image = CompressedImage::load(path)?;
+		if image.width > self.max_texture_size || image.height > self.max_texture_size
+		{
+			return Err(format!(
+				"{}: Compressed texture ({}x{}) exceeds the max texture size of {}",
+				path, image.width, image.height, self.max_texture_size
+			));
+		}
+
+		let properties = self.instance.get_physical_device_format_properties(self.pdevice, image.format);
+		if !properties.optimal_tiling_features.subset(vk::FORMAT_FEATURE_SAMPLED_IMAGE_BIT)
+		{
+			return Err(format!("{}: Format {:?} is not supported by the device", path, image.format));
+		}
+
+		let image_buffer = self.staging.borrow_mut().write(self, &image.data);
+
+		let texture = self.create_texture(
+			vk::Extent3D {
+				width: image.width,
+				height: image.height,
+				depth: 1,
 			},
+			vk::ImageType::Type2d,
+			vk::ImageViewType::Type2d,
+			image.format,
 			vk::IMAGE_ASPECT_COLOR_BIT,
 			vk::IMAGE_USAGE_SAMPLED_BIT,
 			vk::ACCESS_SHADER_READ_BIT,
 			vk::ImageLayout::ShaderReadOnlyOptimal,
 			vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
-			Some(image_buffer),
+			Some((image_buffer, &image.mip_offsets)),
 		);
 
-		// Texture now holds the data, can delete image buffer and memory
+		Ok(texture)
+	}
+
+	/// Copies the first mip level of a 4 bytes per pixel texture (e.g. RGBA8) back to the CPU.
+	///
+	/// Waits for all earlier work on the graphics queue, failing if that takes longer than
+	/// READBACK_TIMEOUT. The texture needs IMAGE_USAGE_TRANSFER_SRC_BIT and is left in the
+	/// TransferSrcOptimal layout.
+	pub fn read_texture(&self, texture: &mut Texture) -> Result<Vec<u8>, String>
+	{
+		let size = texture.extent.width as u64 * texture.extent.height as u64 * 4;
+		let (buffer, memory) = self.create_buffer(
+			vk::BUFFER_USAGE_TRANSFER_DST_BIT,
+			vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT,
+			size,
+		);
+
+		let cmd_buf = self.begin_single_time_commands();
+		self.transition_texture(
+			texture,
+			vk::ACCESS_TRANSFER_READ_BIT,
+			vk::ImageLayout::TransferSrcOptimal,
+			vk::PIPELINE_STAGE_TRANSFER_BIT,
+			Some(cmd_buf),
+		);
+		let region = vk::BufferImageCopy {
+			buffer_offset: 0,
+			buffer_row_length: 0,
+			buffer_image_height: 0,
+			image_subresource: vk::ImageSubresourceLayers {
+				aspect_mask: texture.aspect_mask,
+				mip_level: 0,
+				base_array_layer: 0,
+				layer_count: 1,
+			},
+			image_extent: texture.extent,
+			image_offset: vk::Offset3D {
+				x: 0,
+				y: 0,
+				z: 0,
+			},
+		};
 		unsafe {
-			self.device.destroy_buffer(image_buffer, None);
-			self.device.free_memory(image_memory, None);
+			self.device.cmd_copy_image_to_buffer(
+				cmd_buf,
+				texture.image,
+				vk::ImageLayout::TransferSrcOptimal,
+				buffer,
+				&[region],
+			);
 		}
+		// Waits for the copy to finish
+		self.submit_and_wait(cmd_buf, READBACK_TIMEOUT, vec![(buffer, memory)])
+			.map_err(|e| format!("Failed to read back a texture: {}", e))?;
 
-		texture
+		let mut pixels = vec![0u8; size as usize];
+		unsafe {
+			let mem_ptr = self.device
+				.map_memory(memory, 0, size, vk::MemoryMapFlags::empty())
+				.expect("Failed to map readback memory");
+			ptr::copy_nonoverlapping(mem_ptr as *const u8, pixels.as_mut_ptr(), pixels.len());
+			self.device.unmap_memory(memory);
+
+			self.device.destroy_buffer(buffer, None);
+			self.device.free_memory(memory, None);
+		}
+
+		Ok(pixels)
 	}
 
 	/// Transitions a Texture from its current access_mask/layout/pipeline_stage to the passed
@@ -846,11 +1795,11 @@ impl RenderState
 			dst_queue_family_index: vk::VK_QUEUE_FAMILY_IGNORED,
 			image: texture.image,
 			subresource_range: vk::ImageSubresourceRange {
-				aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+				aspect_mask: texture.aspect_mask,
 				base_mip_level: 0,
-				level_count: 1,
+				level_count: texture.mip_levels,
 				base_array_layer: 0,
-				layer_count: 1,
+				layer_count: texture.array_layers,
 			},
 		};
 
@@ -899,6 +1848,8 @@ impl Drop for RenderState
 	/// been freed at this point.
 	fn drop(&mut self)
 	{
+		teardown_scope!("RenderState");
+
 		// We must have the only reference to device at this point
 		debug_assert!(1 == Rc::strong_count(&self.device));
 
@@ -906,6 +1857,20 @@ impl Drop for RenderState
 			// Always wait for device idle
 			self.device.device_wait_idle().unwrap();
 
+			let fallbacks = self.default_texture.iter().chain(self.flat_normal_texture.iter());
+			for texture in fallbacks.chain(self.white_texture.iter())
+			{
+				self.device.destroy_sampler(texture.sampler, None);
+				self.device.destroy_image_view(texture.view, None);
+				self.device.destroy_image(texture.image, None);
+				self.device.free_memory(texture.memory, None);
+			}
+			self.staging.borrow_mut().destroy(&self.device);
+			for submission in self.timed_out.borrow().iter()
+			{
+				submission.destroy(&self.device, self.commandpool);
+			}
+
 			self.device.destroy_command_pool(self.commandpool, None);
 			self.device.destroy_device(None);
 			if cfg!(feature = "debug_layer")
@@ -924,3 +1889,126 @@ impl Drop for RenderState
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	/// Returns the texel halfway between two RGBA8 texels, as bilinear filtering at their edge does.
+	fn midpoint(a: &[u8], b: &[u8]) -> Vec<f32>
+	{
+		a.iter().zip(b.iter()).map(|(&a, &b)| (a as f32 + b as f32) / 2.0).collect()
+	}
+
+	#[test]
+	fn sampler_create_info_has_the_requested_anisotropy_and_lod_bias()
+	{
+		let options = SamplerOptions {
+			mip_lod_bias: -0.5,
+			max_anisotropy: 8.0,
+		};
+		let info = sampler_create_info(5, &options, 16.0, 4.0);
+		assert_eq!(info.mip_lod_bias, -0.5);
+		assert_eq!(info.anisotropy_enable, 1);
+		assert_eq!(info.max_anisotropy, 8.0);
+		assert_eq!(info.max_lod, 4.0);
+
+		// Clamped to the device limits
+		let info = sampler_create_info(1, &options, 4.0, 0.25);
+		assert_eq!(info.mip_lod_bias, -0.25);
+		assert_eq!(info.max_anisotropy, 4.0);
+
+		// Without anisotropy support it is disabled
+		let info = sampler_create_info(1, &options, 1.0, 4.0);
+		assert_eq!(info.anisotropy_enable, 0);
+		assert_eq!(info.max_anisotropy, 1.0);
+	}
+
+	#[test]
+	fn texture_format_matches_the_color_space()
+	{
+		// Only sRGB textures are decoded to linear by the sampler, data like normals must stay as is
+		assert_eq!(ColorSpace::Srgb.rgba8_format(), vk::Format::R8g8b8a8Srgb);
+		assert_eq!(ColorSpace::Linear.rgba8_format(), vk::Format::R8g8b8a8Unorm);
+	}
+
+	#[test]
+	fn premultiplied_alpha_has_no_fringe_at_an_edge()
+	{
+		// An opaque red texel next to a fully transparent white one
+		let straight = [255u8, 0, 0, 255, 255, 255, 255, 0];
+		let mut premultiplied = straight;
+		premultiply_alpha(&mut premultiplied, ColorSpace::Linear);
+		assert_eq!(premultiplied, [255, 0, 0, 255, 0, 0, 0, 0]);
+
+		// Straight alpha mixes the white of the invisible texel into the edge
+		let edge = midpoint(&straight[..4], &straight[4..]);
+		assert!(edge[1] > 100.0 && edge[2] > 100.0);
+
+		// Premultiplied, the edge is pure red at half coverage
+		let edge = midpoint(&premultiplied[..4], &premultiplied[4..]);
+		assert_eq!(edge, vec![127.5, 0.0, 0.0, 127.5]);
+	}
+
+	#[test]
+	fn premultiplying_srgb_scales_in_linear_space()
+	{
+		let mut linear = [200u8, 100, 0, 128];
+		premultiply_alpha(&mut linear, ColorSpace::Linear);
+		assert_eq!(linear, [100, 50, 0, 128]);
+
+		// Half the linear intensity of sRGB 200 is about sRGB 147, brighter than 100
+		let mut srgb = [200u8, 0, 0, 128];
+		premultiply_alpha(&mut srgb, ColorSpace::Srgb);
+		assert!(srgb[0] > 140 && srgb[0] < 150);
+		assert_eq!(srgb[3], 128);
+	}
+
+	#[test]
+	fn timeout_is_converted_to_nanoseconds()
+	{
+		assert_eq!(timeout_nanos(Duration::from_millis(1500)), 1_500_000_000);
+		assert_eq!(timeout_nanos(READBACK_TIMEOUT), 5_000_000_000);
+	}
+
+	/// Needs a Vulkan device, run with cargo test -- --ignored.
+	#[test]
+	#[ignore]
+	fn trivial_submit_signals_its_fence_within_the_timeout()
+	{
+		let rs = RenderState::init_headless(&Config::default()).unwrap();
+		let cmd_buf = rs.begin_single_time_commands();
+		let buffers = rs.submit_and_wait(cmd_buf, READBACK_TIMEOUT, Vec::new()).unwrap();
+		assert!(buffers.is_empty());
+		assert!(rs.timed_out.borrow().is_empty());
+	}
+
+	/// Needs a Vulkan device, run with cargo test -- --ignored.
+	#[test]
+	#[ignore]
+	fn submit_past_the_timeout_returns_an_error()
+	{
+		let rs = RenderState::init_headless(&Config::default()).unwrap();
+
+		// Filling 16 GiB takes far longer than the timeout on any GPU
+		let size = 256 * 1024 * 1024;
+		let buffer = rs.create_buffer(vk::BUFFER_USAGE_TRANSFER_DST_BIT, vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT, size);
+		let cmd_buf = rs.begin_single_time_commands();
+		for fill in 0..64
+		{
+			unsafe {
+				rs.device.cmd_fill_buffer(cmd_buf, buffer.0, 0, size, fill);
+			}
+		}
+		let result = rs.submit_and_wait(cmd_buf, Duration::new(0, 1), vec![buffer]);
+		assert!(result.is_err());
+		assert_eq!(rs.timed_out.borrow().len(), 1);
+
+		// Cleaned up by the next submission once it has finished
+		rs.device.device_wait_idle().unwrap();
+		let cmd_buf = rs.begin_single_time_commands();
+		rs.submit_and_wait(cmd_buf, READBACK_TIMEOUT, Vec::new()).unwrap();
+		assert!(rs.timed_out.borrow().is_empty());
+	}
+}
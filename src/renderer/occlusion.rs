@@ -0,0 +1,136 @@
+use ash::Device;
+use ash::version::{DeviceV1_0, V1_0};
+use ash::vk;
+use std::ptr;
+use std::rc::Rc;
+
+use renderer::RenderState;
+
+/// Occlusion queries for skipping objects that were hidden in the previous frame.
+///
+/// Every object gets a query, indexed like the per-object uniforms. Visible objects are drawn inside
+/// their query, while hidden ones only draw a bounding box proxy to find out when they show up
+/// again. Results are read with one frame of latency, objects without a result count as visible.
+pub struct OcclusionQueries
+{
+	query_pool: vk::QueryPool,
+	query_count: u32,
+	likely_visible: Vec<bool>,
+	// The queries have been reset and used at least once
+	has_results: bool,
+	culled_count: usize,
+	// Depth tested pipeline writing neither color nor depth, for the proxies
+	pub proxy_pipeline: vk::Pipeline,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device<V1_0>>,
+}
+
+impl OcclusionQueries
+{
+	pub fn new(rs: &RenderState, query_count: u32, proxy_pipeline: vk::Pipeline) -> OcclusionQueries
+	{
+		let query_pool_info = vk::QueryPoolCreateInfo {
+			s_type: vk::StructureType::QueryPoolCreateInfo,
+			p_next: ptr::null(),
+			flags: Default::default(),
+			query_type: vk::QueryType::Occlusion,
+			query_count: query_count,
+			pipeline_statistics: Default::default(),
+		};
+		let query_pool;
+		unsafe {
+			query_pool = rs.device.create_query_pool(&query_pool_info, None).unwrap();
+		}
+
+		OcclusionQueries {
+			query_pool: query_pool,
+			query_count: query_count,
+			likely_visible: vec![true; query_count as usize],
+			has_results: false,
+			culled_count: 0,
+			proxy_pipeline: proxy_pipeline,
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	/// Reads the results of the previous frame, and resets the queries for this one.
+	///
+	/// Must be recorded outside of a renderpass.
+	pub fn begin_frame(&mut self, rs: &RenderState, cmd_buf: vk::CommandBuffer)
+	{
+		if self.has_results
+		{
+			self.culled_count = 0;
+			for query in 0..self.query_count
+			{
+				// Don't wait, queries that weren't used or aren't done yet count as visible
+				let mut samples = [0u32];
+				let result;
+				unsafe {
+					result = rs.device.get_query_pool_results(
+						self.query_pool,
+						query,
+						1,
+						&mut samples,
+						vk::QueryResultFlags::empty(),
+					);
+				}
+				let visible = result.is_err() || samples[0] > 0;
+				self.likely_visible[query as usize] = visible;
+				if !visible
+				{
+					self.culled_count += 1;
+				}
+			}
+		}
+
+		unsafe {
+			rs.device.cmd_reset_query_pool(cmd_buf, self.query_pool, 0, self.query_count);
+		}
+		self.has_results = true;
+	}
+
+	/// Returns false if the object with the given index was hidden in the previous frame.
+	pub fn is_likely_visible(&self, index: usize) -> bool
+	{
+		self.likely_visible.get(index).cloned().unwrap_or(true)
+	}
+
+	/// Returns the number of objects that were hidden in the previous frame.
+	pub fn culled_count(&self) -> usize
+	{
+		self.culled_count
+	}
+
+	/// Starts counting the samples passing the depth test for the object with the given index.
+	pub fn begin_query(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, index: usize)
+	{
+		unsafe {
+			rs.device.cmd_begin_query(cmd_buf, self.query_pool, index as u32, vk::QueryControlFlags::empty());
+		}
+	}
+
+	pub fn end_query(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer, index: usize)
+	{
+		unsafe {
+			rs.device.cmd_end_query(cmd_buf, self.query_pool, index as u32);
+		}
+	}
+}
+
+impl Drop for OcclusionQueries
+{
+	fn drop(&mut self)
+	{
+		teardown_scope!("OcclusionQueries");
+
+		unsafe {
+			// Always wait for device idle
+			self.device.device_wait_idle().unwrap();
+
+			self.device.destroy_pipeline(self.proxy_pipeline, None);
+			self.device.destroy_query_pool(self.query_pool, None);
+		}
+	}
+}
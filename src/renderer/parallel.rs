@@ -0,0 +1,311 @@
+use ash::Device;
+use ash::version::{DeviceV1_0, V1_0};
+use ash::vk;
+use std::ptr;
+use std::rc::Rc;
+use std::thread;
+
+use renderer::RenderState;
+use renderer::mainpass::{DepthBias, LinePushConstants, PipelineKind};
+
+/// Everything needed to record the draw of one object.
+///
+/// Only holds Vulkan handles, so it can be sent to a recording thread.
+#[derive(Clone, Copy)]
+pub struct DrawCommand
+{
+	pub descriptor_set: vk::DescriptorSet,
+	pub vertices: vk::Buffer,
+	pub indices: vk::Buffer,
+	pub num_indices: u32,
+	/// Index into the per-object uniforms.
+	pub object_index: usize,
+	pub pipeline_kind: PipelineKind,
+	pub depth_bias: DepthBias,
+}
+
+/// The state every secondary command buffer has to set up, as nothing is inherited from the
+/// primary command buffer.
+#[derive(Clone, Copy)]
+pub struct RecordState
+{
+	pub renderpass: vk::RenderPass,
+	pub framebuffer: vk::Framebuffer,
+	/// Indexed by PipelineKind.
+	pub pipelines: [vk::Pipeline; 8],
+	pub pipeline_layout: vk::PipelineLayout,
+	pub view_matrix_ds: vk::DescriptorSet,
+	pub object_ds: vk::DescriptorSet,
+	pub object_ub_stride: vk::DeviceSize,
+	pub viewport: vk::Viewport,
+	pub scissor: vk::Rect2D,
+	pub stencil_reference: Option<u32>,
+	/// Set if lines are drawn as quads.
+	pub line_constants: Option<LinePushConstants>,
+}
+
+/// Returns how many draws each thread records, so the draws are spread evenly and every thread but the
+/// last records as many.
+fn chunk_size(commands: usize, threads: usize) -> usize
+{
+	(commands + threads - 1) / threads
+}
+
+/// Records draws on several threads, each with its own command pool and secondary command buffer.
+///
+/// Command pools must only be used by one thread at a time, which is why RenderState's pool can't be
+/// shared by the recording threads.
+pub struct ParallelRecorder
+{
+	pools: Vec<vk::CommandPool>,
+	command_buffers: Vec<vk::CommandBuffer>,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device<V1_0>>,
+}
+
+impl ParallelRecorder
+{
+	/// Creates a command pool and secondary command buffer for each thread.
+	pub fn new(rs: &RenderState, threads: usize) -> ParallelRecorder
+	{
+		let mut pools = Vec::with_capacity(threads);
+		let mut command_buffers = Vec::with_capacity(threads);
+		for _ in 0..threads
+		{
+			let cmd_pool_create_info = vk::CommandPoolCreateInfo {
+				s_type: vk::StructureType::CommandPoolCreateInfo,
+				p_next: ptr::null(),
+				flags: vk::COMMAND_POOL_CREATE_RESET_COMMAND_BUFFER_BIT,
+				queue_family_index: rs.queue_family_index,
+			};
+			let pool;
+			unsafe {
+				pool = rs.device.create_command_pool(&cmd_pool_create_info, None).unwrap();
+			}
+
+			let command_buffer_allocate_info = vk::CommandBufferAllocateInfo {
+				s_type: vk::StructureType::CommandBufferAllocateInfo,
+				p_next: ptr::null(),
+				command_buffer_count: 1,
+				command_pool: pool,
+				level: vk::CommandBufferLevel::Secondary,
+			};
+			let command_buffer;
+			unsafe {
+				command_buffer = rs.device.allocate_command_buffers(&command_buffer_allocate_info).unwrap()[0];
+			}
+
+			pools.push(pool);
+			command_buffers.push(command_buffer);
+		}
+
+		ParallelRecorder {
+			pools: pools,
+			command_buffers: command_buffers,
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	/// Returns the number of recording threads.
+	pub fn threads(&self) -> usize
+	{
+		self.command_buffers.len()
+	}
+
+	/// Splits the draws evenly across the threads and records them into the secondary command buffers.
+	///
+	/// The draw order is kept, as the command buffers are executed in order.
+	/// Returns the recorded command buffers, to be executed from the primary command buffer with
+	/// cmd_execute_commands(). The render pass must have been begun with secondary command buffer
+	/// contents. The previous frame must have finished executing.
+	pub fn record(&self, state: RecordState, commands: &[DrawCommand]) -> Vec<vk::CommandBuffer>
+	{
+		if commands.is_empty()
+		{
+			return Vec::new();
+		}
+
+		let workers: Vec<_> = commands
+			.chunks(chunk_size(commands.len(), self.threads()))
+			.zip(self.command_buffers.iter())
+			.map(|(chunk, &cmd_buf)| {
+				let device = self.device.as_ref().clone();
+				let chunk = chunk.to_vec();
+				thread::spawn(move || {
+					ParallelRecorder::record_chunk(&device, cmd_buf, state, &chunk);
+					cmd_buf
+				})
+			})
+			.collect();
+
+		workers
+			.into_iter()
+			.map(|worker| worker.join().expect("Recording thread panicked"))
+			.collect()
+	}
+
+	/// Records the draws into a secondary command buffer. Runs on a recording thread.
+	fn record_chunk(device: &Device<V1_0>, cmd_buf: vk::CommandBuffer, state: RecordState, commands: &[DrawCommand])
+	{
+		let inheritance_info = vk::CommandBufferInheritanceInfo {
+			s_type: vk::StructureType::CommandBufferInheritanceInfo,
+			p_next: ptr::null(),
+			render_pass: state.renderpass,
+			subpass: 0,
+			framebuffer: state.framebuffer,
+			occlusion_query_enable: 0,
+			query_flags: vk::QueryControlFlags::empty(),
+			pipeline_statistics: vk::QueryPipelineStatisticFlags::empty(),
+		};
+		let cmd_buf_begin_info = vk::CommandBufferBeginInfo {
+			s_type: vk::StructureType::CommandBufferBeginInfo,
+			p_next: ptr::null(),
+			p_inheritance_info: &inheritance_info,
+			flags: vk::COMMAND_BUFFER_USAGE_RENDER_PASS_CONTINUE_BIT | vk::COMMAND_BUFFER_USAGE_ONE_TIME_SUBMIT_BIT,
+		};
+
+		unsafe {
+			device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
+
+			device.cmd_set_viewport(cmd_buf, &[state.viewport]);
+			device.cmd_set_scissor(cmd_buf, &[state.scissor]);
+			if let Some(reference) = state.stencil_reference
+			{
+				device.cmd_set_stencil_reference(cmd_buf, vk::STENCIL_FRONT_AND_BACK, reference);
+			}
+			if let Some(line_constants) = state.line_constants
+			{
+				line_constants.push(device, cmd_buf, state.pipeline_layout);
+			}
+
+			let mut bound_pipeline = vk::Pipeline::null();
+			// Dynamic state isn't inherited either, so the depth bias must be set before the first draw
+			let mut depth_bias = None;
+			for command in commands.iter()
+			{
+				let pipeline = state.pipelines[command.pipeline_kind as usize];
+				if pipeline != bound_pipeline
+				{
+					device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::Graphics, pipeline);
+					bound_pipeline = pipeline;
+				}
+				if depth_bias != Some(command.depth_bias)
+				{
+					let bias = command.depth_bias;
+					device.cmd_set_depth_bias(cmd_buf, bias.constant, 0.0, bias.slope);
+					depth_bias = Some(bias);
+				}
+
+				let dynamic_offset = (state.object_ub_stride * command.object_index as u64) as u32;
+				device.cmd_bind_descriptor_sets(
+					cmd_buf,
+					vk::PipelineBindPoint::Graphics,
+					state.pipeline_layout,
+					0,
+					&[command.descriptor_set, state.view_matrix_ds, state.object_ds],
+					&[dynamic_offset],
+				);
+				device.cmd_bind_vertex_buffers(cmd_buf, 0, &[command.vertices], &[0]);
+				device.cmd_bind_index_buffer(cmd_buf, command.indices, 0, vk::IndexType::Uint16);
+				device.cmd_draw_indexed(cmd_buf, command.num_indices, 1, 0, 0, 1);
+			}
+
+			device.end_command_buffer(cmd_buf).expect("End commandbuffer");
+		}
+	}
+}
+
+impl Drop for ParallelRecorder
+{
+	/// Drops the ParallelRecorder. Destroying the pools also frees their command buffers.
+	fn drop(&mut self)
+	{
+		teardown_scope!("ParallelRecorder");
+
+		// We cannot have the last reference to device at this point
+		debug_assert!(1 < Rc::strong_count(&self.device));
+
+		unsafe {
+			for &pool in self.pools.iter()
+			{
+				self.device.destroy_command_pool(pool, None);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use cgmath::{Matrix4, Point3, SquareMatrix};
+	use config::Config;
+	use object::DrawObject;
+	use renderer::{FrameGraph, LightUniforms, MainPass, ObjectUniforms, PresentedImage};
+
+	#[test]
+	fn draws_are_split_evenly_in_order()
+	{
+		let threads = 2;
+		assert_eq!(chunk_size(2, threads), 1);
+		assert_eq!(chunk_size(5, threads), 3);
+		assert_eq!(chunk_size(1, threads), 1);
+	}
+
+	/// Needs a Vulkan device, run with cargo test -- --ignored.
+	#[test]
+	#[ignore]
+	fn two_secondary_command_buffers_are_executed_from_the_primary()
+	{
+		let cfg = Config {
+			record_threads: 2,
+			show_axis_gizmo: false,
+			show_minimap: false,
+			..Default::default()
+		};
+		let rs = RenderState::init_headless(&cfg).unwrap();
+		let mut mp = MainPass::init(&rs, &cfg);
+		assert!(mp.is_recording_parallel());
+
+		// One triangle per thread, the second one flipped so one of them faces the camera either way
+		let triangles = [
+			DrawObject::new_colored_triangle(&rs, &mp, Point3::new(0.0, 0.0, 0.0), 1.0).unwrap(),
+			DrawObject::new_colored_triangle(&rs, &mp, Point3::new(0.0, 0.0, 0.0), 1.0).unwrap(),
+		];
+		let commands: Vec<DrawCommand> =
+			triangles.iter().enumerate().map(|(i, triangle)| triangle.draw_command(0, i)).collect();
+		assert_eq!(chunk_size(commands.len(), 2), 1);
+		let flip = Matrix4::from_nonuniform_scale(1.0, -1.0, 1.0);
+		let uniforms = [
+			ObjectUniforms {
+				model: Matrix4::identity(),
+				mvp: Matrix4::identity(),
+				specular: [0.0; 4],
+			},
+			ObjectUniforms {
+				model: flip,
+				mvp: flip,
+				specular: [0.0; 4],
+			},
+		];
+		mp.upload_object_uniforms(&rs, &uniforms);
+		mp.set_lighting(
+			&rs,
+			&LightUniforms {
+				direction: [0.0, 0.0, 1.0, 0.0],
+				color: [0.0; 4],
+				ambient: [1.0, 1.0, 1.0, 0.0],
+			},
+		);
+
+		let cmd_buf = mp.begin_frame(&rs, &FrameGraph::new(PresentedImage::Color));
+		mp.execute_parallel(&rs, cmd_buf, &commands);
+		mp.end_frame(&rs);
+		let (width, height, pixels) = mp.read_render_image(&rs).unwrap();
+
+		// The center is covered by the triangles instead of the green clear color
+		let center = 4 * ((height / 2) * width + width / 2) as usize;
+		assert!(pixels[center..center + 4] != [0, 255, 0, 255]);
+	}
+}
@@ -4,11 +4,112 @@ use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0, V1_0};
 use ash::vk;
 use std;
 use std::ffi::CString;
+use std::mem::size_of;
 use std::ptr;
+use std::slice;
 use std::rc::Rc;
 use winit;
 
+use config::Config;
+use log::StartupReport;
 use renderer::{RenderState, Texture};
+use renderer::gbuffer::GBufferTarget;
+use renderer::graph::{FrameGraph, ResourceId};
+
+/// The push constants of the present shaders, matching their PresentParams block.
+///
+/// Both the push constant range and the values pushed each frame come from this struct, so the Rust
+/// side can't disagree with itself about the layout.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct PushConstants
+{
+	exposure: f32,
+	/// Near and far planes for the depth view, or the scale and bias for the G-buffer view.
+	near: f32,
+	far: f32,
+}
+
+/// Returns the push constant range of the pipeline layout, covering PushConstants.
+fn push_constant_range() -> vk::PushConstantRange
+{
+	vk::PushConstantRange {
+		stage_flags: vk::SHADER_STAGE_FRAGMENT_BIT,
+		offset: 0,
+		size: size_of::<PushConstants>() as u32,
+	}
+}
+
+/// Returns the parts of the surface outside of the viewport: the full width bands above and below
+/// it, and the bands left and right of it in between.
+fn letterbox(surface: vk::Rect2D, viewport: &vk::Viewport) -> Vec<vk::Rect2D>
+{
+	let surface_width = surface.extent.width as i32;
+	let surface_height = surface.extent.height as i32;
+	// The image covers the pixels whose center is inside the viewport, so rounding the edges leaves no
+	// gap between it and the bands
+	let left = (viewport.x.round() as i32).max(0).min(surface_width);
+	let top = (viewport.y.round() as i32).max(0).min(surface_height);
+	let right = ((viewport.x + viewport.width).round() as i32).max(left).min(surface_width);
+	let bottom = ((viewport.y + viewport.height).round() as i32).max(top).min(surface_height);
+
+	let rect = |x: i32, y: i32, width: i32, height: i32| vk::Rect2D {
+		offset: vk::Offset2D {
+			x: x,
+			y: y,
+		},
+		extent: vk::Extent2D {
+			width: width as u32,
+			height: height as u32,
+		},
+	};
+	let bands = [
+		rect(0, 0, surface_width, top),
+		rect(0, bottom, surface_width, surface_height - bottom),
+		rect(0, top, left, bottom - top),
+		rect(right, top, surface_width - right, bottom - top),
+	];
+	bands.iter().filter(|band| band.extent.width > 0 && band.extent.height > 0).cloned().collect()
+}
+
+/// How many times a frame is drawn again after the swapchain had to be recreated, before giving up on it.
+const MAX_PRESENT_ATTEMPTS: u32 = 3;
+
+/// How the rendered image is scaled to the window when presenting.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ScalingMode
+{
+	/// Stretch to the window, nearest neighbour filtered.
+	Nearest,
+	/// Stretch to the window, linearly filtered.
+	Linear,
+	/// Scale by the largest whole number that fits, centered and nearest neighbour filtered.
+	Integer,
+}
+
+impl ScalingMode
+{
+	/// Returns the next mode, for cycling through them at runtime.
+	pub fn next(self) -> ScalingMode
+	{
+		match self
+		{
+			ScalingMode::Nearest => ScalingMode::Linear,
+			ScalingMode::Linear => ScalingMode::Integer,
+			ScalingMode::Integer => ScalingMode::Nearest,
+		}
+	}
+
+	/// Returns the filter the mode samples the rendered image with.
+	pub fn filter(self) -> vk::Filter
+	{
+		match self
+		{
+			ScalingMode::Linear => vk::Filter::Linear,
+			ScalingMode::Nearest | ScalingMode::Integer => vk::Filter::Nearest,
+		}
+	}
+}
 
 pub struct PresentPass
 {
@@ -16,6 +117,8 @@ pub struct PresentPass
 	surface_loader: Surface,
 	surface: vk::SurfaceKHR,
 	surface_format: vk::SurfaceFormatKHR,
+	// Mailbox if the surface supports it, else Fifo
+	present_mode: vk::PresentModeKHR,
 
 	// Semaphores
 	image_available_sem: vk::Semaphore,
@@ -27,6 +130,8 @@ pub struct PresentPass
 	swapchain: vk::SwapchainKHR,
 	// presentable images for the screen
 	present_image_views: Vec<vk::ImageView>,
+	// Number of images requested from the swapchain, the driver may create more
+	desired_image_count: u32,
 	renderpass: vk::RenderPass,
 	descriptor_pool: vk::DescriptorPool,
 	descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
@@ -35,10 +140,26 @@ pub struct PresentPass
 	viewport: vk::Viewport,
 	scissor: vk::Rect2D,
 	pipeline: vk::Pipeline,
+	// Shows a depth image as linear grayscale
+	depth_pipeline: vk::Pipeline,
+	// Shows the overdraw counted by the MainPass as a heatmap
+	heatmap_pipeline: vk::Pipeline,
+	// Shows a G-buffer target, scaled and biased into the visible range
+	gbuffer_pipeline: vk::Pipeline,
 	// one framebuffer/commandbuffer per image
 	framebuffers: Vec<vk::Framebuffer>,
 	commandbuffers: Vec<vk::CommandBuffer>,
 
+	// Scaling of the presented image
+	scaling_mode: ScalingMode,
+	filter: vk::Filter,
+	// Brightness multiplier applied when presenting
+	exposure: f32,
+	// Clear color of the area around the image
+	letterbox_color: [f32; 4],
+	nearest_sampler: vk::Sampler,
+	linear_sampler: vk::Sampler,
+
 	// The current idx
 	current_present_idx: usize,
 
@@ -71,24 +192,38 @@ impl PresentPass
 		result
 	}
 
-	/// Creates a vk::Swapchain and a vk::Rect2D for the current RenderState and surface.
+	/// Creates a vk::Swapchain and a vk::Rect2D for the current RenderState and surface, and returns
+	/// them with the present mode it uses.
 	///
-	/// Swapchain is used to queue and present stuff to the screen.
+	/// Swapchain is used to queue and present stuff to the screen. The desired image count is clamped
+	/// to what the surface supports.
 	fn create_swapchain(
 		rs: &RenderState, surface_loader: &Surface, surface: &vk::SurfaceKHR, surface_format: &vk::SurfaceFormatKHR,
-		old_swapchain: vk::SwapchainKHR, swapchain_loader: &Swapchain,
-	) -> (vk::SwapchainKHR, vk::Rect2D)
+		old_swapchain: vk::SwapchainKHR, swapchain_loader: &Swapchain, desired_image_count: u32,
+	) -> (vk::SwapchainKHR, vk::Rect2D, vk::PresentModeKHR)
 	{
 		let surface_capabilities =
 			surface_loader.get_physical_device_surface_capabilities_khr(rs.pdevice, *surface).unwrap();
 
-		// TODO double-buffering for now
-		let mut desired_image_count = 2;
-		debug_assert!(desired_image_count >= surface_capabilities.min_image_count);
-		if surface_capabilities.max_image_count > 0 && desired_image_count > surface_capabilities.max_image_count
+		// A max image count of 0 means there is no limit
+		let mut image_count = desired_image_count.max(surface_capabilities.min_image_count);
+		if surface_capabilities.max_image_count > 0 && image_count > surface_capabilities.max_image_count
 		{
-			desired_image_count = surface_capabilities.max_image_count;
+			image_count = surface_capabilities.max_image_count;
 		}
+		if image_count != desired_image_count
+		{
+			warning!(
+				"Requested {} swapchain images, the surface supports {} to {}, using {}",
+				desired_image_count,
+				surface_capabilities.min_image_count,
+				surface_capabilities.max_image_count,
+				image_count
+			);
+		}
+
+		let window_size = rs.window().expect("PresentPass needs a window").get_inner_size();
+		let extent = PresentPass::pick_extent(&surface_capabilities, window_size);
 
 		let pre_transform = if surface_capabilities.supported_transforms.subset(vk::SURFACE_TRANSFORM_IDENTITY_BIT_KHR)
 		{
@@ -110,10 +245,10 @@ impl PresentPass
 			p_next: ptr::null(),
 			flags: Default::default(),
 			surface: *surface,
-			min_image_count: desired_image_count,
+			min_image_count: image_count,
 			image_color_space: surface_format.color_space,
 			image_format: surface_format.format,
-			image_extent: surface_capabilities.current_extent.clone(),
+			image_extent: extent,
 			image_usage: vk::IMAGE_USAGE_COLOR_ATTACHMENT_BIT,
 			image_sharing_mode: vk::SharingMode::Exclusive,
 			pre_transform: pre_transform,
@@ -137,11 +272,38 @@ impl PresentPass
 					x: 0,
 					y: 0,
 				},
-				extent: surface_capabilities.current_extent.clone(),
+				extent: extent,
 			},
+			present_mode,
 		)
 	}
 
+	/// Returns the size of the swapchain images. The surface dictates it unless its current extent is
+	/// the special 0xFFFFFFFF, then the window size is used, clamped to what the surface supports.
+	fn pick_extent(capabilities: &vk::SurfaceCapabilitiesKHR, window_size: Option<(u32, u32)>) -> vk::Extent2D
+	{
+		if capabilities.current_extent.width != u32::max_value()
+		{
+			return capabilities.current_extent.clone();
+		}
+
+		let (min, max) = (capabilities.min_image_extent, capabilities.max_image_extent);
+		// Without a window size the smallest supported extent is better than failing
+		let (width, height) = window_size.unwrap_or((min.width, min.height));
+		let extent = vk::Extent2D {
+			width: width.max(min.width).min(max.width),
+			height: height.max(min.height).min(max.height),
+		};
+		if extent.width != width || extent.height != height
+		{
+			warning!(
+				"Requested a {}x{} swapchain, the surface supports {}x{} to {}x{}, using {}x{}",
+				width, height, min.width, min.height, max.width, max.height, extent.width, extent.height
+			);
+		}
+		extent
+	}
+
 	/// Creates a Vec of vk::ImageViews for the presentable images in the swapchain.
 	///
 	/// This will create two imageviews for double-buffering, three imageviews for
@@ -196,6 +358,8 @@ impl PresentPass
 				format: surface_format.format,
 				flags: vk::AttachmentDescriptionFlags::empty(),
 				samples: vk::SAMPLE_COUNT_1_BIT,
+				// The image is drawn over the previous contents, and begin_frame() clears the letterbox
+				// around it
 				load_op: vk::AttachmentLoadOp::DontCare,
 				store_op: vk::AttachmentStoreOp::Store,
 				stencil_load_op: vk::AttachmentLoadOp::DontCare,
@@ -242,6 +406,7 @@ impl PresentPass
 	/// Creates a pipeline for the given presentable renderpass.
 	///
 	/// Very straigt forward pipeline: Loads some hard-coded shaders that will draw a triangle.
+	/// A second pipeline sharing the layout is created for the depth debug view.
 	fn create_pipeline(
 		rs: &RenderState, surface_size: vk::Rect2D, renderpass: vk::RenderPass
 	) -> (
@@ -252,6 +417,9 @@ impl PresentPass
 		vk::Viewport,
 		vk::Rect2D,
 		vk::Pipeline,
+		vk::Pipeline,
+		vk::Pipeline,
+		vk::Pipeline,
 	)
 	{
 		// Descriptors
@@ -304,14 +472,15 @@ impl PresentPass
 		unsafe {
 			descriptor_sets = rs.device.allocate_descriptor_sets(&desc_alloc_info).unwrap();
 		}
+		let push_constant_range = push_constant_range();
 		let layout_create_info = vk::PipelineLayoutCreateInfo {
 			s_type: vk::StructureType::PipelineLayoutCreateInfo,
 			p_next: ptr::null(),
 			flags: Default::default(),
 			set_layout_count: descriptor_set_layouts.len() as u32,
 			p_set_layouts: descriptor_set_layouts.as_ptr(),
-			push_constant_range_count: 0,
-			p_push_constant_ranges: ptr::null(),
+			push_constant_range_count: 1,
+			p_push_constant_ranges: &push_constant_range,
 		};
 
 		let pipeline_layout;
@@ -321,6 +490,9 @@ impl PresentPass
 
 		let vertex_shader_module = rs.load_shader("shaders/final_pass_vert.spv");
 		let fragment_shader_module = rs.load_shader("shaders/final_pass_frag.spv");
+		let depth_fragment_shader_module = rs.load_shader("shaders/depth_view_frag.spv");
+		let heatmap_fragment_shader_module = rs.load_shader("shaders/overdraw_heatmap_frag.spv");
+		let gbuffer_fragment_shader_module = rs.load_shader("shaders/gbuffer_view_frag.spv");
 
 		let shader_entry_name = CString::new("main").unwrap();
 		let shader_stage_create_infos = [
@@ -343,6 +515,27 @@ impl PresentPass
 				stage: vk::SHADER_STAGE_FRAGMENT_BIT,
 			},
 		];
+		let depth_shader_stage_create_infos = [
+			shader_stage_create_infos[0].clone(),
+			vk::PipelineShaderStageCreateInfo {
+				module: depth_fragment_shader_module,
+				..shader_stage_create_infos[1].clone()
+			},
+		];
+		let heatmap_shader_stage_create_infos = [
+			shader_stage_create_infos[0].clone(),
+			vk::PipelineShaderStageCreateInfo {
+				module: heatmap_fragment_shader_module,
+				..shader_stage_create_infos[1].clone()
+			},
+		];
+		let gbuffer_shader_stage_create_infos = [
+			shader_stage_create_infos[0].clone(),
+			vk::PipelineShaderStageCreateInfo {
+				module: gbuffer_fragment_shader_module,
+				..shader_stage_create_infos[1].clone()
+			},
+		];
 		let vertex_input_binding_descriptions = [];
 		let vertex_input_attribute_descriptions = [];
 		let vertex_input_state_info = vk::PipelineVertexInputStateCreateInfo {
@@ -479,13 +672,35 @@ impl PresentPass
 			base_pipeline_handle: vk::Pipeline::null(),
 			base_pipeline_index: 0,
 		};
+		let depth_pipeline_info = vk::GraphicsPipelineCreateInfo {
+			stage_count: depth_shader_stage_create_infos.len() as u32,
+			p_stages: depth_shader_stage_create_infos.as_ptr(),
+			..graphic_pipeline_info.clone()
+		};
+		let heatmap_pipeline_info = vk::GraphicsPipelineCreateInfo {
+			stage_count: heatmap_shader_stage_create_infos.len() as u32,
+			p_stages: heatmap_shader_stage_create_infos.as_ptr(),
+			..graphic_pipeline_info.clone()
+		};
+		let gbuffer_pipeline_info = vk::GraphicsPipelineCreateInfo {
+			stage_count: gbuffer_shader_stage_create_infos.len() as u32,
+			p_stages: gbuffer_shader_stage_create_infos.as_ptr(),
+			..graphic_pipeline_info.clone()
+		};
 		let graphics_pipelines;
 		unsafe {
 			graphics_pipelines = rs.device
-				.create_graphics_pipelines(vk::PipelineCache::null(), &[graphic_pipeline_info], None)
+				.create_graphics_pipelines(
+					vk::PipelineCache::null(),
+					&[graphic_pipeline_info, depth_pipeline_info, heatmap_pipeline_info, gbuffer_pipeline_info],
+					None,
+				)
 				.expect("Unable to create graphics pipeline");
 
 			// Graphics pipeline created, we no longer need the shader modules
+			rs.device.destroy_shader_module(gbuffer_fragment_shader_module, None);
+			rs.device.destroy_shader_module(heatmap_fragment_shader_module, None);
+			rs.device.destroy_shader_module(depth_fragment_shader_module, None);
 			rs.device.destroy_shader_module(fragment_shader_module, None);
 			rs.device.destroy_shader_module(vertex_shader_module, None);
 		}
@@ -498,6 +713,9 @@ impl PresentPass
 			viewport,
 			scissor,
 			graphics_pipelines[0],
+			graphics_pipelines[1],
+			graphics_pipelines[2],
+			graphics_pipelines[3],
 		)
 	}
 
@@ -551,14 +769,47 @@ impl PresentPass
 		command_buffers
 	}
 
+	/// Creates a sampler for reading the presented image with the given filter.
+	fn create_sampler(rs: &RenderState, filter: vk::Filter) -> vk::Sampler
+	{
+		let sampler_info = vk::SamplerCreateInfo {
+			s_type: vk::StructureType::SamplerCreateInfo,
+			p_next: ptr::null(),
+			flags: Default::default(),
+			mag_filter: filter,
+			min_filter: filter,
+			mipmap_mode: vk::SamplerMipmapMode::Nearest,
+			address_mode_u: vk::SamplerAddressMode::ClampToEdge,
+			address_mode_v: vk::SamplerAddressMode::ClampToEdge,
+			address_mode_w: vk::SamplerAddressMode::ClampToEdge,
+			mip_lod_bias: 0.0,
+			min_lod: 0.0,
+			max_lod: 0.0,
+			anisotropy_enable: 0,
+			max_anisotropy: 1.0,
+			border_color: vk::BorderColor::FloatOpaqueBlack,
+			compare_enable: 0,
+			compare_op: vk::CompareOp::Never,
+			unnormalized_coordinates: 0,
+		};
+		let sampler;
+		unsafe {
+			sampler = rs.device.create_sampler(&sampler_info, None).unwrap();
+		}
+
+		sampler
+	}
+
 	/// Initializes the PresentPass based on a RenderState
 	///
-	/// This will set up the swapchain, renderpass, etc.
-	pub fn init(rs: &RenderState) -> PresentPass
+	/// This will set up the swapchain, renderpass, etc. The RenderState must have a window, so it
+	/// can't be headless.
+	pub fn init(rs: &RenderState, cfg: &Config) -> PresentPass
 	{
 		// Surface
 		let surface_loader = Surface::new(&rs.entry, &rs.instance).expect("Unable to load the Surface extension");
-		let surface = PresentPass::create_surface(&rs.entry, &rs.instance, &rs.window).unwrap();
+		let window = rs.window().expect("PresentPass needs a window");
+		let surface = PresentPass::create_surface(&rs.entry, &rs.instance, window).unwrap();
 		assert!(surface_loader.get_physical_device_surface_support_khr(rs.pdevice, rs.queue_family_index, surface,));
 		let surface_formats = surface_loader.get_physical_device_surface_formats_khr(rs.pdevice, surface).unwrap();
 		let surface_format = surface_formats
@@ -588,26 +839,42 @@ impl PresentPass
 
 		let swapchain_loader = Swapchain::new(&rs.instance, rs.device.as_ref()).expect("Unable to load swapchain");
 
-		let (swapchain, surface_size) = PresentPass::create_swapchain(
+		let (swapchain, surface_size, present_mode) = PresentPass::create_swapchain(
 			rs,
 			&surface_loader,
 			&surface,
 			&surface_format,
 			vk::SwapchainKHR::null(),
 			&swapchain_loader,
+			cfg.swapchain_images,
 		);
 		let present_image_views = PresentPass::create_imageviews(rs, &surface_format, &swapchain_loader, swapchain);
+		// The driver may create more images than requested
+		verbose!("Swapchain created with {} images", present_image_views.len());
 		let renderpass = PresentPass::create_renderpass(rs, &surface_format);
-		let (descriptor_pool, descriptor_set_layouts, descriptor_sets, pipeline_layout, viewport, scissor, pipeline) =
-			PresentPass::create_pipeline(rs, surface_size, renderpass);
+		let (
+			descriptor_pool,
+			descriptor_set_layouts,
+			descriptor_sets,
+			pipeline_layout,
+			viewport,
+			scissor,
+			pipeline,
+			depth_pipeline,
+			heatmap_pipeline,
+			gbuffer_pipeline,
+		) = PresentPass::create_pipeline(rs, surface_size, renderpass);
 		let framebuffers = PresentPass::create_framebuffers(rs, surface_size, &present_image_views, renderpass);
 		let command_buffers = PresentPass::create_commandbuffers(rs, &framebuffers);
+		let nearest_sampler = PresentPass::create_sampler(rs, vk::Filter::Nearest);
+		let linear_sampler = PresentPass::create_sampler(rs, vk::Filter::Linear);
 
 		PresentPass {
 			// Surface
 			surface_loader: surface_loader,
 			surface: surface,
 			surface_format: surface_format,
+			present_mode: present_mode,
 
 			// Semaphores
 			image_available_sem: image_available_sem,
@@ -618,6 +885,7 @@ impl PresentPass
 			// Swapchain
 			swapchain: swapchain,
 			present_image_views: present_image_views,
+			desired_image_count: cfg.swapchain_images,
 			renderpass: renderpass,
 			descriptor_pool: descriptor_pool,
 			descriptor_set_layouts: descriptor_set_layouts,
@@ -626,10 +894,21 @@ impl PresentPass
 			viewport: viewport,
 			scissor: scissor,
 			pipeline: pipeline,
+			depth_pipeline: depth_pipeline,
+			heatmap_pipeline: heatmap_pipeline,
+			gbuffer_pipeline: gbuffer_pipeline,
 			// one framebuffer/commandbuffer per image
 			framebuffers: framebuffers,
 			commandbuffers: command_buffers,
 
+			// Scaling of the presented image
+			scaling_mode: cfg.present_scaling,
+			filter: cfg.present_scaling.filter(),
+			exposure: cfg.exposure,
+			letterbox_color: cfg.letterbox_color,
+			nearest_sampler: nearest_sampler,
+			linear_sampler: linear_sampler,
+
 			// The current idx
 			current_present_idx: std::usize::MAX,
 
@@ -652,6 +931,9 @@ impl PresentPass
 				self.device.destroy_framebuffer(framebuffer, None);
 			}
 
+			self.device.destroy_pipeline(self.gbuffer_pipeline, None);
+			self.device.destroy_pipeline(self.heatmap_pipeline, None);
+			self.device.destroy_pipeline(self.depth_pipeline, None);
 			self.device.destroy_pipeline(self.pipeline, None);
 			self.device.destroy_pipeline_layout(self.pipeline_layout, None);
 
@@ -676,26 +958,42 @@ impl PresentPass
 	/// Releases the old and creates a new swapchain.
 	///
 	/// This function should be called when the presentable surface is resized, etc.
-	fn recreate_swapchain(&mut self, rs: &RenderState)
+	pub fn recreate_swapchain(&mut self, rs: &RenderState)
 	{
 		self.cleanup_swapchain();
 
-		let (swapchain, surface_size) = PresentPass::create_swapchain(
+		let (swapchain, surface_size, present_mode) = PresentPass::create_swapchain(
 			rs,
 			&self.surface_loader,
 			&self.surface,
 			&self.surface_format,
 			vk::SwapchainKHR::null(),
 			&self.swapchain_loader,
+			self.desired_image_count,
 		);
 		self.swapchain = swapchain;
+		self.present_mode = present_mode;
 		let present_image_views =
 			PresentPass::create_imageviews(rs, &self.surface_format, &self.swapchain_loader, swapchain);
+		if present_image_views.len() != self.present_image_views.len()
+		{
+			verbose!("Swapchain recreated with {} images", present_image_views.len());
+		}
 		self.present_image_views = present_image_views;
 		let renderpass = PresentPass::create_renderpass(rs, &self.surface_format);
 		self.renderpass = renderpass;
-		let (descriptor_pool, descriptor_set_layouts, descriptor_sets, pipeline_layout, viewport, scissor, pipeline) =
-			PresentPass::create_pipeline(rs, surface_size, renderpass);
+		let (
+			descriptor_pool,
+			descriptor_set_layouts,
+			descriptor_sets,
+			pipeline_layout,
+			viewport,
+			scissor,
+			pipeline,
+			depth_pipeline,
+			heatmap_pipeline,
+			gbuffer_pipeline,
+		) = PresentPass::create_pipeline(rs, surface_size, renderpass);
 		self.descriptor_pool = descriptor_pool;
 		self.descriptor_set_layouts = descriptor_set_layouts;
 		self.descriptor_sets = descriptor_sets;
@@ -703,18 +1001,143 @@ impl PresentPass
 		self.viewport = viewport;
 		self.scissor = scissor;
 		self.pipeline = pipeline;
+		self.depth_pipeline = depth_pipeline;
+		self.heatmap_pipeline = heatmap_pipeline;
+		self.gbuffer_pipeline = gbuffer_pipeline;
 		let framebuffers = PresentPass::create_framebuffers(rs, surface_size, &self.present_image_views, renderpass);
 		self.framebuffers = framebuffers;
 		let command_buffers = PresentPass::create_commandbuffers(rs, &self.framebuffers);
 		self.commandbuffers = command_buffers;
 	}
 
+	/// Recreates the swapchain after acquiring or presenting reported it as out of date or suboptimal.
+	///
+	/// A suboptimal acquire still signals the image available semaphore, but ash drops the image
+	/// index, so the image can't be used. The semaphore is replaced, as it must be unsignaled for the
+	/// next acquire.
+	fn handle_outdated_swapchain(&mut self, rs: &RenderState, result: vk::Result)
+	{
+		verbose!("Swapchain {:?}, recreating it", result);
+		// Also waits for the device to be idle, so the semaphore is no longer in use
+		self.recreate_swapchain(rs);
+		let sem_create_info = vk::SemaphoreCreateInfo {
+			s_type: vk::StructureType::SemaphoreCreateInfo,
+			p_next: ptr::null(),
+			flags: Default::default(),
+		};
+		unsafe {
+			rs.device.destroy_semaphore(self.image_available_sem, None);
+			self.image_available_sem = rs.device.create_semaphore(&sem_create_info, None).unwrap();
+		}
+	}
+
+	/// Sets how the rendered image is scaled to the window.
+	///
+	/// Also resets the filter to the one belonging to the mode.
+	pub fn set_scaling_mode(&mut self, mode: ScalingMode)
+	{
+		self.scaling_mode = mode;
+		self.filter = mode.filter();
+	}
+
+	/// Returns how the rendered image is scaled to the window.
+	pub fn scaling_mode(&self) -> ScalingMode
+	{
+		self.scaling_mode
+	}
+
+	/// Switches the filter used to sample the rendered image between nearest and linear, keeping the
+	/// scaling. Returns the new filter.
+	pub fn toggle_filter(&mut self) -> vk::Filter
+	{
+		self.filter = match self.filter
+		{
+			vk::Filter::Nearest => vk::Filter::Linear,
+			_ => vk::Filter::Nearest,
+		};
+		self.filter
+	}
+
+	/// Returns the filter used to sample the rendered image.
+	pub fn filter(&self) -> vk::Filter
+	{
+		self.filter
+	}
+
+	/// Sets the brightness multiplier applied to the presented image.
+	pub fn set_exposure(&mut self, exposure: f32)
+	{
+		self.exposure = exposure.max(0.0);
+	}
+
+	/// Returns the brightness multiplier applied to the presented image.
+	pub fn exposure(&self) -> f32
+	{
+		self.exposure
+	}
+
+	/// Adds the swapchain setup to the startup report of the RenderState.
+	pub fn add_to_report(&self, report: &mut StartupReport)
+	{
+		report.add("Present mode", format!("{:?}", self.present_mode));
+		report.add(
+			"Swapchain format",
+			format!("{:?} ({:?})", self.surface_format.format, self.surface_format.color_space),
+		);
+		report.add(
+			"Swapchain images",
+			format!("{} ({} requested)", self.present_image_views.len(), self.desired_image_count),
+		);
+		report.add("Swapchain size", format!("{}x{}", self.scissor.extent.width, self.scissor.extent.height));
+	}
+
+	/// Returns the viewport to draw an image of the given size to, based on the scaling mode.
+	fn scaled_viewport(&self, image_size: vk::Extent3D) -> vk::Viewport
+	{
+		if self.scaling_mode != ScalingMode::Integer
+		{
+			return self.viewport;
+		}
+
+		let surface_width = self.scissor.extent.width;
+		let surface_height = self.scissor.extent.height;
+		let scale = std::cmp::min(surface_width / image_size.width, surface_height / image_size.height);
+		let (width, height) = if scale > 0
+		{
+			((image_size.width * scale) as f32, (image_size.height * scale) as f32)
+		}
+		else
+		{
+			// The image is larger than the window, shrink it to fit while keeping its aspect ratio
+			let fit = f32::min(
+				surface_width as f32 / image_size.width as f32,
+				surface_height as f32 / image_size.height as f32,
+			);
+			((image_size.width as f32 * fit).floor(), (image_size.height as f32 * fit).floor())
+		};
+
+		// Center on whole pixels to keep the scaled pixels crisp
+		vk::Viewport {
+			x: ((surface_width as f32 - width) / 2.0).floor(),
+			y: ((surface_height as f32 - height) / 2.0).floor(),
+			width: width,
+			height: height,
+			min_depth: 0.0,
+			max_depth: 1.0,
+		}
+	}
+
 	/// Starts a frame for the current swapchain. The returned commandbuffer should be used for
 	/// rendering.
 	///
-	/// On error (for example when the swapchain needs to be recreated), this function returns
-	/// None, meaning that the current frame should be skipped.
-	fn begin_frame(&mut self, rs: &RenderState, image: &mut Texture) -> Option<vk::CommandBuffer>
+	/// The image is transitioned to image_layout for sampling, and the passed pipeline is bound.
+	///
+	/// If the swapchain is out of date or suboptimal, it is recreated and None is returned, so the
+	/// frame can be retried on the new swapchain.
+	fn begin_frame(
+		&mut self, rs: &RenderState, frame: &FrameGraph, image: &mut Texture, resource: ResourceId,
+		pipeline: vk::Pipeline,
+	) -> Option<vk::CommandBuffer>
 	{
 		let result;
 		unsafe {
@@ -732,11 +1155,12 @@ impl PresentPass
 			{
 				self.current_present_idx = idx as usize;
 			}
-			Err(vkres) => if vkres == vk::Result::ErrorOutOfDateKhr
+			Err(vk::Result::ErrorOutOfDateKhr) | Err(vk::Result::SuboptimalKhr) =>
 			{
-				self.recreate_swapchain(rs);
+				self.handle_outdated_swapchain(rs, result.unwrap_err());
 				return None;
-			},
+			}
+			Err(e) => panic!("Failed to acquire a swapchain image: {:?}", e),
 		}
 
 		// Begin commandbuffer
@@ -750,15 +1174,10 @@ impl PresentPass
 		unsafe {
 			rs.device.begin_command_buffer(cmd_buf, &cmd_buf_begin_info).expect("Begin commandbuffer");
 		}
+		rs.push_debug_label(cmd_buf, "Present pass", [1.0, 0.5, 0.0, 1.0]);
 
 		// Transition the mainpass output to a samplable image
-		rs.transition_texture(
-			image,
-			vk::ACCESS_SHADER_READ_BIT,
-			vk::ImageLayout::ShaderReadOnlyOptimal,
-			vk::PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
-			Some(cmd_buf),
-		);
+		frame.graph.record_transitions(rs, frame.present_pass, cmd_buf, &mut [(resource, image)]);
 
 		// Begin renderpass
 		let render_pass_begin_info = vk::RenderPassBeginInfo {
@@ -770,14 +1189,34 @@ impl PresentPass
 			clear_value_count: 0,
 			p_clear_values: ptr::null(),
 		};
+		let viewport = self.scaled_viewport(image.extent);
+		let letterbox_rects: Vec<vk::ClearRect> = letterbox(self.scissor, &viewport)
+			.into_iter()
+			.map(|rect| vk::ClearRect {
+				rect: rect,
+				base_array_layer: 0,
+				layer_count: 1,
+			})
+			.collect();
 		unsafe {
 			// Start the render pass
 			rs.device.cmd_begin_render_pass(cmd_buf, &render_pass_begin_info, vk::SubpassContents::Inline);
 
+			// Only clear around the image, which covers the rest
+			if !letterbox_rects.is_empty()
+			{
+				let clear_letterbox = vk::ClearAttachment {
+					aspect_mask: vk::IMAGE_ASPECT_COLOR_BIT,
+					color_attachment: 0,
+					clear_value: vk::ClearValue::new_color(vk::ClearColorValue::new_float32(self.letterbox_color)),
+				};
+				rs.device.cmd_clear_attachments(cmd_buf, &[clear_letterbox], &letterbox_rects);
+			}
+
 			// Bind pipeline
-			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::Graphics, self.pipeline);
+			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::Graphics, pipeline);
 
-			rs.device.cmd_set_viewport(cmd_buf, &[self.viewport]);
+			rs.device.cmd_set_viewport(cmd_buf, &[viewport]);
 			rs.device.cmd_set_scissor(cmd_buf, &[self.scissor]);
 		}
 
@@ -786,8 +1225,10 @@ impl PresentPass
 
 	/// Ends the current frame and presents it.
 	///
-	/// begin_frame() must have been called before this function.
-	fn end_frame_and_present(&mut self, rs: &RenderState)
+	/// begin_frame() must have been called before this function. Returns false if the swapchain
+	/// was out of date, then it has been recreated and the frame should be retried. A suboptimal
+	/// swapchain still presents the frame, it is recreated for the next one.
+	fn end_frame_and_present(&mut self, rs: &RenderState) -> bool
 	{
 		debug_assert!(self.current_present_idx < std::usize::MAX);
 
@@ -795,6 +1236,9 @@ impl PresentPass
 		unsafe {
 			// End render pass and command buffer
 			rs.device.cmd_end_render_pass(cmd_buf);
+		}
+		rs.pop_debug_label(cmd_buf);
+		unsafe {
 			rs.device.end_command_buffer(cmd_buf).expect("End commandbuffer");
 		}
 
@@ -835,39 +1279,124 @@ impl PresentPass
 			p_image_indices: &(self.current_present_idx as u32),
 			p_results: ptr::null_mut(),
 		};
+		let result;
 		unsafe {
-			self.swapchain_loader.queue_present_khr(rs.graphics_queue, &present_info).unwrap();
+			result = self.swapchain_loader.queue_present_khr(rs.graphics_queue, &present_info);
 		}
 
 		// Make sure we call begin_frame() before calling this function again
 		self.current_present_idx = std::usize::MAX;
+
+		match result
+		{
+			Ok(_) => true,
+			// The frame was still presented, so only the next one needs the new swapchain
+			Err(vk::Result::SuboptimalKhr) =>
+			{
+				self.handle_outdated_swapchain(rs, vk::Result::SuboptimalKhr);
+				true
+			}
+			Err(vk::Result::ErrorOutOfDateKhr) =>
+			{
+				self.handle_outdated_swapchain(rs, vk::Result::ErrorOutOfDateKhr);
+				false
+			}
+			Err(e) => panic!("Failed to present: {:?}", e),
+		}
 	}
 
 	/// Presents the passed image to the screen.
 	///
-	/// If swapchain is outdated, a new one is created, but no image output is done.
-	pub fn present_image(&mut self, rs: &RenderState, image: &mut Texture)
+	/// If the swapchain is outdated, a new one is created and the image is presented on that.
+	pub fn present_image(&mut self, rs: &RenderState, frame: &FrameGraph, image: &mut Texture)
+	{
+		let view = image.view;
+		let (pipeline, filter) = (self.pipeline, self.filter);
+		self.draw_image(rs, frame, image, view, frame.main_color, pipeline, filter, [0.0, 0.0]);
+	}
+
+	/// Presents a depth image to the screen as grayscale, linearized using the near and far planes
+	/// of the projection.
+	///
+	/// The view must be a depth-only view of the image, which is the given resource of the frame graph.
+	/// Linear filtering falls back to nearest if the device doesn't support it for the format of the image.
+	pub fn present_depth(
+		&mut self, rs: &RenderState, frame: &FrameGraph, image: &mut Texture, resource: ResourceId,
+		view: vk::ImageView, format: vk::Format, near: f32, far: f32,
+	)
+	{
+		let pipeline = self.depth_pipeline;
+		let filter = if rs.supports_linear_filter(format) { self.filter } else { vk::Filter::Nearest };
+		self.draw_image(rs, frame, image, view, resource, pipeline, filter, [near, far]);
+	}
+
+	/// Presents the overdraw counted into the image as a heatmap, from blue for a single fragment to red
+	/// where the count saturates. See MainPass::toggle_overdraw().
+	pub fn present_overdraw(&mut self, rs: &RenderState, frame: &FrameGraph, image: &mut Texture)
 	{
-		let cmd_buf;
-		let res = self.begin_frame(rs, image);
-		match res
+		let view = image.view;
+		let (pipeline, filter) = (self.heatmap_pipeline, self.filter);
+		self.draw_image(rs, frame, image, view, frame.main_color, pipeline, filter, [0.0, 0.0]);
+	}
+
+	/// Presents a G-buffer target of the MainPass. Normals are mapped from [-1, 1] and positions from
+	/// [-range, range] to [0, 1].
+	pub fn present_gbuffer(
+		&mut self, rs: &RenderState, frame: &FrameGraph, image: &mut Texture, target: GBufferTarget, range: f32
+	)
+	{
+		let (resource, scale) = match target
 		{
-			Some(buf) =>
-			{
-				cmd_buf = buf;
-			}
-			None =>
+			GBufferTarget::Normal => (frame.main_normal, 0.5),
+			GBufferTarget::Position => (frame.main_position, 0.5 / range),
+		};
+		let view = image.view;
+		let (pipeline, filter) = (self.gbuffer_pipeline, self.filter);
+		self.draw_image(rs, frame, image, view, resource, pipeline, filter, [scale, 0.5]);
+	}
+
+	/// Draws the image with a full-screen triangle using the given pipeline and filter, and presents it.
+	///
+	/// The image is transitioned as planned for the given resource of the frame graph. The params are
+	/// pushed after the exposure, see PushConstants. If the swapchain turns out to be outdated, it is
+	/// recreated and the image drawn again, giving up on the frame after a few attempts.
+	fn draw_image(
+		&mut self, rs: &RenderState, frame: &FrameGraph, image: &mut Texture, view: vk::ImageView,
+		resource: ResourceId, pipeline: vk::Pipeline, filter: vk::Filter, params: [f32; 2],
+	)
+	{
+		for _ in 0..MAX_PRESENT_ATTEMPTS
+		{
+			if self.try_draw_image(rs, frame, image, view, resource, pipeline, filter, params)
 			{
-				// Swapchain was outdated, but now one was created.
-				// Skip this frame.
 				return;
 			}
 		}
+		warning!("Swapchain keeps getting outdated, skipping the frame");
+	}
+
+	/// Draws and presents the image once, returning false if the swapchain was outdated and the image
+	/// has to be drawn again.
+	fn try_draw_image(
+		&mut self, rs: &RenderState, frame: &FrameGraph, image: &mut Texture, view: vk::ImageView,
+		resource: ResourceId, pipeline: vk::Pipeline, filter: vk::Filter, params: [f32; 2],
+	) -> bool
+	{
+		let cmd_buf = match self.begin_frame(rs, frame, image, resource, pipeline)
+		{
+			Some(cmd_buf) => cmd_buf,
+			None => return false,
+		};
 		// Draw stuff
 		let image_descriptor = vk::DescriptorImageInfo {
 			image_layout: image.current_layout,
-			image_view: image.view,
-			sampler: image.sampler,
+			image_view: view,
+			// Both samplers live as long as the pass, so switching between them needs no cleanup
+			sampler: match filter
+			{
+				vk::Filter::Nearest => self.nearest_sampler,
+				_ => self.linear_sampler,
+			},
 		};
 		let write_desc_sets = [
 			vk::WriteDescriptorSet {
@@ -896,11 +1425,26 @@ impl PresentPass
 				&[],
 			);
 
+			let constants = PushConstants {
+				exposure: self.exposure,
+				near: params[0],
+				far: params[1],
+			};
+			let constant_bytes =
+				slice::from_raw_parts(&constants as *const PushConstants as *const u32, size_of::<PushConstants>());
+			rs.device.cmd_push_constants(
+				cmd_buf,
+				self.pipeline_layout,
+				vk::SHADER_STAGE_FRAGMENT_BIT,
+				0,
+				constant_bytes,
+			);
+
 			// We have a hardcoded quad shader, so just draw three vertices
 			rs.device.cmd_draw(cmd_buf, 3, 1, 0, 0);
 		}
 		// then swapbuffers etc.
-		self.end_frame_and_present(rs);
+		self.end_frame_and_present(rs)
 	}
 }
 
@@ -909,6 +1453,8 @@ impl Drop for PresentPass
 	/// Drops the PresentPass. This destroys the swapchain and surface.
 	fn drop(&mut self)
 	{
+		teardown_scope!("PresentPass");
+
 		// We cannot have the last reference to device at this point
 		debug_assert!(1 < Rc::strong_count(&self.device));
 
@@ -916,9 +1462,80 @@ impl Drop for PresentPass
 		self.cleanup_swapchain();
 
 		unsafe {
+			self.device.destroy_sampler(self.linear_sampler, None);
+			self.device.destroy_sampler(self.nearest_sampler, None);
 			self.device.destroy_semaphore(self.rendering_finished_sem, None);
 			self.device.destroy_semaphore(self.image_available_sem, None);
 			self.surface_loader.destroy_surface_khr(self.surface, None);
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::fs::File;
+	use std::io::Read;
+
+	/// Size in bytes of the PresentParams push constant block in depth_view.frag and gbuffer_view.frag.
+	const PUSH_CONSTANTS_SIZE: usize = 12;
+
+	/// Returns the size in bytes of the PresentParams block in the given shader source, which only
+	/// holds floats.
+	fn present_params_size(path: &str) -> usize
+	{
+		let mut source = String::new();
+		File::open(path).unwrap().read_to_string(&mut source).unwrap();
+		let block = source.split("uniform PresentParams {").nth(1).unwrap();
+		let block = block.split('}').next().unwrap();
+		block.matches("float ").count() * size_of::<f32>()
+	}
+
+	#[test]
+	fn push_constants_match_the_shader_block()
+	{
+		assert_eq!(size_of::<PushConstants>(), PUSH_CONSTANTS_SIZE);
+		let range = push_constant_range();
+		assert_eq!(range.offset, 0);
+		assert_eq!(range.size as usize, PUSH_CONSTANTS_SIZE);
+		assert_eq!(present_params_size("shaders/depth_view.frag"), PUSH_CONSTANTS_SIZE);
+		assert_eq!(present_params_size("shaders/gbuffer_view.frag"), PUSH_CONSTANTS_SIZE);
+		// Only reads the exposure, at the start of the block
+		assert!(present_params_size("shaders/final_pass.frag") <= PUSH_CONSTANTS_SIZE);
+	}
+
+	#[test]
+	fn letterbox_leaves_no_gap_at_a_fractional_viewport()
+	{
+		let surface = vk::Rect2D {
+			offset: vk::Offset2D {
+				x: 0,
+				y: 0,
+			},
+			extent: vk::Extent2D {
+				width: 100,
+				height: 50,
+			},
+		};
+		let viewport = vk::Viewport {
+			x: 10.6,
+			y: 0.0,
+			width: 78.8,
+			height: 50.0,
+			min_depth: 0.0,
+			max_depth: 1.0,
+		};
+		let bands = letterbox(surface, &viewport);
+		// Every pixel of a row is either cleared by a band or has its center inside the viewport
+		for x in 0..100
+		{
+			let center = x as f32 + 0.5;
+			let drawn = center >= viewport.x && center < viewport.x + viewport.width;
+			let cleared = bands
+				.iter()
+				.any(|band| x >= band.offset.x && x < band.offset.x + band.extent.width as i32);
+			assert!(drawn != cleared, "pixel {} drawn {} cleared {}", x, drawn, cleared);
+		}
+	}
+}
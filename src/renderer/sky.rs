@@ -0,0 +1,156 @@
+use ash::Device;
+use ash::version::{DeviceV1_0, V1_0};
+use ash::vk;
+use cgmath::{Matrix4, SquareMatrix, Vector4};
+use std::mem::size_of;
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+
+use renderer::RenderState;
+
+/// What the MainPass draws behind the scene.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Background
+{
+	/// Only the clear color.
+	Clear,
+	/// A vertical gradient from the horizon color to the zenith color, see Sky.
+	Gradient,
+}
+
+impl Background
+{
+	/// Returns the background after this one, for cycling through them with a key.
+	pub fn next(self) -> Background
+	{
+		match self
+		{
+			Background::Clear => Background::Gradient,
+			Background::Gradient => Background::Clear,
+		}
+	}
+}
+
+/// The push constants of the sky, matching the SkyParams block in sky.frag.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SkyPushConstants
+{
+	/// Turns normalized device coordinates into world space directions from the camera.
+	inverse_view_projection: Matrix4<f32>,
+	horizon_color: [f32; 4],
+	zenith_color: [f32; 4],
+}
+
+/// A full-screen triangle drawn before the scene, shaded with a gradient by the height of the view ray
+/// through each pixel, so it tilts with the camera. Needs no textures, unlike a skybox.
+pub struct Sky
+{
+	pipeline_layout: vk::PipelineLayout,
+	pipeline: vk::Pipeline,
+	constants: SkyPushConstants,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device<V1_0>>,
+}
+
+impl Sky
+{
+	/// Creates the layout of the sky pipeline, which only has the push constants.
+	pub fn create_pipeline_layout(rs: &RenderState) -> vk::PipelineLayout
+	{
+		let push_constant_range = vk::PushConstantRange {
+			stage_flags: vk::SHADER_STAGE_FRAGMENT_BIT,
+			offset: 0,
+			size: size_of::<SkyPushConstants>() as u32,
+		};
+		let layout_create_info = vk::PipelineLayoutCreateInfo {
+			s_type: vk::StructureType::PipelineLayoutCreateInfo,
+			p_next: ptr::null(),
+			flags: Default::default(),
+			set_layout_count: 0,
+			p_set_layouts: ptr::null(),
+			push_constant_range_count: 1,
+			p_push_constant_ranges: &push_constant_range,
+		};
+
+		let pipeline_layout;
+		unsafe {
+			pipeline_layout = rs.device.create_pipeline_layout(&layout_create_info, None).unwrap();
+		}
+		pipeline_layout
+	}
+
+	/// Takes over the pipeline and its layout from create_pipeline_layout(). The colors are linear rgb.
+	pub fn new(
+		rs: &RenderState, pipeline_layout: vk::PipelineLayout, pipeline: vk::Pipeline, horizon_color: [f32; 3],
+		zenith_color: [f32; 3],
+	) -> Sky
+	{
+		let mut sky = Sky {
+			pipeline_layout: pipeline_layout,
+			pipeline: pipeline,
+			constants: SkyPushConstants {
+				inverse_view_projection: Matrix4::identity(),
+				horizon_color: [0.0; 4],
+				zenith_color: [0.0; 4],
+			},
+			device: Rc::clone(&rs.device),
+		};
+		sky.set_colors(horizon_color, zenith_color);
+		sky
+	}
+
+	pub fn set_colors(&mut self, horizon_color: [f32; 3], zenith_color: [f32; 3])
+	{
+		self.constants.horizon_color = [horizon_color[0], horizon_color[1], horizon_color[2], 1.0];
+		self.constants.zenith_color = [zenith_color[0], zenith_color[1], zenith_color[2], 1.0];
+	}
+
+	/// Points the view rays along the camera. Only the rotation of the view matrix is used, as the sky
+	/// is infinitely far away.
+	///
+	/// With an orthographic projection all rays are parallel, the gradient then only changes slightly
+	/// across the screen.
+	pub fn set_view_projection(&mut self, view_matrix: &Matrix4<f32>, projection_matrix: &Matrix4<f32>)
+	{
+		let mut rotation = *view_matrix;
+		rotation.w = Vector4::new(0.0, 0.0, 0.0, 1.0);
+		if let Some(inverse) = (projection_matrix * rotation).invert()
+		{
+			self.constants.inverse_view_projection = inverse;
+		}
+	}
+
+	/// Records drawing the sky over the whole viewport, which must be set.
+	pub fn draw(&self, rs: &RenderState, cmd_buf: vk::CommandBuffer)
+	{
+		unsafe {
+			rs.device.cmd_bind_pipeline(cmd_buf, vk::PipelineBindPoint::Graphics, self.pipeline);
+			let constant_bytes = slice::from_raw_parts(
+				&self.constants as *const SkyPushConstants as *const u32,
+				size_of::<SkyPushConstants>(),
+			);
+			rs.device.cmd_push_constants(
+				cmd_buf,
+				self.pipeline_layout,
+				vk::SHADER_STAGE_FRAGMENT_BIT,
+				0,
+				constant_bytes,
+			);
+			rs.device.cmd_draw(cmd_buf, 3, 1, 0, 0);
+		}
+	}
+}
+
+impl Drop for Sky
+{
+	fn drop(&mut self)
+	{
+		unsafe {
+			self.device.destroy_pipeline(self.pipeline, None);
+			self.device.destroy_pipeline_layout(self.pipeline_layout, None);
+		}
+	}
+}
@@ -0,0 +1,152 @@
+use ash::Device;
+use ash::util::Align;
+use ash::version::{DeviceV1_0, V1_0};
+use ash::vk;
+use std::mem::{align_of, size_of};
+use std::ptr;
+use std::u64;
+
+use renderer::RenderState;
+
+/// A host visible buffer shared by all uploads, so loading doesn't allocate a staging buffer for each
+/// texture and mesh.
+///
+/// The buffer grows to the largest upload. It is only overwritten once the fence of the last
+/// submission that may read it is signaled.
+pub struct StagingBuffer
+{
+	// Null until the first upload
+	buffer: vk::Buffer,
+	memory: vk::DeviceMemory,
+	size: vk::DeviceSize,
+	// Smallest size allocated, to avoid growing in small steps
+	min_size: vk::DeviceSize,
+	// Created signaled, as nothing reads the buffer yet
+	fence: vk::Fence,
+}
+
+impl StagingBuffer
+{
+	/// Creates the fence, the buffer is allocated by the first write().
+	pub fn new(device: &Device<V1_0>, min_size: vk::DeviceSize) -> StagingBuffer
+	{
+		let fence_create_info = vk::FenceCreateInfo {
+			s_type: vk::StructureType::FenceCreateInfo,
+			p_next: ptr::null(),
+			flags: vk::FENCE_CREATE_SIGNALED_BIT,
+		};
+		let fence;
+		unsafe {
+			fence = device.create_fence(&fence_create_info, None).expect("Failed to create staging fence");
+		}
+
+		StagingBuffer {
+			buffer: vk::Buffer::null(),
+			memory: vk::DeviceMemory::null(),
+			size: 0,
+			min_size: min_size,
+			fence: fence,
+		}
+	}
+
+	/// Copies the data to the start of the buffer and returns the buffer, for copying it on the GPU.
+	///
+	/// Waits for the last submission using the buffer first, and reallocates it if it is too small.
+	pub fn write<T: Copy>(&mut self, rs: &RenderState, data: &[T]) -> vk::Buffer
+	{
+		let size = (size_of::<T>() * data.len()) as vk::DeviceSize;
+		unsafe {
+			rs.device.wait_for_fences(&[self.fence], true, u64::MAX).expect("Wait for staging fence failed");
+		}
+
+		if let Some(new_size) = grown_size(self.size, self.min_size, size)
+		{
+			self.destroy_buffer(&rs.device);
+			let (buffer, memory) = rs.create_buffer(
+				vk::BUFFER_USAGE_TRANSFER_SRC_BIT,
+				vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT,
+				new_size,
+			);
+			self.buffer = buffer;
+			self.memory = memory;
+			self.size = new_size;
+		}
+
+		unsafe {
+			let mem_ptr = rs.device
+				.map_memory(self.memory, 0, size, vk::MemoryMapFlags::empty())
+				.expect("Failed to map staging memory");
+			let mut mem_align = Align::new(mem_ptr, align_of::<T>() as u64, size);
+			mem_align.copy_from_slice(data);
+			rs.device.unmap_memory(self.memory);
+		}
+
+		self.buffer
+	}
+
+	/// Resets the fence and returns it, for signaling by a submission that may read the buffer.
+	pub fn submit_fence(&self, device: &Device<V1_0>) -> vk::Fence
+	{
+		unsafe {
+			device.reset_fences(&[self.fence]).expect("Failed to reset staging fence");
+		}
+		self.fence
+	}
+
+	/// Destroys the buffer and the fence. The device must be idle.
+	pub fn destroy(&mut self, device: &Device<V1_0>)
+	{
+		self.destroy_buffer(device);
+		unsafe {
+			device.destroy_fence(self.fence, None);
+		}
+	}
+
+	fn destroy_buffer(&mut self, device: &Device<V1_0>)
+	{
+		if self.buffer != vk::Buffer::null()
+		{
+			unsafe {
+				device.destroy_buffer(self.buffer, None);
+				device.free_memory(self.memory, None);
+			}
+			self.buffer = vk::Buffer::null();
+			self.memory = vk::DeviceMemory::null();
+			self.size = 0;
+		}
+	}
+}
+
+/// Returns the size to reallocate the buffer with for an upload of the given size, or None if the current
+/// allocation can be reused.
+fn grown_size(current: vk::DeviceSize, min_size: vk::DeviceSize, upload: vk::DeviceSize) -> Option<vk::DeviceSize>
+{
+	if upload > current
+	{
+		Some(upload.max(min_size))
+	}
+	else
+	{
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn sequential_uploads_reuse_the_allocation()
+	{
+		let min_size = 1 << 20;
+		// The first upload allocates at least the minimum size
+		let size = grown_size(0, min_size, 4096).unwrap();
+		assert_eq!(size, min_size);
+		// The following ones fit and reuse it
+		assert_eq!(grown_size(size, min_size, 4096), None);
+		assert_eq!(grown_size(size, min_size, min_size), None);
+		// Only a larger upload reallocates
+		assert_eq!(grown_size(size, min_size, min_size + 1), Some(min_size + 1));
+	}
+}
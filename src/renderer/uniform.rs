@@ -0,0 +1,140 @@
+use ash::Device;
+use ash::util::Align;
+use ash::version::{DeviceV1_0, V1_0};
+use ash::vk;
+use std::cell::Cell;
+use std::mem::size_of;
+use std::rc::Rc;
+
+use renderer::RenderState;
+
+/// Where uniform buffers live, which decides how they are updated.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum UniformMemory
+{
+	/// Written directly by the CPU. Cheap to update, and as fast as it gets on integrated GPUs.
+	HostCoherent,
+	/// Read from device local memory, updated by copying from a host visible staging buffer at the
+	/// start of the frame. Meant for faster shader reads on discrete GPUs.
+	DeviceLocal,
+}
+
+/// A uniform buffer in the memory chosen by RenderState::uniform_memory.
+///
+/// Writes go to host visible memory. With device local memory that is a staging buffer, and the
+/// written range is copied over by the commands recorded with record_upload().
+pub struct UniformBuffer
+{
+	pub buffer: vk::Buffer,
+	memory: vk::DeviceMemory,
+	// Staging buffer written by the CPU, only for device local memory
+	staging: Option<(vk::Buffer, vk::DeviceMemory)>,
+	// Start and end of the range written since the last upload
+	dirty: Cell<Option<(vk::DeviceSize, vk::DeviceSize)>>,
+
+	// Keep a pointer to the device for cleanup
+	device: Rc<Device<V1_0>>,
+}
+
+impl UniformBuffer
+{
+	pub fn new(rs: &RenderState, size: vk::DeviceSize) -> UniformBuffer
+	{
+		let host_visible = vk::MEMORY_PROPERTY_HOST_VISIBLE_BIT | vk::MEMORY_PROPERTY_HOST_COHERENT_BIT;
+		let (buffer, memory, staging) = match rs.uniform_memory
+		{
+			UniformMemory::HostCoherent =>
+			{
+				let (buffer, memory) = rs.create_buffer(vk::BUFFER_USAGE_UNIFORM_BUFFER_BIT, host_visible, size);
+				(buffer, memory, None)
+			}
+			UniformMemory::DeviceLocal =>
+			{
+				let (buffer, memory) = rs.create_buffer(
+					vk::BUFFER_USAGE_UNIFORM_BUFFER_BIT | vk::BUFFER_USAGE_TRANSFER_DST_BIT,
+					vk::MEMORY_PROPERTY_DEVICE_LOCAL_BIT,
+					size,
+				);
+				let staging = rs.create_buffer(vk::BUFFER_USAGE_TRANSFER_SRC_BIT, host_visible, size);
+				(buffer, memory, Some(staging))
+			}
+		};
+
+		UniformBuffer {
+			buffer: buffer,
+			memory: memory,
+			staging: staging,
+			dirty: Cell::new(None),
+			device: Rc::clone(&rs.device),
+		}
+	}
+
+	/// Writes the data at the given offset, padding each element to a multiple of the alignment.
+	///
+	/// With device local memory, the data is only visible to shaders after the next upload.
+	pub fn write<T: Copy>(&self, offset: vk::DeviceSize, alignment: vk::DeviceSize, data: &[T])
+	{
+		let element_size = (size_of::<T>() as u64 + alignment - 1) / alignment * alignment;
+		let size = element_size * data.len() as u64;
+		let memory = self.staging.map(|(_, memory)| memory).unwrap_or(self.memory);
+		unsafe {
+			let mem_ptr = self.device
+				.map_memory(memory, offset, size, vk::MemoryMapFlags::empty())
+				.expect("Failed to map uniform memory");
+			let mut mem_align = Align::new(mem_ptr, alignment, size);
+			mem_align.copy_from_slice(data);
+			self.device.unmap_memory(memory);
+		}
+
+		if self.staging.is_some()
+		{
+			let dirty = match self.dirty.get()
+			{
+				Some((start, end)) => (start.min(offset), end.max(offset + size)),
+				None => (offset, offset + size),
+			};
+			self.dirty.set(Some(dirty));
+		}
+	}
+
+	/// Returns true if data was written to the staging buffer since the last upload.
+	pub fn has_pending_upload(&self) -> bool
+	{
+		self.staging.is_some() && self.dirty.get().is_some()
+	}
+
+	/// Records copying the range written since the last upload from the staging buffer, if any.
+	///
+	/// The caller must synchronize the copy with the shader reads, before and after.
+	pub fn record_upload(&self, cmd_buf: vk::CommandBuffer)
+	{
+		if let (Some((staging_buffer, _)), Some((start, end))) = (self.staging, self.dirty.get())
+		{
+			let region = vk::BufferCopy {
+				src_offset: start,
+				dst_offset: start,
+				size: end - start,
+			};
+			unsafe {
+				self.device.cmd_copy_buffer(cmd_buf, staging_buffer, self.buffer, &[region]);
+			}
+			self.dirty.set(None);
+		}
+	}
+}
+
+impl Drop for UniformBuffer
+{
+	fn drop(&mut self)
+	{
+		unsafe {
+			self.device.destroy_buffer(self.buffer, None);
+			self.device.free_memory(self.memory, None);
+			if let Some((staging_buffer, staging_memory)) = self.staging
+			{
+				self.device.destroy_buffer(staging_buffer, None);
+				self.device.free_memory(staging_memory, None);
+			}
+		}
+	}
+}
@@ -0,0 +1,131 @@
+use cgmath::{Deg, Point3, Quaternion, Rotation3};
+
+/// Places objects at random inside a box, for varied but reproducible test scenes.
+///
+/// The layout only depends on the parameters, so the same seed always gives the same scene.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Scatter
+{
+	pub seed: u64,
+	pub count: usize,
+	/// Minimum and maximum corners of the box the positions are picked from.
+	pub min: [f32; 3],
+	pub max: [f32; 3],
+	/// Largest rotation in degrees around the X, Y and Z axes, in either direction.
+	pub max_rotation: [f32; 3],
+}
+
+impl Default for Scatter
+{
+	fn default() -> Scatter
+	{
+		Scatter {
+			seed: 1,
+			count: 0,
+			min: [-10.0, -5.0, -30.0],
+			max: [10.0, 5.0, -5.0],
+			max_rotation: [180.0, 180.0, 180.0],
+		}
+	}
+}
+
+impl Scatter
+{
+	/// Returns the position and rotation of each object.
+	pub fn transforms(&self) -> Vec<(Point3<f32>, Quaternion<f32>)>
+	{
+		let mut rng = SplitMix64::new(self.seed);
+		(0..self.count)
+			.map(|_| {
+				let mut position = Point3::new(0.0, 0.0, 0.0);
+				for axis in 0..3
+				{
+					position[axis] = self.min[axis] + rng.next_f32() * (self.max[axis] - self.min[axis]);
+				}
+				let mut angles = [0.0; 3];
+				for axis in 0..3
+				{
+					angles[axis] = (rng.next_f32() * 2.0 - 1.0) * self.max_rotation[axis];
+				}
+				let rotation = Quaternion::from_angle_z(Deg(angles[2])) * Quaternion::from_angle_y(Deg(angles[1]))
+					* Quaternion::from_angle_x(Deg(angles[0]));
+				(position, rotation)
+			})
+			.collect()
+	}
+}
+
+/// SplitMix64, a small and fast generator that gives the same sequence on every platform.
+struct SplitMix64
+{
+	state: u64,
+}
+
+impl SplitMix64
+{
+	fn new(seed: u64) -> SplitMix64
+	{
+		SplitMix64 {
+			state: seed,
+		}
+	}
+
+	fn next_u64(&mut self) -> u64
+	{
+		self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = self.state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^ (z >> 31)
+	}
+
+	/// Returns a number in [0, 1).
+	fn next_f32(&mut self) -> f32
+	{
+		// The upper 24 bits fit exactly in the mantissa
+		(self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn same_seed_gives_same_layout()
+	{
+		let scatter = Scatter {
+			seed: 42,
+			count: 16,
+			..Scatter::default()
+		};
+		let first = scatter.transforms();
+		let second = scatter.clone().transforms();
+		assert_eq!(first.len(), 16);
+		assert_eq!(first, second);
+
+		let other = Scatter {
+			seed: 43,
+			..scatter.clone()
+		};
+		assert_ne!(other.transforms(), first);
+	}
+
+	#[test]
+	fn positions_stay_inside_the_box()
+	{
+		let scatter = Scatter {
+			count: 64,
+			..Scatter::default()
+		};
+		for (position, _) in scatter.transforms()
+		{
+			for axis in 0..3
+			{
+				assert!(position[axis] >= scatter.min[axis] && position[axis] <= scatter.max[axis]);
+			}
+		}
+	}
+}
@@ -1,26 +1,282 @@
+use ash::version::DeviceV1_0;
 use ash::vk;
-use cgmath::{Deg, Matrix4, Point3, Quaternion, Vector3};
+use cgmath::{Deg, Matrix4, Point3, Quaternion, Vector3, Vector4};
 use cgmath::prelude::*;
-use object::{DrawObject, Drawable, Position, Rotation};
-use renderer::{MainPass, RenderState};
+use config::Config;
+use frustum::Frustum;
+use nurbs::NURBSpline;
+use object::{Animation, Camera, DrawObject, Drawable, Keyframe, Material, MeshSource, Position, Rotation,
+             SplineEndMode};
+use renderer::{AlphaMode, Corner, DepthBias, DrawCommand, LightUniforms, MainPass, ObjectUniforms, PipelineKind,
+               RenderState, MAX_OBJECTS};
+use scatter::Scatter;
+use serde_json;
+use std::cmp::Ordering;
 use std::f32;
+use std::fs::File;
+
+/// Number of line segments in each circle of the bounding sphere outline.
+const BOUNDING_SPHERE_SEGMENTS: u16 = 32;
+/// Height above the camera the minimap looks down from, it shows everything up to as far below.
+const MINIMAP_HEIGHT: f32 = 500.0;
+/// Debug objects every scene creates besides its own: the axis gizmo, the bounding sphere and the
+/// minimap marker and frustum.
+pub const HELPER_OBJECTS: usize = 4;
+/// Distance in front of the camera the material preview sphere is placed at.
+const MATERIAL_PREVIEW_DISTANCE: f32 = 3.0;
+/// Degrees per second the material preview sphere turns.
+const MATERIAL_PREVIEW_TURN: f32 = 15.0;
+/// Degrees per second the first object spins around the Y axis.
+const SPIN_SPEED: f32 = -30.0;
+
+/// The work recorded by a Scene::draw(), for spotting expensive frames.
+///
+/// Culled and hidden objects don't add to the draw counts, but the bounding boxes drawn for objects
+/// hidden by occlusion culling do. Draws are not instanced, each draw call draws a single instance.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DrawStats
+{
+	pub draw_calls: usize,
+	pub pipeline_binds: usize,
+	/// Calls binding descriptor sets, each may bind several sets.
+	pub descriptor_set_binds: usize,
+	pub triangles: usize,
+	/// Objects skipped by frustum culling, solo mode or hiding.
+	pub culled: usize,
+	/// Objects only drawn as their bounding box, because they were occluded in the previous frame.
+	pub occlusion_proxies: usize,
+}
+
+/// The lighting in use, which starts out as configured and is replaced by the lighting of loaded scenes.
+///
+/// Kept apart from the Config, so scene lighting isn't written to the options when the Config is saved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lighting
+{
+	/// Direction towards the directional light.
+	pub direction: [f32; 3],
+	pub color: [f32; 3],
+	pub intensity: f32,
+	pub ambient: [f32; 3],
+}
+
+impl Lighting
+{
+	pub fn from_config(cfg: &Config) -> Lighting
+	{
+		Lighting {
+			direction: cfg.light_direction,
+			color: cfg.light_color,
+			intensity: cfg.light_intensity,
+			ambient: cfg.ambient_color,
+		}
+	}
+
+	/// Generates the lighting uniforms for MainPass::set_lighting().
+	pub fn uniforms(&self) -> LightUniforms
+	{
+		let direction = self.direction;
+		let color = self.color;
+		let ambient = self.ambient;
+		LightUniforms {
+			direction: [direction[0], direction[1], direction[2], 0.0],
+			color: [color[0], color[1], color[2], self.intensity],
+			ambient: [ambient[0], ambient[1], ambient[2], 0.0],
+		}
+	}
+}
+
+/// An object as written by Scene::save_to_json().
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SavedObject
+{
+	mesh: MeshSource,
+	position: [f32; 3],
+	/// Quaternion as [s, x, y, z].
+	rotation: [f32; 4],
+	material: Material,
+	layer: i32,
+	overlay: bool,
+	/// Missing in scenes saved before decals existed.
+	depth_bias: Option<DepthBias>,
+	/// Missing in scenes saved before shadow casting could be turned off.
+	casts_shadow: Option<bool>,
+	/// Missing in scenes saved before objects could be hidden.
+	visible: Option<bool>,
+}
+
+/// A scene as written by Scene::save_to_json(), along with the camera pose and lighting.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SavedScene
+{
+	objects: Vec<SavedObject>,
+	camera_position: [f32; 3],
+	camera_front: [f32; 3],
+	light_direction: [f32; 3],
+	light_color: [f32; 3],
+	light_intensity: f32,
+	ambient_color: [f32; 3],
+}
+
+impl SavedScene
+{
+	fn write(&self, path: &str) -> Result<(), String>
+	{
+		let file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+		serde_json::to_writer_pretty(file, self).map_err(|e| format!("Failed to write {}: {}", path, e))
+	}
+
+	fn read(path: &str) -> Result<SavedScene, String>
+	{
+		let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+		serde_json::from_reader(file).map_err(|e| format!("Failed to parse {}: {}", path, e))
+	}
+
+	/// Moves the camera to the saved pose and replaces the lighting with the saved one.
+	fn restore_camera_and_lighting(&self, camera: &mut Camera, lighting: &mut Lighting)
+	{
+		camera.stop_following_spline();
+		camera.set_position(Point3::from(self.camera_position));
+		camera.face_direction(Vector3::from(self.camera_front));
+		*lighting = Lighting {
+			direction: self.light_direction,
+			color: self.light_color,
+			intensity: self.light_intensity,
+			ambient: self.ambient_color,
+		};
+	}
+}
+
+/// What decides whether and when an object is drawn, see draw_order().
+#[derive(Clone, Copy)]
+struct DrawKey
+{
+	/// False for objects that are invisible, filtered out or culled.
+	shown: bool,
+	layer: i32,
+	kind: PipelineKind,
+	/// Distance from the camera.
+	distance: f32,
+}
+
+/// Returns the indices of the shown objects in the order they are drawn.
+///
+/// Sorted by layer first. Within a layer, overlays go last so they end up on top of the depth tested
+/// geometry, and the rest is grouped by pipeline to save rebinds and drawn front to back. Blended objects
+/// go after the opaque ones and back to front, so they blend with what is behind them.
+fn draw_order(keys: &[DrawKey]) -> Vec<usize>
+{
+	let mut order: Vec<usize> = (0..keys.len()).filter(|&i| keys[i].shown).collect();
+	let sort_key = |i: usize| (keys[i].layer, keys[i].kind == PipelineKind::Overlay, keys[i].kind.is_blended());
+	order.sort_by(|&a, &b| {
+		sort_key(a).cmp(&sort_key(b)).then_with(|| {
+			let kind = keys[a].kind;
+			if kind.is_blended()
+			{
+				keys[b].distance.partial_cmp(&keys[a].distance).unwrap_or(Ordering::Equal)
+			}
+			else
+			{
+				(kind as usize).cmp(&(keys[b].kind as usize)).then_with(|| {
+					keys[a].distance.partial_cmp(&keys[b].distance).unwrap_or(Ordering::Equal)
+				})
+			}
+		})
+	});
+	order
+}
 
 pub struct Scene
 {
 	objects: Vec<DrawObject>,
+	/// Animations and the index of the object they move.
+	animations: Vec<(usize, Animation)>,
+	/// Wireframe of a frozen culling frustum, drawn after the objects.
+	debug_frustum: Option<DrawObject>,
+	/// Shows the camera orientation in a corner of the screen.
+	axis_gizmo: DrawObject,
+	show_axis_gizmo: bool,
+	axis_gizmo_size: u32,
+	/// Circle marking the objects on the minimap, scaled to their bounding sphere.
+	minimap_marker: DrawObject,
+	/// Wireframe of the clip volume, turned into the camera frustum on the minimap.
+	minimap_frustum: DrawObject,
+	show_minimap: bool,
+	minimap_size: u32,
+	minimap_range: f32,
+	/// Unit sphere outline, scaled to the bounding sphere of each drawn object when shown.
+	bounding_sphere: DrawObject,
+	show_bounds: bool,
+	/// False while the objects are drawn with flat normal maps.
+	normal_maps: bool,
+	/// Sphere drawn instead of the scene objects while previewing a material.
+	material_preview: Option<DrawObject>,
+	/// Index of the selected object, for the debug filters below.
+	selected: Option<usize>,
+	/// Only draw the selected object.
+	solo: bool,
+	/// Indices of the objects hidden with toggle_hide_selected().
+	hidden: Vec<usize>,
 }
 
 impl Scene
 {
-	pub fn new(rs: &RenderState, mp: &MainPass) -> Scene
+	/// Creates a scene without any objects.
+	fn empty(rs: &RenderState, mp: &MainPass, cfg: &Config) -> Result<Scene, String>
 	{
-		let mut scene = Scene {
+		Ok(Scene {
 			objects: Vec::new(),
-		};
+			animations: Vec::new(),
+			debug_frustum: None,
+			axis_gizmo: DrawObject::new_axis_gizmo(rs, mp)?,
+			show_axis_gizmo: cfg.show_axis_gizmo,
+			axis_gizmo_size: cfg.axis_gizmo_size,
+			minimap_marker: DrawObject::new_sphere_outline(rs, mp, BOUNDING_SPHERE_SEGMENTS, [1.0, 1.0, 1.0, 1.0])?,
+			minimap_frustum: Scene::frustum_lines(rs, mp, &Frustum::from_matrix(&Matrix4::identity()))?,
+			show_minimap: cfg.show_minimap,
+			minimap_size: cfg.minimap_size,
+			minimap_range: cfg.minimap_range,
+			bounding_sphere: DrawObject::new_sphere_outline(rs, mp, BOUNDING_SPHERE_SEGMENTS, [1.0, 1.0, 0.0, 1.0])?,
+			show_bounds: false,
+			normal_maps: true,
+			material_preview: None,
+			selected: None,
+			solo: false,
+			hidden: Vec::new(),
+		})
+	}
+
+	pub fn new(rs: &RenderState, mp: &MainPass, cfg: &Config) -> Result<Scene, String>
+	{
+		let mut scene = Scene::empty(rs, mp, cfg)?;
 
-		let cuboid = DrawObject::new_cuboid(rs, mp, Point3::new(1.0, 0.0, -4.0), 2.0, 2.0, 2.0);
+		let cuboid = DrawObject::new_cuboid(rs, mp, Point3::new(1.0, 0.0, -4.0), 2.0, 2.0, 2.0)?;
 		scene.objects.push(cuboid);
 
+		// A shiny and a matte cube to compare the materials
+		let mut shiny = DrawObject::new_cuboid(rs, mp, Point3::new(4.0, 0.0, -6.0), 1.0, 1.0, 1.0)?;
+		shiny.set_material(rs, Material::shiny());
+		let shiny_index = scene.objects.len();
+		scene.objects.push(shiny);
+		let mut matte = DrawObject::new_cuboid(rs, mp, Point3::new(6.0, 0.0, -6.0), 1.0, 1.0, 1.0)?;
+		matte.set_material(rs, Material::matte());
+		scene.objects.push(matte);
+
+		// Let the shiny cube bob up and down while spinning
+		let keyframes = (0..5)
+			.map(|i| Keyframe {
+				time: i as f32,
+				position: Point3::new(4.0, if i % 2 == 0 { 0.0 } else { 1.0 }, -6.0),
+				rotation: Quaternion::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), Deg(90.0 * i as f32)),
+			})
+			.collect();
+		scene
+			.set_object_animation(shiny_index, keyframes, SplineEndMode::Loop)
+			.expect("Failed to animate the shiny cube");
+
+		let triangle = DrawObject::new_colored_triangle(rs, mp, Point3::new(-2.0, 0.0, -4.0), 0.5)?;
+		scene.objects.push(triangle);
+
 		let points = vec![
 			Point3::new(1.0, 0.0, 0.0),
 			Point3::new(-1.0, 0.0, 0.0),
@@ -43,18 +299,342 @@ impl Scene
 			let x:f32 = points[i].x;
 			let y:f32 = points[i].y;
 			let z:f32 = points[i].z;
-			let mut wall = DrawObject::new_quad(rs, mp, Point3::new(0., 0., 0.), 20.0, 20.0);
+			let mut wall = DrawObject::new_plane(rs, mp, Point3::new(0., 0., 0.), 20.0, 20.0, 8, cfg.strip_primitives)?;
 			wall.set_rotation(Quaternion::from_axis_angle( directions[i], Deg(90.0)));
 			if i==5 { wall.set_rotation(Quaternion::new( 0.0, 0.0, 1.0, 0.0 )); }
 			wall.set_position( Point3::new(20.*x, 20.*y, 20.*z) );	
 			scene.objects.push(wall);
 		}
 
-		return scene
+		// The same transparent logo blended with straight and premultiplied alpha, to compare the edges
+		let logo = "assets/original/textures/project_peril_logo.png";
+		for &(x, alpha_mode) in [(-2.5, AlphaMode::Straight), (2.5, AlphaMode::Premultiplied)].iter()
+		{
+			let position = Point3::new(x, 2.0, -7.0);
+			scene.objects.push(DrawObject::new_transparent_quad(rs, mp, position, 2.0, 0.8, logo, alpha_mode)?);
+		}
+
+		// The logo as a decal lying right on the far wall, which would z-fight without the depth bias
+		let position = Point3::new(0.0, 2.0, -20.0);
+		let mut decal = DrawObject::new_transparent_quad(rs, mp, position, 4.0, 1.6, logo, AlphaMode::Premultiplied)?;
+		decal.set_depth_bias(cfg.decal_depth_bias);
+		scene.objects.push(decal);
+
+		Ok(scene)
+	}
+
+	/// Writes the objects, the camera pose and the lighting to a JSON file, which load_from_json() turns
+	/// back into the same scene.
+	///
+	/// Animations are not saved, and objects built from custom meshes are skipped. Returns the number
+	/// of saved objects.
+	pub fn save_to_json(&self, path: &str, camera: &Camera, lighting: &Lighting) -> Result<usize, String>
+	{
+		let mut objects = Vec::with_capacity(self.objects.len());
+		for (i, object) in self.objects.iter().enumerate()
+		{
+			if *object.source() == MeshSource::Custom
+			{
+				warning!("Not saving object {}, its mesh can't be created again", i);
+				continue;
+			}
+			let rotation = object.get_rotation();
+			objects.push(SavedObject {
+				mesh: object.source().clone(),
+				position: object.get_position().into(),
+				rotation: [rotation.s, rotation.v.x, rotation.v.y, rotation.v.z],
+				material: object.material().clone(),
+				layer: object.layer(),
+				overlay: object.pipeline_kind() == PipelineKind::Overlay,
+				depth_bias: Some(object.depth_bias()),
+				casts_shadow: Some(object.casts_shadow()),
+				visible: Some(object.is_visible()),
+			});
+		}
+
+		let saved = SavedScene {
+			objects: objects,
+			camera_position: camera.get_position().into(),
+			camera_front: camera.get_cam_front().into(),
+			light_direction: lighting.direction,
+			light_color: lighting.color,
+			light_intensity: lighting.intensity,
+			ambient_color: lighting.ambient,
+		};
+		saved.write(path)?;
+		Ok(saved.objects.len())
+	}
+
+	/// Creates the scene saved by save_to_json(), and moves the camera to the saved pose.
+	///
+	/// Fails if the scene has more than max_objects objects, which is lowered while other scenes are
+	/// resident. The lighting is replaced with the saved one, the caller has to upload it.
+	pub fn load_from_json(
+		rs: &RenderState, mp: &MainPass, cfg: &Config, camera: &mut Camera, lighting: &mut Lighting, path: &str,
+		max_objects: usize,
+	) -> Result<Scene, String>
+	{
+		let saved = SavedScene::read(path)?;
+		let max_objects = max_objects.min(MAX_OBJECTS);
+		if saved.objects.len() > max_objects
+		{
+			return Err(format!("{} has {} objects, at most {} are supported", path, saved.objects.len(), max_objects));
+		}
+
+		// Decode all the textures in the background while the meshes are created
+		for saved_object in saved.objects.iter()
+		{
+			for path in saved_object.mesh.texture_paths()
+			{
+				rs.request_image(path);
+			}
+			if let Some(ref path) = saved_object.material.specular_map
+			{
+				rs.request_image(path);
+			}
+		}
+
+		let mut scene = Scene::empty(rs, mp, cfg)?;
+		for saved_object in saved.objects.iter()
+		{
+			let position = Point3::from(saved_object.position);
+			let mut object = DrawObject::from_source(rs, mp, position, &saved_object.mesh)?;
+			let r = saved_object.rotation;
+			object.set_rotation(Quaternion::new(r[0], r[1], r[2], r[3]));
+			object.store_previous_transform();
+			object.set_material(rs, saved_object.material.clone());
+			object.set_layer(saved_object.layer);
+			object.set_depth_bias(saved_object.depth_bias.unwrap_or_default());
+			object.set_casts_shadow(saved_object.casts_shadow.unwrap_or(true));
+			object.set_visible(saved_object.visible.unwrap_or(true));
+			if saved_object.overlay
+			{
+				object.set_overlay();
+			}
+			scene.objects.push(object);
+		}
+
+		saved.restore_camera_and_lighting(camera, lighting);
+		Ok(scene)
+	}
+
+	/// Adds a cube with the given side length to the scene, if room is not zero.
+	///
+	/// Room is the number of objects that still fit, see SceneManager::free_object_slots().
+	pub fn add_cuboid(
+		&mut self, rs: &RenderState, mp: &MainPass, position: Point3<f32>, size: f32, room: usize
+	) -> Result<(), String>
+	{
+		if room == 0
+		{
+			return Err(String::from("No room for more objects, unload a scene first"));
+		}
+
+		let cuboid = DrawObject::new_cuboid(rs, mp, position, size, size, size)?;
+		self.objects.push(cuboid);
+		Ok(())
 	}
 
-	pub fn update(&mut self)
+	/// Adds a grid of objects for stress testing, in front of the default camera position.
+	///
+	/// The mesh is one of "cube", "quad", "triangle" or "hexagon". Returns the number of objects added, which
+	/// is limited by room, see add_cuboid().
+	pub fn add_stress_grid(
+		&mut self, rs: &RenderState, mp: &MainPass, count: usize, mesh: &str, room: usize
+	) -> Result<usize, String>
 	{
+		Scene::check_stress_mesh(mesh)?;
+
+		let count = count.min(room);
+		let columns = (count as f32).sqrt().ceil().max(1.0) as usize;
+		let spacing = 1.5;
+		for i in 0..count
+		{
+			let x = (i % columns) as f32 - (columns - 1) as f32 / 2.0;
+			let y = (i / columns) as f32 - (columns - 1) as f32 / 2.0;
+			let position = Point3::new(x * spacing, y * spacing, -10.0);
+			let object = Scene::new_stress_object(rs, mp, mesh, position)?;
+			self.objects.push(object);
+		}
+
+		Ok(count)
+	}
+
+	/// Adds objects at the seeded random positions and rotations of the scatter, for varied scenes that
+	/// are the same on every run.
+	///
+	/// The mesh is one of the stress test meshes, see add_stress_grid(). Returns the number of objects
+	/// added, which is limited by room, see add_cuboid().
+	pub fn add_scatter(
+		&mut self, rs: &RenderState, mp: &MainPass, scatter: &Scatter, mesh: &str, room: usize
+	) -> Result<usize, String>
+	{
+		Scene::check_stress_mesh(mesh)?;
+
+		let transforms = scatter.transforms();
+		for &(position, rotation) in transforms.iter().take(room)
+		{
+			let mut object = Scene::new_stress_object(rs, mp, mesh, position)?;
+			object.set_rotation(rotation);
+			object.store_previous_transform();
+			self.objects.push(object);
+		}
+
+		Ok(transforms.len().min(room))
+	}
+
+	/// Fails if the mesh is not one of the stress test meshes.
+	fn check_stress_mesh(mesh: &str) -> Result<(), String>
+	{
+		match mesh
+		{
+			"cube" | "quad" | "triangle" | "hexagon" => Ok(()),
+			_ => Err(format!("Unknown stress test mesh: {}", mesh)),
+		}
+	}
+
+	/// Creates a stress test object with the given mesh, see add_stress_grid().
+	fn new_stress_object(
+		rs: &RenderState, mp: &MainPass, mesh: &str, position: Point3<f32>
+	) -> Result<DrawObject, String>
+	{
+		match mesh
+		{
+			"quad" => DrawObject::new_quad(rs, mp, position, 0.5, 0.5),
+			"triangle" => DrawObject::new_colored_triangle(rs, mp, position, 0.5),
+			"hexagon" =>
+			{
+				// Drawn as a triangle fan where supported
+				let hexagon: Vec<[f32; 2]> = (0..6)
+					.map(|corner| {
+						let angle = corner as f32 * f32::consts::PI / 3.0;
+						[0.5 * angle.cos(), 0.5 * angle.sin()]
+					})
+					.collect();
+				DrawObject::new_polygon(rs, mp, position, &hexagon, [1.0, 0.8, 0.2, 1.0])
+			}
+			_ => DrawObject::new_cuboid(rs, mp, position, 1.0, 1.0, 1.0),
+		}
+	}
+
+	/// Returns the number of objects in the scene.
+	pub fn object_count(&self) -> usize
+	{
+		self.objects.len()
+	}
+
+	/// Returns the number of DrawObjects holding GPU resources, the objects along with the debug ones.
+	pub fn resident_object_count(&self) -> usize
+	{
+		self.objects.len() + HELPER_OBJECTS + self.debug_frustum.iter().count() + self.material_preview.iter().count()
+	}
+
+	/// Adds the spline as a line through the given number of sample points, or through adaptively
+	/// placed samples if a max error is given, see NURBSpline::sample_adaptive().
+	///
+	/// Fails if room is zero, see add_cuboid().
+	pub fn add_spline(
+		&mut self, rs: &RenderState, mp: &MainPass, spline: &NURBSpline, samples: usize, max_error: Option<f32>,
+		room: usize,
+	) -> Result<(), String>
+	{
+		if room == 0
+		{
+			return Err(String::from("No room for more objects, unload a scene first"));
+		}
+		let samples = match max_error
+		{
+			Some(max_error) if max_error > 0.0 => spline.sample_adaptive(max_error as f64),
+			Some(max_error) => return Err(format!("Invalid spline max error: {}", max_error)),
+			None if samples >= 2 => spline.sample(samples),
+			None => return Err(format!("Invalid number of spline samples: {}", samples)),
+		};
+		if samples.len() > u16::max_value() as usize
+		{
+			return Err(format!("Too many spline samples: {}", samples.len()));
+		}
+
+		let points: Vec<Point3<f32>> = samples
+			.iter()
+			.map(|p| Point3::new(p.x as f32, p.y as f32, p.z as f32))
+			.collect();
+		let line = DrawObject::new_line_strip(rs, mp, &points, [1.0, 0.8, 0.0, 1.0])?;
+		self.objects.push(line);
+		Ok(())
+	}
+
+	/// Animates the object with the given index through the keyframes, replacing any previous animation.
+	pub fn set_object_animation(
+		&mut self, id: usize, keyframes: Vec<Keyframe>, loop_mode: SplineEndMode
+	) -> Result<(), String>
+	{
+		if id >= self.objects.len()
+		{
+			return Err(format!("No object with index {}", id));
+		}
+		let animation = Animation::new(keyframes, loop_mode)?;
+
+		self.animations.retain(|&(object, _)| object != id);
+		self.animations.push((id, animation));
+		Ok(())
+	}
+
+	/// Sets the render layer of the object with the given index. Lower layers are drawn first.
+	pub fn set_object_layer(&mut self, id: usize, layer: i32) -> Result<(), String>
+	{
+		match self.objects.get_mut(id)
+		{
+			Some(object) =>
+			{
+				object.set_layer(layer);
+				Ok(())
+			}
+			None => Err(format!("No object with index {}", id)),
+		}
+	}
+
+	/// Sets whether the object with the given index is drawn by draw_depth(), e.g. to keep a ground
+	/// plane from shadowing itself.
+	pub fn set_casts_shadow(&mut self, id: usize, casts_shadow: bool) -> Result<(), String>
+	{
+		match self.objects.get_mut(id)
+		{
+			Some(object) =>
+			{
+				object.set_casts_shadow(casts_shadow);
+				Ok(())
+			}
+			None => Err(format!("No object with index {}", id)),
+		}
+	}
+
+	/// Shows or hides an object. Hidden objects keep their GPU resources, but aren't drawn or selected.
+	///
+	/// Unlike the debug filters, this is part of the scene and saved with it.
+	pub fn set_visible(&mut self, id: usize, visible: bool) -> Result<(), String>
+	{
+		match self.objects.get_mut(id)
+		{
+			Some(object) =>
+			{
+				object.set_visible(visible);
+				Ok(())
+			}
+			None => Err(format!("No object with index {}", id)),
+		}
+	}
+
+	/// Advances the scene by one simulation step of the given length.
+	///
+	/// Elapsed time is the simulated time in seconds, used to sample the animations. Both are in
+	/// simulated time, so replaying the same steps gives the same scene at any frame rate.
+	pub fn update(&mut self, elapsed_time: f32, step_secs: f32)
+	{
+		for object in self.objects.iter_mut()
+		{
+			object.store_previous_transform();
+		}
+
 		for (i, mut object) in self.objects.iter_mut().enumerate()
 		{
 			if i > 0
@@ -62,18 +642,689 @@ impl Scene
 				break;
 			}
 			// TODO: Move this.
-			object.globally_rotate(Quaternion::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), Deg(-0.5)));
+			let spin = Quaternion::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), Deg(SPIN_SPEED * step_secs));
+			object.globally_rotate(spin);
+		}
+
+		for &(i, ref animation) in self.animations.iter()
+		{
+			let (position, rotation) = animation.sample(elapsed_time);
+			self.objects[i].set_position(position);
+			self.objects[i].set_rotation(rotation);
+		}
+
+		if let Some(ref mut preview) = self.material_preview
+		{
+			preview.store_previous_transform();
+			let turn = Quaternion::from_axis_angle(Vector3::new(0.0, 1.0, 0.0), Deg(MATERIAL_PREVIEW_TURN * step_secs));
+			preview.globally_rotate(turn);
 		}
 	}
 
+	/// Records the depth only draws of all triangle list objects casting shadows, e.g. for
+	/// MainPass::render_cube_depth().
+	///
+	/// Uses the per-object uniforms uploaded by the last draw(), so it must be called after that. Draws
+	/// nothing while the material preview is shown, as the uniforms are the preview's then.
+	pub fn draw_depth(&self, rs: &RenderState, mp: &MainPass, cmd_buf: vk::CommandBuffer)
+	{
+		if self.material_preview.is_some()
+		{
+			return;
+		}
+
+		let casters = self.objects.iter().enumerate().filter(|&(_, object)| object.casts_shadow());
+		for (i, object) in casters.filter(|&(_, object)| object.is_visible())
+		{
+			// The depth only pipeline draws triangle lists, and overlays don't occlude anything
+			match object.pipeline_kind()
+			{
+				PipelineKind::Textured | PipelineKind::VertexColor => (),
+				_ => continue,
+			}
+
+			mp.bind_object(rs, cmd_buf, i);
+			object.draw_lod(cmd_buf, mp.pipeline_layout, 0);
+		}
+	}
+
+	/// Shows the wireframe of the given frustum, or hides it if None.
+	pub fn set_debug_frustum(
+		&mut self, rs: &RenderState, mp: &MainPass, frustum: Option<&Frustum>
+	) -> Result<(), String>
+	{
+		// The old wireframe may still be in use
+		rs.device.device_wait_idle().unwrap();
+		self.debug_frustum = None;
+		if let Some(frustum) = frustum
+		{
+			self.debug_frustum = Some(Scene::frustum_lines(rs, mp, frustum)?);
+		}
+		Ok(())
+	}
+
+	/// Creates the wireframe of the frustum.
+	fn frustum_lines(rs: &RenderState, mp: &MainPass, frustum: &Frustum) -> Result<DrawObject, String>
+	{
+		let indices = Frustum::edges().iter().flat_map(|&(a, b)| vec![a, b]).collect();
+		DrawObject::new_lines(rs, mp, frustum.corners(), indices, [1.0, 0.0, 1.0, 1.0])
+	}
+
+	/// Selects the next visible object, or none after the last one. Returns the new selection.
+	pub fn select_next(&mut self) -> Option<usize>
+	{
+		let first = match self.selected
+		{
+			Some(i) => i + 1,
+			None => 0,
+		};
+		self.selected = (first..self.objects.len()).find(|&i| self.objects[i].is_visible());
+		if self.selected.is_none()
+		{
+			// Soloing nothing would hide everything
+			self.solo = false;
+		}
+		self.selected
+	}
+
+	/// Returns the position of the selected object, if any.
+	pub fn selected_position(&self) -> Option<Point3<f32>>
+	{
+		self.selected.map(|i| self.objects[i].get_position())
+	}
+
+	/// Turns the selected object by the rotation, see DrawObject::turn(). Returns false without a
+	/// selection.
+	pub fn turn_selected(&mut self, rotation: Quaternion<f32>) -> bool
+	{
+		match self.selected
+		{
+			Some(i) =>
+			{
+				self.objects[i].turn(rotation);
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Scales the tessellation of the selected sphere or plane, or of all of them without a selection, see
+	/// DrawObject::scale_tessellation(). Returns the number of objects generated again.
+	pub fn scale_tessellation(&mut self, rs: &RenderState, factor: f32) -> usize
+	{
+		// The old meshes may still be in use
+		rs.device.device_wait_idle().unwrap();
+		let indices: Vec<usize> = match self.selected
+		{
+			Some(i) => vec![i],
+			None => (0..self.objects.len()).collect(),
+		};
+		let objects = &mut self.objects;
+		indices.into_iter().filter(|&i| objects[i].scale_tessellation(rs, factor)).count()
+	}
+
+	/// Returns the number of triangles of the scene objects at full detail.
+	pub fn triangle_count(&self) -> usize
+	{
+		self.objects.iter().map(|object| object.triangle_count(0)).sum()
+	}
+
+	/// Toggles drawing only the selected object, returning whether solo mode is on now.
+	///
+	/// This only filters what is drawn, turning it off shows the whole scene again.
+	pub fn toggle_solo(&mut self) -> Result<bool, String>
+	{
+		if !self.solo && self.selected.is_none()
+		{
+			return Err(String::from("Select an object before enabling solo mode"));
+		}
+		self.solo = !self.solo;
+		Ok(self.solo)
+	}
+
+	/// Hides the selected object, or shows it again if it is hidden. Returns whether it is hidden now.
+	pub fn toggle_hide_selected(&mut self) -> Result<bool, String>
+	{
+		let selected = match self.selected
+		{
+			Some(selected) => selected,
+			None => return Err(String::from("No object selected")),
+		};
+		if self.hidden.contains(&selected)
+		{
+			self.hidden.retain(|&i| i != selected);
+			Ok(false)
+		}
+		else
+		{
+			self.hidden.push(selected);
+			Ok(true)
+		}
+	}
+
+	/// Returns false if the object with the given index is invisible, or filtered out by solo mode or hiding.
+	fn is_shown(&self, index: usize) -> bool
+	{
+		if !self.objects[index].is_visible()
+		{
+			false
+		}
+		else if self.solo
+		{
+			self.selected == Some(index)
+		}
+		else
+		{
+			!self.hidden.contains(&index)
+		}
+	}
+
+	/// Shows or hides the axis gizmo, returning whether it is shown now.
+	pub fn toggle_axis_gizmo(&mut self) -> bool
+	{
+		self.show_axis_gizmo = !self.show_axis_gizmo;
+		self.show_axis_gizmo
+	}
+
+	/// Shows or hides the minimap, returning whether it is shown now.
+	pub fn toggle_minimap(&mut self) -> bool
+	{
+		self.show_minimap = !self.show_minimap;
+		self.show_minimap
+	}
+
+	/// Shows or hides the bounding spheres of the drawn objects, returning whether they are shown now.
+	///
+	/// These are the spheres tested against the culling frustum, to see why an object gets culled.
+	pub fn toggle_bounds(&mut self) -> bool
+	{
+		self.show_bounds = !self.show_bounds;
+		self.show_bounds
+	}
+
+	/// Returns whether the objects use their normal maps, see toggle_normal_maps().
+	pub fn normal_maps_enabled(&self) -> bool
+	{
+		self.normal_maps
+	}
+
+	/// Switches all objects between their normal maps and flat ones, returning whether the normal maps
+	/// are used now.
+	pub fn toggle_normal_maps(&mut self, rs: &RenderState) -> bool
+	{
+		self.normal_maps = !self.normal_maps;
+		// The descriptor sets may still be in use
+		rs.device.device_wait_idle().unwrap();
+		for object in self.objects.iter_mut()
+		{
+			object.set_normal_map_enabled(rs, self.normal_maps);
+		}
+		self.normal_maps
+	}
+
+	/// Shows a sphere with the material of the selected object in front of the camera instead of the
+	/// scene, or the scene again. Returns whether the preview is shown now.
+	///
+	/// The sphere slowly turns, so the material can be seen from all sides under the current lighting.
+	pub fn toggle_material_preview(&mut self, rs: &RenderState, mp: &MainPass, camera: &Camera) -> Result<bool, String>
+	{
+		if self.material_preview.is_some()
+		{
+			// The sphere may still be in use
+			rs.device.device_wait_idle().unwrap();
+			self.material_preview = None;
+			return Ok(false);
+		}
+
+		let position = camera.get_position() + camera.get_cam_front() * MATERIAL_PREVIEW_DISTANCE;
+		let mut preview = DrawObject::new_sphere(rs, mp, position, 1.0, 64, 32)?;
+		let material = self.selected.map(|i| self.objects[i].material().clone()).unwrap_or_default();
+		preview.set_material(rs, material);
+		preview.set_normal_map_enabled(rs, self.normal_maps);
+		self.material_preview = Some(preview);
+		Ok(true)
+	}
+
+	/// Draws all objects in the scene that are inside the culling frustum, followed by the axis gizmo.
+	///
+	/// Alpha is the fraction of a simulation step that has passed since the last update. The axis gizmo
+	/// and the bounding spheres are not drawn when recording in parallel. Returns what was recorded.
 	pub fn draw(
-		&self, cmd_buf: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout, view_matrix: &Matrix4<f32>,
-		projection_matrix: &Matrix4<f32>,
-	)
+		&self, rs: &RenderState, mp: &MainPass, cmd_buf: vk::CommandBuffer, view_matrix: &Matrix4<f32>,
+		projection_matrix: &Matrix4<f32>, cull_frustum: &Frustum, alpha: f32,
+	) -> DrawStats
+	{
+		// The debug frustum only gets drawn if there is room for its uniforms. The material preview
+		// replaces everything.
+		let objects: Vec<&DrawObject> = match self.material_preview
+		{
+			Some(ref preview) => vec![preview],
+			None => self.objects.iter().chain(self.debug_frustum.iter()).take(MAX_OBJECTS).collect(),
+		};
+		let scene_object_count = if self.material_preview.is_some() { 1 } else { self.objects.len() };
+
+		// Write all object matrices in one go
+		let mut uniforms: Vec<ObjectUniforms> = objects
+			.iter()
+			.map(|object| {
+				let model_matrix = object.get_model_matrix(alpha);
+				ObjectUniforms {
+					model: model_matrix,
+					mvp: projection_matrix * view_matrix * model_matrix,
+					specular: object.material().specular_params(),
+				}
+			})
+			.collect();
+		// The gizmo uses the next free slot, if any
+		let axis_gizmo_index = uniforms.len();
+		let draw_axis_gizmo = self.show_axis_gizmo && axis_gizmo_index < MAX_OBJECTS;
+		if draw_axis_gizmo
+		{
+			uniforms.push(ObjectUniforms {
+				model: Matrix4::identity(),
+				mvp: Scene::axis_gizmo_matrix(view_matrix),
+				specular: self.axis_gizmo.material().specular_params(),
+			});
+		}
+
+		// The translation of the model-view matrix is the object's position relative to the camera
+		let distances: Vec<f32> = uniforms
+			.iter()
+			.map(|object_uniforms| (view_matrix * object_uniforms.model).w.truncate().magnitude())
+			.collect();
+		let lod_for = |i: usize, object: &DrawObject| object.lod_for_distance(distances[i]);
+
+		// Skip objects that are filtered out or whose bounding sphere is outside the frustum. The debug
+		// frustum comes after the scene objects and is always shown.
+		let keys: Vec<DrawKey> = (0..objects.len())
+			.map(|i| {
+				let center = Point3::from_vec(uniforms[i].model.w.truncate());
+				let filtered = i < scene_object_count && self.material_preview.is_none() && !self.is_shown(i);
+				DrawKey {
+					shown: !filtered && cull_frustum.intersects_sphere(center, objects[i].bounding_radius()),
+					layer: objects[i].layer(),
+					kind: objects[i].pipeline_kind(),
+					distance: distances[i],
+				}
+			})
+			.collect();
+		let draw_order = draw_order(&keys);
+		let mut stats = DrawStats {
+			culled: objects.len() - draw_order.len(),
+			..Default::default()
+		};
+
+		// The bounding spheres of the drawn scene objects use the free slots after the gizmo
+		let mut bounds_slots = Vec::new();
+		if self.show_bounds && !mp.is_recording_parallel()
+		{
+			for &i in draw_order.iter().filter(|&&i| i < scene_object_count)
+			{
+				if uniforms.len() >= MAX_OBJECTS
+				{
+					break;
+				}
+				let model_matrix = Matrix4::from_translation(uniforms[i].model.w.truncate())
+					* Matrix4::from_scale(objects[i].bounding_radius());
+				bounds_slots.push(uniforms.len());
+				uniforms.push(ObjectUniforms {
+					model: model_matrix,
+					mvp: projection_matrix * view_matrix * model_matrix,
+					specular: self.bounding_sphere.material().specular_params(),
+				});
+			}
+		}
+
+		// The minimap marks the shown scene objects within its bounds and the camera frustum, using the
+		// free slots after the bounding spheres. The frustum comes last.
+		let mut minimap_slots = Vec::new();
+		if self.show_minimap && self.material_preview.is_none() && !mp.is_recording_parallel()
+		{
+			let minimap_matrix = self.minimap_matrix(view_matrix);
+			let minimap_bounds = Frustum::from_matrix(&minimap_matrix);
+			for i in (0..scene_object_count).filter(|&i| self.is_shown(i))
+			{
+				if uniforms.len() >= MAX_OBJECTS
+				{
+					break;
+				}
+				let center = uniforms[i].model.w.truncate();
+				let radius = objects[i].bounding_radius();
+				if !minimap_bounds.intersects_sphere(Point3::from_vec(center), radius)
+				{
+					continue;
+				}
+				let model_matrix = Matrix4::from_translation(center) * Matrix4::from_scale(radius);
+				minimap_slots.push(uniforms.len());
+				uniforms.push(ObjectUniforms {
+					model: model_matrix,
+					mvp: minimap_matrix * model_matrix,
+					specular: self.minimap_marker.material().specular_params(),
+				});
+			}
+			if uniforms.len() < MAX_OBJECTS
+			{
+				// Maps the corners of the clip volume back to the corners of the camera frustum
+				let model_matrix = (projection_matrix * view_matrix).invert().unwrap_or(Matrix4::identity());
+				minimap_slots.push(uniforms.len());
+				uniforms.push(ObjectUniforms {
+					model: model_matrix,
+					mvp: minimap_matrix * model_matrix,
+					specular: self.minimap_frustum.material().specular_params(),
+				});
+			}
+		}
+		mp.upload_object_uniforms(rs, &uniforms);
+
+		if mp.is_recording_parallel()
+		{
+			let commands: Vec<DrawCommand> = draw_order
+				.iter()
+				.map(|&i| {
+					let object = objects[i];
+					let lod = lod_for(i, object);
+					stats.triangles += object.triangle_count(lod);
+					object.draw_command(lod, i)
+				})
+				.collect();
+			// Each command binds its descriptor sets in one call. Only the pipeline changes between
+			// consecutive commands are counted. The recording threads split the commands into chunks and
+			// each binds the pipeline of its first command again, those binds are not counted.
+			stats.draw_calls = commands.len();
+			stats.descriptor_set_binds = commands.len();
+			stats.pipeline_binds = commands
+				.iter()
+				.enumerate()
+				.filter(|&(n, command)| n == 0 || commands[n - 1].pipeline_kind != command.pipeline_kind)
+				.count();
+			mp.execute_parallel(rs, cmd_buf, &commands);
+			return stats;
+		}
+
+		// begin_frame() binds the regular pipeline
+		let mut bound_kind = Some(PipelineKind::Textured);
+		// Also set to zero by begin_frame(), but binding the occlusion proxy pipeline loses it
+		let mut depth_bias = Some(DepthBias::default());
+		for &i in draw_order.iter()
+		{
+			let object = objects[i];
+			// Overlays and lines are cheap and should never disappear, so they are not tested
+			let kind = object.pipeline_kind();
+			let occlusion_tested =
+				mp.occlusion_enabled() && kind != PipelineKind::Overlay && kind != PipelineKind::Line;
+			if occlusion_tested && !mp.is_likely_visible(i)
+			{
+				// Only draw the bounding box, to find out if the object shows up again
+				mp.bind_occlusion_proxy_pipeline(rs, cmd_buf);
+				bound_kind = None;
+				depth_bias = None;
+				mp.bind_object(rs, cmd_buf, i);
+				mp.begin_occlusion_query(rs, cmd_buf, i);
+				object.draw_occlusion_proxy(cmd_buf, mp.pipeline_layout);
+				mp.end_occlusion_query(rs, cmd_buf, i);
+				stats.occlusion_proxies += 1;
+				stats.pipeline_binds += 1;
+				stats.descriptor_set_binds += 2;
+				stats.draw_calls += 1;
+				stats.triangles += object.occlusion_proxy_triangle_count();
+				continue;
+			}
+
+			if bound_kind != Some(kind)
+			{
+				bound_kind = Some(kind);
+				mp.bind_pipeline_kind(rs, cmd_buf, kind);
+				stats.pipeline_binds += 1;
+			}
+			if depth_bias != Some(object.depth_bias())
+			{
+				depth_bias = Some(object.depth_bias());
+				mp.set_depth_bias(rs, cmd_buf, object.depth_bias());
+			}
+
+			let lod = lod_for(i, object);
+			// bind_object() and draw_lod() each bind descriptor sets
+			stats.descriptor_set_binds += 2;
+			stats.draw_calls += 1;
+			stats.triangles += object.triangle_count(lod);
+
+			rs.push_debug_label(cmd_buf, &format!("Object {}", i), [0.5, 0.5, 0.5, 1.0]);
+			mp.bind_object(rs, cmd_buf, i);
+			if occlusion_tested
+			{
+				mp.begin_occlusion_query(rs, cmd_buf, i);
+				object.draw_lod(cmd_buf, mp.pipeline_layout, lod);
+				mp.end_occlusion_query(rs, cmd_buf, i);
+			}
+			else
+			{
+				object.draw_lod(cmd_buf, mp.pipeline_layout, lod);
+			}
+			rs.pop_debug_label(cmd_buf);
+		}
+
+		if !bounds_slots.is_empty()
+		{
+			rs.push_debug_label(cmd_buf, "Bounding spheres", [0.5, 0.5, 0.5, 1.0]);
+			if bound_kind != Some(PipelineKind::Line)
+			{
+				bound_kind = Some(PipelineKind::Line);
+				mp.bind_pipeline_kind(rs, cmd_buf, PipelineKind::Line);
+				stats.pipeline_binds += 1;
+			}
+			if depth_bias != Some(DepthBias::default())
+			{
+				depth_bias = Some(DepthBias::default());
+				mp.set_depth_bias(rs, cmd_buf, DepthBias::default());
+			}
+			for &slot in bounds_slots.iter()
+			{
+				mp.bind_object(rs, cmd_buf, slot);
+				self.bounding_sphere.draw_lod(cmd_buf, mp.pipeline_layout, 0);
+			}
+			rs.pop_debug_label(cmd_buf);
+			stats.descriptor_set_binds += 2 * bounds_slots.len();
+			stats.draw_calls += bounds_slots.len();
+		}
+
+		if draw_axis_gizmo
+		{
+			rs.push_debug_label(cmd_buf, "Axis gizmo", [0.5, 0.5, 0.5, 1.0]);
+			mp.set_corner_viewport(rs, cmd_buf, self.axis_gizmo_size, Corner::BottomLeft);
+			if bound_kind != Some(PipelineKind::Line)
+			{
+				bound_kind = Some(PipelineKind::Line);
+				mp.bind_pipeline_kind(rs, cmd_buf, PipelineKind::Line);
+				stats.pipeline_binds += 1;
+			}
+			if depth_bias != Some(DepthBias::default())
+			{
+				depth_bias = Some(DepthBias::default());
+				mp.set_depth_bias(rs, cmd_buf, DepthBias::default());
+			}
+			mp.bind_object(rs, cmd_buf, axis_gizmo_index);
+			self.axis_gizmo.draw_lod(cmd_buf, mp.pipeline_layout, 0);
+			mp.reset_viewport(rs, cmd_buf);
+			rs.pop_debug_label(cmd_buf);
+			stats.descriptor_set_binds += 2;
+			stats.draw_calls += 1;
+		}
+
+		if !minimap_slots.is_empty()
+		{
+			rs.push_debug_label(cmd_buf, "Minimap", [0.5, 0.5, 0.5, 1.0]);
+			mp.set_corner_viewport(rs, cmd_buf, self.minimap_size, Corner::BottomRight);
+			if bound_kind != Some(PipelineKind::Line)
+			{
+				mp.bind_pipeline_kind(rs, cmd_buf, PipelineKind::Line);
+				stats.pipeline_binds += 1;
+			}
+			if depth_bias != Some(DepthBias::default())
+			{
+				mp.set_depth_bias(rs, cmd_buf, DepthBias::default());
+			}
+			let (&frustum_slot, marker_slots) = minimap_slots.split_last().unwrap();
+			for &slot in marker_slots.iter()
+			{
+				mp.bind_object(rs, cmd_buf, slot);
+				self.minimap_marker.draw_lod(cmd_buf, mp.pipeline_layout, 0);
+			}
+			mp.bind_object(rs, cmd_buf, frustum_slot);
+			self.minimap_frustum.draw_lod(cmd_buf, mp.pipeline_layout, 0);
+			mp.reset_viewport(rs, cmd_buf);
+			rs.pop_debug_label(cmd_buf);
+			stats.descriptor_set_binds += 2 * minimap_slots.len();
+			stats.draw_calls += minimap_slots.len();
+		}
+
+		stats
+	}
+
+	/// Returns the matrix projecting the minimap, looking straight down on the camera with -Z pointing
+	/// up on the map.
+	fn minimap_matrix(&self, view_matrix: &Matrix4<f32>) -> Matrix4<f32>
 	{
-		for object in self.objects.iter()
+		let camera_position = match view_matrix.invert()
 		{
-			object.draw(cmd_buf, pipeline_layout, view_matrix, projection_matrix);
+			Some(inverse) => Point3::from_vec(inverse.w.truncate()),
+			None => Point3::new(0.0, 0.0, 0.0),
+		};
+		let eye = camera_position + Vector3::new(0.0, MINIMAP_HEIGHT, 0.0);
+		let view = Matrix4::look_at(eye, camera_position, Vector3::new(0.0, 0.0, -1.0));
+
+		// Orthographic projection of the range around the camera, flipped for the Vulkan NDC and mapping
+		// view depth [0, -2 * MINIMAP_HEIGHT] to [0, 1]
+		let scale = 1.0 / self.minimap_range;
+		let projection = Matrix4::new(
+			scale, 0.0, 0.0, 0.0, 0.0, -scale, 0.0, 0.0, 0.0, 0.0, -0.5 / MINIMAP_HEIGHT, 0.0, 0.0, 0.0, 0.0, 1.0,
+		);
+		projection * view
+	}
+
+	/// Returns the matrix projecting the axis gizmo into its viewport, rotated like the camera.
+	fn axis_gizmo_matrix(view_matrix: &Matrix4<f32>) -> Matrix4<f32>
+	{
+		// Only keep the rotation of the camera
+		let mut rotation = *view_matrix;
+		rotation.w = Vector4::new(0.0, 0.0, 0.0, 1.0);
+
+		// Orthographic projection of the unit axes with some margin, flipped for the Vulkan NDC and
+		// mapping view depth [-1, 1] to [0.9, 0.1]
+		let scale = 0.8;
+		let projection = Matrix4::new(
+			scale, 0.0, 0.0, 0.0, 0.0, -scale, 0.0, 0.0, 0.0, 0.0, -0.5 * scale, 0.0, 0.0, 0.0, 0.5, 1.0,
+		);
+		projection * rotation
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use std::env;
+	use std::fs;
+
+	fn saved_scene() -> SavedScene
+	{
+		SavedScene {
+			objects: vec![
+				SavedObject {
+					mesh: MeshSource::Cuboid {
+						width: 1.0,
+						height: 2.0,
+						depth: 3.0,
+					},
+					position: [1.0, -2.0, 3.5],
+					rotation: [0.5, 0.5, -0.5, 0.5],
+					material: Material::default(),
+					layer: 2,
+					overlay: false,
+					depth_bias: Some(DepthBias::default()),
+					casts_shadow: Some(false),
+					visible: Some(true),
+				},
+				SavedObject {
+					mesh: MeshSource::Quad {
+						width: 0.5,
+						height: 0.25,
+					},
+					position: [0.0, 0.0, -10.0],
+					rotation: [1.0, 0.0, 0.0, 0.0],
+					material: Material::default(),
+					layer: -1,
+					overlay: true,
+					depth_bias: None,
+					casts_shadow: None,
+					visible: Some(false),
+				},
+			],
+			camera_position: [4.0, 5.0, 6.0],
+			camera_front: [0.6, 0.0, -0.8],
+			light_direction: [0.0, -1.0, 0.0],
+			light_color: [1.0, 0.9, 0.8],
+			light_intensity: 2.5,
+			ambient_color: [0.1, 0.2, 0.3],
 		}
 	}
+
+	fn key(shown: bool, layer: i32, kind: PipelineKind, distance: f32) -> DrawKey
+	{
+		DrawKey {
+			shown: shown,
+			layer: layer,
+			kind: kind,
+			distance: distance,
+		}
+	}
+
+	#[test]
+	fn hidden_objects_are_not_drawn()
+	{
+		let keys = [
+			key(true, 0, PipelineKind::Textured, 1.0),
+			key(false, 0, PipelineKind::Textured, 2.0),
+			key(true, 0, PipelineKind::Textured, 3.0),
+		];
+		assert_eq!(draw_order(&keys), vec![0, 2]);
+		assert!(draw_order(&[key(false, 0, PipelineKind::Overlay, 1.0)]).is_empty());
+	}
+
+	#[test]
+	fn draw_order_follows_layer_pipeline_and_distance()
+	{
+		let keys = [
+			key(true, 1, PipelineKind::Textured, 1.0),
+			key(true, 0, PipelineKind::Overlay, 1.0),
+			key(true, 0, PipelineKind::Blended, 2.0),
+			key(true, 0, PipelineKind::Blended, 5.0),
+			key(true, 0, PipelineKind::Textured, 3.0),
+			key(true, 0, PipelineKind::Textured, 1.0),
+		];
+		// Opaque front to back, then blended back to front, then overlays, then the next layer.
+		assert_eq!(draw_order(&keys), vec![5, 4, 3, 2, 1, 0]);
+	}
+
+	#[test]
+	fn save_then_load_is_equivalent()
+	{
+		let path = env::temp_dir().join("project_peril_scene_round_trip.json");
+		let path = path.to_str().unwrap();
+		let saved = saved_scene();
+		saved.write(path).unwrap();
+		let loaded = SavedScene::read(path);
+		fs::remove_file(path).unwrap();
+		let loaded = loaded.unwrap();
+		assert_eq!(loaded, saved);
+
+		let mut camera = Camera::new(Point3::new(0.0, 0.0, 0.0));
+		let mut lighting = Lighting::from_config(&Config::default());
+		loaded.restore_camera_and_lighting(&mut camera, &mut lighting);
+		assert_eq!(camera.get_position(), Point3::from(saved.camera_position));
+		assert!((camera.get_cam_front() - Vector3::from(saved.camera_front)).magnitude() < 1e-5);
+		assert_eq!(lighting.direction, saved.light_direction);
+		assert_eq!(lighting.color, saved.light_color);
+		assert_eq!(lighting.intensity, saved.light_intensity);
+		assert_eq!(lighting.ambient, saved.ambient_color);
+	}
 }
@@ -0,0 +1,198 @@
+use cgmath::{Point3, Vector3};
+use config::Config;
+use object::{Camera, Position};
+use renderer::{MainPass, RenderState, MAX_OBJECTS};
+use scene::{Lighting, Scene, HELPER_OBJECTS};
+
+/// At most one scene per number key.
+pub const MAX_SCENES: usize = 9;
+
+/// The camera pose and lighting a scene was loaded with, restored when switching to it.
+struct SceneStart
+{
+	camera_position: Point3<f32>,
+	camera_front: Vector3<f32>,
+	lighting: Lighting,
+}
+
+impl SceneStart
+{
+	fn capture(camera: &Camera, lighting: &Lighting) -> SceneStart
+	{
+		SceneStart {
+			camera_position: camera.get_position(),
+			camera_front: camera.get_cam_front(),
+			lighting: *lighting,
+		}
+	}
+}
+
+struct LoadedScene
+{
+	scene: Scene,
+	name: String,
+	start: SceneStart,
+}
+
+/// Returns the number of objects that still fit next to the given number of resident DrawObjects, keeping
+/// room for the helper objects of one more scene.
+pub fn object_slots_left(resident: usize) -> usize
+{
+	MAX_OBJECTS.saturating_sub(resident + HELPER_OBJECTS)
+}
+
+/// Keeps several scenes loaded at once and draws one of them, so switching between them is instant.
+///
+/// All loaded scenes stay resident on the GPU, and their objects share the descriptor sets of the
+/// MainPass. A scene only fits while free_object_slots() allows it, unload() makes room again.
+pub struct SceneManager
+{
+	// Unloaded scenes leave an empty slot, so the others keep their number keys
+	scenes: Vec<Option<LoadedScene>>,
+	active: usize,
+	/// Leave the camera where it is when switching, instead of moving it to the start of the scene.
+	keep_camera: bool,
+}
+
+impl SceneManager
+{
+	/// Creates the manager with the first scene active. It starts at the current camera pose and lighting.
+	pub fn new(scene: Scene, name: &str, camera: &Camera, lighting: &Lighting, cfg: &Config) -> SceneManager
+	{
+		SceneManager {
+			scenes: vec![Some(LoadedScene {
+				scene: scene,
+				name: name.to_string(),
+				start: SceneStart::capture(camera, lighting),
+			})],
+			active: 0,
+			keep_camera: cfg.keep_camera_on_scene_switch,
+		}
+	}
+
+	/// Loads a scene saved by Scene::save_to_json() into the first empty slot, if its objects fit next
+	/// to the resident scenes. Returns the slot of the scene.
+	///
+	/// Unlike Scene::load_from_json(), this leaves the camera and the lighting in use alone.
+	pub fn preload(&mut self, rs: &RenderState, mp: &MainPass, cfg: &Config, path: &str) -> Result<usize, String>
+	{
+		let index = match self.scenes.iter().position(|slot| slot.is_none())
+		{
+			Some(index) => index,
+			None if self.scenes.len() < MAX_SCENES => self.scenes.len(),
+			None => return Err(format!("At most {} scenes can be loaded, unload one first", MAX_SCENES)),
+		};
+
+		let mut scene_camera = Camera::new(Point3::from(cfg.camera_position));
+		let mut scene_lighting = Lighting::from_config(cfg);
+		let max_objects = self.free_object_slots();
+		let result = Scene::load_from_json(rs, mp, cfg, &mut scene_camera, &mut scene_lighting, path, max_objects);
+		let start = SceneStart::capture(&scene_camera, &scene_lighting);
+
+		let loaded = LoadedScene {
+			scene: result?,
+			name: path.to_string(),
+			start: start,
+		};
+		if index == self.scenes.len()
+		{
+			self.scenes.push(Some(loaded));
+		}
+		else
+		{
+			self.scenes[index] = Some(loaded);
+		}
+		Ok(index)
+	}
+
+	/// Replaces the active scene, e.g. with one loaded from a file. The device must be idle.
+	pub fn replace_active(&mut self, scene: Scene, name: &str, camera: &Camera, lighting: &Lighting)
+	{
+		self.scenes[self.active] = Some(LoadedScene {
+			scene: scene,
+			name: name.to_string(),
+			start: SceneStart::capture(camera, lighting),
+		});
+	}
+
+	/// Makes the scene in the given slot active. Unless the camera is kept, moves the camera to the
+	/// start of the scene.
+	///
+	/// The lighting is replaced with the one of the scene, the caller has to upload it.
+	pub fn switch_to(&mut self, index: usize, camera: &mut Camera, lighting: &mut Lighting) -> Result<(), String>
+	{
+		{
+			let start = match self.scenes.get(index)
+			{
+				Some(&Some(ref loaded)) => &loaded.start,
+				_ => return Err(format!("No scene loaded in slot {}", index + 1)),
+			};
+			if !self.keep_camera
+			{
+				camera.stop_following_spline();
+				camera.set_position(start.camera_position);
+				camera.face_direction(start.camera_front);
+			}
+			*lighting = start.lighting;
+		}
+		self.active = index;
+		Ok(())
+	}
+
+	/// Unloads the scene in the given slot, freeing its GPU resources. The active scene can't be
+	/// unloaded, and the device must be idle.
+	pub fn unload(&mut self, index: usize) -> Result<String, String>
+	{
+		if index == self.active
+		{
+			return Err(String::from("Can't unload the active scene, switch to another one first"));
+		}
+		match self.scenes.get_mut(index).and_then(|slot| slot.take())
+		{
+			Some(loaded) => Ok(loaded.name),
+			None => Err(format!("No scene loaded in slot {}", index + 1)),
+		}
+	}
+
+	pub fn active(&self) -> &Scene
+	{
+		&self.scenes[self.active].as_ref().unwrap().scene
+	}
+
+	pub fn active_mut(&mut self) -> &mut Scene
+	{
+		&mut self.scenes[self.active].as_mut().unwrap().scene
+	}
+
+	/// Returns the name of the active scene, the path it was loaded from.
+	pub fn active_name(&self) -> &str
+	{
+		&self.scenes[self.active].as_ref().unwrap().name
+	}
+
+	/// Returns the number of objects the next loaded scene may have, so all scenes fit in the descriptor
+	/// sets of the MainPass together.
+	pub fn free_object_slots(&self) -> usize
+	{
+		let resident: usize = self.scenes
+			.iter()
+			.filter_map(|slot| slot.as_ref())
+			.map(|loaded| loaded.scene.resident_object_count())
+			.sum();
+		object_slots_left(resident)
+	}
+
+	/// Prints the loaded scenes and their objects, marking the active one.
+	pub fn print_scenes(&self)
+	{
+		for (i, slot) in self.scenes.iter().enumerate()
+		{
+			if let Some(ref loaded) = *slot
+			{
+				let marker = if i == self.active { "*" } else { " " };
+				println!("{} {}: {} ({} objects)", marker, i + 1, loaded.name, loaded.scene.object_count());
+			}
+		}
+		println!("Room for {} more objects", self.free_object_slots());
+	}
+}